@@ -0,0 +1,349 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Wallet accounts identifying own outputs in arbitrary transactions.
+
+use std::collections::BTreeMap;
+
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::{Script, Transaction};
+use bitcoin_hd::{DerivationAccount, DeriveError, SegmentIndexes, TerminalStep, UnhardenedIndex};
+use miniscript::{Descriptor, ForEachKey};
+
+use crate::derive::Descriptor as _;
+
+/// Role of a transaction output relative to a wallet [`Account`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+pub enum OutputRole {
+    /// Output does not belong to the wallet
+    #[display("external")]
+    External,
+
+    /// Output pays to the wallet receive address with the given index
+    #[display("receive({0})")]
+    Receive(UnhardenedIndex),
+
+    /// Output pays to the wallet change address with the given index
+    #[display("change({0})")]
+    Change(UnhardenedIndex),
+}
+
+impl OutputRole {
+    /// Detects whether the output belongs to the wallet.
+    #[inline]
+    pub fn is_own(self) -> bool { self != OutputRole::External }
+}
+
+/// Wallet account defined by a descriptor, which caches scripts derived for
+/// the first indexes of its receive and change branches, such that outputs of
+/// arbitrary transactions can be matched against the wallet without repeated
+/// key derivation.
+///
+/// Descriptors with two variable derivation steps (like `<0;1>/*`) use the
+/// first step as a branch (`0` for receive and `1` for change addresses).
+/// Descriptors with a single variable step derive addresses of the branch
+/// given by the preceding terminal step, such that `/1/*` descriptor derives
+/// change addresses and all others derive receive addresses. Separate receive
+/// and change descriptors are combined with [`Account::with_change`].
+///
+/// Account may know its birthday: height of the block at which it was
+/// created. Chain backends use it to skip blocks preceding the account
+/// creation when scanning for the account outputs.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Account {
+    descriptor: Descriptor<DerivationAccount>,
+    change: Option<Descriptor<DerivationAccount>>,
+    birthday: Option<u32>,
+    derived: u32,
+    scripts: BTreeMap<Script, (UnhardenedIndex, UnhardenedIndex)>,
+}
+
+/// Account descriptor with the value of its variable branch step (if any) and
+/// the branch it derives (`0` for receive and `1` for change addresses).
+type Branch<'descr> = (
+    &'descr Descriptor<DerivationAccount>,
+    Option<UnhardenedIndex>,
+    u8,
+);
+
+/// Returns the branch fixed by the terminal step preceding the last step of
+/// the descriptor keys, if all keys agree on it.
+fn fixed_branch(descriptor: &Descriptor<DerivationAccount>) -> Option<UnhardenedIndex> {
+    let mut branch = None;
+    let consistent = descriptor.for_each_key(|key| {
+        let len = key.terminal_path.len();
+        let step = match len {
+            0 | 1 => None,
+            _ => match key.terminal_path[len - 2] {
+                TerminalStep::Index(index) => Some(index),
+                _ => None,
+            },
+        };
+        *branch.get_or_insert(step) == step
+    });
+    branch.flatten().filter(|_| consistent)
+}
+
+impl Account {
+    /// Constructs account for the descriptor, deriving scripts for the
+    /// first `lookahead` indexes of each branch.
+    ///
+    /// # Errors
+    ///
+    /// If the descriptor uses more than two variable derivation steps or
+    /// the derivation fails.
+    pub fn with<C: Verification>(
+        secp: &Secp256k1<C>,
+        descriptor: Descriptor<DerivationAccount>,
+        lookahead: u32,
+    ) -> Result<Account, DeriveError> {
+        if descriptor.derive_pattern_len()? > 2 {
+            return Err(DeriveError::DerivePatternMismatch);
+        }
+        let mut account = Account {
+            descriptor,
+            change: None,
+            birthday: None,
+            derived: 0,
+            scripts: none!(),
+        };
+        account.extend(secp, lookahead)?;
+        Ok(account)
+    }
+
+    /// Constructs account from a pair of descriptors for receive and change
+    /// addresses, deriving scripts for the first `lookahead` indexes of each
+    /// of them.
+    ///
+    /// # Errors
+    ///
+    /// If any of the descriptors uses more than a single variable derivation
+    /// step or the derivation fails.
+    pub fn with_change<C: Verification>(
+        secp: &Secp256k1<C>,
+        receive: Descriptor<DerivationAccount>,
+        change: Descriptor<DerivationAccount>,
+        lookahead: u32,
+    ) -> Result<Account, DeriveError> {
+        if receive.derive_pattern_len()? > 1 || change.derive_pattern_len()? > 1 {
+            return Err(DeriveError::DerivePatternMismatch);
+        }
+        let mut account = Account {
+            descriptor: receive,
+            change: Some(change),
+            birthday: None,
+            derived: 0,
+            scripts: none!(),
+        };
+        account.extend(secp, lookahead)?;
+        Ok(account)
+    }
+
+    /// Returns descriptor of the account; for accounts constructed from a
+    /// pair of descriptors this is the receive descriptor.
+    #[inline]
+    pub fn descriptor(&self) -> &Descriptor<DerivationAccount> { &self.descriptor }
+
+    /// Returns change descriptor, if the account was constructed from a pair
+    /// of receive and change descriptors.
+    #[inline]
+    pub fn change_descriptor(&self) -> Option<&Descriptor<DerivationAccount>> {
+        self.change.as_ref()
+    }
+
+    /// Returns height of the block at which the account was created, if known.
+    #[inline]
+    pub fn birthday(&self) -> Option<u32> { self.birthday }
+
+    /// Sets height of the block at which the account was created. Outputs
+    /// mined before this height are not looked up by the chain backends, so
+    /// the birthday must not follow the first account transaction.
+    #[inline]
+    pub fn set_birthday(&mut self, birthday: Option<u32>) { self.birthday = birthday; }
+
+    /// Returns number of indexes cached for each of the account branches.
+    #[inline]
+    pub fn derived_count(&self) -> u32 { self.derived }
+
+    /// Lists account descriptors with the branches they derive.
+    fn branches(&self) -> Result<Vec<Branch<'_>>, DeriveError> {
+        let receive = &self.descriptor;
+        if let Some(change) = &self.change {
+            return Ok(vec![(receive, None, 0), (change, None, 1)]);
+        }
+        Ok(match receive.derive_pattern_len()? {
+            2 => vec![
+                (receive, Some(0u8.into()), 0),
+                (receive, Some(1u8.into()), 1),
+            ],
+            _ => {
+                let branch = fixed_branch(receive).filter(|branch| branch.first_index() == 1);
+                vec![(receive, None, u8::from(branch.is_some()))]
+            }
+        })
+    }
+
+    /// Extends the cache to cover the first `count` indexes of each branch.
+    pub fn extend<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        count: u32,
+    ) -> Result<(), DeriveError> {
+        let mut scripts = vec![];
+        for (descriptor, step, branch) in self.branches()? {
+            let branch = UnhardenedIndex::from(branch);
+            let pattern_len = descriptor.derive_pattern_len()?;
+            for index in self.derived..count {
+                let index = UnhardenedIndex::from_index(index)
+                    .map_err(|_| DeriveError::DerivePatternMismatch)?;
+                let pat = match (pattern_len, step) {
+                    (0, _) => vec![],
+                    (_, None) => vec![index],
+                    (_, Some(step)) => vec![step, index],
+                };
+                let script = match descriptor {
+                    Descriptor::Tr(_) => descriptor.script_pubkey_tr(secp, &pat)?,
+                    _ => descriptor.script_pubkey_pretr(secp, &pat)?,
+                };
+                scripts.push((script, (branch, index)));
+                if pattern_len == 0 {
+                    break;
+                }
+            }
+        }
+        self.scripts.extend(scripts);
+        self.derived = self.derived.max(count);
+        Ok(())
+    }
+
+    /// Returns branch (`0` for receive and `1` for change addresses) and index
+    /// of the derivation producing `script`, if the script belongs to the
+    /// account and is within the cached index range.
+    #[inline]
+    pub fn owns(&self, script: &Script) -> Option<(UnhardenedIndex, UnhardenedIndex)> {
+        self.scripts.get(script).copied()
+    }
+
+    /// Detects role of the output with the given `scriptPubkey`.
+    pub fn output_role(&self, script: &Script) -> OutputRole {
+        match self.owns(script) {
+            None => OutputRole::External,
+            Some((branch, index)) if branch.first_index() == 1 => OutputRole::Change(index),
+            Some((_, index)) => OutputRole::Receive(index),
+        }
+    }
+
+    /// Labels each output of the transaction as external, receive or change
+    /// relative to the account.
+    pub fn analyze_tx(&self, tx: &Transaction) -> Vec<OutputRole> {
+        tx.output
+            .iter()
+            .map(|txout| self.output_role(&txout.script_pubkey))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{PackedLockTime, TxOut};
+
+    use super::*;
+
+    #[test]
+    fn own_outputs() {
+        let secp = Secp256k1::verification_only();
+        let key = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(key).unwrap();
+        let mut account = Account::with(&secp, descriptor.clone(), 10).unwrap();
+        assert_eq!(account.derived_count(), 10);
+        assert_eq!(account.birthday(), None);
+        account.set_birthday(Some(800_000));
+        assert_eq!(account.birthday(), Some(800_000));
+
+        let pat = |branch: u8, index: u8| [UnhardenedIndex::from(branch), index.into()];
+        let script = |branch, index| {
+            descriptor
+                .script_pubkey_pretr(&secp, pat(branch, index))
+                .unwrap()
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![],
+            output: [script(0, 3), script(1, 7), Script::new(), script(0, 15)]
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 1000,
+                    script_pubkey,
+                })
+                .collect(),
+        };
+        assert_eq!(account.owns(&script(1, 7)), Some((1u8.into(), 7u8.into())));
+        assert_eq!(account.analyze_tx(&tx), vec![
+            OutputRole::Receive(3u8.into()),
+            OutputRole::Change(7u8.into()),
+            OutputRole::External,
+            OutputRole::External,
+        ]);
+
+        account.extend(&secp, 20).unwrap();
+        assert_eq!(
+            account.output_role(&script(0, 15)),
+            OutputRole::Receive(15u8.into())
+        );
+    }
+
+    #[test]
+    fn branch_descriptors() {
+        let secp = Secp256k1::verification_only();
+        let xpub = "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ";
+        let descriptor = |terminal: &str| {
+            let key = DerivationAccount::from_str(&format!("{}/{}", xpub, terminal)).unwrap();
+            Descriptor::new_wpkh(key).unwrap()
+        };
+        let multipath = descriptor("<0;1>/*");
+        let script = |branch: u8, index: u8| {
+            multipath
+                .script_pubkey_pretr(&secp, [UnhardenedIndex::from(branch), index.into()])
+                .unwrap()
+        };
+
+        let change = Account::with(&secp, descriptor("1/*"), 10).unwrap();
+        assert_eq!(
+            change.output_role(&script(1, 2)),
+            OutputRole::Change(2u8.into())
+        );
+        assert_eq!(change.output_role(&script(0, 2)), OutputRole::External);
+
+        let account =
+            Account::with_change(&secp, descriptor("0/*"), descriptor("1/*"), 10).unwrap();
+        assert_eq!(account.change_descriptor(), Some(&descriptor("1/*")));
+        assert_eq!(
+            account.output_role(&script(0, 4)),
+            OutputRole::Receive(4u8.into())
+        );
+        assert_eq!(
+            account.output_role(&script(1, 5)),
+            OutputRole::Change(5u8.into())
+        );
+        assert_eq!(account.owns(&script(1, 5)), Some((1u8.into(), 5u8.into())));
+
+        assert!(matches!(
+            Account::with_change(&secp, multipath, descriptor("1/*"), 10),
+            Err(DeriveError::DerivePatternMismatch)
+        ));
+    }
+}
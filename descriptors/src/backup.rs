@@ -0,0 +1,271 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Versioned wallet backup file format.
+//!
+//! Backup file consists of the [`BACKUP_MAGIC`] bytes, 16-bit little-endian
+//! format version, strict-encoded [`WalletBackup`] data and a checksum, which
+//! is first 4 bytes of double SHA256 hash of all the preceding bytes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin_hd::DerivationAccount;
+use miniscript::Descriptor;
+#[cfg(feature = "serde")]
+use serde_with::{As, DisplayFromStr};
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::P2cRegistry;
+
+/// Magic bytes starting wallet backup file.
+pub const BACKUP_MAGIC: [u8; 4] = *b"DWBK";
+
+/// Most recent version of the wallet backup format, used for writing backups.
+pub const BACKUP_VERSION: u16 = 2;
+
+/// Errors reading and writing wallet backup files.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum BackupError {
+    /// I/O error. {0}
+    #[from]
+    Io(io::Error),
+
+    /// invalid backup data. {0}
+    #[from]
+    Encoding(strict_encoding::Error),
+
+    /// the data are not a wallet backup file
+    InvalidMagic,
+
+    /// wallet backup file has version {0}, which is not supported; please
+    /// upgrade the software
+    UnsupportedVersion(u16),
+
+    /// wallet backup file is corrupted: checksum does not match the data
+    ChecksumMismatch,
+}
+
+/// Wallet backup data, providing portable alternative to the descriptor text
+/// files.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct WalletBackup {
+    /// Wallet output descriptors
+    #[cfg_attr(feature = "serde", serde(with = "As::<Vec<DisplayFromStr>>"))]
+    pub descriptors: Vec<Descriptor<DerivationAccount>>,
+
+    /// Account extended public keys with their origins
+    #[cfg_attr(feature = "serde", serde(with = "As::<Vec<DisplayFromStr>>"))]
+    pub accounts: Vec<DerivationAccount>,
+
+    /// Height of the block at which the wallet was created, if known
+    pub birthday: Option<u32>,
+
+    /// Labels for addresses, transactions and outputs, keyed by their string
+    /// representation
+    pub labels: BTreeMap<String, String>,
+
+    /// Gap limits, keyed by the keychain (unhardened derivation index
+    /// following account derivation path: 0 for receiving, 1 for change
+    /// addresses)
+    pub gap_limits: BTreeMap<u32, u32>,
+
+    /// Pay-to-contract tweaks applied to the wallet keys (since version 2 of
+    /// the format)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub p2c_tweaks: P2cRegistry,
+}
+
+impl StrictEncode for WalletBackup {
+    fn strict_encode<E: io::Write>(&self, mut e: E) -> Result<usize, strict_encoding::Error> {
+        let descriptors = self.descriptors.iter().map(Descriptor::to_string).collect::<Vec<_>>();
+        Ok(strict_encode_list!(e;
+            descriptors,
+            self.accounts,
+            self.birthday,
+            self.labels,
+            self.gap_limits,
+            self.p2c_tweaks
+        ))
+    }
+}
+
+impl StrictDecode for WalletBackup {
+    #[inline]
+    fn strict_decode<D: io::Read>(d: D) -> Result<Self, strict_encoding::Error> {
+        WalletBackup::strict_decode_version(d, BACKUP_VERSION)
+    }
+}
+
+impl WalletBackup {
+    /// Decodes backup data written with the given version of the format.
+    fn strict_decode_version(
+        mut d: impl io::Read,
+        version: u16,
+    ) -> Result<Self, strict_encoding::Error> {
+        let descriptors = Vec::<String>::strict_decode(&mut d)?
+            .iter()
+            .map(|s| {
+                Descriptor::from_str(s).map_err(|err| {
+                    strict_encoding::Error::DataIntegrityError(format!(
+                        "invalid wallet descriptor `{}`: {}",
+                        s, err
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(WalletBackup {
+            descriptors,
+            accounts: StrictDecode::strict_decode(&mut d)?,
+            birthday: StrictDecode::strict_decode(&mut d)?,
+            labels: StrictDecode::strict_decode(&mut d)?,
+            gap_limits: StrictDecode::strict_decode(&mut d)?,
+            p2c_tweaks: if version >= 2 {
+                StrictDecode::strict_decode(&mut d)?
+            } else {
+                P2cRegistry::default()
+            },
+        })
+    }
+
+    /// Writes backup file data with the most recent format version, returning
+    /// the number of bytes written.
+    pub fn save(&self, mut writer: impl Write) -> Result<usize, BackupError> {
+        let mut data = BACKUP_MAGIC.to_vec();
+        data.extend(BACKUP_VERSION.to_le_bytes());
+        self.strict_encode(&mut data)?;
+        let checksum = sha256d::Hash::hash(&data);
+        data.extend(&checksum[..4]);
+        writer.write_all(&data)?;
+        Ok(data.len())
+    }
+
+    /// Reads backup file data, verifying its version and checksum.
+    pub fn load(mut reader: impl Read) -> Result<Self, BackupError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        if data.len() < BACKUP_MAGIC.len() + 2 + 4 {
+            return Err(BackupError::InvalidMagic);
+        }
+        let (data, checksum) = data.split_at(data.len() - 4);
+        if !data.starts_with(&BACKUP_MAGIC) {
+            return Err(BackupError::InvalidMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version == 0 || version > BACKUP_VERSION {
+            return Err(BackupError::UnsupportedVersion(version));
+        }
+        if checksum != &sha256d::Hash::hash(data)[..4] {
+            return Err(BackupError::ChecksumMismatch);
+        }
+        let mut payload = &data[6..];
+        let backup = WalletBackup::strict_decode_version(&mut payload, version)?;
+        if !payload.is_empty() {
+            return Err(strict_encoding::Error::DataNotEntirelyConsumed.into());
+        }
+        Ok(backup)
+    }
+
+    /// Writes backup to a file; see [`WalletBackup::save`].
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<usize, BackupError> {
+        self.save(fs::File::create(path)?)
+    }
+
+    /// Reads backup from a file; see [`WalletBackup::load`].
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, BackupError> {
+        WalletBackup::load(fs::File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hd::{SegmentIndexes, UnhardenedIndex};
+
+    use super::*;
+
+    const ACCOUNT: &str = "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*";
+
+    fn backup() -> WalletBackup {
+        let account = DerivationAccount::from_str(ACCOUNT).unwrap();
+        let mut p2c_tweaks = P2cRegistry::new();
+        p2c_tweaks.register(&account, &[UnhardenedIndex::zero()][..], "LNPBP1", *b"contract");
+        WalletBackup {
+            descriptors: vec![Descriptor::new_wpkh(account.clone()).unwrap()],
+            accounts: vec![account],
+            birthday: Some(700_000),
+            labels: bmap! { s!("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh") => s!("savings") },
+            gap_limits: bmap! { 0 => 20, 1 => 10 },
+            p2c_tweaks,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let backup = backup();
+        let mut data = vec![];
+        let len = backup.save(&mut data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(&data[..4], b"DWBK");
+        assert_eq!(WalletBackup::load(data.as_slice()).unwrap(), backup);
+    }
+
+    #[test]
+    fn version1() {
+        let mut backup = backup();
+        backup.p2c_tweaks = P2cRegistry::default();
+        let mut data = BACKUP_MAGIC.to_vec();
+        data.extend(1u16.to_le_bytes());
+        let descriptors = backup.descriptors.iter().map(Descriptor::to_string).collect::<Vec<_>>();
+        descriptors.strict_encode(&mut data).unwrap();
+        backup.accounts.strict_encode(&mut data).unwrap();
+        backup.birthday.strict_encode(&mut data).unwrap();
+        backup.labels.strict_encode(&mut data).unwrap();
+        backup.gap_limits.strict_encode(&mut data).unwrap();
+        let checksum = sha256d::Hash::hash(&data);
+        data.extend(&checksum[..4]);
+        assert_eq!(WalletBackup::load(data.as_slice()).unwrap(), backup);
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut data = vec![];
+        backup().save(&mut data).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 0x01;
+        assert!(matches!(
+            WalletBackup::load(corrupted.as_slice()),
+            Err(BackupError::ChecksumMismatch)
+        ));
+
+        let mut future = data.clone();
+        future[4] = 0xFF;
+        assert!(matches!(
+            WalletBackup::load(future.as_slice()),
+            Err(BackupError::UnsupportedVersion(0xFF))
+        ));
+
+        assert!(matches!(
+            WalletBackup::load(&data[1..]),
+            Err(BackupError::InvalidMagic)
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+use std::collections::BTreeSet;
+
+#[cfg(feature = "miniscript")]
+use bitcoin::util::bip32::Fingerprint;
+#[cfg(feature = "miniscript")]
+use bitcoin_hd::DerivationAccount;
+#[cfg(feature = "miniscript")]
+use miniscript::policy::semantic::Policy;
+#[cfg(feature = "miniscript")]
+use miniscript::policy::Liftable;
+#[cfg(feature = "miniscript")]
+use miniscript::descriptor::DescriptorType;
+#[cfg(feature = "miniscript")]
+use miniscript::ForEachKey;
+
+/// Signature hash algorithm context applicable to descriptor spendings.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub enum SighashContext {
+    /// Legacy (pre-segwit) signature hashes
+    #[display("legacy")]
+    Legacy,
+
+    /// BIP-143 signature hashes of segwit v0
+    #[display("segwit")]
+    SegwitV0,
+
+    /// BIP-341 signature hashes for taproot key path spendings
+    #[display("taproot-key")]
+    TaprootKeyPath,
+
+    /// BIP-341 signature hashes for taproot script path spendings
+    #[display("taproot-script")]
+    TaprootScriptPath,
+}
+
+/// Ability of a wallet to sign descriptor spendings with the keys it has.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub enum SigningCapability {
+    /// None of the descriptor keys is available
+    #[display("watch-only")]
+    WatchOnly,
+
+    /// Some of the descriptor keys are available, but signatures from the
+    /// other parties (or hash preimages) are required
+    #[display("partial")]
+    Partial,
+
+    /// Available keys are sufficient to satisfy at least one of the spending
+    /// conditions (possibly after a timelock)
+    #[display("full")]
+    Full,
+}
+
+/// Features supported by a descriptor, which can be used by wallet UIs to
+/// enable or disable functionality for an account. Returned by
+/// [`DescriptorCapabilities::capabilities`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub struct Capabilities {
+    /// Spendings are RBF-friendly: descriptor is segwit, such that the ids
+    /// of the spending transactions are not malleable and replaced
+    /// transactions with their descendants can be reliably tracked and
+    /// fee-bumped
+    pub rbf: bool,
+
+    /// Descriptor is taproot and can be spent with the key path, i.e. its
+    /// internal key is not the BIP-341 unspendable (NUMS) point
+    pub taproot_key_path: bool,
+
+    /// Signature hash contexts used by the descriptor spendings
+    pub sighash_contexts: BTreeSet<SighashContext>,
+
+    /// Maximum weight of the input satisfaction (script sig and witness), or
+    /// `None` if the descriptor can't be satisfied
+    pub max_satisfaction_weight: Option<usize>,
+
+    /// Whether the descriptor can be signed with the available keys
+    pub signing: SigningCapability,
+}
+
+impl Capabilities {
+    /// Detects whether the descriptor is watch-only with the available keys.
+    #[inline]
+    pub fn is_watch_only(&self) -> bool { self.signing == SigningCapability::WatchOnly }
+}
+
+/// BIP-341 "nothing up my sleeve" point with unknown discrete logarithm, used
+/// as a taproot internal key to disable key path spendings.
+pub const TAPROOT_NUMS_POINT: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Detects whether the account key is the BIP-341 unspendable point, such
+/// that the keys derived from it can't be used for signing.
+#[cfg(feature = "miniscript")]
+fn is_unspendable(account: &DerivationAccount) -> bool {
+    account.account_xpub.public_key.x_only_public_key().0.serialize() == TAPROOT_NUMS_POINT
+}
+
+/// Introspection of the features supported by a descriptor.
+#[cfg(feature = "miniscript")]
+pub trait DescriptorCapabilities {
+    /// Reports features supported by the descriptor. Signing capability is
+    /// determined for the keys with the given master or account fingerprints,
+    /// which are assumed to be available to the wallet.
+    fn capabilities(&self, signing_keys: &BTreeSet<Fingerprint>) -> Capabilities;
+}
+
+#[cfg(feature = "miniscript")]
+impl DescriptorCapabilities for miniscript::Descriptor<DerivationAccount> {
+    fn capabilities(&self, signing_keys: &BTreeSet<Fingerprint>) -> Capabilities {
+        let sighash_contexts = match self {
+            miniscript::Descriptor::Tr(tr) if is_unspendable(tr.internal_key()) => {
+                bset! { SighashContext::TaprootScriptPath }
+            }
+            miniscript::Descriptor::Tr(tr) if tr.taptree().is_some() => {
+                bset! { SighashContext::TaprootKeyPath, SighashContext::TaprootScriptPath }
+            }
+            miniscript::Descriptor::Tr(_) => bset! { SighashContext::TaprootKeyPath },
+            _ => match self.desc_type() {
+                DescriptorType::Bare
+                | DescriptorType::Sh
+                | DescriptorType::Pkh
+                | DescriptorType::ShSortedMulti => bset! { SighashContext::Legacy },
+                _ => bset! { SighashContext::SegwitV0 },
+            },
+        };
+
+        let is_known = |account: &DerivationAccount| {
+            signing_keys.contains(&account.account_fingerprint())
+                || account
+                    .master_fingerprint()
+                    .map_or(false, |fp| signing_keys.contains(&fp))
+        };
+        let signing = if self.lift().map_or(false, |policy| satisfiable(&policy, &is_known)) {
+            SigningCapability::Full
+        } else if self.for_any_key(is_known) {
+            SigningCapability::Partial
+        } else {
+            SigningCapability::WatchOnly
+        };
+
+        Capabilities {
+            rbf: !sighash_contexts.contains(&SighashContext::Legacy),
+            taproot_key_path: sighash_contexts.contains(&SighashContext::TaprootKeyPath),
+            sighash_contexts,
+            max_satisfaction_weight: self.max_satisfaction_weight().ok(),
+            signing,
+        }
+    }
+}
+
+/// Checks whether the policy can be satisfied with the known keys, not
+/// taking timelocks into account.
+#[cfg(feature = "miniscript")]
+fn satisfiable(
+    policy: &Policy<DerivationAccount>,
+    is_known: &impl Fn(&DerivationAccount) -> bool,
+) -> bool {
+    match policy {
+        Policy::Trivial | Policy::After(_) | Policy::Older(_) => true,
+        Policy::Key(account) => is_known(account),
+        Policy::Threshold(k, subs) => {
+            subs.iter().filter(|sub| satisfiable(sub, is_known)).count() >= *k
+        }
+        _ => false,
+    }
+}
+
+#[cfg(all(test, feature = "miniscript"))]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::secp256k1::{Parity, XOnlyPublicKey};
+    use bitcoin::util::bip32::{ChainCode, ChildNumber, ExtendedPubKey};
+    use bitcoin::Network;
+
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let descriptor = miniscript::Descriptor::<DerivationAccount>::from_str(
+            "wsh(multi(2,[d34db33f/48h/0h/0h/2h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*,[deadbeef/48h/0h/0h/2h]xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/<0;1>/*))",
+        )
+        .unwrap();
+        let d34db33f = Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]);
+        let deadbeef = Fingerprint::from(&[0xde, 0xad, 0xbe, 0xef][..]);
+
+        let capabilities = descriptor.capabilities(&BTreeSet::new());
+        assert!(capabilities.rbf);
+        assert!(!capabilities.taproot_key_path);
+        assert_eq!(capabilities.sighash_contexts, bset! { SighashContext::SegwitV0 });
+        assert!(capabilities.max_satisfaction_weight.is_some());
+        assert!(capabilities.is_watch_only());
+
+        let capabilities = descriptor.capabilities(&bset! { d34db33f });
+        assert_eq!(capabilities.signing, SigningCapability::Partial);
+        let capabilities = descriptor.capabilities(&bset! { d34db33f, deadbeef });
+        assert_eq!(capabilities.signing, SigningCapability::Full);
+
+        let descriptor = miniscript::Descriptor::<DerivationAccount>::from_str(
+            "tr([d34db33f/86h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*)",
+        )
+        .unwrap();
+        let capabilities = descriptor.capabilities(&bset! { d34db33f });
+        assert!(capabilities.taproot_key_path);
+        assert_eq!(capabilities.sighash_contexts, bset! { SighashContext::TaprootKeyPath });
+        assert_eq!(capabilities.signing, SigningCapability::Full);
+
+        let nums = ExtendedPubKey {
+            network: Network::Bitcoin,
+            depth: 3,
+            parent_fingerprint: default!(),
+            child_number: ChildNumber::Hardened { index: 0 },
+            public_key: XOnlyPublicKey::from_slice(&TAPROOT_NUMS_POINT)
+                .unwrap()
+                .public_key(Parity::Even),
+            chain_code: ChainCode::from(&[1u8; 32][..]),
+        };
+        let descriptor = miniscript::Descriptor::<DerivationAccount>::from_str(&format!(
+            "tr([deadbeef/86h/0h/0h]{}/<0;1>/*,pk([d34db33f/86h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*))",
+            nums
+        ))
+        .unwrap();
+        let capabilities = descriptor.capabilities(&bset! { d34db33f });
+        assert!(!capabilities.taproot_key_path);
+        assert_eq!(capabilities.sighash_contexts, bset! { SighashContext::TaprootScriptPath });
+        assert_eq!(capabilities.signing, SigningCapability::Full);
+    }
+}
@@ -0,0 +1,332 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Semantic comparison of descriptors and accounts, used for verifying that a
+//! wallet backup matches descriptor displayed by a signing device.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin_hd::{DerivationAccount, XpubkeyCore};
+use miniscript::descriptor::{ShInner, WshInner};
+use miniscript::{
+    translate_hash_fail, Descriptor, ForEachKey, MiniscriptKey, TranslatePk, Translator,
+};
+use slip132::FromSlip132;
+
+/// Result of comparing two descriptor keys.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum KeyMatch {
+    /// Keys are identical
+    #[display("identical")]
+    Identical,
+
+    /// Keys use the same extended public key, but differ in the master key
+    /// fingerprint or account derivation path
+    #[display("key origin mismatch")]
+    OriginMismatch,
+
+    /// Keys use the same extended public key and origin, but differ in the
+    /// terminal derivation path
+    #[display("terminal derivation mismatch")]
+    TerminalMismatch,
+
+    /// Keys use different extended public keys
+    #[display("different keys")]
+    Different,
+}
+
+impl KeyMatch {
+    /// Compares two accounts.
+    pub fn with(ours: &DerivationAccount, theirs: &DerivationAccount) -> KeyMatch {
+        if XpubkeyCore::from(ours.account_xpub) != XpubkeyCore::from(theirs.account_xpub) {
+            KeyMatch::Different
+        } else if ours.master_fingerprint() != theirs.master_fingerprint()
+            || ours.account_path != theirs.account_path
+        {
+            KeyMatch::OriginMismatch
+        } else if ours.terminal_path != theirs.terminal_path {
+            KeyMatch::TerminalMismatch
+        } else {
+            KeyMatch::Identical
+        }
+    }
+}
+
+/// Report on the differences between two descriptors.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DescriptorDiff {
+    /// Whether both descriptors define the same spending policy, ignoring key
+    /// ordering inside `sortedmulti` and key origin information
+    pub same_policy: bool,
+
+    /// Keys present in both descriptors
+    pub matching: Vec<DerivationAccount>,
+
+    /// Keys using the same extended public key, but differing in some other
+    /// information
+    pub mismatching: Vec<(DerivationAccount, DerivationAccount, KeyMatch)>,
+
+    /// Keys of our descriptor missing in their descriptor
+    pub missing: Vec<DerivationAccount>,
+
+    /// Keys of their descriptor missing in our descriptor
+    pub extra: Vec<DerivationAccount>,
+}
+
+impl DescriptorDiff {
+    /// Compares our descriptor with theirs.
+    pub fn with(
+        ours: &Descriptor<DerivationAccount>,
+        theirs: &Descriptor<DerivationAccount>,
+    ) -> DescriptorDiff {
+        let mut diff = DescriptorDiff {
+            same_policy: canonical_policy(ours) == canonical_policy(theirs),
+            ..default!()
+        };
+
+        // The same extended public key may be used in a descriptor multiple
+        // times (for instance, with different terminal derivations), so keys
+        // are kept as a multiset. Identical keys are paired first, such that
+        // the order of the duplicated keys does not matter.
+        let mut their_keys = BTreeMap::<_, Vec<DerivationAccount>>::new();
+        theirs.for_each_key(|account| {
+            their_keys
+                .entry(XpubkeyCore::from(account.account_xpub))
+                .or_default()
+                .push(account.clone());
+            true
+        });
+        let mut unmatched = vec![];
+        ours.for_each_key(|account| {
+            let candidates = their_keys
+                .entry(XpubkeyCore::from(account.account_xpub))
+                .or_default();
+            match candidates
+                .iter()
+                .position(|their| KeyMatch::with(account, their) == KeyMatch::Identical)
+            {
+                Some(pos) => {
+                    candidates.remove(pos);
+                    diff.matching.push(account.clone());
+                }
+                None => unmatched.push(account.clone()),
+            }
+            true
+        });
+        for account in unmatched {
+            let candidates = their_keys
+                .entry(XpubkeyCore::from(account.account_xpub))
+                .or_default();
+            if candidates.is_empty() {
+                diff.missing.push(account);
+                continue;
+            }
+            let their = candidates.remove(0);
+            let status = KeyMatch::with(&account, &their);
+            diff.mismatching.push((account, their, status));
+        }
+        diff.extra = their_keys.into_values().flatten().collect();
+        diff
+    }
+
+    /// Detects whether the descriptors are equivalent, i.e. have the same
+    /// policy and identical keys.
+    #[inline]
+    pub fn is_equivalent(&self) -> bool {
+        self.same_policy
+            && self.mismatching.is_empty()
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+    }
+}
+
+impl Display for DescriptorDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_equivalent() {
+            return writeln!(f, "descriptors are equivalent");
+        }
+        if !self.same_policy {
+            writeln!(f, "spending policies differ")?;
+        }
+        for (ours, theirs, status) in &self.mismatching {
+            writeln!(
+                f,
+                "key {}: {} ({} vs {})",
+                ours.account_fingerprint(),
+                status,
+                ours,
+                theirs
+            )?;
+        }
+        for account in &self.missing {
+            writeln!(
+                f,
+                "key {} is missing: {}",
+                account.account_fingerprint(),
+                account
+            )?;
+        }
+        for account in &self.extra {
+            writeln!(
+                f,
+                "key {} is unexpected: {}",
+                account.account_fingerprint(),
+                account
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether two extended public keys are the same, which may be
+/// encoded with different SLIP-132 versions.
+pub fn same_xpub(ours: &str, theirs: &str) -> Result<bool, slip132::Error> {
+    Ok(ExtendedPubKey::from_slip132_str(ours)? == ExtendedPubKey::from_slip132_str(theirs)?)
+}
+
+struct CoreTranslator;
+
+impl Translator<DerivationAccount, String, ()> for CoreTranslator {
+    fn pk(&mut self, pk: &DerivationAccount) -> Result<String, ()> {
+        let core = XpubkeyCore::from(pk.account_xpub);
+        Ok(format!("{}{}", core.public_key, core.chain_code))
+    }
+
+    translate_hash_fail!(DerivationAccount, String, ());
+}
+
+/// Represents descriptor policy with the keys reduced to their extended public
+/// key data and `sortedmulti` keys put in order.
+fn canonical_policy(descriptor: &Descriptor<DerivationAccount>) -> String {
+    let descriptor = descriptor
+        .translate_pk(&mut CoreTranslator)
+        .expect("key translation is infallible");
+    match sorted_multi(&descriptor) {
+        Some((k, pks)) => {
+            let mut pks = pks.to_vec();
+            pks.sort();
+            format!("{:?}({},{})", descriptor.desc_type(), k, pks.join(","))
+        }
+        None => descriptor.to_string(),
+    }
+}
+
+fn sorted_multi<Pk: MiniscriptKey>(descriptor: &Descriptor<Pk>) -> Option<(usize, &[Pk])> {
+    match descriptor {
+        Descriptor::Sh(sh) => match sh.as_inner() {
+            ShInner::SortedMulti(smv) => Some((smv.k, &smv.pks)),
+            ShInner::Wsh(wsh) => match wsh.as_inner() {
+                WshInner::SortedMulti(smv) => Some((smv.k, &smv.pks)),
+                _ => None,
+            },
+            _ => None,
+        },
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::SortedMulti(smv) => Some((smv.k, &smv.pks)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::Network;
+    use bitcoin_hd::{Bip48Path, Bip48ScriptType, TerminalStep};
+    use slip132::{KeyApplication, ToSlip132};
+
+    use super::*;
+
+    fn cosigner(seed: u8, account: u8) -> DerivationAccount {
+        let secp = Secp256k1::new();
+        let path = Bip48Path::with(0u8, account, Bip48ScriptType::Native);
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[seed; 32]).unwrap();
+        let xpriv = master
+            .derive_priv(&secp, &path.to_derivation_path())
+            .unwrap();
+        path.to_account(
+            master.fingerprint(&secp),
+            ExtendedPubKey::from_priv(&secp, &xpriv),
+        )
+    }
+
+    #[test]
+    fn sortedmulti_diff() {
+        let (alice, bob, carol) = (cosigner(1, 0), cosigner(2, 0), cosigner(3, 0));
+        let ours =
+            Descriptor::new_wsh_sortedmulti(2, vec![alice.clone(), bob.clone(), carol.clone()])
+                .unwrap();
+        let theirs =
+            Descriptor::new_wsh_sortedmulti(2, vec![carol.clone(), alice.clone(), bob.clone()])
+                .unwrap();
+        let diff = DescriptorDiff::with(&ours, &theirs);
+        assert!(diff.is_equivalent());
+        assert_eq!(diff.matching.len(), 3);
+
+        let mut bob_moved = bob.clone();
+        bob_moved.account_path = cosigner(2, 1).account_path;
+        let dave = cosigner(4, 0);
+        let theirs = Descriptor::new_wsh_sortedmulti(2, vec![
+            alice.clone(),
+            bob_moved.clone(),
+            dave.clone(),
+        ])
+        .unwrap();
+        let diff = DescriptorDiff::with(&ours, &theirs);
+        assert!(!diff.is_equivalent());
+        assert!(!diff.same_policy);
+        assert_eq!(diff.matching, vec![alice.clone()]);
+        assert_eq!(diff.mismatching, vec![(
+            bob.clone(),
+            bob_moved,
+            KeyMatch::OriginMismatch
+        )]);
+        assert_eq!(diff.missing, vec![carol]);
+        assert_eq!(diff.extra, vec![dave]);
+
+        let mut alice_change = alice.clone();
+        alice_change.terminal_path = vec![TerminalStep::from(1u8), TerminalStep::Wildcard].into();
+        let ours = Descriptor::new_wsh_sortedmulti(2, vec![
+            alice.clone(),
+            alice_change.clone(),
+            bob.clone(),
+        ])
+        .unwrap();
+        let theirs = Descriptor::new_wsh_sortedmulti(2, vec![
+            bob.clone(),
+            alice_change.clone(),
+            alice.clone(),
+        ])
+        .unwrap();
+        let diff = DescriptorDiff::with(&ours, &theirs);
+        assert!(diff.is_equivalent());
+        assert_eq!(diff.matching.len(), 3);
+        let theirs =
+            Descriptor::new_wsh_sortedmulti(2, vec![alice_change.clone(), bob.clone()]).unwrap();
+        let diff = DescriptorDiff::with(&ours, &theirs);
+        assert_eq!(diff.matching, vec![alice_change, bob.clone()]);
+        assert_eq!(diff.missing, vec![alice.clone()]);
+        assert!(diff.extra.is_empty());
+
+        let multi = Descriptor::new_wsh_sortedmulti(1, vec![alice.clone()]).unwrap();
+        assert!(!DescriptorDiff::with(&ours, &multi).same_policy);
+
+        let zpub = alice
+            .account_xpub
+            .to_slip132_string(KeyApplication::SegWitMultisig, Network::Bitcoin);
+        assert!(same_xpub(&alice.account_xpub.to_string(), &zpub).unwrap());
+        assert!(!same_xpub(&bob.account_xpub.to_string(), &zpub).unwrap());
+    }
+}
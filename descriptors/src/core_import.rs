@@ -0,0 +1,351 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Export of wallet descriptors as Bitcoin Core `importdescriptors` RPC
+//! requests, allowing provisioning of watch-only Bitcoin Core wallets.
+
+use std::convert::Infallible;
+use std::fmt::{self, Display, Formatter};
+
+use bitcoin_hd::{DerivationAccount, DeriveError, SegmentIndexes, TerminalStep, UnhardenedIndex};
+use miniscript::{Descriptor, TranslatePk, Translator};
+
+use crate::derive::Descriptor as _;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (bit, generator) in [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd]
+        .into_iter()
+        .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// Computes BIP-380 descriptor checksum for the descriptor string without
+/// the checksum part. Returns `None` if the string contains characters not
+/// allowed in descriptors.
+pub fn descriptor_checksum(desc: &str) -> Option<String> {
+    let mut c = 1u64;
+    let mut cls = 0u64;
+    let mut cls_count = 0u8;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// Errors exporting descriptors for Bitcoin Core.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum CoreImportError {
+    /// invalid descriptor. {0}
+    #[from]
+    Derive(DeriveError),
+
+    /// descriptor uses derivation pattern of {0} variable steps, while Bitcoin
+    /// Core supports only ranged descriptors with a single wildcard, optionally
+    /// preceded by receive/change step
+    DerivePattern(usize),
+
+    /// descriptor `{0}` contains characters not allowed by Bitcoin Core
+    InvalidCharacters(String),
+}
+
+/// Timestamp from which Bitcoin Core rescans the blockchain for the wallet
+/// transactions.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum CoreTimestamp {
+    /// Skip rescan, assuming the descriptor was never used before
+    #[display("\"now\"")]
+    Now,
+
+    /// UNIX timestamp of the earliest possible wallet transaction (usually
+    /// the time of the wallet birthday block); zero rescans the whole chain
+    #[display(inner)]
+    Time(u32),
+}
+
+impl Default for CoreTimestamp {
+    fn default() -> Self { CoreTimestamp::Time(0) }
+}
+
+impl CoreTimestamp {
+    /// Constructs timestamp from the wallet birthday (see
+    /// [`crate::Account::birthday`]), which is a block height, converting it
+    /// into the block time with `block_time` function (for instance, reading
+    /// block header from an Electrum server). If the birthday is unknown, the
+    /// whole blockchain is rescanned.
+    pub fn with_birthday<E>(
+        birthday: Option<u32>,
+        block_time: impl FnOnce(u32) -> Result<u32, E>,
+    ) -> Result<CoreTimestamp, E> {
+        match birthday {
+            None => Ok(CoreTimestamp::Time(0)),
+            Some(height) => block_time(height).map(CoreTimestamp::Time),
+        }
+    }
+}
+
+/// Single request of Bitcoin Core `importdescriptors` RPC.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CoreImportRequest {
+    /// Descriptor string including checksum
+    pub desc: String,
+
+    /// Time from which the blockchain is rescanned
+    pub timestamp: CoreTimestamp,
+
+    /// Inclusive range of indexes to import for ranged descriptors
+    pub range: Option<(u32, u32)>,
+
+    /// Whether the descriptor is used for change outputs
+    pub internal: bool,
+
+    /// Whether the descriptor becomes active descriptor for new addresses
+    pub active: bool,
+}
+
+impl Display for CoreImportRequest {
+    /// Formats request as a JSON object in the form expected by Bitcoin Core.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let desc = self.desc.replace('\\', "\\\\").replace('"', "\\\"");
+        write!(f, "{{\"desc\": \"{}\", \"timestamp\": {}", desc, self.timestamp)?;
+        if let Some((start, end)) = self.range {
+            write!(f, ", \"range\": [{}, {}]", start, end)?;
+        }
+        write!(f, ", \"internal\": {}, \"active\": {}}}", self.internal, self.active)
+    }
+}
+
+/// Argument of Bitcoin Core `importdescriptors` RPC, consisting of requests
+/// for receive and change descriptors of one or more wallet descriptors.
+///
+/// Formatting with [`Display`] produces JSON array which can be passed to
+/// `bitcoin-cli importdescriptors` as is.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct CoreImport(pub Vec<CoreImportRequest>);
+
+impl Display for CoreImport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (index, request) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            Display::fmt(request, f)?;
+        }
+        f.write_str("]")
+    }
+}
+
+struct CaseTranslator(Option<UnhardenedIndex>);
+
+impl Translator<DerivationAccount, DerivationAccount, Infallible> for CaseTranslator {
+    fn pk(&mut self, pk: &DerivationAccount) -> Result<DerivationAccount, Infallible> {
+        let mut account = pk.clone();
+        if let Some(case) = self.0 {
+            if let Some(step) = account.terminal_path.iter_mut().find(|step| step.count() > 1) {
+                *step = TerminalStep::Index(case);
+            }
+        }
+        Ok(account)
+    }
+
+    fn sha256(&mut self, sha256: &DerivationAccount) -> Result<DerivationAccount, Infallible> {
+        Ok(sha256.clone())
+    }
+
+    fn hash256(&mut self, hash256: &DerivationAccount) -> Result<DerivationAccount, Infallible> {
+        Ok(hash256.clone())
+    }
+
+    fn ripemd160(
+        &mut self,
+        ripemd160: &DerivationAccount,
+    ) -> Result<DerivationAccount, Infallible> {
+        Ok(ripemd160.clone())
+    }
+
+    fn hash160(&mut self, hash160: &DerivationAccount) -> Result<DerivationAccount, Infallible> {
+        Ok(hash160.clone())
+    }
+}
+
+impl CoreImport {
+    /// Constructs import requests for the descriptor. Descriptors with two
+    /// variable derivation steps (like `<0;1>/*`) are split into receive and
+    /// change descriptors, which are marked as active; `range_end` defines
+    /// the last imported index for ranged descriptors.
+    ///
+    /// To rescan the blockchain from the descriptor birthday use
+    /// [`CoreImport::with_birthday`].
+    pub fn with(
+        descriptor: &Descriptor<DerivationAccount>,
+        timestamp: CoreTimestamp,
+        range_end: u32,
+    ) -> Result<CoreImport, CoreImportError> {
+        let len = descriptor.derive_pattern_len()?;
+        let cases: &[Option<bool>] = match len {
+            0 => &[None],
+            1 => &[Some(false)],
+            2 => &[Some(false), Some(true)],
+            _ => return Err(CoreImportError::DerivePattern(len)),
+        };
+        cases
+            .iter()
+            .map(|&internal| {
+                let case = internal
+                    .filter(|_| len == 2)
+                    .map(|internal| UnhardenedIndex::from(u8::from(internal)));
+                let desc = descriptor
+                    .translate_pk(&mut CaseTranslator(case))
+                    .expect("infallible")
+                    .to_string();
+                let desc = desc.split('#').next().unwrap_or_default();
+                let checksum = descriptor_checksum(desc)
+                    .ok_or_else(|| CoreImportError::InvalidCharacters(desc.to_owned()))?;
+                Ok(CoreImportRequest {
+                    desc: format!("{}#{}", desc, checksum),
+                    timestamp,
+                    range: internal.map(|_| (0, range_end)),
+                    internal: internal.unwrap_or_default(),
+                    active: internal.is_some(),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(CoreImport)
+    }
+
+    /// Constructs import requests for the descriptor (see [`CoreImport::with`])
+    /// with the rescan starting from the time of the wallet birthday block,
+    /// which is resolved from the block height with `block_time` function
+    /// (see [`CoreTimestamp::with_birthday`]).
+    pub fn with_birthday<E>(
+        descriptor: &Descriptor<DerivationAccount>,
+        birthday: Option<u32>,
+        block_time: impl FnOnce(u32) -> Result<u32, E>,
+        range_end: u32,
+    ) -> Result<CoreImport, E>
+    where
+        E: From<CoreImportError>,
+    {
+        let timestamp = CoreTimestamp::with_birthday(birthday, block_time)?;
+        Ok(CoreImport::with(descriptor, timestamp, range_end)?)
+    }
+
+    /// Appends requests for another descriptor.
+    pub fn extend(
+        &mut self,
+        descriptor: &Descriptor<DerivationAccount>,
+        timestamp: CoreTimestamp,
+        range_end: u32,
+    ) -> Result<(), CoreImportError> {
+        self.0.extend(CoreImport::with(descriptor, timestamp, range_end)?.0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        // Test vectors from BIP-380
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(
+            descriptor_checksum("addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)").unwrap(),
+            "02wpgw69"
+        );
+        assert_eq!(descriptor_checksum("raw(deadbeef)\u{00e9}"), None);
+    }
+
+    #[test]
+    fn export() {
+        let account = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(account).unwrap();
+
+        assert_eq!(
+            CoreTimestamp::with_birthday(Some(800_000), |height| {
+                assert_eq!(height, 800_000);
+                Ok::<_, ()>(1690000000)
+            }),
+            Ok(CoreTimestamp::Time(1690000000))
+        );
+        let block_time = |_| Ok::<_, CoreImportError>(1690000000);
+        let import = CoreImport::with_birthday(&descriptor, Some(800_000), block_time, 999).unwrap();
+        assert_eq!(import.0.len(), 2);
+        let (receive, change) = (&import.0[0], &import.0[1]);
+        assert!(receive.desc.contains("/0/*)#"));
+        assert!(change.desc.contains("/1/*)#"));
+        for request in [receive, change] {
+            let (desc, checksum) = request.desc.split_once('#').unwrap();
+            assert!(desc.starts_with("wpkh([d34db33f/84"));
+            assert_eq!(descriptor_checksum(desc).unwrap(), checksum);
+        }
+        assert!(!receive.internal && change.internal);
+        assert!(receive.active && change.active);
+        assert_eq!(
+            import.to_string(),
+            format!(
+                "[{{\"desc\": \"{}\", \"timestamp\": 1690000000, \"range\": [0, 999], \
+                 \"internal\": false, \"active\": true}}, {{\"desc\": \"{}\", \"timestamp\": \
+                 1690000000, \"range\": [0, 999], \"internal\": true, \"active\": true}}]",
+                receive.desc, change.desc
+            )
+        );
+
+        let single = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/0/5",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(single).unwrap();
+        assert_eq!(CoreTimestamp::with_birthday(None, |_| Err(())), Ok(CoreTimestamp::Time(0)));
+        let import = CoreImport::with(&descriptor, CoreTimestamp::Now, 999).unwrap();
+        assert_eq!(import.0.len(), 1);
+        assert_eq!(import.0[0].range, None);
+        assert!(!import.0[0].active);
+        assert!(import.to_string().contains("\"timestamp\": \"now\""));
+    }
+}
@@ -14,7 +14,11 @@ use bitcoin::{Network, Script};
 #[cfg(feature = "miniscript")]
 use bitcoin_hd::{DerivationAccount, DerivePatternError};
 use bitcoin_hd::{DeriveError, UnhardenedIndex};
-use bitcoin_scripts::address::AddressCompat;
+use bitcoin_scripts::address::{AddressCompat, AddressNetwork};
+use bitcoin_scripts::PubkeyScript;
+use slip132::ChainParams;
+
+use crate::{ScriptPubkeyDescr, UnsupportedScriptPubkey};
 
 #[cfg(not(feature = "miniscript"))]
 pub mod miniscript {
@@ -39,6 +43,51 @@ pub trait DeriveDescriptor<Key> {
     ) -> Result<Self::Output, DeriveError>;
 }
 
+/// Keys which may have been used to produce a `scriptPubkey`, provided to
+/// [`InferDescriptor::infer`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct KeyHints<'keys, Key> {
+    /// Known keys (like account extended public keys) of the wallet
+    pub keys: &'keys [Key],
+
+    /// Number of indexes to try for each of the variable (wildcard or
+    /// multipath) derivation steps of the keys
+    pub lookahead: u32,
+}
+
+/// Descriptor inferred from a `scriptPubkey` by [`InferDescriptor::infer`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Inferred<D> {
+    /// The type of the script is recognized, but it can't be reconstructed
+    /// from the provided key hints
+    Pattern(ScriptPubkeyDescr),
+
+    /// Concrete descriptor together with the derivation pattern producing the
+    /// script
+    Derived {
+        /// Descriptor with the keys from the hints
+        descriptor: D,
+
+        /// Derivation pattern for the variable steps of the descriptor keys
+        terminal: Vec<UnhardenedIndex>,
+    },
+}
+
+/// Inference of descriptors from the `scriptPubkey`s they have produced.
+pub trait InferDescriptor<Key>: Sized {
+    /// Infers descriptor from a `scriptPubkey` (or an address converted into
+    /// it). Without `key_hints` only the script pattern (p2pk, p2pkh, p2sh,
+    /// p2wpkh, p2wsh, p2tr) is recognized; with the hints the function tries
+    /// single-key and sorted multisig descriptors made of the hinted keys and
+    /// reconstructs the descriptor and derivation indexes which have produced
+    /// the script.
+    fn infer<C: Verification>(
+        secp: &Secp256k1<C>,
+        script: &PubkeyScript,
+        key_hints: Option<KeyHints<Key>>,
+    ) -> Result<Inferred<Self>, UnsupportedScriptPubkey>;
+}
+
 /// Standard methods which should be supported by descriptors of different
 /// sorts.
 pub trait Descriptor<Key> {
@@ -62,6 +111,43 @@ pub trait Descriptor<Key> {
         regtest: bool,
     ) -> Result<AddressCompat, DeriveError>;
 
+    /// Generates address from the descriptor for specific derive pattern on
+    /// a given network. Since extended keys do not distinguish networks from
+    /// testnet family (testnet3, testnet4, signets and regtest), descriptor
+    /// keys are only checked to belong either to mainnet or to testnet family.
+    fn address_on<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        pat: impl AsRef<[UnhardenedIndex]>,
+        network: Network,
+    ) -> Result<AddressCompat, DeriveError> {
+        if (self.network(false)? == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(DeriveError::InconsistentKeyNetwork);
+        }
+        let spk = self.script_pubkey_pretr(secp, pat)?;
+        AddressCompat::from_script(&spk.into(), AddressNetwork::from(network))
+            .ok_or(DeriveError::NoAddressForDescriptor)
+    }
+
+    /// Generates address string from the descriptor for specific derive
+    /// pattern using address encoding of a chain defined by its parameters.
+    /// Descriptor keys must belong to the same network family (mainnet or
+    /// testnet) as the chain.
+    fn address_with<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        pat: impl AsRef<[UnhardenedIndex]>,
+        params: &ChainParams,
+    ) -> Result<String, DeriveError> {
+        if (self.network(false)? == Network::Bitcoin) == params.is_testnet() {
+            return Err(DeriveError::InconsistentKeyNetwork);
+        }
+        let spk = self.script_pubkey_pretr(secp, pat)?;
+        params
+            .encode_address(&spk)
+            .ok_or(DeriveError::NoAddressForDescriptor)
+    }
+
     /// Creates scriptPubkey for specific derive pattern in pre-taproot
     /// descriptors
     fn script_pubkey_pretr<C: Verification>(
@@ -85,7 +171,6 @@ mod ms {
     use bitcoin::XOnlyPublicKey;
     use bitcoin_hd::account::DerivePublicKey;
     use bitcoin_hd::{DeriveError, SegmentIndexes};
-    use bitcoin_scripts::address::{AddressCompat, AddressNetwork};
     use miniscript::{translate_hash_fail, ForEachKey, TranslatePk, Translator};
 
     use super::*;
@@ -245,4 +330,207 @@ mod ms {
             Ok(d.script_pubkey())
         }
     }
+
+    impl InferDescriptor<DerivationAccount> for miniscript::Descriptor<DerivationAccount> {
+        fn infer<C: Verification>(
+            secp: &Secp256k1<C>,
+            script: &PubkeyScript,
+            key_hints: Option<KeyHints<DerivationAccount>>,
+        ) -> Result<Inferred<Self>, UnsupportedScriptPubkey> {
+            let pattern = ScriptPubkeyDescr::try_from(script.clone())?;
+            let hints = match key_hints {
+                Some(hints) => hints,
+                None => return Ok(Inferred::Pattern(pattern)),
+            };
+
+            let all_keys = hints.keys;
+            let keys = all_keys.iter().cloned();
+            let multisig = |f: MultisigConstructor| {
+                (1..=all_keys.len()).filter_map(move |thresh| f(thresh, all_keys.to_vec()).ok())
+            };
+            let candidates: Vec<Self> = match pattern {
+                ScriptPubkeyDescr::Bare(_) => vec![],
+                ScriptPubkeyDescr::Pk(_) => keys.map(Self::new_pk).collect(),
+                ScriptPubkeyDescr::Pkh(_) => keys.map(Self::new_pkh).collect(),
+                ScriptPubkeyDescr::Wpkh(_) => {
+                    keys.filter_map(|key| Self::new_wpkh(key).ok()).collect()
+                }
+                ScriptPubkeyDescr::Sh(_) => keys
+                    .filter_map(|key| Self::new_sh_wpkh(key).ok())
+                    .chain(multisig(Self::new_sh_sortedmulti))
+                    .chain(multisig(Self::new_sh_wsh_sortedmulti))
+                    .collect(),
+                ScriptPubkeyDescr::Wsh(_) => multisig(Self::new_wsh_sortedmulti).collect(),
+                ScriptPubkeyDescr::Tr(_) => {
+                    keys.filter_map(|key| Self::new_tr(key, None).ok()).collect()
+                }
+            };
+
+            for descriptor in candidates {
+                let taproot = matches!(descriptor, miniscript::Descriptor::Tr(_));
+                for terminal in terminal_patterns(&descriptor, hints.lookahead) {
+                    let spk = if taproot {
+                        Descriptor::script_pubkey_tr(&descriptor, secp, &terminal)
+                    } else {
+                        Descriptor::script_pubkey_pretr(&descriptor, secp, &terminal)
+                    };
+                    if matches!(spk, Ok(ref spk) if PubkeyScript::from(spk.clone()) == *script) {
+                        return Ok(Inferred::Derived {
+                            descriptor,
+                            terminal,
+                        });
+                    }
+                }
+            }
+            Ok(Inferred::Pattern(pattern))
+        }
+    }
+
+    type MultisigConstructor = fn(
+        usize,
+        Vec<DerivationAccount>,
+    ) -> Result<miniscript::Descriptor<DerivationAccount>, miniscript::Error>;
+
+    /// Enumerates derivation patterns for the variable terminal steps of the
+    /// descriptor keys, taking up to `lookahead` indexes for each of the steps.
+    fn terminal_patterns(
+        descriptor: &miniscript::Descriptor<DerivationAccount>,
+        lookahead: u32,
+    ) -> Vec<Vec<UnhardenedIndex>> {
+        let mut terminal_path = None;
+        descriptor.for_each_key(|key| {
+            terminal_path = Some(key.terminal_path.clone());
+            false
+        });
+        let terminal_path = match terminal_path {
+            Some(terminal_path) => terminal_path,
+            None => return vec![],
+        };
+
+        let mut patterns = vec![vec![]];
+        for step in terminal_path.iter().filter(|step| step.count() > 1) {
+            let first = step.first_index();
+            let indexes = (0..lookahead)
+                .filter_map(|offset| first.checked_add(offset))
+                .filter(|index| step.contains(*index))
+                .filter_map(|index| UnhardenedIndex::from_index(index).ok())
+                .collect::<Vec<_>>();
+            patterns = patterns
+                .into_iter()
+                .flat_map(|pattern: Vec<UnhardenedIndex>| {
+                    indexes.iter().map(move |index| {
+                        let mut pattern = pattern.clone();
+                        pattern.push(*index);
+                        pattern
+                    })
+                })
+                .collect();
+        }
+        patterns
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::str::FromStr;
+
+        use super::*;
+
+        type Descr = miniscript::Descriptor<DerivationAccount>;
+
+        fn account() -> DerivationAccount {
+            DerivationAccount::from_str(
+                "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+            )
+            .unwrap()
+        }
+
+        fn pattern(change: u32, index: u32) -> Vec<UnhardenedIndex> {
+            vec![
+                UnhardenedIndex::from_index(change).unwrap(),
+                UnhardenedIndex::from_index(index).unwrap(),
+            ]
+        }
+
+        #[test]
+        fn infer_pattern() {
+            let secp = Secp256k1::verification_only();
+            let descriptor = miniscript::Descriptor::new_wpkh(account()).unwrap();
+            let spk = Descriptor::script_pubkey_pretr(&descriptor, &secp, pattern(1, 5)).unwrap();
+            let inferred = Descr::infer(&secp, &spk.into(), None).unwrap();
+            assert!(matches!(inferred, Inferred::Pattern(ScriptPubkeyDescr::Wpkh(_))));
+        }
+
+        #[test]
+        fn infer_derived() {
+            let secp = Secp256k1::verification_only();
+            let keys = [account()];
+            let hints = KeyHints {
+                keys: &keys,
+                lookahead: 10,
+            };
+            for descriptor in [
+                miniscript::Descriptor::new_pkh(account()),
+                miniscript::Descriptor::new_wpkh(account()).unwrap(),
+                miniscript::Descriptor::new_sh_wpkh(account()).unwrap(),
+                miniscript::Descriptor::new_wsh_sortedmulti(1, vec![account()]).unwrap(),
+                miniscript::Descriptor::new_tr(account(), None).unwrap(),
+            ] {
+                let spk = if matches!(descriptor, miniscript::Descriptor::Tr(_)) {
+                    Descriptor::script_pubkey_tr(&descriptor, &secp, pattern(1, 5))
+                } else {
+                    Descriptor::script_pubkey_pretr(&descriptor, &secp, pattern(1, 5))
+                }
+                .unwrap();
+                let inferred = Descr::infer(&secp, &spk.into(), Some(hints.clone())).unwrap();
+                assert_eq!(inferred, Inferred::Derived {
+                    descriptor,
+                    terminal: pattern(1, 5)
+                });
+            }
+
+            let descriptor = miniscript::Descriptor::new_wpkh(account()).unwrap();
+            let spk = Descriptor::script_pubkey_pretr(&descriptor, &secp, pattern(0, 20)).unwrap();
+            let inferred = Descr::infer(&secp, &spk.into(), Some(hints)).unwrap();
+            assert!(matches!(inferred, Inferred::Pattern(ScriptPubkeyDescr::Wpkh(_))));
+        }
+
+        #[test]
+        fn address_on_network() {
+            let secp = Secp256k1::verification_only();
+            let descriptor = miniscript::Descriptor::new_wpkh(account()).unwrap();
+            assert_eq!(
+                Descriptor::address_on(&descriptor, &secp, pattern(0, 0), Network::Bitcoin)
+                    .unwrap(),
+                Descriptor::address(&descriptor, &secp, pattern(0, 0), false).unwrap()
+            );
+            for network in [Network::Testnet, Network::Signet, Network::Regtest] {
+                assert!(matches!(
+                    Descriptor::address_on(&descriptor, &secp, pattern(0, 0), network),
+                    Err(DeriveError::InconsistentKeyNetwork)
+                ));
+            }
+        }
+
+        #[test]
+        fn address_with_params() {
+            let secp = Secp256k1::verification_only();
+            let descriptor = miniscript::Descriptor::new_wpkh(account()).unwrap();
+            let mut params = ChainParams::bitcoin();
+            assert_eq!(
+                Descriptor::address_with(&descriptor, &secp, pattern(0, 0), &params).unwrap(),
+                Descriptor::address(&descriptor, &secp, pattern(0, 0), false)
+                    .unwrap()
+                    .to_string()
+            );
+            params.bech32_hrp = s!("fork");
+            assert!(Descriptor::address_with(&descriptor, &secp, pattern(0, 0), &params)
+                .unwrap()
+                .starts_with("fork1q"));
+            let regtest = ChainParams::regtest();
+            assert!(matches!(
+                Descriptor::address_with(&descriptor, &secp, pattern(0, 0), &regtest),
+                Err(DeriveError::InconsistentKeyNetwork)
+            ));
+        }
+    }
 }
@@ -30,6 +30,7 @@ use miniscript::descriptor::DescriptorType;
 use miniscript::policy::compiler::CompilerError;
 #[cfg(feature = "miniscript")]
 use miniscript::{Descriptor, MiniscriptKey, Terminal};
+use slip132::KeyApplication;
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 #[derive(StrictEncode, StrictDecode)]
@@ -273,6 +274,19 @@ impl CompositeDescrType {
     pub fn has_witness_script(self) -> bool {
         self.is_segwit() && !self.is_taproot() && !matches!(self, CompositeDescrType::Wpkh)
     }
+
+    /// Returns SLIP-132 application of the extended keys used in descriptors
+    /// of this type, if SLIP-132 defines one.
+    pub fn key_application(self) -> Option<KeyApplication> {
+        match self {
+            CompositeDescrType::Pkh | CompositeDescrType::Sh => Some(KeyApplication::Hashed),
+            CompositeDescrType::Wpkh => Some(KeyApplication::SegWit),
+            CompositeDescrType::Wsh => Some(KeyApplication::SegWitMultisig),
+            CompositeDescrType::ShWpkh => Some(KeyApplication::Nested),
+            CompositeDescrType::ShWsh => Some(KeyApplication::NestedMultisig),
+            CompositeDescrType::Bare | CompositeDescrType::Pk | CompositeDescrType::Tr => None,
+        }
+    }
 }
 
 #[cfg(feature = "miniscript")]
@@ -927,6 +941,16 @@ impl From<LockScriptError> for Error {
 mod test {
     use super::*;
 
+    #[test]
+    fn key_application() {
+        assert_eq!(CompositeDescrType::Wpkh.key_application(), Some(KeyApplication::SegWit));
+        assert_eq!(
+            CompositeDescrType::ShWsh.key_application(),
+            Some(KeyApplication::NestedMultisig)
+        );
+        assert_eq!(CompositeDescrType::Tr.key_application(), None);
+    }
+
     #[test]
     fn outer_descr_type_from_str() {
         assert_eq!(OuterDescrType::from_str("bare"), Ok(OuterDescrType::Bare));
@@ -11,11 +11,13 @@
 
 use core::fmt::{self, Display, Formatter};
 use core::str::FromStr;
+use std::collections::BTreeSet;
 
 use bitcoin::blockdata::transaction::ParseOutPointError;
 use bitcoin::hashes::sha256;
 use bitcoin::util::bip32;
 use bitcoin::util::bip32::Fingerprint;
+use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::{EcdsaSighashType as SighashType, OutPoint};
 use bitcoin_blockchain::locks::{self, SeqNo};
 use bitcoin_hd::{DerivationSubpath, UnhardenedIndex};
@@ -30,6 +32,105 @@ pub struct InputDescriptor {
     pub sighash_type: SighashType,
 }
 
+/// Input descriptor together with the satisfaction template of the input.
+///
+/// The template is kept outside of [`InputDescriptor`], such that the input
+/// descriptor strict encoding remains compatible with the previous versions.
+/// String representation is the input descriptor string optionally followed
+/// by the template (see [`SatisfactionTemplate`]).
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct TemplatedInput {
+    /// Input descriptor
+    pub input: InputDescriptor,
+
+    /// Template defining how the input must be satisfied; empty template
+    /// keeps the default satisfaction
+    pub template: SatisfactionTemplate,
+}
+
+impl From<InputDescriptor> for TemplatedInput {
+    fn from(input: InputDescriptor) -> Self {
+        TemplatedInput {
+            input,
+            template: none!(),
+        }
+    }
+}
+
+/// Template defining how the input spending conditions must be satisfied:
+/// which keys sign, which hash preimages are revealed and which taproot script
+/// branch is used. Allows spending complex miniscript policies, where the
+/// default satisfaction may pick an undesired branch.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct SatisfactionTemplate {
+    /// Master key fingerprints of the keys whose signatures are used in the
+    /// satisfaction. If empty, all available signatures are used.
+    pub signers: BTreeSet<Fingerprint>,
+
+    /// SHA256 hashes of the preimages which may be revealed in the
+    /// satisfaction, whichever hash function is used by the hash lock. If
+    /// empty, no preimages are revealed.
+    pub preimages: BTreeSet<sha256::Hash>,
+
+    /// Taproot script leaf used for spending; `None` for non-taproot inputs
+    /// and taproot key path spendings.
+    pub leaf: Option<TapLeafHash>,
+}
+
+impl SatisfactionTemplate {
+    /// Detects whether the template does not put any restrictions on the
+    /// satisfaction.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.signers.is_empty() && self.preimages.is_empty() && self.leaf.is_none()
+    }
+}
+
+impl Display for SatisfactionTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let items = self
+            .signers
+            .iter()
+            .map(|fingerprint| format!("key:{}", fingerprint))
+            .chain(self.preimages.iter().map(|hash| format!("hash:{}", hash)))
+            .chain(self.leaf.iter().map(|leaf| format!("leaf:{}", leaf)))
+            .collect::<Vec<_>>();
+        write!(f, "satisfy({})", items.join(","))
+    }
+}
+
+impl FromStr for SatisfactionTemplate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseError::InvalidTemplate(s.to_owned());
+        let items = s
+            .strip_prefix("satisfy(")
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(err)?;
+        let mut template = SatisfactionTemplate::default();
+        for item in items.split(',').filter(|item| !item.is_empty()) {
+            match item.split_once(':').ok_or_else(err)? {
+                ("key", fingerprint) => {
+                    template
+                        .signers
+                        .insert(fingerprint.parse().map_err(|_| err())?);
+                }
+                ("hash", hash) => {
+                    template.preimages.insert(hash.parse().map_err(|_| err())?);
+                }
+                ("leaf", leaf) if template.leaf.is_none() => {
+                    template.leaf = Some(leaf.parse().map_err(|_| err())?);
+                }
+                _ => return Err(err()),
+            }
+        }
+        Ok(template)
+    }
+}
+
 impl Display for InputDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         Display::fmt(&self.outpoint, f)?;
@@ -52,6 +153,17 @@ impl Display for InputDescriptor {
     }
 }
 
+impl Display for TemplatedInput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.input, f)?;
+        if !self.template.is_empty() {
+            f.write_str(" ")?;
+            Display::fmt(&self.template, f)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Display, From)]
 #[display(doc_comments)]
 pub enum ParseError {
@@ -78,6 +190,11 @@ pub enum ParseError {
     /// xpub fingerprint and 256-bit number, separated by `:`
     InvalidTweakFormat(String),
 
+    /// invalid satisfaction template `{0}`; template must have form of
+    /// `satisfy(...)` with comma-separated list of `key:<fingerprint>`,
+    /// `hash:<sha256>` and a single optional `leaf:<tap_leaf_hash>` items
+    InvalidTemplate(String),
+
     /// invalid input descriptor: outpoint information is required
     NoOutpoint,
 
@@ -97,6 +214,7 @@ impl std::error::Error for ParseError {
             ParseError::InvalidTweak(err) => Some(err),
             ParseError::InvalidOutpoint(err) => Some(err),
             ParseError::InvalidTweakFormat(_) => None,
+            ParseError::InvalidTemplate(_) => None,
             ParseError::NoOutpoint => None,
             ParseError::NoDerivation => None,
             ParseError::UnrecognizedFragment(_) => None,
@@ -143,10 +261,89 @@ impl FromStr for InputDescriptor {
     }
 }
 
+impl FromStr for TemplatedInput {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (input, template) = match s.rsplit_once(char::is_whitespace) {
+            Some((input, template)) if template.starts_with("satisfy(") => {
+                (input, template.parse()?)
+            }
+            _ => (s, none!()),
+        };
+        Ok(TemplatedInput {
+            input: input.parse()?,
+            template,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    use bitcoin_hd::SegmentIndexes;
+    use proptest::collection::{btree_set, vec};
+    use proptest::option;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use strict_encoding::{StrictDecode, StrictEncode};
+
     use super::*;
 
+    fn fingerprint() -> impl Strategy<Value = Fingerprint> {
+        any::<[u8; 4]>().prop_map(|data| Fingerprint::from(&data[..]))
+    }
+
+    fn input_descriptor() -> impl Strategy<Value = InputDescriptor> {
+        (
+            any::<[u8; 32]>(),
+            any::<u32>(),
+            vec(0..bitcoin_hd::HARDENED_INDEX_BOUNDARY, 0..5),
+            any::<u32>(),
+            option::of((fingerprint(), any::<[u8; 32]>())),
+            select(vec![
+                SighashType::All,
+                SighashType::None,
+                SighashType::Single,
+                SighashType::AllPlusAnyoneCanPay,
+                SighashType::NonePlusAnyoneCanPay,
+                SighashType::SinglePlusAnyoneCanPay,
+            ]),
+        )
+            .prop_map(|(txid, vout, terminal, seq_no, tweak, sighash_type)| {
+                InputDescriptor {
+                    outpoint: OutPoint::new(Txid::from_inner(txid), vout),
+                    terminal: terminal
+                        .into_iter()
+                        .map(|index| UnhardenedIndex::from_index(index).expect("unhardened index"))
+                        .collect(),
+                    seq_no: SeqNo::from_consensus(seq_no),
+                    tweak: tweak
+                        .map(|(fingerprint, tweak)| (fingerprint, sha256::Hash::from_inner(tweak))),
+                    sighash_type,
+                }
+            })
+    }
+
+    fn templated_input() -> impl Strategy<Value = TemplatedInput> {
+        (
+            input_descriptor(),
+            btree_set(fingerprint(), 0..3),
+            btree_set(any::<[u8; 32]>().prop_map(sha256::Hash::from_inner), 0..3),
+            option::of(any::<[u8; 32]>().prop_map(TapLeafHash::from_inner)),
+        )
+            .prop_map(|(input, signers, preimages, leaf)| TemplatedInput {
+                input,
+                template: SatisfactionTemplate {
+                    signers,
+                    preimages,
+                    leaf,
+                },
+            })
+    }
+
     #[test]
     fn display_from_str() {
         let input = InputDescriptor {
@@ -172,4 +369,79 @@ mod test {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn template() {
+        let s = "9a035b0e6e9d07065a31c49884cb1c2d8953636346e91948df75b20e27f50f24:8 /1/167 \
+                 satisfy(key:d34db33f,key:f00dbabe,hash:\
+                 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855)";
+        let templated = TemplatedInput::from_str(s).unwrap();
+        assert_eq!(templated.template.signers.len(), 2);
+        assert_eq!(templated.template.preimages.len(), 1);
+        assert_eq!(templated.template.leaf, None);
+        assert_eq!(templated.to_string(), s);
+        assert_eq!(
+            TemplatedInput::from_str(&templated.to_string()).unwrap(),
+            templated
+        );
+        assert!(InputDescriptor::from_str(s).is_err());
+
+        let plain = "9a035b0e6e9d07065a31c49884cb1c2d8953636346e91948df75b20e27f50f24:8 /1/167";
+        let templated = TemplatedInput::from_str(plain).unwrap();
+        assert!(templated.template.is_empty());
+        assert_eq!(
+            templated,
+            TemplatedInput::from(InputDescriptor::from_str(plain).unwrap())
+        );
+        assert_eq!(templated.to_string(), plain);
+
+        let template = SatisfactionTemplate::from_str(
+            "satisfy(leaf:0000000000000000000000000000000000000000000000000000000000000000)",
+        )
+        .unwrap();
+        assert!(template.leaf.is_some() && template.signers.is_empty());
+        assert!(SatisfactionTemplate::from_str("satisfy()")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            SatisfactionTemplate::from_str("satisfy(key:xyz)"),
+            Err(ParseError::InvalidTemplate(s!("satisfy(key:xyz)")))
+        );
+    }
+
+    #[test]
+    fn strict_encoding() {
+        let input = InputDescriptor::from_str(
+            "9a035b0e6e9d07065a31c49884cb1c2d8953636346e91948df75b20e27f50f24:8 /1/167 rbf(1) \
+             SIGHASH_ALL|SIGHASH_ANYONECANPAY",
+        )
+        .unwrap();
+        let data = input.strict_serialize().unwrap();
+        assert_eq!(InputDescriptor::strict_deserialize(&data).unwrap(), input);
+
+        let templated = TemplatedInput {
+            input,
+            template: SatisfactionTemplate::from_str("satisfy(key:d34db33f)").unwrap(),
+        };
+        let templated_data = templated.strict_serialize().unwrap();
+        assert!(templated_data.starts_with(&data));
+        assert_eq!(
+            TemplatedInput::strict_deserialize(templated_data).unwrap(),
+            templated
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn input_strict_roundtrip(input in input_descriptor()) {
+            let data = input.strict_serialize().unwrap();
+            prop_assert_eq!(InputDescriptor::strict_deserialize(data)?, input);
+        }
+
+        #[test]
+        fn templated_input_strict_roundtrip(templated in templated_input()) {
+            let data = templated.strict_serialize().unwrap();
+            prop_assert_eq!(TemplatedInput::strict_deserialize(data)?, templated);
+        }
+    }
 }
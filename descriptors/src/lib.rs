@@ -30,18 +30,53 @@ extern crate miniscript_crate as miniscript;
 #[macro_use]
 extern crate serde_crate as serde;
 
+#[cfg(feature = "miniscript")]
+mod account;
+#[cfg(feature = "miniscript")]
+mod backup;
+mod capabilities;
+#[cfg(feature = "miniscript")]
+mod compare;
+#[cfg(feature = "miniscript")]
+mod core_import;
 mod deduction;
 pub mod derive;
 mod descriptor;
 mod input;
 #[cfg(feature = "miniscript")]
+mod p2c;
+#[cfg(feature = "miniscript")]
+mod registry;
+mod taproot;
+#[cfg(feature = "miniscript")]
 mod templates;
 
+#[cfg(feature = "miniscript")]
+pub use account::{Account, OutputRole};
+#[cfg(feature = "miniscript")]
+pub use backup::{BackupError, WalletBackup, BACKUP_MAGIC, BACKUP_VERSION};
+pub use capabilities::{Capabilities, SighashContext, SigningCapability, TAPROOT_NUMS_POINT};
+#[cfg(feature = "miniscript")]
+pub use capabilities::DescriptorCapabilities;
+#[cfg(feature = "miniscript")]
+pub use compare::{same_xpub, DescriptorDiff, KeyMatch};
+#[cfg(feature = "miniscript")]
+pub use core_import::{
+    descriptor_checksum, CoreImport, CoreImportError, CoreImportRequest, CoreTimestamp,
+};
 pub use deduction::DeductionError;
+pub use derive::{InferDescriptor, Inferred, KeyHints};
 pub use descriptor::{
     BareDescriptor, CompositeDescrType, DescrVariants, DescriptorClass, Error, InnerDescrType,
     OuterDescrType, ParseError, ScriptPubkeyDescr, SpkClass, UnsupportedScriptPubkey,
 };
-pub use input::InputDescriptor;
+pub use input::{InputDescriptor, SatisfactionTemplate, TemplatedInput};
+#[cfg(feature = "miniscript")]
+pub use p2c::{P2cError, P2cRegistry, P2cTweak};
+#[cfg(feature = "miniscript")]
+pub use registry::{AccountRegistry, RegisteredAccount};
+#[cfg(feature = "miniscript")]
+pub use taproot::verify_bip86_descriptor;
+pub use taproot::{verify_bip86, Bip86Error, TaprootComponents};
 #[cfg(feature = "miniscript")]
 pub use templates::ScriptTemplate;
@@ -0,0 +1,452 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Pay-to-contract (P2C) tweaks: bookkeeping of contracts committed into
+//! wallet keys, derivation of the tweaked receiving addresses and recovery of
+//! the tweaked private keys for signing.
+
+use std::collections::BTreeMap;
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{
+    self, KeyPair, Parity, PublicKey, Scalar, Secp256k1, SecretKey, Signing, Verification,
+};
+use bitcoin::util::bip32::Fingerprint;
+use bitcoin::{Address, Network, XOnlyPublicKey};
+use bitcoin_hd::account::DerivePublicKey;
+use bitcoin_hd::{DerivationAccount, DerivationSubpath, DeriveError, UnhardenedIndex};
+use miniscript::{translate_hash_fail, Descriptor, ForEachKey, TranslatePk, Translator};
+#[cfg(feature = "serde")]
+use serde_with::{As, DisplayFromStr};
+
+/// Errors in deriving P2C-tweaked keys and addresses.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum P2cError {
+    /// unable to derive account key. {0}
+    #[from]
+    Derive(DeriveError),
+
+    /// unable to apply P2C tweak to the key. {0}
+    #[from]
+    Tweak(secp256k1::Error),
+
+    /// unable to construct address for the tweaked descriptor. {0}
+    #[from]
+    Miniscript(miniscript::Error),
+
+    /// no P2C contract is registered for the derivation
+    NotRegistered,
+}
+
+/// Contract committed into a wallet key with a pay-to-contract tweak.
+///
+/// The tweak is a tagged hash `SHA256(SHA256(protocol) || SHA256(protocol) ||
+/// pubkey || contract)` of the original (untweaked) compressed public key.
+/// Taproot keys are committed with their even-parity representation.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct P2cTweak {
+    /// Fingerprint of the account extended public key
+    pub account: Fingerprint,
+
+    /// Terminal derivation of the tweaked key
+    #[cfg_attr(feature = "serde", serde(with = "As::<DisplayFromStr>"))]
+    pub terminal: DerivationSubpath<UnhardenedIndex>,
+
+    /// Protocol tag used for the commitment
+    pub protocol: String,
+
+    /// Contract data
+    pub contract: Vec<u8>,
+}
+
+impl P2cTweak {
+    /// Computes tweak value committing to the contract for the original
+    /// public key.
+    pub fn tweak(&self, pubkey: &PublicKey) -> sha256::Hash {
+        let tag = sha256::Hash::hash(self.protocol.as_bytes());
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&pubkey.serialize());
+        engine.input(&self.contract);
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// Applies tweak to the original public key.
+    pub fn tweak_pubkey<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        pubkey: PublicKey,
+    ) -> Result<PublicKey, secp256k1::Error> {
+        pubkey.add_exp_tweak(secp, &scalar(self.tweak(&pubkey)))
+    }
+
+    /// Applies tweak to the original x-only public key.
+    pub fn tweak_xonly<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        pubkey: XOnlyPublicKey,
+    ) -> Result<XOnlyPublicKey, secp256k1::Error> {
+        let tweak = self.tweak(&pubkey.public_key(Parity::Even));
+        pubkey.add_tweak(secp, &scalar(tweak)).map(|(pubkey, _)| pubkey)
+    }
+
+    /// Reconstructs tweaked private key out of the original one.
+    pub fn tweak_seckey<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        seckey: SecretKey,
+    ) -> Result<SecretKey, secp256k1::Error> {
+        let tweak = self.tweak(&PublicKey::from_secret_key(secp, &seckey));
+        seckey.add_tweak(&scalar(tweak))
+    }
+
+    /// Reconstructs tweaked taproot key pair out of the original one.
+    pub fn tweak_keypair<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        keypair: KeyPair,
+    ) -> Result<KeyPair, secp256k1::Error> {
+        let (xonly, _) = keypair.x_only_public_key();
+        let tweak = self.tweak(&xonly.public_key(Parity::Even));
+        keypair.add_xonly_tweak(secp, &scalar(tweak))
+    }
+}
+
+fn scalar(tweak: sha256::Hash) -> Scalar {
+    Scalar::from_be_bytes(tweak.into_inner()).expect("negligible probability")
+}
+
+/// Registry of P2C tweaks applied to the wallet keys, persisted with other
+/// wallet data.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct P2cRegistry {
+    tweaks: Vec<P2cTweak>,
+}
+
+impl P2cRegistry {
+    /// Constructs empty registry.
+    #[inline]
+    pub fn new() -> P2cRegistry { P2cRegistry::default() }
+
+    /// Registers contract committed into the account key at the terminal
+    /// derivation, returning previously registered contract for the same key.
+    pub fn register(
+        &mut self,
+        account: &DerivationAccount,
+        terminal: impl Into<DerivationSubpath<UnhardenedIndex>>,
+        protocol: impl ToString,
+        contract: impl Into<Vec<u8>>,
+    ) -> Option<P2cTweak> {
+        let tweak = P2cTweak {
+            account: account.account_fingerprint(),
+            terminal: terminal.into(),
+            protocol: protocol.to_string(),
+            contract: contract.into(),
+        };
+        let prev = self.remove(tweak.account, &tweak.terminal);
+        self.tweaks.push(tweak);
+        prev
+    }
+
+    /// Removes contract registered for the account key at the terminal
+    /// derivation.
+    pub fn remove(
+        &mut self,
+        account: Fingerprint,
+        terminal: &[UnhardenedIndex],
+    ) -> Option<P2cTweak> {
+        let pos = self
+            .tweaks
+            .iter()
+            .position(|tweak| tweak.account == account && tweak.terminal.as_ref() == terminal)?;
+        Some(self.tweaks.remove(pos))
+    }
+
+    /// Returns contract registered for the account key at the terminal
+    /// derivation.
+    pub fn get(&self, account: Fingerprint, terminal: &[UnhardenedIndex]) -> Option<&P2cTweak> {
+        self.tweaks
+            .iter()
+            .find(|tweak| tweak.account == account && tweak.terminal.as_ref() == terminal)
+    }
+
+    /// Iterates over registered contracts.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &P2cTweak> { self.tweaks.iter() }
+
+    /// Returns number of registered contracts.
+    #[inline]
+    pub fn len(&self) -> usize { self.tweaks.len() }
+
+    /// Detects whether the registry is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.tweaks.is_empty() }
+
+    /// Computes P2C tweaks for the original public keys of the descriptor,
+    /// keyed by these keys (taproot keys use even-parity representation). The
+    /// result is used to fill in PSBT P2C data for the signers.
+    pub fn pubkey_tweaks<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        descriptor: &Descriptor<DerivationAccount>,
+    ) -> Result<BTreeMap<PublicKey, sha256::Hash>, DeriveError> {
+        let taproot = matches!(descriptor, Descriptor::Tr(_));
+        let mut accounts = vec![];
+        descriptor.for_each_key(|account| {
+            accounts.push(account);
+            true
+        });
+        let mut tweaks = bmap! {};
+        for account in accounts {
+            let fingerprint = account.account_fingerprint();
+            for tweak in self.tweaks.iter().filter(|tweak| tweak.account == fingerprint) {
+                let mut pubkey =
+                    account.derive_public_key(secp, tweak.terminal.iter().copied())?;
+                if taproot {
+                    pubkey = pubkey.x_only_public_key().0.public_key(Parity::Even);
+                }
+                tweaks.insert(pubkey, tweak.tweak(&pubkey));
+            }
+        }
+        Ok(tweaks)
+    }
+
+    /// Detects whether any of the descriptor keys has contract registered for
+    /// the terminal derivation.
+    pub fn is_tweaked(
+        &self,
+        descriptor: &Descriptor<DerivationAccount>,
+        terminal: &[UnhardenedIndex],
+    ) -> bool {
+        !descriptor
+            .for_each_key(|account| self.get(account.account_fingerprint(), terminal).is_none())
+    }
+
+    /// Derives non-taproot descriptor at the terminal derivation with the keys
+    /// tweaked by the registered contracts. Keys without registered contracts
+    /// are left untweaked.
+    pub fn tweaked_descriptor<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        descriptor: &Descriptor<DerivationAccount>,
+        terminal: &[UnhardenedIndex],
+    ) -> Result<Descriptor<bitcoin::PublicKey>, P2cError> {
+        let mut translator = TweakTranslator {
+            secp,
+            registry: self,
+            terminal,
+        };
+        <Descriptor<DerivationAccount> as TranslatePk<_, bitcoin::PublicKey>>::translate_pk(
+            descriptor,
+            &mut translator,
+        )
+    }
+
+    /// Derives taproot descriptor at the terminal derivation with the keys
+    /// tweaked by the registered contracts. Keys without registered contracts
+    /// are left untweaked.
+    pub fn tweaked_tr_descriptor<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        descriptor: &Descriptor<DerivationAccount>,
+        terminal: &[UnhardenedIndex],
+    ) -> Result<Descriptor<XOnlyPublicKey>, P2cError> {
+        let mut translator = TweakTranslator {
+            secp,
+            registry: self,
+            terminal,
+        };
+        <Descriptor<DerivationAccount> as TranslatePk<_, XOnlyPublicKey>>::translate_pk(
+            descriptor,
+            &mut translator,
+        )
+    }
+
+    /// Derives address for the descriptor at the terminal derivation with the
+    /// keys tweaked by the registered contracts.
+    ///
+    /// # Errors
+    ///
+    /// If none of the descriptor keys has contract registered for the
+    /// derivation, or the derivation fails.
+    pub fn tweaked_address<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        descriptor: &Descriptor<DerivationAccount>,
+        terminal: &[UnhardenedIndex],
+        network: Network,
+    ) -> Result<Address, P2cError> {
+        if !self.is_tweaked(descriptor, terminal) {
+            return Err(P2cError::NotRegistered);
+        }
+        Ok(match descriptor {
+            Descriptor::Tr(_) => {
+                self.tweaked_tr_descriptor(secp, descriptor, terminal)?.address(network)?
+            }
+            _ => self.tweaked_descriptor(secp, descriptor, terminal)?.address(network)?,
+        })
+    }
+}
+
+struct TweakTranslator<'a, C: Verification> {
+    secp: &'a Secp256k1<C>,
+    registry: &'a P2cRegistry,
+    terminal: &'a [UnhardenedIndex],
+}
+
+impl<'a, C: Verification> TweakTranslator<'a, C> {
+    fn derive(
+        &self,
+        account: &DerivationAccount,
+    ) -> Result<(PublicKey, Option<&'a P2cTweak>), P2cError> {
+        let pubkey = account
+            .derive_public_key(self.secp, self.terminal.iter().copied())
+            .map_err(DeriveError::from)?;
+        let tweak = self.registry.get(account.account_fingerprint(), self.terminal);
+        Ok((pubkey, tweak))
+    }
+}
+
+impl<'a, C: Verification> Translator<DerivationAccount, bitcoin::PublicKey, P2cError>
+    for TweakTranslator<'a, C>
+{
+    fn pk(&mut self, pk: &DerivationAccount) -> Result<bitcoin::PublicKey, P2cError> {
+        let (pubkey, tweak) = self.derive(pk)?;
+        let pubkey = match tweak {
+            Some(tweak) => tweak.tweak_pubkey(self.secp, pubkey)?,
+            None => pubkey,
+        };
+        Ok(bitcoin::PublicKey::new(pubkey))
+    }
+
+    translate_hash_fail!(DerivationAccount, bitcoin::PublicKey, P2cError);
+}
+
+impl<'a, C: Verification> Translator<DerivationAccount, XOnlyPublicKey, P2cError>
+    for TweakTranslator<'a, C>
+{
+    fn pk(&mut self, pk: &DerivationAccount) -> Result<XOnlyPublicKey, P2cError> {
+        let (pubkey, tweak) = self.derive(pk)?;
+        let (pubkey, _) = pubkey.x_only_public_key();
+        Ok(match tweak {
+            Some(tweak) => tweak.tweak_xonly(self.secp, pubkey)?,
+            None => pubkey,
+        })
+    }
+
+    translate_hash_fail!(DerivationAccount, XOnlyPublicKey, P2cError);
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin_hd::{SegmentIndexes, TerminalStep};
+
+    use super::*;
+    use crate::derive::DeriveDescriptor;
+
+    #[test]
+    fn p2c_roundtrip() {
+        let secp = Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let account = DerivationAccount {
+            master: default!(),
+            account_path: default!(),
+            account_xpub: ExtendedPubKey::from_priv(&secp, &xpriv),
+            revocation_seal: None,
+            terminal_path: vec![TerminalStep::range(0u8, 1u8), TerminalStep::Wildcard]
+                .into_iter()
+                .collect(),
+        };
+        let terminal = [UnhardenedIndex::zero(), UnhardenedIndex::from(5u8)];
+        let descriptor = Descriptor::new_wpkh(account.clone()).unwrap();
+
+        let mut registry = P2cRegistry::new();
+        assert!(matches!(
+            registry.tweaked_address(&secp, &descriptor, &terminal, Network::Bitcoin),
+            Err(P2cError::NotRegistered)
+        ));
+        assert!(!registry.is_tweaked(&descriptor, &terminal));
+        assert_eq!(registry.register(&account, &terminal[..], "LNPBP1", *b"contract"), None);
+        let address = registry
+            .tweaked_address(&secp, &descriptor, &terminal, Network::Bitcoin)
+            .unwrap();
+        let untweaked = DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
+            &descriptor,
+            &secp,
+            terminal,
+        )
+        .unwrap()
+        .address(Network::Bitcoin)
+        .unwrap();
+        assert_ne!(address, untweaked);
+        assert!(registry.is_tweaked(&descriptor, &terminal));
+        assert_eq!(
+            registry
+                .tweaked_descriptor(&secp, &descriptor, &[UnhardenedIndex::one(), 5u8.into()])
+                .unwrap()
+                .address(Network::Bitcoin)
+                .unwrap(),
+            DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
+                &descriptor,
+                &secp,
+                [UnhardenedIndex::one(), 5u8.into()],
+            )
+            .unwrap()
+            .address(Network::Bitcoin)
+            .unwrap()
+        );
+
+        let seckey = xpriv
+            .derive_priv(&secp, &account.to_terminal_derivation_path(terminal).unwrap())
+            .unwrap()
+            .private_key;
+        let tweak = registry.get(account.account_fingerprint(), &terminal).unwrap();
+        let tweaked = tweak.tweak_seckey(&secp, seckey).unwrap();
+        let pubkey = bitcoin::PublicKey::new(PublicKey::from_secret_key(&secp, &tweaked));
+        assert_eq!(Address::p2wpkh(&pubkey, Network::Bitcoin).unwrap(), address);
+
+        let pubkey = PublicKey::from_secret_key(&secp, &seckey);
+        assert_eq!(
+            registry.pubkey_tweaks(&secp, &descriptor).unwrap(),
+            bmap! { pubkey => tweak.tweak(&pubkey) }
+        );
+
+        let taproot = Descriptor::new_tr(account.clone(), None).unwrap();
+        let address = registry
+            .tweaked_address(&secp, &taproot, &terminal, Network::Bitcoin)
+            .unwrap();
+        let keypair = tweak
+            .tweak_keypair(&secp, KeyPair::from_secret_key(&secp, &seckey))
+            .unwrap();
+        assert_eq!(
+            Address::p2tr(&secp, keypair.x_only_public_key().0, None, Network::Bitcoin),
+            address
+        );
+
+        assert!(registry.remove(account.account_fingerprint(), &terminal).is_some());
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Registry of named watch-only accounts with their cosigner metadata.
+
+use std::collections::BTreeMap;
+
+use bitcoin::util::bip32::{Fingerprint, KeySource};
+use bitcoin_hd::{DerivationAccount, DescriptorType, UnhardenedIndex};
+use miniscript::{Descriptor, ForEachKey};
+#[cfg(feature = "serde")]
+use serde_with::{As, DisplayFromStr};
+
+/// Watch-only account registered in the [`AccountRegistry`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct RegisteredAccount {
+    /// Account name
+    pub name: String,
+
+    /// Account output descriptor
+    #[cfg_attr(feature = "serde", serde(with = "As::<DisplayFromStr>"))]
+    pub descriptor: Descriptor<DerivationAccount>,
+
+    /// Names of the cosigners, keyed by the fingerprint of their master (or,
+    /// if the master key is unknown, account) extended public key
+    pub cosigners: BTreeMap<Fingerprint, String>,
+
+    /// Height of the block at which the account was created, if known
+    pub birthday: Option<u32>,
+}
+
+impl RegisteredAccount {
+    /// Constructs account with no cosigner names and unknown birthday.
+    pub fn with(name: impl ToString, descriptor: Descriptor<DerivationAccount>) -> Self {
+        RegisteredAccount {
+            name: name.to_string(),
+            descriptor,
+            cosigners: none!(),
+            birthday: None,
+        }
+    }
+
+    /// Returns script type used by the account.
+    #[inline]
+    pub fn script_type(&self) -> DescriptorType { self.descriptor.desc_type() }
+
+    /// Returns keys of the account descriptor.
+    pub fn keys(&self) -> Vec<&DerivationAccount> {
+        let mut keys = vec![];
+        self.descriptor.for_each_key(|key| {
+            keys.push(key);
+            true
+        });
+        keys
+    }
+
+    /// Returns name of the cosigner owning the key, if known.
+    pub fn cosigner_name(&self, key: &DerivationAccount) -> Option<&str> {
+        self.cosigners
+            .get(&key.master_fingerprint().unwrap_or_else(|| key.account_fingerprint()))
+            .map(String::as_str)
+    }
+
+    /// Detects whether the account uses a key with the given master or
+    /// account fingerprint.
+    pub fn has_fingerprint(&self, fingerprint: Fingerprint) -> bool {
+        self.keys().into_iter().any(|key| {
+            key.master_fingerprint() == Some(fingerprint)
+                || key.account_fingerprint() == fingerprint
+        })
+    }
+
+    /// Finds account key from which a key with the given origin may be
+    /// derived, returning it together with the derivation pattern (see
+    /// [`DerivationAccount::match_key_source`]).
+    pub fn match_key_source(
+        &self,
+        key_source: &KeySource,
+    ) -> Option<(&DerivationAccount, Vec<UnhardenedIndex>)> {
+        self.keys()
+            .into_iter()
+            .find_map(|key| key.match_key_source(key_source).map(|pattern| (key, pattern)))
+    }
+}
+
+/// Registry of watch-only accounts, keyed by the account names.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct AccountRegistry {
+    accounts: BTreeMap<String, RegisteredAccount>,
+}
+
+impl AccountRegistry {
+    /// Constructs empty registry.
+    #[inline]
+    pub fn new() -> AccountRegistry { AccountRegistry::default() }
+
+    /// Registers account, returning previously registered account with the
+    /// same name.
+    pub fn register(&mut self, account: RegisteredAccount) -> Option<RegisteredAccount> {
+        self.accounts.insert(account.name.clone(), account)
+    }
+
+    /// Removes account with the given name.
+    #[inline]
+    pub fn remove(&mut self, name: &str) -> Option<RegisteredAccount> {
+        self.accounts.remove(name)
+    }
+
+    /// Returns account with the given name.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&RegisteredAccount> { self.accounts.get(name) }
+
+    /// Returns mutable reference to the account with the given name.
+    #[inline]
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut RegisteredAccount> {
+        self.accounts.get_mut(name)
+    }
+
+    /// Iterates over registered accounts in the order of their names.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredAccount> { self.accounts.values() }
+
+    /// Returns number of registered accounts.
+    #[inline]
+    pub fn len(&self) -> usize { self.accounts.len() }
+
+    /// Detects whether the registry is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.accounts.is_empty() }
+
+    /// Lists accounts using a key with the given master or account
+    /// fingerprint.
+    pub fn by_fingerprint(&self, fingerprint: Fingerprint) -> Vec<&RegisteredAccount> {
+        self.iter()
+            .filter(|account| account.has_fingerprint(fingerprint))
+            .collect()
+    }
+
+    /// Finds account containing a key from which a key with the given origin
+    /// may be derived; see [`RegisteredAccount::match_key_source`].
+    pub fn by_origin(
+        &self,
+        key_source: &KeySource,
+    ) -> Option<(&RegisteredAccount, &DerivationAccount, Vec<UnhardenedIndex>)> {
+        self.iter().find_map(|account| {
+            account
+                .match_key_source(key_source)
+                .map(|(key, pattern)| (account, key, pattern))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::util::bip32::DerivationPath;
+
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let key = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let mut account =
+            RegisteredAccount::with("savings", Descriptor::new_wpkh(key.clone()).unwrap());
+        let master = Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]);
+        account.cosigners.insert(master, s!("alice"));
+        assert_eq!(account.script_type(), DescriptorType::Wpkh);
+        assert_eq!(account.cosigner_name(&key), Some("alice"));
+
+        let mut registry = AccountRegistry::new();
+        assert_eq!(registry.register(account.clone()), None);
+        assert_eq!(registry.by_fingerprint(master), vec![&account]);
+        assert_eq!(registry.by_fingerprint(key.account_fingerprint()), vec![&account]);
+        assert!(registry.by_fingerprint(Fingerprint::default()).is_empty());
+
+        let origin = (master, DerivationPath::from_str("m/84'/0'/0'/1/7").unwrap());
+        let (found, found_key, pattern) = registry.by_origin(&origin).unwrap();
+        assert_eq!(found, &account);
+        assert_eq!(found_key, &key);
+        assert_eq!(pattern, vec![UnhardenedIndex::from(1u8), UnhardenedIndex::from(7u8)]);
+
+        for path in ["m/84'/0'/1'/1/7", "m/84'/0'/0'/2/7", "m/84'/0'/0'/1/7'", "m/84'/0'/0'/1"] {
+            let origin = (master, DerivationPath::from_str(path).unwrap());
+            assert_eq!(registry.by_origin(&origin), None);
+        }
+        assert_eq!(registry.remove("savings"), Some(account));
+        assert!(registry.is_empty());
+    }
+}
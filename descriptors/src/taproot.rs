@@ -0,0 +1,225 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Utilities for exporting and importing taproot output key components.
+
+use bitcoin::schnorr::{TapTweak, TweakedPublicKey};
+use bitcoin::secp256k1::{Secp256k1, Verification, SECP256K1};
+use bitcoin::util::taproot::TapBranchHash;
+use bitcoin::{Script, XOnlyPublicKey};
+#[cfg(feature = "miniscript")]
+use miniscript::{Descriptor, MiniscriptKey, ToPublicKey};
+
+/// Errors from BIP-86 output key verification.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Bip86Error {
+    /// descriptor is not a key-only `tr(KEY)` descriptor
+    NotKeyOnly,
+
+    /// scriptPubkey `{0}` is not a P2TR one
+    NonTaprootScript(Script),
+
+    /// output key {actual} does not match BIP-86 tweak {expected} of the
+    /// internal key {internal_key}; taproot data are probably populated
+    /// incorrectly
+    Mismatch {
+        /// Internal key
+        internal_key: XOnlyPublicKey,
+
+        /// BIP-341 tweak of the internal key with empty merkle root
+        expected: XOnlyPublicKey,
+
+        /// Output key which was verified
+        actual: XOnlyPublicKey,
+    },
+}
+
+/// Verifies that the output key is the BIP-341 tweak of the internal key with
+/// empty merkle root, as required by BIP-86.
+pub fn verify_bip86(
+    internal_key: XOnlyPublicKey,
+    output_key: XOnlyPublicKey,
+) -> Result<(), Bip86Error> {
+    let expected = internal_key.tap_tweak(SECP256K1, None).0.to_inner();
+    if expected != output_key {
+        return Err(Bip86Error::Mismatch {
+            internal_key,
+            expected,
+            actual: output_key,
+        });
+    }
+    Ok(())
+}
+
+/// Verifies that the scriptPubkey contains output key produced from the
+/// internal key of a key-only `tr(KEY)` descriptor according to BIP-86.
+#[cfg(feature = "miniscript")]
+pub fn verify_bip86_descriptor<Pk>(
+    descriptor: &Descriptor<Pk>,
+    script_pubkey: &Script,
+) -> Result<(), Bip86Error>
+where
+    Pk: MiniscriptKey + ToPublicKey,
+{
+    let internal_key = match descriptor {
+        Descriptor::Tr(tr) if tr.taptree().is_none() => tr.internal_key().to_x_only_pubkey(),
+        _ => return Err(Bip86Error::NotKeyOnly),
+    };
+    let output_key = TaprootComponents::output_key_from_script(script_pubkey)
+        .ok_or_else(|| Bip86Error::NonTaprootScript(script_pubkey.clone()))?;
+    verify_bip86(internal_key, output_key.to_inner())
+}
+
+/// Components from which taproot output key is constructed: the internal key
+/// and the optional merkle root of the script tree.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct TaprootComponents {
+    /// Taproot internal key
+    pub internal_key: XOnlyPublicKey,
+
+    /// Merkle root of the script tree; `None` for key-only spendings
+    pub merkle_root: Option<TapBranchHash>,
+}
+
+impl TaprootComponents {
+    /// Extracts taproot components from a `tr()` descriptor with
+    /// already-derived keys. Returns `None` for other descriptor types.
+    #[cfg(feature = "miniscript")]
+    pub fn from_descriptor<Pk>(descriptor: &Descriptor<Pk>) -> Option<TaprootComponents>
+    where
+        Pk: MiniscriptKey + ToPublicKey,
+    {
+        match descriptor {
+            Descriptor::Tr(tr) => Some(TaprootComponents {
+                internal_key: tr.internal_key().to_x_only_pubkey(),
+                merkle_root: tr.spend_info().merkle_root(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Extracts taproot output key from a final P2TR scriptPubkey. Returns
+    /// `None` if the script is not a P2TR one.
+    pub fn output_key_from_script(script_pubkey: &Script) -> Option<TweakedPublicKey> {
+        if !script_pubkey.is_v1_p2tr() {
+            return None;
+        }
+        XOnlyPublicKey::from_slice(&script_pubkey[2..])
+            .ok()
+            .map(TweakedPublicKey::dangerous_assume_tweaked)
+    }
+
+    /// Reconstructs taproot output key from the components.
+    #[inline]
+    pub fn output_key<C: Verification>(&self, secp: &Secp256k1<C>) -> TweakedPublicKey {
+        self.internal_key.tap_tweak(secp, self.merkle_root).0
+    }
+
+    /// Constructs P2TR scriptPubkey from the components.
+    #[inline]
+    pub fn script_pubkey<C: Verification>(&self, secp: &Secp256k1<C>) -> Script {
+        Script::new_v1_p2tr(secp, self.internal_key, self.merkle_root)
+    }
+
+    /// Checks whether the components produce given scriptPubkey.
+    #[inline]
+    pub fn matches<C: Verification>(&self, secp: &Secp256k1<C>, script_pubkey: &Script) -> bool {
+        Self::output_key_from_script(script_pubkey) == Some(self.output_key(secp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{KeyPair, SecretKey};
+
+    use super::*;
+
+    fn internal_key() -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        let seckey = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        KeyPair::from_secret_key(&secp, &seckey).x_only_public_key().0
+    }
+
+    #[test]
+    fn output_key_roundtrip() {
+        let secp = Secp256k1::verification_only();
+        for merkle_root in [None, Some(TapBranchHash::hash(b"tree"))] {
+            let components = TaprootComponents {
+                internal_key: internal_key(),
+                merkle_root,
+            };
+            let script_pubkey = components.script_pubkey(&secp);
+            assert_eq!(
+                TaprootComponents::output_key_from_script(&script_pubkey),
+                Some(components.output_key(&secp))
+            );
+            assert!(components.matches(&secp, &script_pubkey));
+        }
+        assert_eq!(TaprootComponents::output_key_from_script(&Script::new()), None);
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn from_descriptor() {
+        let descriptor = Descriptor::new_tr(internal_key(), None).unwrap();
+        assert_eq!(
+            TaprootComponents::from_descriptor(&descriptor),
+            Some(TaprootComponents {
+                internal_key: internal_key(),
+                merkle_root: None,
+            })
+        );
+        assert_eq!(TaprootComponents::from_descriptor(&Descriptor::new_pk(internal_key())), None);
+    }
+
+    #[test]
+    fn bip86() {
+        let internal_key = internal_key();
+        let output_key = internal_key.tap_tweak(SECP256K1, None).0.to_inner();
+        assert_eq!(verify_bip86(internal_key, output_key), Ok(()));
+        assert_eq!(
+            verify_bip86(internal_key, internal_key),
+            Err(Bip86Error::Mismatch {
+                internal_key,
+                expected: output_key,
+                actual: internal_key
+            })
+        );
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn bip86_descriptor() {
+        let descriptor = Descriptor::new_tr(internal_key(), None).unwrap();
+        let script_pubkey = descriptor.script_pubkey();
+        assert_eq!(verify_bip86_descriptor(&descriptor, &script_pubkey), Ok(()));
+
+        let tweaked = TaprootComponents {
+            internal_key: internal_key(),
+            merkle_root: Some(TapBranchHash::hash(b"tree")),
+        };
+        assert!(matches!(
+            verify_bip86_descriptor(&descriptor, &tweaked.script_pubkey(SECP256K1)),
+            Err(Bip86Error::Mismatch { .. })
+        ));
+        assert_eq!(
+            verify_bip86_descriptor(&Descriptor::new_pk(internal_key()), &script_pubkey),
+            Err(Bip86Error::NotKeyOnly)
+        );
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Wallet-level libraries for bitcoin protocol by LNP/BP Association
+ *
+ * Written in 2020-2022 by
+ *     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+ *
+ * This software is distributed without any warranty.
+ *
+ * You should have received a copy of the Apache-2.0 License
+ * along with this software.
+ * If not, see <https://opensource.org/licenses/Apache-2.0>.
+ */
+
+#ifndef DESCRIPTOR_WALLET_H
+#define DESCRIPTOR_WALLET_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    WALLET_STATUS_OK = 0,
+    WALLET_STATUS_NULL_POINTER = 1,
+    WALLET_STATUS_INVALID_UTF8 = 2,
+    WALLET_STATUS_INVALID_SPEC = 3,
+    WALLET_STATUS_INVALID_PSBT = 4,
+    WALLET_STATUS_INVALID_KEY = 5,
+    WALLET_STATUS_CONSTRUCTION_FAILED = 6,
+    WALLET_STATUS_SIGNING_FAILED = 7,
+    WALLET_STATUS_FINALIZATION_FAILED = 8,
+    WALLET_STATUS_PANIC = 255,
+} wallet_status_t;
+
+/* Opaque PSBT handle; must be released with `psbt_free` */
+typedef struct PsbtHandle psbt_handle_t;
+
+/* Binary data owned by the library; must be released with `byte_buffer_free` */
+typedef struct {
+    uint8_t *data;
+    size_t len;
+} byte_buffer_t;
+
+wallet_status_t psbt_parse(const uint8_t *data, size_t len, psbt_handle_t **out);
+wallet_status_t psbt_construct(const char *spec, psbt_handle_t **out);
+wallet_status_t psbt_sign(psbt_handle_t *psbt, const char *xpriv, bool musig, size_t *sig_count);
+wallet_status_t psbt_finalize(psbt_handle_t *psbt, byte_buffer_t *tx);
+wallet_status_t psbt_serialize(psbt_handle_t *psbt, byte_buffer_t *out);
+void psbt_free(psbt_handle_t *psbt);
+void byte_buffer_free(byte_buffer_t buffer);
+
+/* Description of the last error in the current thread, or NULL */
+const char *wallet_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DESCRIPTOR_WALLET_H */
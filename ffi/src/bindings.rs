@@ -0,0 +1,437 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Types and functions exposed to Kotlin, Swift and Python through UniFFI.
+//! The interface is defined in `src/wallet.udl`; foreign language sources are
+//! produced with
+//! `uniffi-bindgen generate src/wallet.udl --language <kotlin|swift|python>`.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::ExtendedPrivKey;
+use bitcoin::{consensus, Address, Transaction};
+use bitcoin_hd::{SegmentIndexes, UnhardenedIndex};
+use bitcoin_scripts::PubkeyScript;
+use descriptors::InputDescriptor;
+use psbt::serialize::{Deserialize, Serialize};
+use psbt::{construct, FeeError, InputMatchError, PsbtVersion, TxError, TxinError};
+
+/// Errors returned by the wallet API exposed through UniFFI.
+///
+/// Errors from PSBT construction and fee computation ([`construct::Error`],
+/// [`FeeError`], [`TxError`], [`TxinError`] and [`InputMatchError`]) are
+/// mapped into dedicated variants, so the foreign code can match on them.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum WalletError {
+    /// invalid wallet descriptor. Details: {0}
+    InvalidDescriptor(String),
+
+    /// invalid derivation account. Details: {0}
+    InvalidAccount(String),
+
+    /// invalid address. Details: {0}
+    InvalidAddress(String),
+
+    /// invalid input descriptor. Details: {0}
+    InvalidInput(String),
+
+    /// invalid transaction data. Details: {0}
+    InvalidTransaction(String),
+
+    /// invalid PSBT data. Details: {0}
+    InvalidPsbt(String),
+
+    /// invalid extended private key. Details: {0}
+    InvalidKey(String),
+
+    /// derivation index {0} is out of range of unhardened indexes
+    InvalidIndex(u32),
+
+    /// unable to derive address from the descriptor. Details: {0}
+    Derivation(String),
+
+    /// transaction {0} spent by the PSBT is not known
+    UnknownTransaction(String),
+
+    /// spent transaction {0} does not have output #{1}
+    UnknownOutput(String, u32),
+
+    /// output {0}:{1} does not match scriptPubkey derived from the descriptor
+    ScriptPubkeyMismatch(String, u32),
+
+    /// invalid script data. Details: {0}
+    InvalidScript(String),
+
+    /// PSBT spends {output} sats, which is more than the sum of its input
+    /// amounts ({input} sats)
+    Inflation { input: u64, output: u64 },
+
+    /// PSBT fee does not satisfy fee policy. Details: {0}
+    FeePolicy(String),
+
+    /// unable to apply pay-to-contract tweaks. Details: {0}
+    PayToContract(String),
+
+    /// unable to compute transaction lock time. Details: {0}
+    LockTime(String),
+
+    /// account {account} uses coin type {found} while the chain requires
+    /// coin type {expected}
+    CoinTypeMismatch {
+        account: String,
+        expected: u32,
+        found: u32,
+    },
+
+    /// unable to sign PSBT. Details: {0}
+    Signing(String),
+
+    /// unable to finalize PSBT. Details: {0}
+    Finalization(String),
+
+    /// the scriptSigs in the {0} unsigned transaction output is not empty.
+    UnsignedTxHasScriptSigs(u64),
+
+    /// the scriptWitnesses in the {0} unsigned transaction output is not empty.
+    UnsignedTxHasScriptWitnesses(u64),
+
+    /// the unsigned transaction has negative version value ({0}), which is not
+    /// allowed in PSBT.
+    InvalidTxVersion(i32),
+
+    /// no `witness_utxo` and `non_witness_utxo` is provided
+    NoInputTx,
+
+    /// provided `non_witness_utxo` {0} does not match transaction input
+    /// `prev_out`
+    NoTxidMatch(String),
+
+    /// spent transaction does not contain input #{0} referenced by the PSBT
+    /// input
+    UnmatchedInputNumber(u32),
+
+    /// sum of inputs is less than sum of outputs
+    InputsLessThanOutputs,
+}
+
+impl From<TxinError> for WalletError {
+    fn from(err: TxinError) -> Self {
+        match err {
+            TxinError::UnsignedTxHasScriptSigs(no) => {
+                WalletError::UnsignedTxHasScriptSigs(no as u64)
+            }
+            TxinError::UnsignedTxHasScriptWitnesses(no) => {
+                WalletError::UnsignedTxHasScriptWitnesses(no as u64)
+            }
+        }
+    }
+}
+
+impl From<TxError> for WalletError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::Txin(err) => err.into(),
+            TxError::InvalidTxVersion(version) => WalletError::InvalidTxVersion(version),
+        }
+    }
+}
+
+impl From<InputMatchError> for WalletError {
+    fn from(err: InputMatchError) -> Self {
+        match err {
+            InputMatchError::NoInputTx => WalletError::NoInputTx,
+            InputMatchError::NoTxidMatch(txid) => WalletError::NoTxidMatch(txid.to_string()),
+            InputMatchError::UnmatchedInputNumber(no) => WalletError::UnmatchedInputNumber(no),
+        }
+    }
+}
+
+impl From<FeeError> for WalletError {
+    fn from(err: FeeError) -> Self {
+        match err {
+            FeeError::MatchError(err) => err.into(),
+            FeeError::InputsLessThanOutputs => WalletError::InputsLessThanOutputs,
+        }
+    }
+}
+
+impl From<construct::Error> for WalletError {
+    fn from(err: construct::Error) -> Self {
+        match err {
+            construct::Error::ResolvingTx(err) => {
+                WalletError::UnknownTransaction(err.txid.to_string())
+            }
+            construct::Error::Derive(err) => WalletError::Derivation(err.to_string()),
+            construct::Error::OutputUnknown(txid, vout) => {
+                WalletError::UnknownOutput(txid.to_string(), vout)
+            }
+            construct::Error::ScriptPubkeyMismatch(txid, vout, ..) => {
+                WalletError::ScriptPubkeyMismatch(txid.to_string(), vout)
+            }
+            construct::Error::Miniscript(err) => WalletError::InvalidScript(err.to_string()),
+            construct::Error::TaprootBuilderError(err) => {
+                WalletError::InvalidScript(err.to_string())
+            }
+            construct::Error::Inflation { input, output } => {
+                WalletError::Inflation { input, output }
+            }
+            construct::Error::FeePolicy(err) => WalletError::FeePolicy(err.to_string()),
+            construct::Error::P2c(err) => WalletError::PayToContract(err.to_string()),
+            construct::Error::LockTime(err) => WalletError::LockTime(err.to_string()),
+            construct::Error::CoinTypeMismatch {
+                account,
+                expected,
+                found,
+            } => WalletError::CoinTypeMismatch {
+                account: account.to_string(),
+                expected: expected.first_index(),
+                found: found.first_index(),
+            },
+        }
+    }
+}
+
+/// Transaction output specification for [`construct_psbt`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TxOutput {
+    /// Output address
+    pub address: String,
+    /// Amount in satoshis
+    pub amount: u64,
+}
+
+/// Derivation account: extended public key with information about its origin.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DerivationAccount(bitcoin_hd::DerivationAccount);
+
+impl DerivationAccount {
+    /// Parses derivation account in either bitcoin core or LNPBP format.
+    pub fn new(account: String) -> Result<Self, WalletError> {
+        bitcoin_hd::DerivationAccount::from_str(&account)
+            .map(DerivationAccount)
+            .map_err(|err| WalletError::InvalidAccount(err.to_string()))
+    }
+
+    /// Fingerprint of the master key, if known.
+    pub fn master_fingerprint(&self) -> Option<String> {
+        self.0.master_fingerprint().map(|fp| fp.to_string())
+    }
+
+    /// Fingerprint of the account extended public key.
+    pub fn account_fingerprint(&self) -> String { self.0.account_fingerprint().to_string() }
+
+    /// Base58-encoded account extended public key.
+    pub fn account_xpub(&self) -> String { self.0.account_xpub.to_string() }
+
+    /// String representation of the account.
+    pub fn account_string(&self) -> String { self.0.to_string() }
+}
+
+/// Wallet descriptor with derivation accounts as keys.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WalletDescriptor(miniscript::Descriptor<bitcoin_hd::DerivationAccount>);
+
+impl WalletDescriptor {
+    /// Parses wallet descriptor.
+    pub fn new(descriptor: String) -> Result<Self, WalletError> {
+        miniscript::Descriptor::from_str(&descriptor)
+            .map(WalletDescriptor)
+            .map_err(|err| WalletError::InvalidDescriptor(err.to_string()))
+    }
+
+    /// Lists derivation accounts used by the descriptor.
+    pub fn accounts(&self) -> Vec<Arc<DerivationAccount>> {
+        let mut accounts = vec![];
+        self.0.for_each_key(|account| {
+            accounts.push(Arc::new(DerivationAccount(account.clone())));
+            true
+        });
+        accounts
+    }
+
+    /// Derives address for the given change and index derivation terminal.
+    pub fn address(&self, change: u32, index: u32, regtest: bool) -> Result<String, WalletError> {
+        let secp = Secp256k1::verification_only();
+        let pat = [unhardened(change)?, unhardened(index)?];
+        descriptors::derive::Descriptor::address(&self.0, &secp, pat, regtest)
+            .map(|address| address.to_string())
+            .map_err(|err| WalletError::Derivation(err.to_string()))
+    }
+
+    /// String representation of the descriptor.
+    pub fn descriptor_string(&self) -> String { self.0.to_string() }
+}
+
+/// Partially signed bitcoin transaction which can be shared between threads
+/// of the foreign code.
+#[derive(Debug)]
+pub struct PartiallySignedTx(Mutex<psbt::Psbt>);
+
+impl PartiallySignedTx {
+    /// Parses binary PSBT data.
+    pub fn new(data: Vec<u8>) -> Result<Self, WalletError> {
+        psbt::Psbt::deserialize(&data)
+            .map(PartiallySignedTx::from)
+            .map_err(|err| WalletError::InvalidPsbt(err.to_string()))
+    }
+
+    /// Creates PSBT from consensus-serialized unsigned transaction.
+    pub fn from_unsigned_tx(tx: Vec<u8>) -> Result<Self, WalletError> {
+        let tx = consensus::deserialize::<Transaction>(&tx)
+            .map_err(|err| WalletError::InvalidTransaction(err.to_string()))?;
+        Ok(psbt::Psbt::with(tx, PsbtVersion::V0)?.into())
+    }
+
+    /// Serializes PSBT into binary data.
+    pub fn serialize(&self) -> Vec<u8> { self.lock().serialize() }
+
+    /// Id of the unsigned transaction.
+    pub fn txid(&self) -> String { self.lock().to_txid().to_string() }
+
+    /// Computes transaction fee in satoshis.
+    pub fn fee(&self) -> Result<u64, WalletError> { Ok(self.lock().fee()?) }
+
+    /// Signs PSBT inputs with keys derived from the base58-encoded master
+    /// extended private key, returning number of created signatures.
+    pub fn sign(&self, master_xpriv: String, musig: bool) -> Result<u32, WalletError> {
+        let xpriv = ExtendedPrivKey::from_str(&master_xpriv)
+            .map_err(|err| WalletError::InvalidKey(err.to_string()))?;
+        crate::sign(&mut self.lock(), xpriv, musig)
+            .map(|count| count as u32)
+            .map_err(|err| WalletError::Signing(err.to_string()))
+    }
+
+    /// Finalizes fully-signed PSBT and returns consensus-serialized signed
+    /// transaction.
+    pub fn finalize(&self) -> Result<Vec<u8>, WalletError> {
+        crate::finalize(&mut self.lock())
+            .map(|tx| consensus::serialize(&tx))
+            .map_err(WalletError::Finalization)
+    }
+
+    /// Hex-encoded PSBT data.
+    pub fn to_hex(&self) -> String { self.lock().to_string() }
+
+    fn lock(&self) -> MutexGuard<psbt::Psbt> { self.0.lock().expect("PSBT mutex is poisoned") }
+}
+
+impl From<psbt::Psbt> for PartiallySignedTx {
+    fn from(psbt: psbt::Psbt) -> Self { PartiallySignedTx(Mutex::new(psbt)) }
+}
+
+fn unhardened(index: u32) -> Result<UnhardenedIndex, WalletError> {
+    UnhardenedIndex::from_index(index).map_err(|_| WalletError::InvalidIndex(index))
+}
+
+/// Constructs PSBT spending the provided inputs, with change output derived
+/// from the wallet descriptor using `change_index`.
+pub fn construct_psbt(
+    descriptor: Arc<WalletDescriptor>,
+    inputs: Vec<String>,
+    outputs: Vec<TxOutput>,
+    change_index: u32,
+    fee: u64,
+    transactions: Vec<Vec<u8>>,
+) -> Result<Arc<PartiallySignedTx>, WalletError> {
+    let inputs = inputs
+        .iter()
+        .map(|s| InputDescriptor::from_str(s))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| WalletError::InvalidInput(err.to_string()))?;
+    let outputs = outputs
+        .iter()
+        .map(|output| {
+            Address::from_str(&output.address)
+                .map(|address| (PubkeyScript::from(address.script_pubkey()), output.amount))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| WalletError::InvalidAddress(err.to_string()))?;
+    let tx_map = transactions
+        .iter()
+        .map(|data| consensus::deserialize::<Transaction>(data).map(|tx| (tx.txid(), tx)))
+        .collect::<Result<BTreeMap<_, _>, _>>()
+        .map_err(|err| WalletError::InvalidTransaction(err.to_string()))?;
+
+    psbt::Psbt::construct(&descriptor.0, &inputs, &outputs, unhardened(change_index)?, fee, &tx_map)
+        .map(|psbt| Arc::new(psbt.into()))
+        .map_err(WalletError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    #[test]
+    fn error_mapping() {
+        assert_eq!(
+            WalletError::from(FeeError::MatchError(InputMatchError::UnmatchedInputNumber(2))),
+            WalletError::UnmatchedInputNumber(2)
+        );
+        assert_eq!(
+            WalletError::from(TxError::Txin(TxinError::UnsignedTxHasScriptSigs(1))),
+            WalletError::UnsignedTxHasScriptSigs(1)
+        );
+        assert_eq!(
+            WalletError::from(TxError::InvalidTxVersion(-1)),
+            WalletError::InvalidTxVersion(-1)
+        );
+        assert_eq!(
+            WalletError::from(construct::Error::Inflation {
+                input: 1000,
+                output: 2000
+            }),
+            WalletError::Inflation {
+                input: 1000,
+                output: 2000
+            }
+        );
+    }
+
+    #[test]
+    fn construction_errors() {
+        let descriptor = WalletDescriptor::new(s!(
+            "wpkh([d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*)"
+        ))
+        .unwrap();
+        let txid = bitcoin::Txid::from_inner([1u8; 32]);
+        let err = construct_psbt(
+            Arc::new(descriptor),
+            vec![format!("{}:0 /0/5", txid)],
+            vec![],
+            0,
+            1000,
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(err, WalletError::UnknownTransaction(txid.to_string()));
+    }
+
+    #[test]
+    fn psbt_roundtrip() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: bitcoin::PackedLockTime(0),
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut::default()],
+        };
+        let psbt = PartiallySignedTx::from_unsigned_tx(consensus::serialize(&tx)).unwrap();
+        assert_eq!(psbt.txid(), tx.txid().to_string());
+        let data = psbt.serialize();
+        assert_eq!(PartiallySignedTx::new(data.clone()).unwrap().serialize(), data);
+        assert_eq!(psbt.fee(), Err(WalletError::NoInputTx));
+    }
+}
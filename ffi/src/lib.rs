@@ -0,0 +1,482 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! C foreign function interface to the PSBT constructor, signer and finalizer,
+//! allowing embedding descriptor wallet functionality into mobile and other
+//! non-Rust applications.
+//!
+//! All functions return [`WalletStatus`] code; in case of a failure a
+//! human-readable error description can be retrieved with
+//! [`wallet_last_error`]. PSBTs are passed across the FFI boundary as opaque
+//! [`PsbtHandle`] pointers, which must be released with [`psbt_free`]. Binary
+//! data returned by the library are wrapped into [`ByteBuffer`], which must be
+//! released with [`byte_buffer_free`].
+//!
+//! C declarations for the API are provided in `include/descriptor_wallet.h`.
+//!
+//! With `uniffi` feature enabled the crate also exposes higher-level
+//! object-oriented API for Kotlin, Swift and Python, defined in
+//! `src/wallet.udl`.
+
+// Coding conventions
+#![recursion_limit = "256"]
+#![deny(dead_code, missing_docs, warnings)]
+
+#[cfg(feature = "uniffi")]
+#[macro_use]
+extern crate amplify;
+#[macro_use]
+extern crate serde_crate as serde;
+extern crate miniscript_crate as miniscript;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::{ptr, slice};
+
+use amplify::hex::FromHex;
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::{consensus, Address, Transaction};
+use bitcoin_blockchain::locks::LockTime;
+use bitcoin_hd::{SegmentIndexes, UnhardenedIndex};
+use bitcoin_scripts::PubkeyScript;
+use descriptors::InputDescriptor;
+use miniscript::psbt::PsbtExt;
+use miniscript::Descriptor;
+use psbt::serialize::{Deserialize, Serialize};
+use psbt::sign::{MemoryKeyProvider, MemorySigningAccount, SignAll, SignError};
+use psbt::Psbt;
+
+#[cfg(feature = "uniffi")]
+mod bindings;
+
+#[cfg(feature = "uniffi")]
+pub use bindings::{
+    construct_psbt, DerivationAccount, PartiallySignedTx, TxOutput, WalletDescriptor, WalletError,
+};
+#[cfg(feature = "uniffi")]
+uniffi::include_scaffolding!("wallet");
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Status code returned by all FFI functions.
+#[repr(C)]
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum WalletStatus {
+    /// Operation completed successfully
+    Ok = 0,
+
+    /// One of the required pointer arguments is null
+    NullPointer = 1,
+
+    /// String argument is not a valid UTF-8 string
+    InvalidUtf8 = 2,
+
+    /// PSBT construction spec is not a valid JSON or contains invalid data
+    InvalidSpec = 3,
+
+    /// Invalid binary PSBT data
+    InvalidPsbt = 4,
+
+    /// Invalid extended private key
+    InvalidKey = 5,
+
+    /// PSBT can't be constructed from the provided spec
+    ConstructionFailed = 6,
+
+    /// PSBT signing has failed
+    SigningFailed = 7,
+
+    /// PSBT can't be finalized
+    FinalizationFailed = 8,
+
+    /// Internal library failure
+    Panic = 255,
+}
+
+/// Opaque handle to a PSBT owned by the library.
+#[derive(Clone, Debug)]
+pub struct PsbtHandle(Psbt);
+
+/// Binary data allocated by the library. Must be released with
+/// [`byte_buffer_free`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct ByteBuffer {
+    /// Pointer to the data
+    pub data: *mut u8,
+    /// Length of the data in bytes
+    pub len: usize,
+}
+
+impl From<Vec<u8>> for ByteBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        let len = data.len();
+        let data = Box::into_raw(data.into_boxed_slice()) as *mut u8;
+        ByteBuffer { data, len }
+    }
+}
+
+/// Specification for PSBT construction passed to [`psbt_construct`] as a JSON
+/// string.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
+#[serde(crate = "serde_crate", rename_all = "camelCase")]
+struct ConstructSpec {
+    /// Wallet descriptor
+    descriptor: String,
+    /// Input descriptors, in the format used by `btc-cold construct`
+    inputs: Vec<String>,
+    /// Transaction outputs
+    outputs: Vec<OutputSpec>,
+    /// Derivation index for the change output
+    #[serde(default)]
+    change_index: u32,
+    /// Total fee to pay to the miners, in satoshis
+    fee: u64,
+    /// Transaction `nLockTime`
+    #[serde(default)]
+    lock_time: Option<u32>,
+    /// Hex-encoded transactions spent by the inputs
+    transactions: Vec<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
+#[serde(crate = "serde_crate")]
+struct OutputSpec {
+    address: String,
+    amount: u64,
+}
+
+fn fail(status: WalletStatus, err: impl Display) -> WalletStatus {
+    let msg = CString::new(err.to_string().replace('\0', " "))
+        .expect("null characters are removed from the string");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+    status
+}
+
+fn status(f: impl FnOnce() -> Result<(), WalletStatus>) -> WalletStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => WalletStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => fail(WalletStatus::Panic, "internal library failure"),
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, WalletStatus> {
+    if s.is_null() {
+        return Err(WalletStatus::NullPointer);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|err| fail(WalletStatus::InvalidUtf8, err))
+}
+
+unsafe fn psbt_arg<'a>(psbt: *mut PsbtHandle) -> Result<&'a mut PsbtHandle, WalletStatus> {
+    psbt.as_mut().ok_or(WalletStatus::NullPointer)
+}
+
+/// Signs PSBT with keys derived from the master extended private key,
+/// returning number of created signatures.
+fn sign(psbt: &mut Psbt, xpriv: ExtendedPrivKey, musig: bool) -> Result<usize, SignError> {
+    let secp = Secp256k1::new();
+    let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
+    let account =
+        MemorySigningAccount::with(&secp, xpub.identifier(), DerivationPath::master(), xpriv);
+    let mut provider = MemoryKeyProvider::with(&secp, musig);
+    provider.add_account(account);
+    psbt.sign_all(&provider)
+}
+
+/// Finalizes PSBT inputs and extracts signed transaction; errors are
+/// reported as a single string joining messages for all failed inputs.
+fn finalize(psbt: &mut Psbt) -> Result<Transaction, String> {
+    let secp = Secp256k1::new();
+    let mut v0 = PartiallySignedTransaction::from(psbt.clone());
+    v0.finalize_mut(&secp).map_err(|errs| {
+        errs.iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    })?;
+    *psbt = v0.clone().into();
+    Ok(v0.extract_tx())
+}
+
+fn construct(spec: &str) -> Result<Psbt, WalletStatus> {
+    let spec: ConstructSpec =
+        serde_json::from_str(spec).map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+
+    let descriptor = Descriptor::<bitcoin_hd::DerivationAccount>::from_str(&spec.descriptor)
+        .map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+    let inputs = spec
+        .inputs
+        .iter()
+        .map(|s| InputDescriptor::from_str(s))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+    let outputs = spec
+        .outputs
+        .iter()
+        .map(|output| {
+            Address::from_str(&output.address)
+                .map(|address| (PubkeyScript::from(address.script_pubkey()), output.amount))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+    let tx_map = spec
+        .transactions
+        .iter()
+        .map(|hex| {
+            let data =
+                Vec::<u8>::from_hex(hex).map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+            consensus::deserialize::<Transaction>(&data)
+                .map(|tx| (tx.txid(), tx))
+                .map_err(|err| fail(WalletStatus::InvalidSpec, err))
+        })
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    let change_index = UnhardenedIndex::from_index(spec.change_index)
+        .map_err(|err| fail(WalletStatus::InvalidSpec, err))?;
+
+    let mut psbt = Psbt::construct(&descriptor, &inputs, &outputs, change_index, spec.fee, &tx_map)
+        .map_err(|err| fail(WalletStatus::ConstructionFailed, err))?;
+    psbt.fallback_locktime = spec.lock_time.map(LockTime::from);
+
+    Ok(psbt)
+}
+
+/// Parses binary PSBT data.
+///
+/// # Safety
+///
+/// `data` must point to `len` bytes of readable memory; `out` must be a valid
+/// pointer to write the resulting PSBT handle to.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_parse(
+    data: *const u8,
+    len: usize,
+    out: *mut *mut PsbtHandle,
+) -> WalletStatus {
+    status(|| {
+        if data.is_null() || out.is_null() {
+            return Err(WalletStatus::NullPointer);
+        }
+        let psbt = Psbt::deserialize(slice::from_raw_parts(data, len))
+            .map_err(|err| fail(WalletStatus::InvalidPsbt, err))?;
+        *out = Box::into_raw(Box::new(PsbtHandle(psbt)));
+        Ok(())
+    })
+}
+
+/// Constructs new PSBT from a JSON specification.
+///
+/// The specification is an object with the following fields:
+/// - `descriptor`: wallet descriptor string;
+/// - `inputs`: array of input descriptor strings;
+/// - `outputs`: array of objects with `address` and `amount` (in satoshis);
+/// - `changeIndex`: derivation index for the change output (defaults to 0);
+/// - `fee`: transaction fee in satoshis;
+/// - `lockTime`: optional transaction `nLockTime`;
+/// - `transactions`: array of hex-encoded transactions spent by the inputs.
+///
+/// # Safety
+///
+/// `spec` must be a valid null-terminated string; `out` must be a valid
+/// pointer to write the resulting PSBT handle to.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_construct(
+    spec: *const c_char,
+    out: *mut *mut PsbtHandle,
+) -> WalletStatus {
+    status(|| {
+        if out.is_null() {
+            return Err(WalletStatus::NullPointer);
+        }
+        let psbt = construct(str_arg(spec)?)?;
+        *out = Box::into_raw(Box::new(PsbtHandle(psbt)));
+        Ok(())
+    })
+}
+
+/// Signs PSBT inputs with keys derived from the provided base58-encoded master
+/// extended private key. If `musig` is set, the key participates in aggregated
+/// Schnorr signatures for taproot key path spendings.
+///
+/// Number of created signatures is written to `sig_count`, if it is not null.
+///
+/// # Safety
+///
+/// `psbt` must be a valid handle obtained from this library; `xpriv` must be a
+/// valid null-terminated string; `sig_count` must be either null or a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_sign(
+    psbt: *mut PsbtHandle,
+    xpriv: *const c_char,
+    musig: bool,
+    sig_count: *mut usize,
+) -> WalletStatus {
+    status(|| {
+        let psbt = psbt_arg(psbt)?;
+        let xpriv = ExtendedPrivKey::from_str(str_arg(xpriv)?)
+            .map_err(|err| fail(WalletStatus::InvalidKey, err))?;
+        let count =
+            sign(&mut psbt.0, xpriv, musig).map_err(|err| fail(WalletStatus::SigningFailed, err))?;
+        if let Some(sig_count) = sig_count.as_mut() {
+            *sig_count = count;
+        }
+        Ok(())
+    })
+}
+
+/// Finalizes fully-signed PSBT. If `tx` is not null, the resulting signed
+/// transaction is serialized into it.
+///
+/// # Safety
+///
+/// `psbt` must be a valid handle obtained from this library; `tx` must be
+/// either null or a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_finalize(
+    psbt: *mut PsbtHandle,
+    tx: *mut ByteBuffer,
+) -> WalletStatus {
+    status(|| {
+        let psbt = psbt_arg(psbt)?;
+        let signed_tx =
+            finalize(&mut psbt.0).map_err(|err| fail(WalletStatus::FinalizationFailed, err))?;
+        if let Some(tx) = tx.as_mut() {
+            *tx = consensus::serialize(&signed_tx).into();
+        }
+        Ok(())
+    })
+}
+
+/// Serializes PSBT into binary data.
+///
+/// # Safety
+///
+/// `psbt` must be a valid handle obtained from this library; `out` must be a
+/// valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_serialize(
+    psbt: *mut PsbtHandle,
+    out: *mut ByteBuffer,
+) -> WalletStatus {
+    status(|| {
+        let psbt = psbt_arg(psbt)?;
+        let out = out.as_mut().ok_or(WalletStatus::NullPointer)?;
+        *out = psbt.0.serialize().into();
+        Ok(())
+    })
+}
+
+/// Releases PSBT handle.
+///
+/// # Safety
+///
+/// `psbt` must be either null or a valid handle obtained from this library,
+/// which was not released before.
+#[no_mangle]
+pub unsafe extern "C" fn psbt_free(psbt: *mut PsbtHandle) {
+    if !psbt.is_null() {
+        drop(Box::from_raw(psbt));
+    }
+}
+
+/// Releases binary data returned by the library.
+///
+/// # Safety
+///
+/// `buffer` must be a buffer returned by this library, which was not released
+/// before.
+#[no_mangle]
+pub unsafe extern "C" fn byte_buffer_free(buffer: ByteBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)));
+    }
+}
+
+/// Returns description of the last error happened in the current thread, or
+/// null if the last call has succeeded. The returned string is owned by the
+/// library and remains valid until the next library call.
+#[no_mangle]
+pub extern "C" fn wallet_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|msg| msg.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn null_pointers() {
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(psbt_parse(ptr::null(), 0, &mut handle), WalletStatus::NullPointer);
+            assert_eq!(psbt_construct(ptr::null(), &mut handle), WalletStatus::NullPointer);
+            assert_eq!(
+                psbt_sign(ptr::null_mut(), ptr::null(), false, ptr::null_mut()),
+                WalletStatus::NullPointer
+            );
+            psbt_free(ptr::null_mut());
+        }
+        assert!(handle.is_null());
+    }
+
+    #[test]
+    fn invalid_spec() {
+        let spec = CString::new("{\"descriptor\": 1}").unwrap();
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(psbt_construct(spec.as_ptr(), &mut handle), WalletStatus::InvalidSpec);
+        }
+        assert!(handle.is_null());
+        assert!(!wallet_last_error().is_null());
+    }
+
+    #[test]
+    fn parse_serialize() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: bitcoin::PackedLockTime(0),
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut::default()],
+        };
+        let data = Psbt::with(tx, psbt::PsbtVersion::V0).unwrap().serialize();
+        let mut handle = ptr::null_mut();
+        let mut buffer = ByteBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        unsafe {
+            assert_eq!(psbt_parse(data.as_ptr(), data.len(), &mut handle), WalletStatus::Ok);
+            assert!(wallet_last_error().is_null());
+            assert_eq!(psbt_serialize(handle, &mut buffer), WalletStatus::Ok);
+            assert_eq!(slice::from_raw_parts(buffer.data, buffer.len), &data[..]);
+            byte_buffer_free(buffer);
+            psbt_free(handle);
+        }
+    }
+}
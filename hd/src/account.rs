@@ -242,6 +242,42 @@ impl DerivationAccount {
             ),
         ))
     }
+
+    /// Checks whether the key with the given [`KeySource`] may be derived
+    /// from the account, returning derivation pattern for the variable steps
+    /// of the account terminal path (see
+    /// [`DerivationAccount::to_terminal_derivation_path`]).
+    ///
+    /// The function can be used to detect whether some PSBT input or output
+    /// key belongs to the account.
+    pub fn match_key_source(&self, key_source: &KeySource) -> Option<Vec<UnhardenedIndex>> {
+        let (fingerprint, path) = key_source;
+        let terminal = match self.master_fingerprint() {
+            Some(master) => {
+                let account_path = self.to_account_derivation_path();
+                if *fingerprint != master || !path.as_ref().starts_with(account_path.as_ref()) {
+                    return None;
+                }
+                &path[account_path.len()..]
+            }
+            _ if *fingerprint == self.account_fingerprint() => path.as_ref(),
+            _ => return None,
+        };
+        if terminal.len() != self.terminal_path.len() {
+            return None;
+        }
+        let mut pattern = vec![];
+        for (step, child) in self.terminal_path.iter().zip(terminal) {
+            let index = match child {
+                ChildNumber::Normal { index } if step.contains(*index) => *index,
+                _ => return None,
+            };
+            if step.count() > 1 {
+                pattern.push(UnhardenedIndex::from_index(index).ok()?);
+            }
+        }
+        Some(pattern)
+    }
 }
 
 impl DerivationAccount {
@@ -469,8 +505,11 @@ impl miniscript::MiniscriptKey for DerivationAccount {
 #[cfg(test)]
 mod test {
     use bitcoin::util::bip32::ExtendedPubKey;
+    use proptest::prelude::*;
+    use strict_encoding::{StrictDecode, StrictEncode};
 
     use super::*;
+    use crate::strategies::{derivation_account, strict_roundtrip, xpub_ref};
 
     fn xpubs() -> [ExtendedPubKey; 5] {
         [
@@ -557,4 +596,32 @@ mod test {
             assert_eq!(format!("{}", account), path);
         }
     }
+
+    #[test]
+    fn strict_encoding() {
+        let xpubs = xpubs();
+        for path in [
+            format!("[{}/0h/5h/8h]{}/1/0/*", xpubs[2].fingerprint(), xpubs[3]),
+            format!("{}/0/*/*", xpubs[0]),
+        ] {
+            let account = DerivationAccount::from_str_bitcoin_core(&path).unwrap();
+            let data = account.strict_serialize().unwrap();
+            assert_eq!(
+                DerivationAccount::strict_deserialize(data).unwrap(),
+                account
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn xpub_ref_strict_roundtrip(xpub_ref in xpub_ref()) {
+            strict_roundtrip(&xpub_ref)?;
+        }
+
+        #[test]
+        fn derivation_account_strict_roundtrip(account in derivation_account()) {
+            strict_roundtrip(&account)?;
+        }
+    }
 }
@@ -0,0 +1,346 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! BIP-48 multisig accounts (`m/48'/coin_type'/account'/script_type'`).
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
+use slip132::KeyApplication;
+
+use crate::{
+    AccountStep, Bip43, DerivationAccount, DescriptorType, HardenedIndex, SegmentIndexes,
+    TerminalStep, XpubRef,
+};
+
+/// Errors in BIP-48 account derivation and cosigner validation.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Bip48Error {
+    /// derivation path `{0}` does not follow BIP-48 structure
+    /// `m/48'/coin_type'/account'/script_type'`
+    NotBip48(DerivationPath),
+
+    /// unknown BIP-48 script type {0}
+    UnknownScriptType(HardenedIndex),
+
+    /// no cosigners were provided
+    NoCosigners,
+
+    /// cosigner {cosigner} uses {found} script type, while other cosigners use
+    /// {expected}
+    ScriptTypeMismatch {
+        /// Fingerprint of the cosigner account key
+        cosigner: Fingerprint,
+
+        /// Script type used by the first cosigner
+        expected: Bip48ScriptType,
+
+        /// Script type used by the cosigner
+        found: Bip48ScriptType,
+    },
+
+    /// cosigner {cosigner} uses coin type {found}, while other cosigners use
+    /// {expected}
+    CoinTypeMismatch {
+        /// Fingerprint of the cosigner account key
+        cosigner: Fingerprint,
+
+        /// Coin type used by the first cosigner
+        expected: HardenedIndex,
+
+        /// Coin type used by the cosigner
+        found: HardenedIndex,
+    },
+
+    /// extended public key of cosigner {0} was not derived at the BIP-48
+    /// script type level of its derivation path
+    XpubMismatch(Fingerprint),
+
+    /// cosigner {0} is present more than once
+    DuplicateCosigner(Fingerprint),
+}
+
+/// Script type level of BIP-48 derivation path.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[repr(u8)]
+pub enum Bip48ScriptType {
+    /// P2WSH nested in P2SH (`1'`)
+    #[display("nested")]
+    Nested = 1,
+
+    /// Native P2WSH (`2'`)
+    #[display("native")]
+    Native = 2,
+}
+
+impl Bip48ScriptType {
+    /// Returns hardened index used for the script type in derivation path.
+    #[inline]
+    pub fn index(self) -> HardenedIndex { HardenedIndex::from(self as u8) }
+
+    /// Constructs script type from its index in derivation path.
+    pub fn with(index: HardenedIndex) -> Result<Bip48ScriptType, Bip48Error> {
+        match index.first_index() {
+            1 => Ok(Bip48ScriptType::Nested),
+            2 => Ok(Bip48ScriptType::Native),
+            _ => Err(Bip48Error::UnknownScriptType(index)),
+        }
+    }
+
+    /// Returns descriptor type used by multisig wallets with this script type.
+    pub fn descriptor_type(self) -> DescriptorType {
+        match self {
+            Bip48ScriptType::Nested => DescriptorType::ShWshSortedMulti,
+            Bip48ScriptType::Native => DescriptorType::WshSortedMulti,
+        }
+    }
+
+    /// Returns SLIP-132 key application for the account extended public keys.
+    pub fn slip_application(self) -> KeyApplication {
+        match self {
+            Bip48ScriptType::Nested => KeyApplication::NestedMultisig,
+            Bip48ScriptType::Native => KeyApplication::SegWitMultisig,
+        }
+    }
+}
+
+impl From<Bip48ScriptType> for Bip43 {
+    fn from(script_type: Bip48ScriptType) -> Self {
+        match script_type {
+            Bip48ScriptType::Nested => Bip43::Bip48Nested,
+            Bip48ScriptType::Native => Bip43::Bip48Native,
+        }
+    }
+}
+
+/// BIP-48 account derivation path `m/48'/coin_type'/account'/script_type'`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Bip48Path {
+    /// Coin type (`0'` for bitcoin mainnet, `1'` for testnets)
+    pub coin_type: HardenedIndex,
+
+    /// Account number
+    pub account: HardenedIndex,
+
+    /// Script type
+    pub script_type: Bip48ScriptType,
+}
+
+impl Display for Bip48Path {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "m/{}/{}/{}/{}",
+            Self::PURPOSE,
+            self.coin_type,
+            self.account,
+            self.script_type.index()
+        )
+    }
+}
+
+impl Bip48Path {
+    /// BIP-43 purpose index used by BIP-48.
+    pub const PURPOSE: HardenedIndex = HardenedIndex(48);
+
+    /// Constructs BIP-48 path.
+    #[inline]
+    pub fn with(
+        coin_type: impl Into<HardenedIndex>,
+        account: impl Into<HardenedIndex>,
+        script_type: Bip48ScriptType,
+    ) -> Bip48Path {
+        Bip48Path {
+            coin_type: coin_type.into(),
+            account: account.into(),
+            script_type,
+        }
+    }
+
+    /// Parses BIP-48 account derivation path.
+    pub fn from_derivation_path(path: &DerivationPath) -> Result<Bip48Path, Bip48Error> {
+        let err = || Bip48Error::NotBip48(path.clone());
+        let steps = path
+            .into_iter()
+            .copied()
+            .map(HardenedIndex::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err())?;
+        match steps[..] {
+            [purpose, coin_type, account, script_type] if purpose == Self::PURPOSE => {
+                Ok(Bip48Path {
+                    coin_type,
+                    account,
+                    script_type: Bip48ScriptType::with(script_type)?,
+                })
+            }
+            _ => Err(err()),
+        }
+    }
+
+    /// Constructs derivation path for the account extended public key.
+    pub fn to_derivation_path(&self) -> DerivationPath {
+        [Self::PURPOSE, self.coin_type, self.account, self.script_type.index()]
+            .into_iter()
+            .map(ChildNumber::from)
+            .collect()
+    }
+
+    /// Constructs cosigner account from the cosigner master key fingerprint
+    /// and extended public key derived at this path. The account uses
+    /// `<0;1>/*` terminal path for receive and change addresses.
+    pub fn to_account(&self, master: Fingerprint, xpub: ExtendedPubKey) -> DerivationAccount {
+        DerivationAccount {
+            master: XpubRef::Fingerprint(master),
+            account_path: [Self::PURPOSE, self.coin_type, self.account, self.script_type.index()]
+                .into_iter()
+                .map(AccountStep::hardened)
+                .collect(),
+            account_xpub: xpub,
+            revocation_seal: None,
+            terminal_path: vec![TerminalStep::range(0u8, 1u8), TerminalStep::Wildcard]
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+impl DerivationAccount {
+    /// Returns BIP-48 derivation path of the account, if the account follows
+    /// BIP-48.
+    pub fn bip48_path(&self) -> Result<Bip48Path, Bip48Error> {
+        Bip48Path::from_derivation_path(&self.to_account_derivation_path())
+    }
+}
+
+/// Checks that multisig cosigner accounts follow BIP-48 and use the same
+/// script and coin types, returning the common script type.
+///
+/// # Errors
+///
+/// If the account list is empty, contains duplicated cosigners, accounts not
+/// following BIP-48 or not matching the first account.
+pub fn check_bip48_cosigners<'accounts>(
+    accounts: impl IntoIterator<Item = &'accounts DerivationAccount>,
+) -> Result<Bip48ScriptType, Bip48Error> {
+    let mut expected: Option<Bip48Path> = None;
+    let mut xpubs = BTreeSet::new();
+    for account in accounts {
+        let cosigner = account.account_fingerprint();
+        let path = account.bip48_path()?;
+        if account.account_xpub.depth != 4
+            || account.account_xpub.child_number != path.script_type.index().into()
+        {
+            return Err(Bip48Error::XpubMismatch(cosigner));
+        }
+        if !xpubs.insert(account.account_xpub) {
+            return Err(Bip48Error::DuplicateCosigner(cosigner));
+        }
+        let expected = match expected {
+            None => {
+                expected = Some(path);
+                continue;
+            }
+            Some(expected) => expected,
+        };
+        if path.script_type != expected.script_type {
+            return Err(Bip48Error::ScriptTypeMismatch {
+                cosigner,
+                expected: expected.script_type,
+                found: path.script_type,
+            });
+        }
+        if path.coin_type != expected.coin_type {
+            return Err(Bip48Error::CoinTypeMismatch {
+                cosigner,
+                expected: expected.coin_type,
+                found: path.coin_type,
+            });
+        }
+    }
+    expected
+        .map(|path| path.script_type)
+        .ok_or(Bip48Error::NoCosigners)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::Network;
+
+    use super::*;
+
+    fn cosigner(seed: u8, path: Bip48Path) -> DerivationAccount {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[seed; 32]).unwrap();
+        let xpriv = master
+            .derive_priv(&secp, &path.to_derivation_path())
+            .unwrap();
+        let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
+        path.to_account(master.fingerprint(&secp), xpub)
+    }
+
+    #[test]
+    fn bip48_cosigners() {
+        let native = Bip48Path::with(0u8, 0u8, Bip48ScriptType::Native);
+        assert_eq!(native.to_string(), "m/48h/0h/0h/2h");
+        assert_eq!(
+            Bip48Path::from_derivation_path(&DerivationPath::from_str("m/48'/0'/0'/2'").unwrap()),
+            Ok(native)
+        );
+        assert_eq!(
+            Bip48Path::from_derivation_path(&DerivationPath::from_str("m/48'/0'/0'/3'").unwrap()),
+            Err(Bip48Error::UnknownScriptType(HardenedIndex(3)))
+        );
+
+        let alice = cosigner(1, native);
+        let bob = cosigner(2, Bip48Path::with(0u8, 5u8, Bip48ScriptType::Native));
+        assert_eq!(alice.bip48_path(), Ok(native));
+        assert_eq!(check_bip48_cosigners([&alice, &bob]), Ok(Bip48ScriptType::Native));
+
+        let carol = cosigner(3, Bip48Path::with(0u8, 0u8, Bip48ScriptType::Nested));
+        assert_eq!(
+            check_bip48_cosigners([&alice, &carol]),
+            Err(Bip48Error::ScriptTypeMismatch {
+                cosigner: carol.account_fingerprint(),
+                expected: Bip48ScriptType::Native,
+                found: Bip48ScriptType::Nested,
+            })
+        );
+        let dave = cosigner(4, Bip48Path::with(1u8, 0u8, Bip48ScriptType::Native));
+        assert!(matches!(
+            check_bip48_cosigners([&alice, &dave]),
+            Err(Bip48Error::CoinTypeMismatch { .. })
+        ));
+        assert_eq!(
+            check_bip48_cosigners([&alice, &alice]),
+            Err(Bip48Error::DuplicateCosigner(alice.account_fingerprint()))
+        );
+        assert_eq!(check_bip48_cosigners([]), Err(Bip48Error::NoCosigners));
+    }
+}
@@ -0,0 +1,527 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Parse diagnostics for derivation paths, accounts and descriptors, pointing
+//! to the exact fragment of the string which can't be parsed.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey};
+
+use crate::{
+    AccountStep, DerivationAccount, DerivationSubpath, SegmentIndexes, TerminalStep, XpubRef,
+    HARDENED_INDEX_BOUNDARY,
+};
+
+/// Characters which are frequently used in place of `h` or `'` hardened
+/// index markers, for instance when copying derivation paths from documents.
+const HARDENED_LOOKALIKES: [char; 6] = ['H', '`', '’', '‘', '′', 'ʼ'];
+
+/// Byte range of the string fragment which has failed to parse.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default, Display)]
+#[display("{start}..{end}")]
+pub struct Span {
+    /// Byte offset of the first character of the fragment
+    pub start: usize,
+    /// Byte offset following the last character of the fragment
+    pub end: usize,
+}
+
+impl Span {
+    /// Constructs span from the start and end byte offsets.
+    #[inline]
+    pub fn new(start: usize, end: usize) -> Span { Span { start, end } }
+
+    /// Constructs span starting at `start` offset and having `len` bytes.
+    #[inline]
+    pub fn with_len(start: usize, len: usize) -> Span { Span::new(start, start + len) }
+
+    /// Returns span moved forward by `offset` bytes.
+    #[inline]
+    pub fn shifted(self, offset: usize) -> Span {
+        Span::new(self.start + offset, self.end + offset)
+    }
+
+    /// Returns fragment of the string covered by the span, or empty string if
+    /// the span does not match the string.
+    pub fn fragment<'s>(&self, s: &'s str) -> &'s str { s.get(self.start..self.end).unwrap_or("") }
+}
+
+/// Suggestions on fixing parse errors reported by [`Diagnostic`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub enum Suggestion {
+    /// use `h` or `'` as a hardened index marker instead of `{0}`
+    HardenedMarker(char),
+
+    /// derivation steps following extended public key must not be hardened;
+    /// remove the hardened index marker
+    UnhardenIndex,
+
+    /// derivation index must be less than 2^31; use hardened marker instead
+    /// of adding 2^31 to the index
+    IndexOutOfRange,
+
+    /// remove empty derivation step produced by a duplicated `/` separator
+    EmptyStep,
+
+    /// derivation path must start with `/` or `m/`
+    LeadingSeparator,
+
+    /// key origin must start with 8 hex characters of the master key
+    /// fingerprint
+    InvalidFingerprint,
+
+    /// add missing closing bracket `{0}`
+    MissingClosingBracket(char),
+
+    /// add missing opening bracket `{0}`
+    MissingOpeningBracket(char),
+
+    /// descriptor checksum must consist of 8 characters; remove it to skip
+    /// the checksum verification
+    InvalidChecksum,
+
+    /// remove whitespace characters
+    Whitespace,
+}
+
+/// Parse error pointing to the specific fragment of the parsed string, with an
+/// optional suggestion on how the error can be fixed.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Diagnostic {
+    /// Error description
+    pub message: String,
+
+    /// Location of the erroneous fragment in the parsed string
+    pub span: Span,
+
+    /// Suggestion on fixing the error, if any
+    pub suggestion: Option<Suggestion>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)?;
+        if let Some(suggestion) = self.suggestion {
+            write!(f, "; {}", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl Diagnostic {
+    /// Constructs diagnostic without suggestion.
+    pub fn new(message: impl ToString, span: Span) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            span,
+            suggestion: None,
+        }
+    }
+
+    /// Adds suggestion to the diagnostic.
+    pub fn with_suggestion(mut self, suggestion: Suggestion) -> Diagnostic {
+        self.suggestion = Some(suggestion);
+        self
+    }
+
+    /// Moves diagnostic span forward by `offset` bytes; used when the
+    /// diagnosed string is a part of a larger one.
+    pub fn shifted(mut self, offset: usize) -> Diagnostic {
+        self.span = self.span.shifted(offset);
+        self
+    }
+}
+
+/// Types which are able to report [`Diagnostic`] when parsing from a string
+/// fails.
+pub trait FromStrDiagnostic: Sized {
+    /// Parses string, reporting location of the erroneous fragment on failure.
+    fn from_str_diagnostic(s: &str) -> Result<Self, Diagnostic>;
+}
+
+/// Wrapper providing [`FromStr`] implementation which reports [`Diagnostic`]
+/// errors; useful with APIs relying on [`str::parse`].
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Diagnosed<T>(pub T);
+
+impl<T> Diagnosed<T> {
+    /// Returns the wrapped value.
+    #[inline]
+    pub fn into_inner(self) -> T { self.0 }
+}
+
+impl<T> FromStr for Diagnosed<T>
+where
+    T: FromStrDiagnostic,
+{
+    type Err = Diagnostic;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> { T::from_str_diagnostic(s).map(Diagnosed) }
+}
+
+impl FromStrDiagnostic for DerivationPath {
+    fn from_str_diagnostic(s: &str) -> Result<Self, Diagnostic> {
+        let err = match Self::from_str(s) {
+            Ok(path) => return Ok(path),
+            Err(err) => err,
+        };
+        let steps = match s.strip_prefix("m/") {
+            Some(steps) => steps,
+            None if s == "m" => "",
+            None => {
+                return Err(Diagnostic::new(err, Span::with_len(0, 1))
+                    .with_suggestion(Suggestion::LeadingSeparator))
+            }
+        };
+        Err(diagnose_steps::<ChildNumber>(steps, 2)
+            .unwrap_or_else(|| Diagnostic::new(err, Span::new(0, s.len()))))
+    }
+}
+
+impl<Segment> FromStrDiagnostic for DerivationSubpath<Segment>
+where
+    Segment: SegmentIndexes + FromStr,
+    <Segment as FromStr>::Err: Display,
+    bitcoin::util::bip32::Error: From<<Segment as FromStr>::Err>,
+{
+    fn from_str_diagnostic(s: &str) -> Result<Self, Diagnostic> {
+        let err = match Self::from_str(s) {
+            Ok(path) => return Ok(path),
+            Err(err) => err,
+        };
+        let steps = match s.strip_prefix('/') {
+            Some(steps) => steps,
+            None => {
+                return Err(Diagnostic::new(err, Span::with_len(0, 1))
+                    .with_suggestion(Suggestion::LeadingSeparator))
+            }
+        };
+        Err(diagnose_steps::<Segment>(steps, 1)
+            .unwrap_or_else(|| Diagnostic::new(err, Span::new(0, s.len()))))
+    }
+}
+
+impl FromStrDiagnostic for DerivationAccount {
+    /// Diagnoses accounts in bitcoin core format; for the LNPBP format the
+    /// span always covers the whole string.
+    fn from_str_diagnostic(s: &str) -> Result<Self, Diagnostic> {
+        match DerivationAccount::from_str(s) {
+            Ok(account) => Ok(account),
+            Err(err) if s.contains('=') => Err(Diagnostic::new(err, Span::new(0, s.len()))),
+            Err(err) => Err(diagnose_account(s)
+                .unwrap_or_else(|| Diagnostic::new(err, Span::new(0, s.len())))),
+        }
+    }
+}
+
+#[cfg(feature = "miniscript")]
+impl FromStrDiagnostic for miniscript::Descriptor<DerivationAccount> {
+    fn from_str_diagnostic(s: &str) -> Result<Self, Diagnostic> {
+        let err = match Self::from_str(s) {
+            Ok(descriptor) => return Ok(descriptor),
+            Err(err) => err,
+        };
+        if let Some(diagnostic) = diagnose_whitespace(s).or_else(|| diagnose_brackets(s)) {
+            return Err(diagnostic);
+        }
+        let (body, checksum) = s.split_once('#').unwrap_or((s, ""));
+        if s.contains('#') && checksum.len() != 8 {
+            return Err(Diagnostic::new(
+                "descriptor checksum has invalid length",
+                Span::new(body.len(), s.len()),
+            )
+            .with_suggestion(Suggestion::InvalidChecksum));
+        }
+        for (offset, token) in key_tokens(body) {
+            // Extended public keys in all networks and SLIP-132 variants
+            // contain `pub` in their prefix
+            if !token.contains("pub") {
+                continue;
+            }
+            if let Err(diagnostic) = DerivationAccount::from_str_diagnostic(token) {
+                return Err(diagnostic.shifted(offset));
+            }
+        }
+        Err(Diagnostic::new(err, Span::new(0, s.len())))
+    }
+}
+
+fn diagnose_whitespace(s: &str) -> Option<Diagnostic> {
+    s.char_indices()
+        .find(|(_, c)| c.is_whitespace())
+        .map(|(pos, c)| {
+            Diagnostic::new("unexpected whitespace", Span::with_len(pos, c.len_utf8()))
+                .with_suggestion(Suggestion::Whitespace)
+        })
+}
+
+#[cfg(feature = "miniscript")]
+fn diagnose_brackets(s: &str) -> Option<Diagnostic> {
+    fn closing(open: char) -> char {
+        match open {
+            '(' => ')',
+            '[' => ']',
+            '{' => '}',
+            _ => '>',
+        }
+    }
+
+    let mut stack = Vec::<(usize, char)>::new();
+    for (pos, c) in s.char_indices() {
+        let open = match c {
+            '(' | '[' | '{' | '<' => {
+                stack.push((pos, c));
+                continue;
+            }
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            '>' => '<',
+            _ => continue,
+        };
+        match stack.pop() {
+            Some((_, expected)) if expected == open => {}
+            Some((start, expected)) => {
+                return Some(
+                    Diagnostic::new(
+                        format!("bracket `{}` is closed with `{}`", expected, c),
+                        Span::new(start, pos + 1),
+                    )
+                    .with_suggestion(Suggestion::MissingClosingBracket(closing(expected))),
+                )
+            }
+            None => {
+                return Some(
+                    Diagnostic::new(
+                        format!("unmatched closing bracket `{}`", c),
+                        Span::with_len(pos, 1),
+                    )
+                    .with_suggestion(Suggestion::MissingOpeningBracket(open)),
+                )
+            }
+        }
+    }
+    stack.pop().map(|(start, open)| {
+        let message = match open {
+            '[' => s!("key origin is not closed"),
+            open => format!("bracket `{}` is not closed", open),
+        };
+        Diagnostic::new(message, Span::new(start, s.len()))
+            .with_suggestion(Suggestion::MissingClosingBracket(closing(open)))
+    })
+}
+
+/// Splits descriptor into fragments separated by parentheses, braces and
+/// commas, keeping key origins and multipath steps intact. Returns fragments
+/// together with their byte offsets.
+#[cfg(feature = "miniscript")]
+fn key_tokens(s: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    for (pos, c) in s.char_indices() {
+        match c {
+            '[' | '<' => depth += 1,
+            ']' | '>' => depth = depth.saturating_sub(1),
+            '(' | ')' | '{' | '}' | ',' if depth == 0 => {
+                if pos > start {
+                    tokens.push((start, &s[start..pos]));
+                }
+                start = pos + 1;
+            }
+            _ => {}
+        }
+    }
+    if s.len() > start {
+        tokens.push((start, &s[start..]));
+    }
+    tokens
+}
+
+/// Diagnoses derivation account in bitcoin core format:
+/// `[fp/hardened_path/account]xpub/unhardened_path`.
+fn diagnose_account(s: &str) -> Option<Diagnostic> {
+    if let Some(diagnostic) = diagnose_whitespace(s) {
+        return Some(diagnostic);
+    }
+
+    let mut offset = 0;
+    let mut rest = s;
+    if let Some(origin) = s.strip_prefix('[') {
+        let close = match origin.find(']') {
+            Some(close) => close,
+            None => {
+                return Some(
+                    Diagnostic::new("key origin is not closed", Span::new(0, s.len()))
+                        .with_suggestion(Suggestion::MissingClosingBracket(']')),
+                )
+            }
+        };
+        let origin = &origin[..close];
+        let (fingerprint, path) = origin.split_once('/').unwrap_or((origin, ""));
+        if fingerprint != "m" && XpubRef::from_str(fingerprint).is_err() {
+            return Some(
+                Diagnostic::new(
+                    format!("invalid master key fingerprint `{}`", fingerprint),
+                    Span::with_len(1, fingerprint.len()),
+                )
+                .with_suggestion(Suggestion::InvalidFingerprint),
+            );
+        }
+        if origin.contains('/') {
+            if let Some(diagnostic) = diagnose_steps::<AccountStep>(path, fingerprint.len() + 2) {
+                return Some(diagnostic);
+            }
+        }
+        offset = close + 2;
+        rest = &s[offset..];
+    } else if let Some(close) = s.find(']') {
+        return Some(
+            Diagnostic::new("key origin has no opening bracket", Span::with_len(close, 1))
+                .with_suggestion(Suggestion::MissingOpeningBracket('[')),
+        );
+    }
+
+    let (xpub, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if let Err(err) = ExtendedPubKey::from_str(xpub) {
+        return Some(Diagnostic::new(
+            format!("invalid extended public key: {}", err),
+            Span::with_len(offset, xpub.len()),
+        ));
+    }
+    if rest.contains('/') {
+        return diagnose_steps::<TerminalStep>(path, offset + xpub.len() + 1);
+    }
+    None
+}
+
+/// Diagnoses `/`-separated derivation steps starting at `offset` byte of the
+/// original string, returning diagnostic for the first invalid step.
+fn diagnose_steps<Step>(steps: &str, mut offset: usize) -> Option<Diagnostic>
+where
+    Step: FromStr,
+    <Step as FromStr>::Err: Display,
+{
+    for step in steps.split('/') {
+        if let Err(err) = Step::from_str(step) {
+            let diagnostic = Diagnostic::new(
+                format!("invalid derivation step `{}`: {}", step, err),
+                Span::with_len(offset, step.len()),
+            );
+            let index = step.trim_end_matches(&['h', '\''][..]);
+            let marker = step
+                .chars()
+                .last()
+                .filter(|c| HARDENED_LOOKALIKES.contains(c));
+            let out_of_range =
+                u64::from_str(index).map_or(false, |i| i >= u64::from(HARDENED_INDEX_BOUNDARY));
+            let suggestion = if step.is_empty() {
+                Some(Suggestion::EmptyStep)
+            } else if let Some(marker) = marker {
+                Some(Suggestion::HardenedMarker(marker))
+            } else if index != step && Step::from_str(index).is_ok() {
+                Some(Suggestion::UnhardenIndex)
+            } else if out_of_range {
+                Some(Suggestion::IndexOutOfRange)
+            } else {
+                None
+            };
+            return Some(match suggestion {
+                Some(suggestion) => diagnostic.with_suggestion(suggestion),
+                None => diagnostic,
+            });
+        }
+        offset += step.len() + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const XPUB: &str = "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ";
+
+    #[test]
+    fn derivation_path() {
+        let err = DerivationPath::from_str_diagnostic("m/84H/0h/0h").unwrap_err();
+        assert_eq!(err.span, Span::new(2, 5));
+        assert_eq!(err.suggestion, Some(Suggestion::HardenedMarker('H')));
+
+        let err = DerivationPath::from_str_diagnostic("m/84h//0h").unwrap_err();
+        assert_eq!(err.span, Span::new(6, 6));
+        assert_eq!(err.suggestion, Some(Suggestion::EmptyStep));
+
+        let err = "84h/0h".parse::<Diagnosed<DerivationPath>>().unwrap_err();
+        assert_eq!(err.suggestion, Some(Suggestion::LeadingSeparator));
+
+        assert!("m/84h/0'/0h".parse::<Diagnosed<DerivationPath>>().is_ok());
+    }
+
+    #[test]
+    fn subpath() {
+        let err = DerivationSubpath::<TerminalStep>::from_str_diagnostic("/0/1h/*").unwrap_err();
+        assert_eq!(err.span, Span::new(3, 5));
+        assert_eq!(err.suggestion, Some(Suggestion::UnhardenIndex));
+
+        let err =
+            DerivationSubpath::<TerminalStep>::from_str_diagnostic("/2147483648").unwrap_err();
+        assert_eq!(err.suggestion, Some(Suggestion::IndexOutOfRange));
+    }
+
+    #[test]
+    fn account() {
+        let s = format!("[d34db33f/84h/0h/0h{}/0/*", XPUB);
+        let err = DerivationAccount::from_str_diagnostic(&s).unwrap_err();
+        assert_eq!(err.span, Span::new(0, s.len()));
+        assert_eq!(err.suggestion, Some(Suggestion::MissingClosingBracket(']')));
+
+        let s = format!("[d34db33f/84h/0h/0h]{}/1h/*", XPUB);
+        let err = DerivationAccount::from_str_diagnostic(&s).unwrap_err();
+        let pos = s.find("/1h").unwrap() + 1;
+        assert_eq!(err.span, Span::with_len(pos, 2));
+        assert_eq!(err.span.fragment(&s), "1h");
+        assert_eq!(err.suggestion, Some(Suggestion::UnhardenIndex));
+
+        let s = format!("[d34db33/84h/0h/0h]{}/0/*", XPUB);
+        let err = DerivationAccount::from_str_diagnostic(&s).unwrap_err();
+        assert_eq!(err.span, Span::new(1, 8));
+        assert_eq!(err.suggestion, Some(Suggestion::InvalidFingerprint));
+
+        let s = format!("[d34db33f/84h/0h/0h]{}/0/*", XPUB);
+        assert!(DerivationAccount::from_str_diagnostic(&s).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn descriptor() {
+        type Descriptor = miniscript::Descriptor<DerivationAccount>;
+
+        let s = format!("wpkh([d34db33f/84h/0h/0h]{}/0/*)", XPUB);
+        assert!(Descriptor::from_str_diagnostic(&s).is_ok());
+
+        let s = format!("wpkh([d34db33f/84h/0h/0h]{}/0/*", XPUB);
+        let err = Descriptor::from_str_diagnostic(&s).unwrap_err();
+        assert_eq!(err.span, Span::new(4, s.len()));
+        assert_eq!(err.suggestion, Some(Suggestion::MissingClosingBracket(')')));
+
+        let s = format!("wpkh([d34db33f/84H/0h/0h]{}/0/*)", XPUB);
+        let err = Descriptor::from_str_diagnostic(&s).unwrap_err();
+        assert_eq!(err.span, Span::new(15, 18));
+        assert_eq!(err.suggestion, Some(Suggestion::HardenedMarker('H')));
+    }
+}
@@ -30,30 +30,38 @@ extern crate serde_crate as serde;
 extern crate miniscript_crate as miniscript;
 
 pub mod account;
+mod bip48;
 mod derive;
+mod diagnostic;
 mod indexes;
+mod origin;
 mod path;
 mod ranges;
 pub mod standards;
+#[cfg(test)]
+mod strategies;
 mod traits;
 mod unsatisfiable;
 mod xkey;
 mod xpubref;
 
 pub use account::DerivationAccount;
+pub use bip48::{check_bip48_cosigners, Bip48Error, Bip48Path, Bip48ScriptType};
 pub use derive::{DeriveError, DerivePatternError};
+pub use diagnostic::{Diagnosed, Diagnostic, FromStrDiagnostic, Span, Suggestion};
 pub use indexes::{
     AccountStep, HardenedIndex, HardenedIndexExpected, SegmentIndexes, TerminalStep,
     UnhardenedIndex, UnhardenedIndexExpected,
 };
+pub use origin::KeyOrigin;
 pub use path::DerivationSubpath;
 pub use ranges::{IndexRange, IndexRangeList};
 pub use standards::{Bip43, DerivationStandard, DescriptorType};
-pub use traits::{DerivationPathMaster, HardenedNormalSplit};
+pub use traits::{DerivationPathAlgebra, DerivationPathMaster, HardenedNormalSplit};
 pub use unsatisfiable::UnsatisfiableKey;
 pub use xkey::{
     NonStandardDerivation, XpubDescriptor, XpubOrigin, XpubParseError, XpubRequirementError,
-    XpubkeyCore,
+    XpubkeyCore, XPUB_ENCODING_VERSION,
 };
 pub use xpubref::XpubRef;
 
@@ -0,0 +1,198 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+use core::fmt::{self, Display, Formatter};
+
+use bitcoin::util::bip32::{DerivationPath, KeySource};
+
+use crate::{DerivationAccount, DerivationPathAlgebra, XpubRef};
+
+/// Origin of a key: derivation path anchored at some extended public key.
+///
+/// Generalizes BIP32 [`KeySource`] to the anchors which may be known with
+/// more details than just a fingerprint.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub struct KeyOrigin {
+    /// Extended public key the derivation starts from
+    pub anchor: XpubRef,
+
+    /// Derivation path from the anchor key
+    pub path: DerivationPath,
+}
+
+impl From<KeySource> for KeyOrigin {
+    fn from((fingerprint, path): KeySource) -> Self {
+        KeyOrigin {
+            anchor: XpubRef::Fingerprint(fingerprint),
+            path,
+        }
+    }
+}
+
+impl Display for KeyOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let path = self.path.to_canonical_string();
+        write!(f, "{}{}", self.anchor, path.trim_start_matches('m'))
+    }
+}
+
+impl KeyOrigin {
+    /// Constructs key origin from the anchor and derivation path.
+    pub fn with(anchor: impl Into<XpubRef>, path: DerivationPath) -> KeyOrigin {
+        KeyOrigin {
+            anchor: anchor.into(),
+            path,
+        }
+    }
+
+    /// Converts into BIP32 [`KeySource`], if the anchor fingerprint is known.
+    pub fn to_key_source(&self) -> Option<KeySource> {
+        self.anchor
+            .fingerprint()
+            .map(|fingerprint| (fingerprint, self.path.clone()))
+    }
+
+    /// Returns origin with the longest common derivation path of two origins,
+    /// or `None` if the origins are anchored at different keys.
+    pub fn common_prefix(&self, other: &KeyOrigin) -> Option<KeyOrigin> {
+        if !self.anchor.matches(&other.anchor) {
+            return None;
+        }
+        Some(KeyOrigin {
+            anchor: self.anchor,
+            path: self.path.common_prefix(&other.path),
+        })
+    }
+
+    /// Detects whether the origin belongs to the subtree of `root` origin.
+    pub fn is_within(&self, root: &KeyOrigin) -> bool {
+        self.anchor.matches(&root.anchor) && self.path.is_within(&root.path)
+    }
+
+    /// Moves the origin from the `from` subtree into the `onto` subtree,
+    /// keeping the relative derivation path. Returns `None` if the origin
+    /// does not belong to the `from` subtree.
+    pub fn rebase(&self, from: &KeyOrigin, onto: &KeyOrigin) -> Option<KeyOrigin> {
+        if !self.anchor.matches(&from.anchor) {
+            return None;
+        }
+        Some(KeyOrigin {
+            anchor: onto.anchor,
+            path: self.path.rebase(&from.path, &onto.path)?,
+        })
+    }
+
+    /// Detects whether the key with this origin belongs to the subtree of the
+    /// `account` extended public key, returning derivation path from the
+    /// account key. Origins may be anchored either at the account master key
+    /// or at the account key itself.
+    pub fn account_subpath(&self, account: &DerivationAccount) -> Option<DerivationPath> {
+        if self.anchor.matches(&account.master) {
+            self.path.relative_to(&account.to_account_derivation_path())
+        } else if self
+            .anchor
+            .matches(&XpubRef::Xpub(account.account_xpub))
+        {
+            Some(self.path.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::util::bip32::{self, ChildNumber, Fingerprint};
+
+    use super::*;
+
+    #[test]
+    fn path_algebra() {
+        let path = |s: &str| DerivationPath::from_str_normalized(s).unwrap();
+
+        assert_eq!(
+            path("m/48'/0h/0H/2h/0/1"),
+            DerivationPath::from_str("m/48'/0'/0'/2'/0/1").unwrap()
+        );
+        assert_eq!(path("48h/0h"), path("m/48h/0h"));
+        assert_eq!(path("/48h/0h"), path("m/48h/0h"));
+        assert_eq!(path("m"), DerivationPath::master());
+        assert_eq!(path(" M/1 "), DerivationPath::from(vec![ChildNumber::Normal { index: 1 }]));
+        assert_eq!(
+            DerivationPath::from_str_normalized("m/48hh"),
+            Err(bip32::Error::InvalidChildNumberFormat)
+        );
+        assert_eq!(
+            DerivationPath::from_str_normalized("m//1"),
+            Err(bip32::Error::InvalidDerivationPathFormat)
+        );
+        assert_eq!(
+            DerivationPath::from_str_normalized("m/2147483648"),
+            Err(bip32::Error::InvalidChildNumber(2147483648))
+        );
+        assert_eq!(path("m/48'/0'/0/1").to_canonical_string(), "m/48h/0h/0/1");
+        assert_eq!(DerivationPath::master().to_canonical_string(), "m");
+
+        let child = path("m/84h/0h/0h/1/5");
+        assert_eq!(child.common_prefix(&path("m/84h/0h/1h/0/5")), path("m/84h/0h"));
+        assert_eq!(child.common_prefix(&path("m/44h")), DerivationPath::master());
+        assert!(child.is_within(&path("m/84h/0h/0h")));
+        assert!(child.is_within(&child));
+        assert!(!child.is_within(&path("m/84h/0h/1h")));
+        assert_eq!(child.relative_to(&path("m/84h/0h/0h")), Some(path("m/1/5")));
+        assert_eq!(
+            child.rebase(&path("m/84h/0h/0h"), &path("m/86h/0h/3h")),
+            Some(path("m/86h/0h/3h/1/5"))
+        );
+        assert_eq!(child.rebase(&path("m/44h"), &path("m/86h")), None);
+    }
+
+    #[test]
+    fn origin_algebra() {
+        let path = |s: &str| DerivationPath::from_str_normalized(s).unwrap();
+        let d34db33f = Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]);
+        let deadbeef = Fingerprint::from(&[0xde, 0xad, 0xbe, 0xef][..]);
+
+        let origin = KeyOrigin::with(d34db33f, path("m/48h/0h/0h/2h/0/1"));
+        assert_eq!(origin.to_string(), "[d34db33f]/48h/0h/0h/2h/0/1");
+        assert_eq!(origin.to_key_source(), Some((d34db33f, path("m/48h/0h/0h/2h/0/1"))));
+        assert_eq!(KeyOrigin::from(origin.to_key_source().unwrap()), origin);
+
+        let root = KeyOrigin::with(d34db33f, path("m/48h/0h/0h/2h"));
+        let foreign = KeyOrigin::with(deadbeef, path("m/48h/0h/0h/2h"));
+        assert!(origin.is_within(&root));
+        assert!(!origin.is_within(&foreign));
+        assert_eq!(origin.common_prefix(&root), Some(root.clone()));
+        assert_eq!(origin.common_prefix(&foreign), None);
+        assert_eq!(
+            origin.rebase(&root, &foreign),
+            Some(KeyOrigin::with(deadbeef, path("m/48h/0h/0h/2h/0/1")))
+        );
+        assert_eq!(origin.rebase(&foreign, &root), None);
+        assert!(!KeyOrigin::default().is_within(&KeyOrigin::default()));
+
+        let account = DerivationAccount::from_str(
+            "[d34db33f/48h/0h/0h/2h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/0/*",
+        )
+        .unwrap();
+        assert_eq!(origin.account_subpath(&account), Some(path("m/0/1")));
+        assert_eq!(foreign.account_subpath(&account), None);
+        let account_origin = KeyOrigin::with(account.account_xpub, path("m/1/3"));
+        assert_eq!(account_origin.account_subpath(&account), Some(path("m/1/3")));
+    }
+}
@@ -18,7 +18,7 @@ use bitcoin::util::bip32::{ChildNumber, DerivationPath};
 use bitcoin::Network;
 #[cfg(feature = "miniscript")]
 pub use miniscript::descriptor::DescriptorType;
-use slip132::KeyApplication;
+use slip132::{ChainParams, KeyApplication};
 
 use crate::{HardenedIndex, HardenedIndexExpected, SegmentIndexes, UnhardenedIndex};
 
@@ -60,6 +60,7 @@ pub enum ParseError {
 #[derive(
     Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, From
 )]
+#[derive(StrictEncode, StrictDecode)]
 #[cfg_attr(
     feature = "serde",
     derive(Serialize, Deserialize),
@@ -106,6 +107,19 @@ impl DerivationBlockchain {
     }
 }
 
+impl From<&ChainParams> for DerivationBlockchain {
+    /// Detects derivation blockchain from the chain parameters coin type.
+    fn from(params: &ChainParams) -> Self {
+        match params.coin_type {
+            0 => DerivationBlockchain::Bitcoin,
+            1 => DerivationBlockchain::Testnet,
+            coin_type => DerivationBlockchain::Custom(
+                HardenedIndex::from_index(coin_type).expect("hardened index from u32 never fails"),
+            ),
+        }
+    }
+}
+
 impl FromStr for DerivationBlockchain {
     type Err = ParseError;
 
@@ -597,6 +611,7 @@ impl DerivationStandard for Bip43 {
 /// used.
 #[cfg(not(feature = "miniscript"))]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(StrictEncode, StrictDecode)]
 pub enum DescriptorType {
     /// Bare descriptor(Contains the native P2pk)
     Bare,
@@ -621,3 +636,22 @@ pub enum DescriptorType {
     /// Tr Descriptor
     Tr,
 }
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::strategies::{bip43, blockchain, strict_roundtrip};
+
+    proptest! {
+        #[test]
+        fn blockchain_strict_roundtrip(blockchain in blockchain()) {
+            strict_roundtrip(&blockchain)?;
+        }
+
+        #[test]
+        fn bip43_strict_roundtrip(bip43 in bip43()) {
+            strict_roundtrip(&bip43)?;
+        }
+    }
+}
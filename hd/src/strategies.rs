@@ -0,0 +1,157 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Property testing strategies generating arbitrary values of the library
+//! types, used by the strict encoding round-trip tests.
+
+use std::fmt::Debug;
+
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{SecretKey, SECP256K1};
+use bitcoin::util::bip32::{ChainCode, ChildNumber, ExtendedPubKey, Fingerprint};
+use bitcoin::{Network, OutPoint, Txid, XpubIdentifier};
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::standards::DerivationBlockchain;
+use crate::{
+    AccountStep, Bip43, DerivationAccount, HardenedIndex, SegmentIndexes, TerminalStep,
+    UnhardenedIndex, XpubRef, HARDENED_INDEX_BOUNDARY,
+};
+
+/// Checks that the value is decoded back from its strict encoding.
+pub(crate) fn strict_roundtrip<T>(value: &T) -> Result<(), TestCaseError>
+where
+    T: StrictEncode + StrictDecode + PartialEq + Debug,
+{
+    let data = value.strict_serialize().expect("in-memory encoding");
+    prop_assert_eq!(&T::strict_deserialize(data)?, value);
+    Ok(())
+}
+
+pub(crate) fn fingerprint() -> impl Strategy<Value = Fingerprint> {
+    any::<[u8; 4]>().prop_map(|data| Fingerprint::from(&data[..]))
+}
+
+pub(crate) fn hardened_index() -> impl Strategy<Value = HardenedIndex> {
+    (0..HARDENED_INDEX_BOUNDARY)
+        .prop_map(|index| HardenedIndex::from_index(index).expect("index below the boundary"))
+}
+
+pub(crate) fn unhardened_index() -> impl Strategy<Value = UnhardenedIndex> {
+    (0..HARDENED_INDEX_BOUNDARY)
+        .prop_map(|index| UnhardenedIndex::from_index(index).expect("index below the boundary"))
+}
+
+pub(crate) fn xpub() -> impl Strategy<Value = ExtendedPubKey> {
+    (
+        any::<bool>(),
+        any::<u8>(),
+        fingerprint(),
+        any::<u32>(),
+        any::<[u8; 32]>(),
+        any::<[u8; 32]>(),
+    )
+        .prop_filter_map(
+            "invalid secret key",
+            |(testnet, depth, parent_fingerprint, child_number, seckey, chain_code)| {
+                let seckey = SecretKey::from_slice(&seckey).ok()?;
+                Some(ExtendedPubKey {
+                    network: if testnet {
+                        Network::Testnet
+                    } else {
+                        Network::Bitcoin
+                    },
+                    depth,
+                    parent_fingerprint,
+                    child_number: ChildNumber::from(child_number),
+                    public_key: seckey.public_key(SECP256K1),
+                    chain_code: ChainCode::from(&chain_code[..]),
+                })
+            },
+        )
+}
+
+pub(crate) fn bip43() -> impl Strategy<Value = Bip43> {
+    prop_oneof![
+        Just(Bip43::Bip44),
+        Just(Bip43::Bip84),
+        Just(Bip43::Bip49),
+        Just(Bip43::Bip86),
+        Just(Bip43::Bip45),
+        Just(Bip43::Bip48Nested),
+        Just(Bip43::Bip48Native),
+        Just(Bip43::Bip87),
+        hardened_index().prop_map(|purpose| Bip43::Bip43 { purpose }),
+    ]
+}
+
+pub(crate) fn blockchain() -> impl Strategy<Value = DerivationBlockchain> {
+    prop_oneof![
+        Just(DerivationBlockchain::Bitcoin),
+        Just(DerivationBlockchain::Testnet),
+        hardened_index().prop_map(DerivationBlockchain::Custom),
+    ]
+}
+
+pub(crate) fn xpub_ref() -> impl Strategy<Value = XpubRef> {
+    prop_oneof![
+        Just(XpubRef::Unknown),
+        fingerprint().prop_map(XpubRef::from),
+        any::<[u8; 20]>().prop_map(|id| XpubRef::from(XpubIdentifier::from_inner(id))),
+        xpub().prop_map(XpubRef::from),
+    ]
+}
+
+pub(crate) fn account_step() -> impl Strategy<Value = AccountStep> {
+    prop_oneof![
+        unhardened_index().prop_map(AccountStep::from),
+        (hardened_index(), xpub_ref())
+            .prop_map(|(index, xpub_ref)| AccountStep::with_xpub(index, xpub_ref)),
+    ]
+}
+
+pub(crate) fn terminal_step() -> impl Strategy<Value = TerminalStep> {
+    prop_oneof![
+        unhardened_index().prop_map(TerminalStep::from),
+        (0u16..1000, 1u16..1000).prop_map(|(start, len)| TerminalStep::range(start, start + len)),
+        Just(TerminalStep::Wildcard),
+    ]
+}
+
+pub(crate) fn outpoint() -> impl Strategy<Value = OutPoint> {
+    (any::<[u8; 32]>(), any::<u32>())
+        .prop_map(|(txid, vout)| OutPoint::new(Txid::from_inner(txid), vout))
+}
+
+pub(crate) fn derivation_account() -> impl Strategy<Value = DerivationAccount> {
+    (
+        xpub_ref(),
+        vec(account_step(), 0..5),
+        xpub(),
+        option::of(outpoint()),
+        vec(terminal_step(), 0..5),
+    )
+        .prop_map(
+            |(master, account_path, account_xpub, revocation_seal, terminal_path)| {
+                DerivationAccount {
+                    master,
+                    account_path: account_path.into(),
+                    account_xpub,
+                    revocation_seal,
+                    terminal_path: terminal_path.into(),
+                }
+            },
+        )
+}
@@ -9,7 +9,9 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use bitcoin::util::bip32::{ChildNumber, DerivationPath};
+use std::str::FromStr;
+
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath};
 
 use crate::{AccountStep, SegmentIndexes, TerminalStep};
 
@@ -62,3 +64,91 @@ impl HardenedNormalSplit for DerivationPath {
         (account_path, terminal_path)
     }
 }
+
+/// Extension trait providing algebraic operations on [`DerivationPath`]s,
+/// which are used for matching keys against accounts and moving derivations
+/// between account roots.
+pub trait DerivationPathAlgebra: Sized {
+    /// Parses derivation path accepting all hardened index markers (`'`, `h`
+    /// and `H`) and an optional `m` prefix.
+    fn from_str_normalized(s: &str) -> Result<Self, bip32::Error>;
+
+    /// Formats derivation path in the canonical form, starting with `m` and
+    /// using `h` as the hardened index marker.
+    fn to_canonical_string(&self) -> String;
+
+    /// Returns the longest path which is a prefix of both paths.
+    fn common_prefix(&self, other: &Self) -> Self;
+
+    /// Detects whether the path belongs to the subtree of `root`, i.e.
+    /// starts with it. Each path is contained within itself.
+    fn is_within(&self, root: &Self) -> bool;
+
+    /// Returns the path relative to `root`, or `None` if the path does not
+    /// belong to the `root` subtree.
+    fn relative_to(&self, root: &Self) -> Option<Self>;
+
+    /// Replaces `from` prefix of the path with `onto`, returning `None` if
+    /// the path does not belong to the `from` subtree.
+    fn rebase(&self, from: &Self, onto: &Self) -> Option<Self>;
+}
+
+impl DerivationPathAlgebra for DerivationPath {
+    fn from_str_normalized(s: &str) -> Result<Self, bip32::Error> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix('m')
+            .or_else(|| s.strip_prefix('M'))
+            .unwrap_or(s);
+        let s = s.strip_prefix('/').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(DerivationPath::master());
+        }
+        s.split('/')
+            .map(|step| {
+                let digits = step.trim_end_matches(&['h', 'H', '\''][..]);
+                if digits.is_empty() {
+                    return Err(bip32::Error::InvalidDerivationPathFormat);
+                }
+                let index =
+                    u32::from_str(digits).map_err(|_| bip32::Error::InvalidChildNumberFormat)?;
+                match step.len() - digits.len() {
+                    0 => ChildNumber::from_normal_idx(index),
+                    1 => ChildNumber::from_hardened_idx(index),
+                    _ => Err(bip32::Error::InvalidChildNumberFormat),
+                }
+            })
+            .collect()
+    }
+
+    fn to_canonical_string(&self) -> String {
+        let mut s = s!("m");
+        for child in self {
+            match child {
+                ChildNumber::Normal { index } => s.push_str(&format!("/{}", index)),
+                ChildNumber::Hardened { index } => s.push_str(&format!("/{}h", index)),
+            }
+        }
+        s
+    }
+
+    fn common_prefix(&self, other: &Self) -> Self {
+        self.into_iter()
+            .zip(other)
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| *a)
+            .collect()
+    }
+
+    fn is_within(&self, root: &Self) -> bool { self.as_ref().starts_with(root.as_ref()) }
+
+    fn relative_to(&self, root: &Self) -> Option<Self> {
+        self.as_ref()
+            .strip_prefix(root.as_ref())
+            .map(DerivationPath::from)
+    }
+
+    fn rebase(&self, from: &Self, onto: &Self) -> Option<Self> {
+        self.relative_to(from).map(|relative| onto.extend(relative))
+    }
+}
@@ -10,7 +10,7 @@
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
 use std::fmt::{self, Display, Formatter};
-use std::io::Write;
+use std::io::{self, Write};
 use std::str::FromStr;
 
 use bitcoin::hashes::Hash;
@@ -19,6 +19,7 @@ use bitcoin::util::bip32;
 use bitcoin::util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
 use bitcoin::{secp256k1, XpubIdentifier};
 use slip132::{DefaultResolver, FromSlip132, KeyVersion};
+use strict_encoding::{self, StrictDecode, StrictEncode};
 
 use crate::{DerivationStandard, HardenedIndex, SegmentIndexes, UnhardenedIndex};
 
@@ -162,6 +163,22 @@ impl XpubkeyCore {
     }
 }
 
+/// Version of the strict encoding of [`XpubOrigin`] and [`XpubDescriptor`],
+/// written as the first byte of their encoded data.
+pub const XPUB_ENCODING_VERSION: u8 = 1;
+
+/// Reads strict encoding version of an extended key structure, failing on
+/// versions not supported by this library.
+fn strict_decode_version(d: impl io::Read, name: &str) -> Result<(), strict_encoding::Error> {
+    match u8::strict_decode(d)? {
+        XPUB_ENCODING_VERSION => Ok(()),
+        version => Err(strict_encoding::Error::DataIntegrityError(format!(
+            "unsupported {} encoding version {}",
+            name, version
+        ))),
+    }
+}
+
 /// Structure describing origin of some extended key
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub struct XpubOrigin<Standard>
@@ -337,6 +354,36 @@ where
     }
 }
 
+impl<Standard> StrictEncode for XpubOrigin<Standard>
+where
+    Standard: DerivationStandard + StrictEncode,
+{
+    fn strict_encode<E: io::Write>(&self, mut e: E) -> Result<usize, strict_encoding::Error> {
+        Ok(strict_encode_list!(e;
+            XPUB_ENCODING_VERSION,
+            self.testnet,
+            self.master_fingerprint,
+            self.standard,
+            self.account
+        ))
+    }
+}
+
+impl<Standard> StrictDecode for XpubOrigin<Standard>
+where
+    Standard: DerivationStandard + StrictDecode,
+{
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, strict_encoding::Error> {
+        strict_decode_version(&mut d, "xpub origin")?;
+        Ok(XpubOrigin {
+            testnet: bool::strict_decode(&mut d)?,
+            master_fingerprint: Option::strict_decode(&mut d)?,
+            standard: Option::strict_decode(&mut d)?,
+            account: Option::strict_decode(&mut d)?,
+        })
+    }
+}
+
 /// Descriptor for extended public key which may also hold the information
 /// information about the key origin.
 #[derive(Getters, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -660,3 +707,129 @@ where
         }
     }
 }
+
+impl<Standard> StrictEncode for XpubDescriptor<Standard>
+where
+    Standard: DerivationStandard + StrictEncode,
+{
+    fn strict_encode<E: io::Write>(&self, mut e: E) -> Result<usize, strict_encoding::Error> {
+        Ok(strict_encode_list!(e;
+            XPUB_ENCODING_VERSION,
+            self.testnet,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            self.public_key,
+            self.chain_code,
+            self.master_fingerprint,
+            self.standard,
+            self.account
+        ))
+    }
+}
+
+impl<Standard> StrictDecode for XpubDescriptor<Standard>
+where
+    Standard: DerivationStandard + StrictDecode,
+{
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, strict_encoding::Error> {
+        strict_decode_version(&mut d, "xpub descriptor")?;
+        Ok(XpubDescriptor {
+            testnet: bool::strict_decode(&mut d)?,
+            depth: u8::strict_decode(&mut d)?,
+            parent_fingerprint: Fingerprint::strict_decode(&mut d)?,
+            child_number: ChildNumber::strict_decode(&mut d)?,
+            public_key: secp256k1::PublicKey::strict_decode(&mut d)?,
+            chain_code: ChainCode::strict_decode(&mut d)?,
+            master_fingerprint: Option::strict_decode(&mut d)?,
+            standard: Option::strict_decode(&mut d)?,
+            account: Option::strict_decode(&mut d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::option;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::strategies::{bip43, fingerprint, hardened_index, strict_roundtrip, xpub};
+    use crate::Bip43;
+
+    fn xpub_origin() -> impl Strategy<Value = XpubOrigin<Bip43>> {
+        (
+            any::<bool>(),
+            option::of(fingerprint()),
+            option::of(bip43()),
+            option::of(hardened_index()),
+        )
+            .prop_map(
+                |(testnet, master_fingerprint, standard, account)| XpubOrigin {
+                    testnet,
+                    master_fingerprint,
+                    standard,
+                    account,
+                },
+            )
+    }
+
+    fn xpub_descriptor() -> impl Strategy<Value = XpubDescriptor<Bip43>> {
+        (xpub(), xpub_origin()).prop_map(|(xpub, origin)| {
+            let mut descriptor = XpubDescriptor::from(xpub);
+            descriptor.testnet = origin.testnet;
+            descriptor.master_fingerprint = origin.master_fingerprint;
+            descriptor.standard = origin.standard;
+            descriptor.account = origin.account;
+            descriptor
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn xpub_origin_strict_roundtrip(origin in xpub_origin()) {
+            strict_roundtrip(&origin)?;
+        }
+
+        #[test]
+        fn xpub_descriptor_strict_roundtrip(descriptor in xpub_descriptor()) {
+            strict_roundtrip(&descriptor)?;
+        }
+    }
+
+    #[test]
+    fn strict_encoding() {
+        let xpub = ExtendedPubKey::from_str(
+            "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+        )
+        .unwrap();
+        let mut descriptor = XpubDescriptor::<Bip43>::from(xpub);
+        let data = descriptor.strict_serialize().unwrap();
+        assert_eq!(data[0], XPUB_ENCODING_VERSION);
+        assert_eq!(
+            XpubDescriptor::strict_deserialize(&data).unwrap(),
+            descriptor
+        );
+
+        descriptor.master_fingerprint = Some(Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]));
+        descriptor.standard = Some(Bip43::Bip84);
+        descriptor.account = Some(HardenedIndex::from(5u8));
+        let data = descriptor.strict_serialize().unwrap();
+        assert_eq!(
+            XpubDescriptor::strict_deserialize(&data).unwrap(),
+            descriptor
+        );
+
+        let origin = descriptor.to_origin();
+        let data = origin.strict_serialize().unwrap();
+        assert_eq!(data[0], XPUB_ENCODING_VERSION);
+        assert_eq!(XpubOrigin::strict_deserialize(&data).unwrap(), origin);
+
+        let mut data = data;
+        data[0] = XPUB_ENCODING_VERSION + 1;
+        assert!(matches!(
+            XpubOrigin::<Bip43>::strict_deserialize(&data),
+            Err(strict_encoding::Error::DataIntegrityError(_))
+        ));
+    }
+}
@@ -81,6 +81,16 @@ impl XpubRef {
             XpubRef::Xpub(xpub) => Some(*xpub),
         }
     }
+
+    /// Detects whether two references point to the same extended public key,
+    /// comparing the most detailed information present in both of them.
+    /// Unknown references never match.
+    pub fn matches(&self, other: &XpubRef) -> bool {
+        match (self.identifier(), other.identifier()) {
+            (Some(id1), Some(id2)) => id1 == id2,
+            _ => self.fingerprint().is_some() && self.fingerprint() == other.fingerprint(),
+        }
+    }
 }
 
 impl FromStr for XpubRef {
@@ -102,6 +102,22 @@ pub enum MiningStatus {
     Blockchain(u64),
 }
 
+impl MiningStatus {
+    /// Estimates wallet birthday during import from the mining status of the
+    /// known wallet transactions, returning height of the block containing
+    /// the earliest mined transaction, or `None` if none of the transactions
+    /// is mined.
+    pub fn estimate_birthday(statuses: impl IntoIterator<Item = MiningStatus>) -> Option<u32> {
+        statuses
+            .into_iter()
+            .filter_map(|status| match status {
+                MiningStatus::Blockchain(height) => u32::try_from(height).ok(),
+                _ => None,
+            })
+            .min()
+    }
+}
+
 /// Full UTXO information
 #[cfg_attr(
     feature = "serde",
@@ -154,3 +170,27 @@ impl From<ListUnspentRes> for Utxo {
         }
     }
 }
+
+/// On-chain usage statistics for a scriptPubkey.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct ScriptStats {
+    /// Number of transactions spending from or paying to the script,
+    /// including mempool transactions
+    pub tx_count: u32,
+    /// Total amount ever received by the script, in satoshis
+    pub received: u64,
+    /// Amount of currently unspent outputs with the script, in satoshis
+    pub unspent: u64,
+}
+
+impl ScriptStats {
+    /// Detects whether the script was ever used in a transaction.
+    #[inline]
+    pub fn is_used(&self) -> bool { self.tx_count > 0 }
+}
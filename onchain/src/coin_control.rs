@@ -0,0 +1,280 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Coin control: user-defined state of wallet UTXOs (frozen coins, coins
+//! reserved by in-flight PSBTs and coin labels), which is consulted by the
+//! coin selection.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bitcoin::{Amount, OutPoint, Txid};
+
+use crate::blockchain::Utxo;
+
+/// Errors of coin control operations and coin selection.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum CoinControlError {
+    /// coin {0} is already reserved by transaction {1}
+    AlreadyReserved(OutPoint, Txid),
+
+    /// insufficient funds: {available} are available for the selection, while
+    /// {required} are required
+    InsufficientFunds {
+        /// Total amount of coins available for the selection
+        available: Amount,
+        /// Target amount of the selection
+        required: Amount,
+    },
+}
+
+/// State of a coin in respect to the coin selection.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+pub enum CoinState {
+    /// Coin may be selected automatically
+    #[display("available")]
+    Available,
+
+    /// Coin is frozen by the user and is never selected automatically
+    #[display("frozen")]
+    Frozen,
+
+    /// Coin is spent by a not yet published transaction with the given id
+    #[display("reserved({0})")]
+    Reserved(Txid),
+}
+
+/// Overrides of the coin control rules applied to a coin selection.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct SelectionOverride {
+    /// Allow selection of frozen coins
+    pub allow_frozen: bool,
+
+    /// Allow selection of coins reserved by other transactions
+    pub allow_reserved: bool,
+}
+
+/// Coin control state of a wallet, persisted together with other wallet data.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[derive(StrictEncode, StrictDecode)]
+pub struct CoinControl {
+    frozen: BTreeSet<OutPoint>,
+    reserved: BTreeMap<OutPoint, Txid>,
+    labels: BTreeMap<OutPoint, String>,
+}
+
+impl CoinControl {
+    /// Constructs empty coin control state.
+    #[inline]
+    pub fn new() -> CoinControl { CoinControl::default() }
+
+    /// Returns state of the coin.
+    pub fn coin_state(&self, outpoint: OutPoint) -> CoinState {
+        if self.frozen.contains(&outpoint) {
+            CoinState::Frozen
+        } else if let Some(txid) = self.reserved.get(&outpoint) {
+            CoinState::Reserved(*txid)
+        } else {
+            CoinState::Available
+        }
+    }
+
+    /// Freezes the coin, excluding it from the automatic coin selection.
+    /// Returns `false` if the coin was already frozen.
+    #[inline]
+    pub fn freeze(&mut self, outpoint: OutPoint) -> bool { self.frozen.insert(outpoint) }
+
+    /// Unfreezes the coin. Returns `false` if the coin was not frozen.
+    #[inline]
+    pub fn unfreeze(&mut self, outpoint: OutPoint) -> bool { self.frozen.remove(&outpoint) }
+
+    /// Iterates over frozen coins.
+    #[inline]
+    pub fn frozen(&self) -> impl Iterator<Item = OutPoint> + '_ { self.frozen.iter().copied() }
+
+    /// Reserves coins spent by the transaction with `txid` (usually the id of
+    /// the unsigned transaction of an in-flight PSBT), such that they are not
+    /// selected by other transactions. Reserving coins already reserved by the
+    /// same transaction is allowed.
+    ///
+    /// # Errors
+    ///
+    /// If some of the coins are reserved by another transaction; in this case
+    /// no coins are reserved.
+    pub fn reserve(
+        &mut self,
+        txid: Txid,
+        outpoints: impl IntoIterator<Item = OutPoint>,
+    ) -> Result<(), CoinControlError> {
+        let outpoints = outpoints.into_iter().collect::<Vec<_>>();
+        for outpoint in &outpoints {
+            match self.reserved.get(outpoint) {
+                Some(other) if *other != txid => {
+                    return Err(CoinControlError::AlreadyReserved(*outpoint, *other))
+                }
+                _ => {}
+            }
+        }
+        self.reserved
+            .extend(outpoints.into_iter().map(|outpoint| (outpoint, txid)));
+        Ok(())
+    }
+
+    /// Releases all coins reserved by the transaction (for instance, once it
+    /// is mined or abandoned), returning the number of released coins.
+    pub fn release(&mut self, txid: Txid) -> usize {
+        let count = self.reserved.len();
+        self.reserved.retain(|_, reserved_by| *reserved_by != txid);
+        count - self.reserved.len()
+    }
+
+    /// Releases a single coin, returning id of the transaction which has
+    /// reserved it.
+    #[inline]
+    pub fn release_coin(&mut self, outpoint: OutPoint) -> Option<Txid> {
+        self.reserved.remove(&outpoint)
+    }
+
+    /// Iterates over reserved coins and ids of the transactions reserving
+    /// them.
+    #[inline]
+    pub fn reserved(&self) -> impl Iterator<Item = (OutPoint, Txid)> + '_ {
+        self.reserved
+            .iter()
+            .map(|(outpoint, txid)| (*outpoint, *txid))
+    }
+
+    /// Assigns label to the coin, returning the previous label, if any.
+    #[inline]
+    pub fn set_label(&mut self, outpoint: OutPoint, label: impl ToString) -> Option<String> {
+        self.labels.insert(outpoint, label.to_string())
+    }
+
+    /// Removes label from the coin, returning it.
+    #[inline]
+    pub fn remove_label(&mut self, outpoint: OutPoint) -> Option<String> {
+        self.labels.remove(&outpoint)
+    }
+
+    /// Returns label of the coin, if any.
+    #[inline]
+    pub fn label(&self, outpoint: OutPoint) -> Option<&str> {
+        self.labels.get(&outpoint).map(String::as_str)
+    }
+
+    /// Detects whether the coin can be selected under the given overrides.
+    pub fn is_selectable(&self, outpoint: OutPoint, overrides: SelectionOverride) -> bool {
+        (overrides.allow_frozen || !self.frozen.contains(&outpoint))
+            && (overrides.allow_reserved || !self.reserved.contains_key(&outpoint))
+    }
+
+    /// Selects coins covering the `target` amount, largest coins first.
+    /// Frozen and reserved coins are skipped unless allowed by `overrides`.
+    ///
+    /// # Errors
+    ///
+    /// If the selectable coins do not cover the target amount.
+    pub fn select_coins<'utxo>(
+        &self,
+        utxos: impl IntoIterator<Item = &'utxo Utxo>,
+        target: Amount,
+        overrides: SelectionOverride,
+    ) -> Result<Vec<Utxo>, CoinControlError> {
+        let mut candidates = utxos
+            .into_iter()
+            .filter(|utxo| self.is_selectable(*utxo.outpoint(), overrides))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            b.amount()
+                .cmp(a.amount())
+                .then_with(|| a.outpoint().cmp(b.outpoint()))
+        });
+
+        let mut selected = vec![];
+        let mut total = Amount::ZERO;
+        for utxo in candidates {
+            if total >= target {
+                break;
+            }
+            total += *utxo.amount();
+            selected.push(utxo.clone());
+        }
+        if total < target {
+            return Err(CoinControlError::InsufficientFunds {
+                available: total,
+                required: target,
+            });
+        }
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    fn utxo(amount: &str, vout: u32) -> Utxo {
+        Utxo::from_str(&format!(
+            "{}@4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:{}",
+            amount, vout
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn selection() {
+        let utxos = [utxo("0.5 BTC", 0), utxo("0.3 BTC", 1), utxo("0.1 BTC", 2)];
+        let mut coin_control = CoinControl::new();
+        let txid = Txid::from_inner([2u8; 32]);
+
+        assert!(coin_control.freeze(*utxos[0].outpoint()));
+        coin_control.reserve(txid, [*utxos[1].outpoint()]).unwrap();
+        assert_eq!(coin_control.coin_state(*utxos[0].outpoint()), CoinState::Frozen);
+        assert_eq!(coin_control.coin_state(*utxos[1].outpoint()), CoinState::Reserved(txid));
+        assert_eq!(
+            coin_control.reserve(Txid::from_inner([1u8; 32]), [*utxos[1].outpoint()]),
+            Err(CoinControlError::AlreadyReserved(*utxos[1].outpoint(), txid))
+        );
+
+        let target = Amount::from_btc(0.2).unwrap();
+        assert_eq!(
+            coin_control.select_coins(&utxos, target, default!()),
+            Err(CoinControlError::InsufficientFunds {
+                available: Amount::from_btc(0.1).unwrap(),
+                required: target,
+            })
+        );
+        let overrides = SelectionOverride {
+            allow_reserved: true,
+            ..default!()
+        };
+        assert_eq!(coin_control.select_coins(&utxos, target, overrides), Ok(vec![
+            utxos[1].clone()
+        ]));
+
+        assert_eq!(coin_control.release(txid), 1);
+        coin_control.set_label(*utxos[0].outpoint(), "cold storage");
+        assert_eq!(coin_control.label(*utxos[0].outpoint()), Some("cold storage"));
+        assert_eq!(
+            coin_control.select_coins(&utxos, Amount::from_btc(0.35).unwrap(), default!()),
+            Ok(vec![utxos[1].clone(), utxos[2].clone()])
+        );
+    }
+}
@@ -27,10 +27,17 @@ extern crate serde_crate as serde;
 extern crate miniscript_crate as miniscript;
 
 pub mod blockchain;
+mod coin_control;
 mod network;
 mod resolvers;
 
-pub use network::PublicNetwork;
+pub use coin_control::{CoinControl, CoinControlError, CoinState, SelectionOverride};
+pub use network::{Chain, NetworkParseError, PublicNetwork};
 #[cfg(feature = "miniscript_descriptors")]
 pub use resolvers::ResolveDescriptor;
-pub use resolvers::{ResolveTx, ResolveTxFee, ResolveUtxo, TxResolverError, UtxoResolverError};
+#[cfg(feature = "async")]
+pub use resolvers::{ResolveScriptStatsAsync, ResolveTxAsync, ResolveTxFeeAsync, ResolveUtxoAsync};
+pub use resolvers::{
+    ResolveMempool, ResolveScriptStats, ResolveTx, ResolveTxFee, ResolveUtxo, TxResolverError,
+    UtxoResolverError,
+};
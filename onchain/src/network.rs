@@ -9,8 +9,23 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use bitcoin::Network;
+use std::str::FromStr;
+
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{Network, Script};
 use bitcoin_hd::standards::DerivationBlockchain;
+use slip132::ChainParams;
+
+/// Errors parsing bitcoin network name
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum NetworkParseError {
+    /// unknown bitcoin network `{0}`
+    UnknownNetwork(String),
+
+    /// custom signet challenge must be a hex-encoded script, not `{0}`
+    InvalidSignetChallenge(String),
+}
 
 /// Public variants of bitcoin networks
 #[derive(
@@ -35,6 +50,24 @@ pub enum PublicNetwork {
     /// Bitcoin signet
     #[display("signet")]
     Signet,
+
+    /// Bitcoin testnet4 (BIP-94)
+    #[display("testnet4")]
+    Testnet4,
+}
+
+impl FromStr for PublicNetwork {
+    type Err = NetworkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "mainnet" | "bitcoin" => PublicNetwork::Mainnet,
+            "testnet" | "testnet3" => PublicNetwork::Testnet,
+            "testnet4" => PublicNetwork::Testnet4,
+            "signet" => PublicNetwork::Signet,
+            _ => return Err(NetworkParseError::UnknownNetwork(s.to_owned())),
+        })
+    }
 }
 
 impl From<PublicNetwork> for Network {
@@ -45,7 +78,9 @@ impl From<&PublicNetwork> for Network {
     fn from(network: &PublicNetwork) -> Self {
         match network {
             PublicNetwork::Mainnet => Network::Bitcoin,
-            PublicNetwork::Testnet => Network::Testnet,
+            // Testnet4 shares address prefixes and SLIP-132 versions with
+            // testnet3
+            PublicNetwork::Testnet | PublicNetwork::Testnet4 => Network::Testnet,
             PublicNetwork::Signet => Network::Signet,
         }
     }
@@ -71,8 +106,9 @@ impl From<&PublicNetwork> for DerivationBlockchain {
     fn from(network: &PublicNetwork) -> Self {
         match network {
             PublicNetwork::Mainnet => DerivationBlockchain::Bitcoin,
-            PublicNetwork::Testnet => DerivationBlockchain::Testnet,
-            PublicNetwork::Signet => DerivationBlockchain::Testnet,
+            PublicNetwork::Testnet | PublicNetwork::Testnet4 | PublicNetwork::Signet => {
+                DerivationBlockchain::Testnet
+            }
         }
     }
 }
@@ -80,7 +116,7 @@ impl From<&PublicNetwork> for DerivationBlockchain {
 impl PublicNetwork {
     /// Detects if the public network is belongs to a testnet
     pub fn is_testnet(self) -> bool {
-        matches!(self, PublicNetwork::Testnet | PublicNetwork::Signet)
+        matches!(self, PublicNetwork::Testnet | PublicNetwork::Testnet4 | PublicNetwork::Signet)
     }
 
     /// Returns default electrum server port for the network
@@ -89,6 +125,167 @@ impl PublicNetwork {
             PublicNetwork::Mainnet => 50001,
             PublicNetwork::Testnet => 60001,
             PublicNetwork::Signet => 60601,
+            PublicNetwork::Testnet4 => 40001,
+        }
+    }
+}
+
+/// Any bitcoin network, including networks without public infrastructure:
+/// custom signets and regtest.
+///
+/// Networks from testnet family share address prefixes and extended key
+/// versions, so keys and descriptors can't tell them apart; the network has
+/// to be provided by the user.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display, From)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Chain {
+    /// Public bitcoin network
+    #[display(inner)]
+    #[from]
+    Public(PublicNetwork),
+
+    /// Signet with a custom block challenge script
+    #[display("signet:{0:x}")]
+    CustomSignet(Script),
+
+    /// Local regression testing network
+    #[display("regtest")]
+    Regtest,
+}
+
+impl Default for Chain {
+    #[inline]
+    fn default() -> Self { Chain::Public(PublicNetwork::default()) }
+}
+
+impl FromStr for Chain {
+    type Err = NetworkParseError;
+
+    /// Parses network name. Custom signets are specified as `signet:` prefix
+    /// followed by the hex-encoded challenge script.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(challenge) = s.strip_prefix("signet:") {
+            return Vec::<u8>::from_hex(challenge)
+                .map(|data| Chain::CustomSignet(Script::from(data)))
+                .map_err(|_| NetworkParseError::InvalidSignetChallenge(challenge.to_owned()));
+        }
+        if s.eq_ignore_ascii_case("regtest") {
+            return Ok(Chain::Regtest);
+        }
+        PublicNetwork::from_str(s).map(Chain::Public)
+    }
+}
+
+impl From<Network> for Chain {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => Chain::Public(PublicNetwork::Mainnet),
+            Network::Testnet => Chain::Public(PublicNetwork::Testnet),
+            Network::Signet => Chain::Public(PublicNetwork::Signet),
+            Network::Regtest => Chain::Regtest,
+        }
+    }
+}
+
+impl From<&Chain> for Network {
+    fn from(chain: &Chain) -> Self {
+        match chain {
+            Chain::Public(network) => network.into(),
+            Chain::CustomSignet(_) => Network::Signet,
+            Chain::Regtest => Network::Regtest,
+        }
+    }
+}
+
+impl From<&Chain> for DerivationBlockchain {
+    fn from(chain: &Chain) -> Self {
+        match chain {
+            Chain::Public(network) => network.into(),
+            Chain::CustomSignet(_) | Chain::Regtest => DerivationBlockchain::Testnet,
+        }
+    }
+}
+
+impl Chain {
+    /// Returns [`bitcoin::Network`] defining address prefixes and extended key
+    /// versions used by the chain.
+    #[inline]
+    pub fn bitcoin_network(&self) -> Network { Network::from(self) }
+
+    /// Detects if the network belongs to testnet family, i.e. is not the
+    /// bitcoin mainnet.
+    #[inline]
+    pub fn is_testnet(&self) -> bool { *self != Chain::Public(PublicNetwork::Mainnet) }
+
+    /// Returns challenge script for custom signets.
+    pub fn signet_challenge(&self) -> Option<&Script> {
+        match self {
+            Chain::CustomSignet(challenge) => Some(challenge),
+            _ => None,
+        }
+    }
+
+    /// Returns default electrum server port for the network, if known.
+    pub fn electrum_port(&self) -> Option<u16> {
+        match self {
+            Chain::Public(network) => Some(network.electrum_port()),
+            Chain::CustomSignet(_) | Chain::Regtest => None,
         }
     }
+
+    /// Returns chain parameters (address prefixes, extended key versions and
+    /// coin type) used by the network.
+    pub fn params(&self) -> ChainParams {
+        let mut params = match self {
+            Chain::Public(PublicNetwork::Mainnet) => ChainParams::bitcoin(),
+            Chain::Public(PublicNetwork::Testnet) => ChainParams::testnet(),
+            Chain::Public(PublicNetwork::Testnet4) => ChainParams::testnet4(),
+            Chain::Public(PublicNetwork::Signet) | Chain::CustomSignet(_) => ChainParams::signet(),
+            Chain::Regtest => ChainParams::regtest(),
+        };
+        params.name = self.to_string();
+        params
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_from_str() {
+        assert_eq!(Chain::from_str("bitcoin"), Ok(Chain::Public(PublicNetwork::Mainnet)));
+        assert_eq!(Chain::from_str("testnet4"), Ok(Chain::Public(PublicNetwork::Testnet4)));
+        assert_eq!(Chain::from_str("regtest"), Ok(Chain::Regtest));
+        let custom = Chain::from_str("signet:5121").unwrap();
+        assert_eq!(custom.signet_challenge(), Some(&Script::from(vec![0x51, 0x21])));
+        assert_eq!(custom.to_string(), "signet:5121");
+        assert_eq!(custom.bitcoin_network(), Network::Signet);
+        assert_eq!(Chain::from_str(&custom.to_string()), Ok(custom));
+        assert_eq!(
+            Chain::from_str("testnet5"),
+            Err(NetworkParseError::UnknownNetwork(s!("testnet5")))
+        );
+        assert_eq!(
+            Chain::Public(PublicNetwork::Testnet4).bitcoin_network(),
+            Network::Testnet
+        );
+    }
+
+    #[test]
+    fn chain_params() {
+        let custom = Chain::from_str("signet:5121").unwrap();
+        let params = custom.params();
+        assert_eq!(params.name, "signet:5121");
+        assert_eq!(params.network, Network::Signet);
+        assert_eq!(params.bech32_hrp, "tb");
+        assert_eq!(Chain::Regtest.params().bech32_hrp, "bcrt");
+        assert_eq!(Chain::Public(PublicNetwork::Mainnet).params().coin_type, 0);
+        assert_eq!(Chain::Public(PublicNetwork::Testnet4).params().coin_type, 1);
+    }
 }
@@ -0,0 +1,70 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Async versions of the resolver traits, for chain backends accessed over
+//! network from async runtimes.
+
+use std::collections::{BTreeMap, HashSet};
+
+use async_trait::async_trait;
+use bitcoin::{Script, Transaction, Txid};
+
+use super::{TxResolverError, UtxoResolverError};
+use crate::blockchain::{ScriptStats, Utxo};
+
+/// Async transaction resolver; see [`super::ResolveTx`].
+#[async_trait]
+pub trait ResolveTxAsync {
+    /// Tries to find a transaction by transaction id ([`Txid`])
+    async fn resolve_tx_async(&self, txid: Txid) -> Result<Transaction, TxResolverError>;
+}
+
+/// Async UTXO resolver; see [`super::ResolveUtxo`].
+#[async_trait]
+pub trait ResolveUtxoAsync {
+    /// Finds UTXO set for the provided address lists
+    async fn resolve_utxo_async(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<HashSet<Utxo>>, UtxoResolverError>;
+}
+
+/// Async resolver of the on-chain usage statistics for scripts; see
+/// [`super::ResolveScriptStats`].
+#[async_trait]
+pub trait ResolveScriptStatsAsync {
+    /// Finds usage statistics for each of the provided scripts, returned in
+    /// the same order.
+    async fn resolve_script_stats_async(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<ScriptStats>, UtxoResolverError>;
+}
+
+/// Async transaction fee resolver; see [`super::ResolveTxFee`].
+#[async_trait]
+pub trait ResolveTxFeeAsync {
+    /// Tries to find a transaction and comput its fee by transaction id
+    /// ([`Txid`])
+    async fn resolve_tx_fee_async(
+        &self,
+        txid: Txid,
+    ) -> Result<Option<(Transaction, u64)>, TxResolverError>;
+}
+
+#[async_trait]
+impl ResolveTxAsync for BTreeMap<Txid, Transaction> {
+    async fn resolve_tx_async(&self, txid: Txid) -> Result<Transaction, TxResolverError> {
+        self.get(&txid)
+            .cloned()
+            .ok_or_else(|| TxResolverError::with(txid))
+    }
+}
@@ -9,13 +9,15 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use bitcoin::{Script, Transaction, Txid};
 use electrum_client::{Client, ElectrumApi};
 
-use super::{ResolveTx, ResolveTxFee, ResolveUtxo, TxResolverError, UtxoResolverError};
-use crate::blockchain::Utxo;
+use super::{
+    ResolveScriptStats, ResolveTx, ResolveTxFee, ResolveUtxo, TxResolverError, UtxoResolverError,
+};
+use crate::blockchain::{ScriptStats, Utxo};
 
 impl ResolveTx for Client {
     fn resolve_tx(&self, txid: Txid) -> Result<Transaction, TxResolverError> {
@@ -63,4 +65,65 @@ impl ResolveUtxo for Client {
             .map(|res| res.into_iter().map(Utxo::from).collect())
             .collect())
     }
+
+    fn resolve_utxo_since<'script>(
+        &self,
+        scripts: impl IntoIterator<Item = &'script Script> + Clone,
+        birthday: u32,
+    ) -> Result<Vec<HashSet<Utxo>>, UtxoResolverError> {
+        // Electrum reports mempool outputs with zero height
+        Ok(self
+            .batch_script_list_unspent(scripts)?
+            .into_iter()
+            .map(|res| {
+                res.into_iter()
+                    .filter(|utxo| utxo.height == 0 || utxo.height >= birthday as usize)
+                    .map(Utxo::from)
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+impl ResolveScriptStats for Client {
+    fn resolve_script_stats<'script>(
+        &self,
+        scripts: impl IntoIterator<Item = &'script Script> + Clone,
+    ) -> Result<Vec<ScriptStats>, UtxoResolverError> {
+        let histories = self.batch_script_get_history(scripts.clone())?;
+        let balances = self.batch_script_get_balance(scripts.clone())?;
+
+        let txids = histories
+            .iter()
+            .flatten()
+            .map(|entry| entry.tx_hash)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let txes = txids
+            .iter()
+            .copied()
+            .zip(self.batch_transaction_get(&txids)?)
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(scripts
+            .into_iter()
+            .zip(histories)
+            .zip(balances)
+            .map(|((script, history), balance)| {
+                let received = history
+                    .iter()
+                    .filter_map(|entry| txes.get(&entry.tx_hash))
+                    .flat_map(|tx| &tx.output)
+                    .filter(|txout| &txout.script_pubkey == script)
+                    .map(|txout| txout.value)
+                    .sum();
+                ScriptStats {
+                    tx_count: history.len() as u32,
+                    received,
+                    unspent: (balance.confirmed as i64 + balance.unconfirmed).max(0) as u64,
+                }
+            })
+            .collect())
+    }
 }
@@ -12,15 +12,21 @@
 //! Resolvers are traits allow accessing or computing information from a
 //! bitcoin transaction graph (from blockchain, state channel, index, PSBT etc).
 
+#[cfg(feature = "async")]
+mod asynchronous;
 #[cfg(feature = "electrum")]
 mod electrum;
 
 use std::collections::{BTreeMap, HashSet};
 
-use bitcoin::{Script, Transaction, Txid};
+use bitcoin::{OutPoint, Script, Transaction, Txid};
 use bitcoin_hd::DeriveError;
 
-use crate::blockchain::Utxo;
+#[cfg(feature = "async")]
+pub use self::asynchronous::{
+    ResolveScriptStatsAsync, ResolveTxAsync, ResolveTxFeeAsync, ResolveUtxoAsync,
+};
+use crate::blockchain::{ScriptStats, Utxo};
 
 #[derive(Debug, Display, Error)]
 #[display(doc_comments)]
@@ -71,6 +77,31 @@ pub trait ResolveUtxo {
         &self,
         scripts: impl IntoIterator<Item = &'script Script> + Clone,
     ) -> Result<Vec<HashSet<Utxo>>, UtxoResolverError>;
+
+    /// Finds UTXO set for the provided address lists of a wallet created at
+    /// `birthday` block height, skipping blocks before the birthday. Outputs
+    /// mined before the birthday are not reported by the backends supporting
+    /// the cutoff (like the Electrum resolver), so the birthday must not
+    /// follow the first wallet transaction. The default implementation
+    /// ignores the birthday and resolves all outputs.
+    fn resolve_utxo_since<'script>(
+        &self,
+        scripts: impl IntoIterator<Item = &'script Script> + Clone,
+        birthday: u32,
+    ) -> Result<Vec<HashSet<Utxo>>, UtxoResolverError> {
+        let _ = birthday;
+        self.resolve_utxo(scripts)
+    }
+}
+
+/// Resolver of the on-chain usage statistics for scripts
+pub trait ResolveScriptStats {
+    /// Finds usage statistics for each of the provided scripts, returned in
+    /// the same order.
+    fn resolve_script_stats<'script>(
+        &self,
+        scripts: impl IntoIterator<Item = &'script Script> + Clone,
+    ) -> Result<Vec<ScriptStats>, UtxoResolverError>;
 }
 
 #[cfg(feature = "miniscript_descriptors")]
@@ -98,46 +129,79 @@ mod _miniscript_descriptors {
             from_index: UnhardenedIndex,
             count: u32,
         ) -> Result<BTreeMap<UnhardenedIndex, (Script, HashSet<Utxo>)>, UtxoResolverError> {
-            let terminal_derivation = terminal_derivation.as_ref();
-            let mut derivation =
-                Vec::<UnhardenedIndex>::with_capacity(terminal_derivation.len() + 1);
-            derivation.extend(terminal_derivation);
-            derivation.push(UnhardenedIndex::zero());
-            let derivation = Rc::new(RefCell::new(derivation));
-
-            let indexes = (0..count)
-                .map(|offset| {
-                    from_index.checked_add(offset).ok_or_else(|| {
-                        UtxoResolverError::IndexOutOfRange(
-                            from_index.first_index() as usize + offset as usize,
-                        )
-                    })
-                })
-                .collect::<Result<Vec<_>, UtxoResolverError>>()?;
-
-            let scripts = indexes
-                .into_iter()
-                .map(|index| {
-                    if let Some(i) = derivation.borrow_mut().last_mut() {
-                        *i = index
-                    }
-                    Ok((
-                        index,
-                        descriptor.script_pubkey_pretr(secp, &*derivation.borrow())?,
-                    ))
-                })
-                .collect::<Result<BTreeMap<_, _>, DeriveError>>()?;
-
-            Ok(self
-                .resolve_utxo(scripts.values())?
-                .into_iter()
-                .zip(scripts.keys())
-                .zip(scripts.values())
-                .map(|((utxo_set, index), script)| (*index, (script.clone(), utxo_set)))
-                .collect())
+            let scripts =
+                descriptor_scripts(secp, descriptor, terminal_derivation, from_index, count)?;
+            let utxo_sets = self.resolve_utxo(scripts.values())?;
+            Ok(zip_utxo_sets(scripts, utxo_sets))
+        }
+
+        /// Finds UTXO set for the addresses derivable from the given descriptor
+        /// of a wallet created at `birthday` block height (see
+        /// [`ResolveUtxo::resolve_utxo_since`]).
+        fn resolve_descriptor_utxo_since<C: Verification>(
+            &self,
+            secp: &Secp256k1<C>,
+            descriptor: &miniscript::Descriptor<DerivationAccount>,
+            terminal_derivation: impl AsRef<[UnhardenedIndex]>,
+            from_index: UnhardenedIndex,
+            count: u32,
+            birthday: u32,
+        ) -> Result<BTreeMap<UnhardenedIndex, (Script, HashSet<Utxo>)>, UtxoResolverError> {
+            let scripts =
+                descriptor_scripts(secp, descriptor, terminal_derivation, from_index, count)?;
+            let utxo_sets = self.resolve_utxo_since(scripts.values(), birthday)?;
+            Ok(zip_utxo_sets(scripts, utxo_sets))
         }
     }
 
+    fn descriptor_scripts<C: Verification>(
+        secp: &Secp256k1<C>,
+        descriptor: &miniscript::Descriptor<DerivationAccount>,
+        terminal_derivation: impl AsRef<[UnhardenedIndex]>,
+        from_index: UnhardenedIndex,
+        count: u32,
+    ) -> Result<BTreeMap<UnhardenedIndex, Script>, UtxoResolverError> {
+        let terminal_derivation = terminal_derivation.as_ref();
+        let mut derivation = Vec::<UnhardenedIndex>::with_capacity(terminal_derivation.len() + 1);
+        derivation.extend(terminal_derivation);
+        derivation.push(UnhardenedIndex::zero());
+        let derivation = Rc::new(RefCell::new(derivation));
+
+        let indexes = (0..count)
+            .map(|offset| {
+                from_index.checked_add(offset).ok_or_else(|| {
+                    UtxoResolverError::IndexOutOfRange(
+                        from_index.first_index() as usize + offset as usize,
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, UtxoResolverError>>()?;
+
+        Ok(indexes
+            .into_iter()
+            .map(|index| {
+                if let Some(i) = derivation.borrow_mut().last_mut() {
+                    *i = index
+                }
+                Ok((
+                    index,
+                    descriptor.script_pubkey_pretr(secp, &*derivation.borrow())?,
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>, DeriveError>>()?)
+    }
+
+    fn zip_utxo_sets(
+        scripts: BTreeMap<UnhardenedIndex, Script>,
+        utxo_sets: Vec<HashSet<Utxo>>,
+    ) -> BTreeMap<UnhardenedIndex, (Script, HashSet<Utxo>)> {
+        scripts
+            .into_iter()
+            .zip(utxo_sets)
+            .map(|((index, script), utxo_set)| (index, (script, utxo_set)))
+            .collect()
+    }
+
     impl<T> ResolveDescriptor for T where T: ResolveUtxo {}
 }
 #[cfg(feature = "miniscript_descriptors")]
@@ -157,3 +221,31 @@ pub trait ResolveTxFee {
     /// ([`Txid`])
     fn resolve_tx_fee(&self, txid: Txid) -> Result<Option<(Transaction, u64)>, TxResolverError>;
 }
+
+/// Resolver of unconfirmed transactions from a mempool
+pub trait ResolveMempool {
+    /// Tries to find an unconfirmed transaction spending the given output,
+    /// returning it together with its fee. Returns `Ok(None)` if the output
+    /// is not spent by any of the mempool transactions.
+    fn resolve_mempool_spender(
+        &self,
+        outpoint: OutPoint,
+    ) -> Result<Option<(Transaction, u64)>, TxResolverError>;
+}
+
+/// Mempool snapshot: unconfirmed transactions with their fees
+impl ResolveMempool for BTreeMap<Txid, (Transaction, u64)> {
+    fn resolve_mempool_spender(
+        &self,
+        outpoint: OutPoint,
+    ) -> Result<Option<(Transaction, u64)>, TxResolverError> {
+        Ok(self
+            .values()
+            .find(|(tx, _)| {
+                tx.input
+                    .iter()
+                    .any(|txin| txin.previous_output == outpoint)
+            })
+            .cloned())
+    }
+}
@@ -0,0 +1,64 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+use bitcoin::hashes::Hash;
+use bitcoin::{OutPoint, Script, TxIn, TxOut, Txid};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use psbt::lex_order::LexOrder;
+use psbt::{Input, Output, Psbt};
+
+/// Constructs PSBT with the given number of inputs and outputs in
+/// pseudo-random order, with non-empty maps to account for the data moved
+/// during sorting.
+fn psbt(count: u32) -> Psbt {
+    let mut psbt = Psbt::default();
+    for no in 0..count {
+        let txid = Txid::hash(&no.to_le_bytes());
+        let txin = TxIn {
+            previous_output: OutPoint::new(txid, no % 4),
+            ..TxIn::default()
+        };
+        let mut input = Input::new(no as usize, txin).expect("unsigned input");
+        input.unknown.insert(
+            psbt::raw::Key {
+                type_value: 0xFC,
+                key: txid[..].to_vec(),
+            },
+            vec![0u8; 256],
+        );
+        psbt.inputs.push(input);
+
+        let amount = u64::from_le_bytes(txid[..8].try_into().expect("fixed size")) % 100_000;
+        psbt.outputs.push(Output::new(no as usize, TxOut {
+            value: amount,
+            script_pubkey: Script::new_op_return(&txid[..]),
+        }));
+    }
+    psbt
+}
+
+fn lex_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lex_order");
+    for count in [100u32, 1_000, 10_000] {
+        let psbt = psbt(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &psbt, |b, psbt| {
+            b.iter_batched(
+                || psbt.clone(),
+                |psbt| psbt.lex_ordered(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lex_order);
+criterion_main!(benches);
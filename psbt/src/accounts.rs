@@ -0,0 +1,133 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Matching PSBT inputs and outputs against accounts from
+//! [`AccountRegistry`].
+
+use std::collections::BTreeMap;
+
+use bitcoin::secp256k1::{PublicKey, XOnlyPublicKey, SECP256K1};
+use bitcoin::util::bip32::KeySource;
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin_hd::account::DerivePublicKey;
+use bitcoin_hd::UnhardenedIndex;
+use descriptors::{AccountRegistry, RegisteredAccount};
+
+use crate::{Input, Output, Psbt};
+
+impl Input {
+    /// Finds registered account owning the input key, returning it together
+    /// with the derivation pattern of the key. Key origin information is
+    /// verified by deriving the key from the account.
+    #[inline]
+    pub fn registered_account<'registry>(
+        &self,
+        registry: &'registry AccountRegistry,
+    ) -> Option<(&'registry RegisteredAccount, Vec<UnhardenedIndex>)> {
+        match_account(registry, &self.bip32_derivation, &self.tap_key_origins)
+    }
+}
+
+impl Output {
+    /// Finds registered account owning the output key (for instance change
+    /// output), returning it together with the derivation pattern of the key.
+    /// Key origin information is verified by deriving the key from the
+    /// account.
+    #[inline]
+    pub fn registered_account<'registry>(
+        &self,
+        registry: &'registry AccountRegistry,
+    ) -> Option<(&'registry RegisteredAccount, Vec<UnhardenedIndex>)> {
+        match_account(registry, &self.bip32_derivation, &self.tap_key_origins)
+    }
+}
+
+impl Psbt {
+    /// Lists names of the registered accounts owning PSBT inputs, in the
+    /// order of the inputs.
+    pub fn input_accounts<'registry>(
+        &self,
+        registry: &'registry AccountRegistry,
+    ) -> Vec<Option<&'registry str>> {
+        self.inputs
+            .iter()
+            .map(|input| {
+                input
+                    .registered_account(registry)
+                    .map(|(account, _)| account.name.as_str())
+            })
+            .collect()
+    }
+}
+
+fn match_account<'registry>(
+    registry: &'registry AccountRegistry,
+    bip32_derivation: &BTreeMap<PublicKey, KeySource>,
+    tap_key_origins: &BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)>,
+) -> Option<(&'registry RegisteredAccount, Vec<UnhardenedIndex>)> {
+    let ecdsa = bip32_derivation
+        .iter()
+        .map(|(pubkey, key_source)| (pubkey.x_only_public_key().0, Some(*pubkey), key_source));
+    let bip340 = tap_key_origins
+        .iter()
+        .map(|(pubkey, (_, key_source))| (*pubkey, None, key_source));
+    ecdsa.chain(bip340).find_map(|(xonly, pubkey, key_source)| {
+        let (account, key, pattern) = registry.by_origin(key_source)?;
+        let derived = key.derive_public_key(SECP256K1, &pattern).ok()?;
+        let valid = match pubkey {
+            Some(pubkey) => derived == pubkey,
+            None => derived.x_only_public_key().0 == xonly,
+        };
+        valid.then_some((account, pattern))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin_hd::DerivationAccount;
+    use miniscript::Descriptor;
+
+    use super::*;
+
+    #[test]
+    fn input_accounts() {
+        let key = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let mut registry = AccountRegistry::new();
+        registry.register(RegisteredAccount::with(
+            "savings",
+            Descriptor::new_wpkh(key.clone()).unwrap(),
+        ));
+
+        let pattern = [UnhardenedIndex::from(1u8), UnhardenedIndex::from(3u8)];
+        let (pubkey, key_source) = key.bip32_derivation(SECP256K1, pattern).unwrap();
+        let own = Input {
+            bip32_derivation: bmap! { pubkey => key_source.clone() },
+            ..default!()
+        };
+        let (other, _) = key.bip32_derivation(SECP256K1, [0u8, 0u8]).unwrap();
+        let forged = Input {
+            bip32_derivation: bmap! { other => key_source },
+            ..default!()
+        };
+        assert_eq!(own.registered_account(&registry).unwrap().1, pattern.to_vec());
+
+        let psbt = Psbt {
+            inputs: vec![own, forged, Input::default()],
+            ..default!()
+        };
+        assert_eq!(psbt.input_accounts(&registry), vec![Some("savings"), None, None]);
+    }
+}
@@ -0,0 +1,149 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Processing proprietary PSBT keys carrying taproot annex (BIP-341) for the
+//! inputs, which is committed to by the signatures and appended to the input
+//! witness during finalization.
+
+use bitcoin::util::sighash::Annex;
+
+use crate::raw::ProprietaryKey;
+use crate::{Input, Psbt};
+
+pub const PSBT_ANNEX_PREFIX: &[u8] = b"ANNEX";
+pub const PSBT_IN_TAP_ANNEX: u8 = 0;
+
+/// First byte of a taproot annex, as defined by BIP-341.
+pub const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+
+/// Taproot annex must start with `0x50` byte.
+#[derive(
+    Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error
+)]
+#[display(doc_comments)]
+pub struct AnnexPrefixError;
+
+fn annex_key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_ANNEX_PREFIX.to_vec(),
+        subtype: PSBT_IN_TAP_ANNEX,
+        key: vec![],
+    }
+}
+
+impl Input {
+    /// Attaches taproot annex to the input. The annex is committed to by all
+    /// signatures created afterwards and is appended to the input witness
+    /// during finalization.
+    ///
+    /// # Errors
+    ///
+    /// If the annex does not start with `0x50` byte.
+    pub fn set_tap_annex(&mut self, annex: Vec<u8>) -> Result<(), AnnexPrefixError> {
+        if annex.first() != Some(&TAPROOT_ANNEX_PREFIX) {
+            return Err(AnnexPrefixError);
+        }
+        self.proprietary.insert(annex_key(), annex);
+        Ok(())
+    }
+
+    /// Removes taproot annex from the input, returning its data, if any.
+    pub fn remove_tap_annex(&mut self) -> Option<Vec<u8>> { self.proprietary.remove(&annex_key()) }
+
+    /// Returns taproot annex attached to the input, if any. Proprietary key
+    /// values not starting with `0x50` byte are ignored.
+    pub fn tap_annex(&self) -> Option<Annex> {
+        self.proprietary
+            .get(&annex_key())
+            .and_then(|data| Annex::new(data).ok())
+    }
+
+    /// Appends taproot annex to the final witness of the input, unless the
+    /// witness already ends with an annex or the input does not spend a
+    /// taproot output. Returns whether the witness was modified.
+    pub fn finalize_tap_annex(&mut self) -> bool {
+        let is_taproot = self.tap_internal_key.is_some()
+            || self
+                .witness_utxo
+                .as_ref()
+                .map(|txout| txout.script_pubkey.is_v1_p2tr())
+                .unwrap_or_default();
+        if !is_taproot {
+            return false;
+        }
+        let annex = match self.tap_annex() {
+            Some(annex) => annex.as_bytes().to_vec(),
+            None => return false,
+        };
+        let witness = match self.final_script_witness {
+            Some(ref mut witness) if !witness.is_empty() => witness,
+            _ => return false,
+        };
+        // BIP-341: with at least two witness elements, the last one starting
+        // with 0x50 is the annex
+        if witness.len() >= 2
+            && witness.last().and_then(|last| last.first()) == Some(&TAPROOT_ANNEX_PREFIX)
+        {
+            return false;
+        }
+        witness.push(annex);
+        true
+    }
+}
+
+impl Psbt {
+    /// Appends taproot annexes to the final witnesses of all finalized inputs
+    /// (see [`Input::finalize_tap_annex`]), returning the number of modified
+    /// inputs.
+    pub fn finalize_tap_annexes(&mut self) -> usize {
+        self.inputs
+            .iter_mut()
+            .map(Input::finalize_tap_annex)
+            .filter(|modified| *modified)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Witness, XOnlyPublicKey};
+
+    use super::*;
+
+    #[test]
+    fn annex_finalization() {
+        let mut input = Input::default();
+        assert_eq!(input.set_tap_annex(vec![0x51, 1]), Err(AnnexPrefixError));
+        assert!(input.tap_annex().is_none());
+        input.set_tap_annex(vec![0x50, 1, 2]).unwrap();
+        assert_eq!(input.tap_annex().unwrap().as_bytes(), &[0x50, 1, 2]);
+
+        input.final_script_witness = Some(Witness::from_vec(vec![vec![0u8; 64]]));
+        assert!(!input.finalize_tap_annex());
+        input.tap_internal_key = Some(
+            XOnlyPublicKey::from_str(
+                "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115",
+            )
+            .unwrap(),
+        );
+        assert!(input.finalize_tap_annex());
+        assert!(!input.finalize_tap_annex());
+        assert_eq!(input.final_script_witness.as_ref().unwrap().to_vec(), vec![
+            vec![0u8; 64],
+            vec![0x50, 1, 2]
+        ]);
+
+        assert_eq!(input.remove_tap_annex(), Some(vec![0x50, 1, 2]));
+        assert!(input.tap_annex().is_none());
+    }
+}
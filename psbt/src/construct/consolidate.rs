@@ -0,0 +1,325 @@
+// Descriptor wallet library extending bitcoin & miniscript functionality
+// by LNP/BP Association (https://lnp-bp.org)
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! UTXO consolidation planning: merging small wallet coins into a single
+//! change output while fees are low, so that they are cheaper to spend once
+//! fees go up.
+
+use std::collections::BTreeSet;
+
+use bitcoin::secp256k1::SECP256K1;
+use bitcoin::XOnlyPublicKey;
+use bitcoin_hd::{DerivationAccount, UnhardenedIndex};
+use bitcoin_onchain::ResolveTx;
+use bitcoin_scripts::PubkeyScript;
+use descriptors::derive::DeriveDescriptor;
+use descriptors::InputDescriptor;
+use miniscript::Descriptor;
+
+use super::Error;
+use crate::{MaxFeePolicy, Psbt};
+
+/// Weight of the transaction fields not depending on the number of inputs
+/// and outputs: version, lock time, single-byte input and output counts and
+/// segwit marker & flag.
+const TX_OVERHEAD_WEIGHT: u64 = 4 * (4 + 4 + 1 + 1) + 2;
+/// Weight of the non-witness input fields: previous outpoint and sequence
+/// number.
+const TXIN_BASE_WEIGHT: u64 = 4 * (32 + 4 + 4);
+/// Weight of the output amount and script length prefix.
+const TXOUT_BASE_WEIGHT: u64 = 4 * (8 + 1);
+
+/// Fee rate environment in which the consolidation is planned, in satoshis
+/// per virtual byte.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct FeeEnvironment {
+    /// Fee rate paid by the consolidation transaction
+    pub current_rate: f64,
+
+    /// Fee rate expected at the time the wallet coins will be spent
+    pub future_rate: f64,
+}
+
+/// Consolidation transaction proposed by the [`ConsolidationPlanner`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ConsolidationPlan {
+    /// Coins merged by the transaction
+    pub inputs: Vec<InputDescriptor>,
+
+    /// Total amount of the merged coins, in satoshis
+    pub amount: u64,
+
+    /// Estimated virtual size of the consolidation transaction
+    pub vsize: u64,
+
+    /// Fee paid by the consolidation transaction, in satoshis
+    pub fee: u64,
+
+    /// Expected fee savings at the future fee rate, in satoshis, already
+    /// accounting for the fee of the consolidation transaction
+    pub future_savings: u64,
+
+    /// Number of distinct wallet addresses which become publicly linked by
+    /// the transaction
+    pub linked_addresses: usize,
+
+    /// Number of distinct transactions which created the merged coins
+    pub linked_transactions: usize,
+}
+
+impl ConsolidationPlan {
+    /// Privacy cost of the consolidation: number of previously unrelated
+    /// wallet addresses linked to the rest of the merged coins.
+    #[inline]
+    pub fn privacy_cost(&self) -> usize { self.linked_addresses.saturating_sub(1) }
+
+    /// Amount of the consolidated output, in satoshis.
+    #[inline]
+    pub fn change_amount(&self) -> u64 { self.amount - self.fee }
+
+    /// Constructs PSBT spending all planned coins to a single change output
+    /// with the given index (see [`Psbt::construct_with_policy`]).
+    pub fn to_psbt(
+        &self,
+        descriptor: &Descriptor<DerivationAccount>,
+        change_index: impl Into<UnhardenedIndex>,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, Error> {
+        Psbt::construct_with_policy(
+            descriptor,
+            &self.inputs,
+            &Vec::<(PubkeyScript, u64)>::new(),
+            change_index,
+            self.fee,
+            fee_policy,
+            tx_resolver,
+        )
+    }
+}
+
+/// Planner proposing consolidation transactions for the wallet coins
+/// controlled by a single descriptor.
+///
+/// Coins are merged smallest-first. Consolidation is proposed only when the
+/// future fee rate exceeds the current one and spending the merged coins
+/// later would cost more than the consolidation itself.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConsolidationPlanner {
+    /// Fee rate environment
+    pub fee_rates: FeeEnvironment,
+
+    /// Maximum number of inputs in a single consolidation transaction
+    pub max_inputs: usize,
+
+    /// Maximum privacy cost (see [`ConsolidationPlan::privacy_cost`]) of a
+    /// single consolidation transaction, if limited
+    pub max_privacy_cost: Option<usize>,
+
+    /// Minimum fee savings, in satoshis, for a consolidation to be proposed
+    pub min_savings: u64,
+}
+
+impl ConsolidationPlanner {
+    /// Default maximum number of inputs in a consolidation transaction.
+    pub const DEFAULT_MAX_INPUTS: usize = 100;
+
+    /// Constructs planner for the given fee rates with no privacy limits.
+    pub fn new(fee_rates: FeeEnvironment) -> ConsolidationPlanner {
+        ConsolidationPlanner {
+            fee_rates,
+            max_inputs: Self::DEFAULT_MAX_INPUTS,
+            max_privacy_cost: None,
+            min_savings: 0,
+        }
+    }
+
+    /// Proposes consolidation transactions for the `coins`, provided with
+    /// their amounts in satoshis. Coins which are not worth spending even at
+    /// the current fee rate are left untouched.
+    ///
+    /// The weight of each coin input is estimated from the descriptor derived
+    /// with the coin terminal derivation; the consolidated output and its
+    /// future spending are estimated with the derivation of the first coin.
+    ///
+    /// # Errors
+    ///
+    /// If the descriptor can't be derived for the coin terminal derivation.
+    pub fn plan<'coins>(
+        &self,
+        descriptor: &Descriptor<DerivationAccount>,
+        coins: impl IntoIterator<Item = &'coins (InputDescriptor, u64)>,
+    ) -> Result<Vec<ConsolidationPlan>, Error> {
+        let mut coins = coins
+            .into_iter()
+            .map(|coin| Ok((coin, weights(descriptor, &coin.0)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let (spend_weight, output_weight) = match coins.first() {
+            Some((_, weights)) => *weights,
+            None => return Ok(vec![]),
+        };
+        let FeeEnvironment {
+            current_rate,
+            future_rate,
+        } = self.fee_rates;
+        if future_rate <= current_rate || self.max_inputs < 2 {
+            return Ok(vec![]);
+        }
+
+        coins.retain(|((_, amount), (input_weight, _))| {
+            *amount > (vsize(*input_weight) as f64 * current_rate).ceil() as u64
+        });
+        coins.sort_by(|((a, a_amount), _), ((b, b_amount), _)| {
+            a_amount.cmp(b_amount).then_with(|| a.outpoint.cmp(&b.outpoint))
+        });
+
+        let mut plans = vec![];
+        while !coins.is_empty() {
+            let mut batch = Vec::<&(InputDescriptor, u64)>::new();
+            let mut inputs_weight = 0u64;
+            let mut addresses = BTreeSet::new();
+            let mut deferred = vec![];
+            for (coin, coin_weights) in coins {
+                let new_address = !addresses.contains(&coin.0.terminal);
+                let privacy_cost = addresses.len() + new_address as usize;
+                let within_privacy = self
+                    .max_privacy_cost
+                    .map(|max| privacy_cost <= max + 1)
+                    .unwrap_or(true);
+                if batch.len() < self.max_inputs && within_privacy {
+                    addresses.insert(coin.0.terminal.clone());
+                    batch.push(coin);
+                    inputs_weight += coin_weights.0;
+                } else {
+                    deferred.push((coin, coin_weights));
+                }
+            }
+            coins = deferred;
+
+            let count = batch.len() as u64;
+            let weight = TX_OVERHEAD_WEIGHT + inputs_weight + output_weight;
+            let tx_vsize = vsize(weight);
+            let fee = (tx_vsize as f64 * current_rate).ceil() as u64;
+            let amount = batch.iter().map(|(_, amount)| amount).sum::<u64>();
+            // Spending the consolidated output later replaces spending of all
+            // the merged coins
+            let saved_vsize = vsize(inputs_weight).saturating_sub(vsize(spend_weight));
+            let savings = saved_vsize as f64 * future_rate - fee as f64;
+            if count < 2 || amount <= fee || savings <= self.min_savings as f64 {
+                continue;
+            }
+            plans.push(ConsolidationPlan {
+                inputs: batch.iter().map(|(input, _)| input.clone()).collect(),
+                amount,
+                vsize: tx_vsize,
+                fee,
+                future_savings: savings as u64,
+                linked_addresses: addresses.len(),
+                linked_transactions: batch
+                    .iter()
+                    .map(|(input, _)| input.outpoint.txid)
+                    .collect::<BTreeSet<_>>()
+                    .len(),
+            });
+        }
+        Ok(plans)
+    }
+}
+
+/// Computes weight of an input spending the descriptor and weight of an
+/// output paying to it.
+fn weights(
+    descriptor: &Descriptor<DerivationAccount>,
+    input: &InputDescriptor,
+) -> Result<(u64, u64), Error> {
+    let (satisfaction_weight, script_len) = match descriptor {
+        Descriptor::Tr(_) => {
+            let output_descriptor = DeriveDescriptor::<XOnlyPublicKey>::derive_descriptor(
+                descriptor,
+                SECP256K1,
+                &input.terminal,
+            )?;
+            (output_descriptor.max_satisfaction_weight()?, output_descriptor.script_pubkey().len())
+        }
+        _ => {
+            let output_descriptor = DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
+                descriptor,
+                SECP256K1,
+                &input.terminal,
+            )?;
+            (output_descriptor.max_satisfaction_weight()?, output_descriptor.script_pubkey().len())
+        }
+    };
+    Ok((
+        TXIN_BASE_WEIGHT + satisfaction_weight as u64,
+        TXOUT_BASE_WEIGHT + 4 * script_len as u64,
+    ))
+}
+
+#[inline]
+fn vsize(weight: u64) -> u64 { (weight + 3) / 4 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn coin(vout: u32, index: u32, amount: u64) -> (InputDescriptor, u64) {
+        let input = InputDescriptor::from_str(&format!(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:{} /0/{}",
+            vout, index
+        ))
+        .unwrap();
+        (input, amount)
+    }
+
+    #[test]
+    fn planning() {
+        let account = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(account).unwrap();
+        let coins = [coin(0, 0, 5_000), coin(1, 1, 20_000), coin(2, 1, 30_000), coin(3, 2, 50)];
+
+        let mut planner = ConsolidationPlanner::new(FeeEnvironment {
+            current_rate: 2.0,
+            future_rate: 50.0,
+        });
+        let plans = planner.plan(&descriptor, &coins).unwrap();
+        assert_eq!(plans.len(), 1);
+        let plan = &plans[0];
+        assert_eq!(plan.inputs, vec![coins[0].0.clone(), coins[1].0.clone(), coins[2].0.clone()]);
+        assert_eq!(plan.amount, 55_000);
+        assert_eq!(plan.linked_addresses, 2);
+        assert_eq!(plan.privacy_cost(), 1);
+        assert_eq!(plan.linked_transactions, 1);
+        assert_eq!(plan.fee, (plan.vsize as f64 * 2.0).ceil() as u64);
+        assert!(plan.future_savings > 0);
+
+        planner.max_privacy_cost = Some(0);
+        let plans = planner.plan(&descriptor, &coins).unwrap();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].inputs, vec![coins[1].0.clone(), coins[2].0.clone()]);
+
+        planner.fee_rates.future_rate = 1.0;
+        assert!(planner.plan(&descriptor, &coins).unwrap().is_empty());
+    }
+}
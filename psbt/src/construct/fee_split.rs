@@ -0,0 +1,240 @@
+// Descriptor wallet library extending bitcoin & miniscript functionality
+// by LNP/BP Association (https://lnp-bp.org)
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Splitting transaction fee among recipient outputs, for the transactions
+//! where the recipients pay the fee (like sweeping the whole wallet or
+//! transferring funds between own accounts).
+
+use std::collections::BTreeSet;
+
+use bitcoin_scripts::PubkeyScript;
+
+/// Errors splitting transaction fee among recipient outputs.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum FeeSplitError {
+    /// no outputs are designated to pay the transaction fee
+    NoPayingOutputs,
+
+    /// output #{0} designated to pay the fee exceeds the number of outputs {1}
+    OutputOutOfRange(u16, usize),
+
+    /// outputs designated to pay the fee with total amount of {available}
+    /// sats can't cover the fee of {fee} sats
+    InsufficientAmount {
+        /// Total amount of the outputs paying the fee
+        available: u64,
+
+        /// Fee to pay
+        fee: u64,
+    },
+
+    /// after deducting its share of the fee output #{index} would have amount
+    /// of {amount} sats, which is below the dust limit of {dust_limit} sats
+    DustOutput {
+        /// Index of the output
+        index: u16,
+
+        /// Amount of the output after the fee deduction
+        amount: u64,
+
+        /// Dust limit which must be satisfied by the outputs
+        dust_limit: u64,
+    },
+}
+
+/// Source of the funds paying transaction fee.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum FeeDeduction {
+    /// Fee is paid by the sender from the inputs, reducing change
+    #[default]
+    Change,
+
+    /// Fee is deducted from all recipient outputs proportionally to their
+    /// amounts. Zero-value outputs (like `OP_RETURN` commitments) do not pay
+    /// the fee.
+    Proportional,
+
+    /// Fee is deducted from the outputs with the given indexes,
+    /// proportionally to their amounts
+    Outputs(BTreeSet<u16>),
+}
+
+impl FeeDeduction {
+    /// Detects whether the fee is paid by the recipients.
+    #[inline]
+    pub fn is_paid_by_recipients(&self) -> bool { *self != FeeDeduction::Change }
+
+    /// Deducts `fee` from the `outputs` according to the deduction policy,
+    /// returning amount deducted from each of the outputs. Fee share of each
+    /// paying output is proportional to the output amount; satoshis left
+    /// from the rounding are deducted one by one from the paying outputs in
+    /// their order.
+    ///
+    /// If [`FeeDeduction::Change`] is used, outputs are not modified.
+    ///
+    /// # Errors
+    ///
+    /// If there are no paying outputs, they can't cover the fee, or any of
+    /// them falls below `dust_limit` after the deduction. Outputs are not
+    /// modified in case of an error.
+    pub fn deduct(
+        &self,
+        outputs: &mut [(PubkeyScript, u64)],
+        fee: u64,
+        dust_limit: u64,
+    ) -> Result<Vec<u64>, FeeSplitError> {
+        let paying = match self {
+            FeeDeduction::Change => return Ok(vec![0; outputs.len()]),
+            FeeDeduction::Proportional => (0..outputs.len())
+                .filter(|index| outputs[*index].1 > 0)
+                .collect::<Vec<_>>(),
+            FeeDeduction::Outputs(indexes) => {
+                let len = outputs.len();
+                if let Some(index) = indexes.iter().find(|index| **index as usize >= len) {
+                    return Err(FeeSplitError::OutputOutOfRange(*index, len));
+                }
+                indexes.iter().map(|index| *index as usize).collect()
+            }
+        };
+        if paying.is_empty() {
+            return Err(FeeSplitError::NoPayingOutputs);
+        }
+
+        let available = paying.iter().map(|index| outputs[*index].1).sum::<u64>();
+        if available < fee {
+            return Err(FeeSplitError::InsufficientAmount { available, fee });
+        }
+
+        let mut shares = vec![0u64; outputs.len()];
+        let mut remainder = fee;
+        for index in &paying {
+            let share = (fee as u128 * outputs[*index].1 as u128 / available as u128) as u64;
+            shares[*index] = share;
+            remainder -= share;
+        }
+        for index in paying.iter().cycle() {
+            if remainder == 0 {
+                break;
+            }
+            if shares[*index] < outputs[*index].1 {
+                shares[*index] += 1;
+                remainder -= 1;
+            }
+        }
+
+        if let Some(index) = paying
+            .iter()
+            .find(|index| outputs[**index].1 - shares[**index] < dust_limit)
+        {
+            return Err(FeeSplitError::DustOutput {
+                index: *index as u16,
+                amount: outputs[*index].1 - shares[*index],
+                dust_limit,
+            });
+        }
+
+        for ((_, amount), share) in outputs.iter_mut().zip(&shares) {
+            *amount -= share;
+        }
+        Ok(shares)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Script;
+
+    use super::*;
+
+    fn outputs(amounts: &[u64]) -> Vec<(PubkeyScript, u64)> {
+        amounts
+            .iter()
+            .map(|amount| (PubkeyScript::from(Script::new()), *amount))
+            .collect()
+    }
+
+    fn amounts(outputs: &[(PubkeyScript, u64)]) -> Vec<u64> {
+        outputs.iter().map(|(_, amount)| *amount).collect()
+    }
+
+    #[test]
+    fn proportional() {
+        let mut outs = outputs(&[10_000, 20_000, 30_001]);
+        let shares = FeeDeduction::Proportional.deduct(&mut outs, 601, 546).unwrap();
+        assert_eq!(shares.iter().sum::<u64>(), 601);
+        assert_eq!(shares, vec![101, 200, 300]);
+        assert_eq!(amounts(&outs), vec![9_899, 19_800, 29_701]);
+
+        let mut outs = outputs(&[1_000, 1_000, 1_000]);
+        let shares = FeeDeduction::Proportional.deduct(&mut outs, 100, 0).unwrap();
+        assert_eq!(shares, vec![34, 33, 33]);
+
+        let mut outs = outputs(&[0, 10_000, 30_000]);
+        let shares = FeeDeduction::Proportional.deduct(&mut outs, 400, 546).unwrap();
+        assert_eq!(shares, vec![0, 100, 300]);
+        assert_eq!(amounts(&outs), vec![0, 9_900, 29_700]);
+
+        let mut outs = outputs(&[0]);
+        assert_eq!(
+            FeeDeduction::Proportional.deduct(&mut outs, 400, 546),
+            Err(FeeSplitError::NoPayingOutputs)
+        );
+    }
+
+    #[test]
+    fn designated() {
+        let mut outs = outputs(&[10_000, 20_000, 30_000]);
+        let deduction = FeeDeduction::Outputs(bset! { 0, 2 });
+        assert_eq!(deduction.deduct(&mut outs, 400, 546).unwrap(), vec![100, 0, 300]);
+        assert_eq!(amounts(&outs), vec![9_900, 20_000, 29_700]);
+
+        let deduction = FeeDeduction::Outputs(bset! { 3 });
+        assert_eq!(
+            deduction.deduct(&mut outs, 400, 546),
+            Err(FeeSplitError::OutputOutOfRange(3, 3))
+        );
+        assert_eq!(
+            FeeDeduction::Outputs(BTreeSet::new()).deduct(&mut outs, 400, 546),
+            Err(FeeSplitError::NoPayingOutputs)
+        );
+    }
+
+    #[test]
+    fn dust() {
+        let mut outs = outputs(&[1_000, 100_000]);
+        assert_eq!(
+            FeeDeduction::Outputs(bset! { 0 }).deduct(&mut outs, 500, 546),
+            Err(FeeSplitError::DustOutput {
+                index: 0,
+                amount: 500,
+                dust_limit: 546
+            })
+        );
+        assert_eq!(amounts(&outs), vec![1_000, 100_000]);
+        assert_eq!(
+            FeeDeduction::Proportional.deduct(&mut outs, 200_000, 546),
+            Err(FeeSplitError::InsufficientAmount {
+                available: 101_000,
+                fee: 200_000
+            })
+        );
+        assert_eq!(FeeDeduction::Change.deduct(&mut outs, 500, 546).unwrap(), vec![0, 0]);
+    }
+}
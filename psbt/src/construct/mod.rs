@@ -14,20 +14,38 @@
 
 //! Functions, errors and traits specific for PSBT constructor role.
 
+mod consolidate;
+mod fee_split;
+mod package;
+mod recipe;
+
 use std::collections::BTreeSet;
 
 use bitcoin::secp256k1::SECP256K1;
 use bitcoin::util::psbt::TapTree;
 use bitcoin::util::taproot::{LeafVersion, TapLeafHash, TaprootBuilder, TaprootBuilderError};
-use bitcoin::{Script, Txid, XOnlyPublicKey};
-use bitcoin_hd::{DerivationAccount, DeriveError, SegmentIndexes, UnhardenedIndex};
+use bitcoin::util::bip32::Fingerprint;
+use bitcoin::{Network, Script, Txid, XOnlyPublicKey};
+use bitcoin_hd::standards::DerivationBlockchain;
+use bitcoin_hd::{
+    Bip43, DerivationAccount, DerivationStandard, DeriveError, HardenedIndex, SegmentIndexes,
+    UnhardenedIndex,
+};
+#[cfg(feature = "async")]
+use bitcoin_onchain::ResolveTxAsync;
 use bitcoin_onchain::{ResolveTx, TxResolverError};
 use bitcoin_scripts::PubkeyScript;
 use descriptors::derive::DeriveDescriptor;
-use descriptors::InputDescriptor;
+use descriptors::{InputDescriptor, P2cError, P2cRegistry};
 use miniscript::{Descriptor, ForEachKey, ToPublicKey};
+use slip132::ChainParams;
+
+use crate::{self as psbt, FeePolicyError, LockTimeError, MaxFeePolicy, Psbt, PsbtVersion};
 
-use crate::{self as psbt, Psbt, PsbtVersion};
+pub use consolidate::{ConsolidationPlan, ConsolidationPlanner, FeeEnvironment};
+pub use fee_split::{FeeDeduction, FeeSplitError};
+pub use package::{PackageTx, PsbtPackage};
+pub use recipe::{ChangePolicy, InputSelection, PsbtRecipe, Recipient, RecipeError};
 
 #[derive(Debug, Display, From)]
 #[display(doc_comments)]
@@ -66,6 +84,31 @@ pub enum Error {
         /// Amount sent: sum of output value + transaction fee
         output: u64,
     },
+
+    /// PSBT fee does not satisfy fee policy. {0}
+    #[from]
+    FeePolicy(FeePolicyError),
+
+    /// unable to apply pay-to-contract tweaks to the spent output keys. {0}
+    #[from]
+    P2c(P2cError),
+
+    /// unable to compute transaction lock time. {0}
+    #[from]
+    LockTime(LockTimeError),
+
+    /// account {account} uses coin type {found} in its derivation path, while
+    /// the chain requires coin type {expected}
+    CoinTypeMismatch {
+        /// Fingerprint of the account extended public key
+        account: Fingerprint,
+
+        /// Coin type required by the chain parameters
+        expected: HardenedIndex,
+
+        /// Coin type used in the account derivation path
+        found: HardenedIndex,
+    },
 }
 
 impl std::error::Error for Error {
@@ -78,11 +121,18 @@ impl std::error::Error for Error {
             Error::Miniscript(err) => Some(err),
             Error::Inflation { .. } => None,
             Error::TaprootBuilderError(err) => Some(err),
+            Error::FeePolicy(err) => Some(err),
+            Error::P2c(err) => Some(err),
+            Error::LockTime(err) => Some(err),
+            Error::CoinTypeMismatch { .. } => None,
         }
     }
 }
 
 impl Psbt {
+    /// Constructs PSBT enforcing the default [`MaxFeePolicy`]; see
+    /// [`Psbt::construct_with_policy`] for details.
+    #[inline]
     pub fn construct<'inputs, 'outputs>(
         descriptor: &Descriptor<DerivationAccount>,
         inputs: impl IntoIterator<Item = &'inputs InputDescriptor>,
@@ -90,6 +140,141 @@ impl Psbt {
         change_index: impl Into<UnhardenedIndex>,
         fee: u64,
         tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, Error> {
+        Psbt::construct_with_policy(
+            descriptor,
+            inputs,
+            outputs,
+            change_index,
+            fee,
+            &MaxFeePolicy::default(),
+            tx_resolver,
+        )
+    }
+
+    /// Constructs PSBT for a chain with the given parameters (see
+    /// [`Psbt::construct_with_policy`] for details). Checks that descriptor
+    /// keys belong to the chain network family and that accounts following
+    /// BIP-43 derivation standards use the chain coin type.
+    #[allow(clippy::too_many_arguments)]
+    pub fn construct_on_chain<'inputs, 'outputs>(
+        chain: &ChainParams,
+        descriptor: &Descriptor<DerivationAccount>,
+        inputs: impl IntoIterator<Item = &'inputs InputDescriptor>,
+        outputs: impl IntoIterator<Item = &'outputs (PubkeyScript, u64)>,
+        change_index: impl Into<UnhardenedIndex>,
+        fee: u64,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, Error> {
+        let expected = DerivationBlockchain::from(chain).coin_type();
+        let mut result = Ok(());
+        descriptor.for_each_key(|account| {
+            if (account.account_xpub.network == Network::Bitcoin) == chain.is_testnet() {
+                result = Err(Error::Derive(DeriveError::InconsistentKeyNetwork));
+                return false;
+            }
+            let path = account.to_account_derivation_path();
+            let coin_type = Bip43::deduce(&path).and_then(|bip43| bip43.extract_coin_type(&path));
+            match coin_type {
+                Some(Ok(found)) if found != expected => {
+                    result = Err(Error::CoinTypeMismatch {
+                        account: account.account_fingerprint(),
+                        expected,
+                        found,
+                    });
+                    false
+                }
+                _ => true,
+            }
+        });
+        result?;
+
+        Psbt::construct_with_policy(
+            descriptor,
+            inputs,
+            outputs,
+            change_index,
+            fee,
+            fee_policy,
+            tx_resolver,
+        )
+    }
+
+    /// Constructs PSBT spending the provided inputs, refusing to use fee not
+    /// satisfying `fee_policy`. The maximum fee allowed by the policy is
+    /// recorded in the PSBT global proprietary key (see
+    /// [`Psbt::set_max_fee`]).
+    pub fn construct_with_policy<'inputs, 'outputs>(
+        descriptor: &Descriptor<DerivationAccount>,
+        inputs: impl IntoIterator<Item = &'inputs InputDescriptor>,
+        outputs: impl IntoIterator<Item = &'outputs (PubkeyScript, u64)>,
+        change_index: impl Into<UnhardenedIndex>,
+        fee: u64,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, Error> {
+        Psbt::construct_with_p2c(
+            descriptor,
+            inputs,
+            outputs,
+            change_index,
+            fee,
+            fee_policy,
+            &P2cRegistry::default(),
+            tx_resolver,
+        )
+    }
+
+    /// Constructs PSBT like [`Psbt::construct_with_policy`], resolving the
+    /// transactions spent by the inputs with an async resolver, for instance a
+    /// chain backend accessed over network. All spent transactions are
+    /// resolved before the construction starts.
+    #[cfg(feature = "async")]
+    pub async fn construct_async<'inputs, 'outputs>(
+        descriptor: &Descriptor<DerivationAccount>,
+        inputs: impl IntoIterator<Item = &'inputs InputDescriptor>,
+        outputs: impl IntoIterator<Item = &'outputs (PubkeyScript, u64)>,
+        change_index: impl Into<UnhardenedIndex>,
+        fee: u64,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTxAsync,
+    ) -> Result<Psbt, Error> {
+        let inputs = inputs.into_iter().collect::<Vec<_>>();
+        let txids = inputs
+            .iter()
+            .map(|input| input.outpoint.txid)
+            .collect::<BTreeSet<_>>();
+        let mut txes = bmap! {};
+        for txid in txids {
+            txes.insert(txid, tx_resolver.resolve_tx_async(txid).await?);
+        }
+        Psbt::construct_with_policy(
+            descriptor,
+            inputs,
+            outputs,
+            change_index,
+            fee,
+            fee_policy,
+            &txes,
+        )
+    }
+
+    /// Constructs PSBT like [`Psbt::construct_with_policy`], allowing to spend
+    /// outputs with the keys tweaked by the pay-to-contract commitments
+    /// registered in `p2c`. Spent outputs are matched against the tweaked
+    /// scripts, and the tweaks are recorded in the PSBT inputs for the signers
+    /// (see [`Psbt::set_p2c_tweaks`]).
+    #[allow(clippy::too_many_arguments)]
+    pub fn construct_with_p2c<'inputs, 'outputs>(
+        descriptor: &Descriptor<DerivationAccount>,
+        inputs: impl IntoIterator<Item = &'inputs InputDescriptor>,
+        outputs: impl IntoIterator<Item = &'outputs (PubkeyScript, u64)>,
+        change_index: impl Into<UnhardenedIndex>,
+        fee: u64,
+        fee_policy: &MaxFeePolicy,
+        p2c: &P2cRegistry,
+        tx_resolver: &impl ResolveTx,
     ) -> Result<Psbt, Error> {
         let mut xpub = bmap! {};
         descriptor.for_each_key(|account| {
@@ -115,13 +300,18 @@ impl Psbt {
                 .output
                 .get(input.outpoint.vout as usize)
                 .ok_or(Error::OutputUnknown(txid, input.outpoint.vout))?;
+            let tweaked = p2c.is_tweaked(descriptor, &input.terminal);
             let (script_pubkey, dtype, tr_descriptor, pretr_descriptor) = match descriptor {
                 Descriptor::Tr(_) => {
-                    let output_descriptor = DeriveDescriptor::<XOnlyPublicKey>::derive_descriptor(
-                        descriptor,
-                        SECP256K1,
-                        &input.terminal,
-                    )?;
+                    let output_descriptor = if tweaked {
+                        p2c.tweaked_tr_descriptor(SECP256K1, descriptor, &input.terminal)?
+                    } else {
+                        DeriveDescriptor::<XOnlyPublicKey>::derive_descriptor(
+                            descriptor,
+                            SECP256K1,
+                            &input.terminal,
+                        )?
+                    };
                     (
                         output_descriptor.script_pubkey(),
                         descriptors::CompositeDescrType::from(&output_descriptor),
@@ -130,12 +320,15 @@ impl Psbt {
                     )
                 }
                 _ => {
-                    let output_descriptor =
+                    let output_descriptor = if tweaked {
+                        p2c.tweaked_descriptor(SECP256K1, descriptor, &input.terminal)?
+                    } else {
                         DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
                             descriptor,
                             SECP256K1,
                             &input.terminal,
-                        )?;
+                        )?
+                    };
                     (
                         output_descriptor.script_pubkey(),
                         descriptors::CompositeDescrType::from(&output_descriptor),
@@ -185,6 +378,28 @@ impl Psbt {
             psbt_input.non_witness_utxo = Some(tx.clone());
 
             if let Some(Descriptor::<XOnlyPublicKey>::Tr(tr)) = tr_descriptor {
+                // Keys used in the tweaked scripts, keyed by the original keys
+                let mut script_keys = bmap! {};
+                if tweaked {
+                    let mut accounts = vec![];
+                    descriptor.for_each_key(|account| {
+                        accounts.push(account);
+                        true
+                    });
+                    for account in accounts {
+                        let tweak = match p2c.get(account.account_fingerprint(), &input.terminal) {
+                            Some(tweak) => tweak,
+                            None => continue,
+                        };
+                        let (pubkey, _) = account
+                            .bip32_derivation(SECP256K1, &input.terminal)
+                            .map_err(DeriveError::from)?;
+                        let pubkey = XOnlyPublicKey::from(pubkey);
+                        let tweaked_key =
+                            tweak.tweak_xonly(SECP256K1, pubkey).map_err(P2cError::from)?;
+                        script_keys.insert(pubkey, tweaked_key);
+                    }
+                }
                 psbt_input.bip32_derivation.clear();
                 psbt_input.tap_merkle_root = tr.spend_info().merkle_root();
                 psbt_input.tap_internal_key = Some(tr.internal_key().to_x_only_pubkey());
@@ -207,10 +422,11 @@ impl Psbt {
                             .bip32_derivation(SECP256K1, &input.terminal)
                             .expect("failing on second pass of the same function");
                         let pubkey = XOnlyPublicKey::from(pubkey);
+                        let script_key = script_keys.get(&pubkey).copied().unwrap_or(pubkey);
                         let mut leaves = vec![];
                         for (_, ms) in taptree.iter() {
                             for pk in ms.iter_pk() {
-                                if pk == pubkey {
+                                if pk == script_key {
                                     leaves.push(TapLeafHash::from_script(
                                         &ms.encode(),
                                         LeafVersion::TapScript,
@@ -231,7 +447,8 @@ impl Psbt {
                         .bip32_derivation(SECP256K1, &input.terminal)
                         .expect("failing on second pass of the same function");
                     let pubkey = XOnlyPublicKey::from(pubkey);
-                    if pubkey == *tr.internal_key() {
+                    let script_key = script_keys.get(&pubkey).copied().unwrap_or(pubkey);
+                    if script_key == *tr.internal_key() {
                         psbt_input
                             .tap_key_origins
                             .entry(pubkey.to_x_only_pubkey())
@@ -352,7 +569,7 @@ impl Psbt {
             psbt_outputs.push(psbt_change_output);
         }
 
-        Ok(Psbt {
+        let mut psbt = Psbt {
             psbt_version: PsbtVersion::V0,
             tx_version: 2,
             xpub,
@@ -361,6 +578,178 @@ impl Psbt {
             fallback_locktime: None,
             proprietary: none!(),
             unknown: none!(),
-        })
+        };
+        if !p2c.is_empty() {
+            psbt.set_p2c_tweaks(&p2c.pubkey_tweaks(SECP256K1, descriptor)?);
+        }
+
+        let dtype = descriptors::CompositeDescrType::from(descriptor);
+        let segwit_marker = if dtype.is_segwit() || dtype.is_taproot() { 2 } else { 0 };
+        let satisfaction_weight =
+            descriptor.max_satisfaction_weight()? * psbt.inputs.len() + segwit_marker;
+        psbt.check_fee_policy(fee_policy, satisfaction_weight)?;
+        if let Some(max_fee) = psbt.policy_max_fee(fee_policy, satisfaction_weight) {
+            psbt.set_max_fee(max_fee);
+        }
+
+        Ok(psbt)
+    }
+}
+
+#[cfg(all(test, feature = "sign"))]
+mod test {
+    use std::str::FromStr;
+
+    use amplify::Wrapper;
+    use bitcoin::psbt::PartiallySignedTransaction;
+    use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+    use miniscript::psbt::PsbtExt;
+
+    use super::*;
+    use crate::fixtures;
+    use crate::sign::{MemoryKeyProvider, MemorySigningAccount, SignAll};
+
+    #[test]
+    fn p2c_spending() {
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let master_id = ExtendedPubKey::from_priv(SECP256K1, &master).identifier();
+        let derivation = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let account_xpriv = master.derive_priv(SECP256K1, &derivation).unwrap();
+        let account = MemorySigningAccount::with(SECP256K1, master_id, derivation, account_xpriv);
+        let terminal = [UnhardenedIndex::zero(), UnhardenedIndex::from(5u8)];
+
+        let descriptors = [
+            Descriptor::new_wpkh(account.to_account()).unwrap(),
+            Descriptor::new_tr(account.to_account(), None).unwrap(),
+        ];
+        for descriptor in descriptors {
+            let mut p2c = P2cRegistry::new();
+            p2c.register(&account.to_account(), &terminal[..], "LNPBP1", *b"contract");
+            let address =
+                p2c.tweaked_address(SECP256K1, &descriptor, &terminal, Network::Bitcoin).unwrap();
+
+            let funding = fixtures::tx([(100_000, address.script_pubkey())]);
+            let resolver = bmap! { funding.txid() => funding.clone() };
+            let coin = InputDescriptor::from_str(&format!("{}:0 /0/5", funding.txid())).unwrap();
+            let outputs = [(PubkeyScript::from_inner(Script::new_op_return(&[])), 0u64)];
+
+            assert!(matches!(
+                Psbt::construct(&descriptor, [&coin], &outputs, 0u8, 1000, &resolver),
+                Err(Error::ScriptPubkeyMismatch(..))
+            ));
+            let mut psbt = Psbt::construct_with_p2c(
+                &descriptor,
+                [&coin],
+                &outputs,
+                0u8,
+                1000,
+                &MaxFeePolicy::default(),
+                &p2c,
+                &resolver,
+            )
+            .unwrap();
+
+            let mut provider = MemoryKeyProvider::with(SECP256K1, false);
+            provider.add_account(MemorySigningAccount::with(
+                SECP256K1,
+                *account.master_id(),
+                account.derivation().clone(),
+                account_xpriv,
+            ));
+            assert_eq!(psbt.sign_all(&provider).unwrap(), 1);
+
+            let mut v0 = PartiallySignedTransaction::from(psbt);
+            v0.finalize_mut(SECP256K1).unwrap();
+            let tx = Psbt::from(v0).extract_signed_tx().unwrap();
+            assert!(!tx.input[0].witness.is_empty());
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_construction() {
+        use std::collections::BTreeMap;
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        use descriptors::derive::Descriptor as _;
+
+        use crate::sign::{LocalSigner, SignAsync};
+
+        // Resolvers used in the test never wait, so the futures are ready once
+        // polled
+        struct NoWake;
+        impl Wake for NoWake {
+            fn wake(self: Arc<Self>) {}
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Arc::new(NoWake).into();
+            let mut cx = Context::from_waker(&waker);
+            let mut future = Box::pin(future);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            }
+        }
+
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let master_id = ExtendedPubKey::from_priv(SECP256K1, &master).identifier();
+        let derivation = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let account_xpriv = master.derive_priv(SECP256K1, &derivation).unwrap();
+        let account = MemorySigningAccount::with(SECP256K1, master_id, derivation, account_xpriv);
+        let descriptor = Descriptor::new_wpkh(account.to_account()).unwrap();
+        let terminal = [UnhardenedIndex::zero(), UnhardenedIndex::from(5u8)];
+
+        let script_pubkey = descriptor.script_pubkey_pretr(SECP256K1, terminal).unwrap();
+        let funding = fixtures::tx([(100_000, script_pubkey)]);
+        let resolver = bmap! { funding.txid() => funding.clone() };
+        let coin = InputDescriptor::from_str(&format!("{}:0 /0/5", funding.txid())).unwrap();
+        let outputs = [(PubkeyScript::from_inner(Script::new_op_return(&[])), 0u64)];
+        let policy = MaxFeePolicy::default();
+
+        assert!(matches!(
+            block_on(Psbt::construct_async(
+                &descriptor,
+                [&coin],
+                &outputs,
+                0u8,
+                1000,
+                &policy,
+                &BTreeMap::new(),
+            )),
+            Err(Error::ResolvingTx(_))
+        ));
+        let mut psbt = block_on(Psbt::construct_async(
+            &descriptor,
+            [&coin],
+            &outputs,
+            0u8,
+            1000,
+            &policy,
+            &resolver,
+        ))
+        .unwrap();
+        let expected = Psbt::construct_with_policy(
+            &descriptor,
+            [&coin],
+            &outputs,
+            0u8,
+            1000,
+            &policy,
+            &resolver,
+        );
+        assert_eq!(psbt, expected.unwrap());
+
+        psbt.inputs[0].non_witness_utxo = None;
+        psbt.inputs[0].witness_utxo = None;
+        assert_eq!(block_on(psbt.populate_prevouts_async(&resolver)).unwrap().len(), 2);
+        assert_eq!(psbt.inputs[0].witness_utxo, Some(funding.output[0].clone()));
+
+        let mut provider = MemoryKeyProvider::with(SECP256K1, false);
+        provider.add_account(account);
+        let signer = LocalSigner::with(provider);
+        assert_eq!(block_on(signer.sign_async(&mut psbt)).unwrap(), 1);
     }
 }
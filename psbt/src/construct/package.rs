@@ -0,0 +1,335 @@
+// Descriptor wallet library extending bitcoin & miniscript functionality
+// by LNP/BP Association (https://lnp-bp.org)
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Transaction packages: a parent transaction and a child spending some of
+//! its outputs before the parent is final (funding transaction with an
+//! anchored child, batched payout with its CPFP anchor etc).
+
+use bitcoin::{OutPoint, Transaction, Txid};
+use bitcoin_hd::{DerivationAccount, UnhardenedIndex};
+use bitcoin_onchain::{ResolveTx, TxResolverError};
+use bitcoin_scripts::PubkeyScript;
+use descriptors::InputDescriptor;
+use miniscript::Descriptor;
+
+use super::Error;
+use crate::{FeeError, LockTimeError, MaxFeePolicy, Psbt};
+
+/// Specification of a transaction constructed as a part of a package.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PackageTx {
+    /// Wallet coins spent by the transaction
+    pub inputs: Vec<InputDescriptor>,
+
+    /// Transaction outputs, except change
+    pub outputs: Vec<(PubkeyScript, u64)>,
+
+    /// Index of the change output derivation
+    pub change_index: UnhardenedIndex,
+
+    /// Transaction fee, in satoshis
+    pub fee: u64,
+}
+
+impl PackageTx {
+    /// Number of the output which will hold the transaction change, if there
+    /// is any change left.
+    #[inline]
+    pub fn change_vout(&self) -> u32 { self.outputs.len() as u32 }
+}
+
+/// Resolver providing the package parent transaction in addition to the
+/// transactions known to the wrapped resolver.
+struct PackageResolver<'resolver, R: ResolveTx> {
+    parent: Transaction,
+    parent_txid: Txid,
+    resolver: &'resolver R,
+}
+
+impl<'resolver, R: ResolveTx> ResolveTx for PackageResolver<'resolver, R> {
+    fn resolve_tx(&self, txid: Txid) -> Result<Transaction, TxResolverError> {
+        if txid == self.parent_txid {
+            Ok(self.parent.clone())
+        } else {
+            self.resolver.resolve_tx(txid)
+        }
+    }
+}
+
+/// Package of a parent PSBT and a child PSBT spending parent outputs.
+///
+/// Child inputs spending the parent are tracked separately from other child
+/// inputs, so once the parent transaction id changes (after signing
+/// non-segwit inputs or replacing the parent) the child can be re-constructed
+/// with [`PsbtPackage::refresh`].
+#[derive(Clone, Debug)]
+pub struct PsbtPackage {
+    descriptor: Descriptor<DerivationAccount>,
+    fee_policy: MaxFeePolicy,
+    parent: Psbt,
+    child: Psbt,
+    child_tx: PackageTx,
+    parent_spends: Vec<InputDescriptor>,
+    parent_txid: Txid,
+}
+
+impl PsbtPackage {
+    /// Constructs parent and child PSBTs atomically.
+    ///
+    /// `parent_spends` are child inputs spending parent outputs; transaction
+    /// id in their outpoints is ignored and replaced with the id of the
+    /// parent transaction. These inputs precede other inputs of the child.
+    ///
+    /// # Errors
+    ///
+    /// If either of the PSBTs can't be constructed; see
+    /// [`Psbt::construct_with_policy`].
+    pub fn construct(
+        descriptor: &Descriptor<DerivationAccount>,
+        parent_tx: &PackageTx,
+        child_tx: &PackageTx,
+        parent_spends: Vec<InputDescriptor>,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<PsbtPackage, Error> {
+        let parent = Psbt::construct_with_policy(
+            descriptor,
+            &parent_tx.inputs,
+            &parent_tx.outputs,
+            parent_tx.change_index,
+            parent_tx.fee,
+            fee_policy,
+            tx_resolver,
+        )?;
+        let (child, parent_txid) = construct_child(
+            descriptor,
+            &parent,
+            child_tx,
+            &parent_spends,
+            fee_policy,
+            tx_resolver,
+        )?;
+        Ok(PsbtPackage {
+            descriptor: descriptor.clone(),
+            fee_policy: *fee_policy,
+            parent,
+            child,
+            child_tx: child_tx.clone(),
+            parent_spends,
+            parent_txid,
+        })
+    }
+
+    /// Returns parent PSBT.
+    #[inline]
+    pub fn parent(&self) -> &Psbt { &self.parent }
+
+    /// Returns child PSBT.
+    #[inline]
+    pub fn child(&self) -> &Psbt { &self.child }
+
+    /// Returns mutable reference to the child PSBT, for instance for signing.
+    #[inline]
+    pub fn child_mut(&mut self) -> &mut Psbt { &mut self.child }
+
+    /// Releases parent and child PSBTs.
+    #[inline]
+    pub fn into_psbts(self) -> (Psbt, Psbt) { (self.parent, self.child) }
+
+    /// Transaction id which the parent will have once its current data are
+    /// extracted, including finalized signatures of non-segwit inputs.
+    #[inline]
+    pub fn parent_txid(&self) -> Result<Txid, LockTimeError> {
+        self.parent.extract_signed_tx().map(|tx| tx.txid())
+    }
+
+    /// Lists dependencies of the child on the parent: parent outpoints and
+    /// indexes of the child inputs spending them.
+    pub fn dependencies(&self) -> Vec<(OutPoint, usize)> {
+        self.child
+            .inputs
+            .iter()
+            .take(self.parent_spends.len())
+            .map(|input| (input.previous_outpoint, input.index))
+            .collect()
+    }
+
+    /// Detects whether the child spends outputs of the current parent
+    /// transaction.
+    #[inline]
+    pub fn is_child_current(&self) -> bool { self.parent_txid() == Ok(self.parent_txid) }
+
+    /// Mutates the parent PSBT (for instance signing or finalizing it) and
+    /// re-constructs the child if the parent transaction id has changed.
+    /// Returns whether the child was re-constructed.
+    ///
+    /// NB: re-constructed child loses all its signatures.
+    pub fn update_parent(
+        &mut self,
+        f: impl FnOnce(&mut Psbt),
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<bool, Error> {
+        f(&mut self.parent);
+        self.refresh(tx_resolver)
+    }
+
+    /// Re-constructs the child PSBT if the parent transaction id has changed
+    /// since the child was constructed. Returns whether the child was
+    /// re-constructed.
+    ///
+    /// NB: re-constructed child loses all its signatures.
+    pub fn refresh(&mut self, tx_resolver: &impl ResolveTx) -> Result<bool, Error> {
+        if self.is_child_current() {
+            return Ok(false);
+        }
+        let (child, parent_txid) = construct_child(
+            &self.descriptor,
+            &self.parent,
+            &self.child_tx,
+            &self.parent_spends,
+            &self.fee_policy,
+            tx_resolver,
+        )?;
+        self.child = child;
+        self.parent_txid = parent_txid;
+        Ok(true)
+    }
+
+    /// Computes total fee paid by the package.
+    pub fn package_fee(&self) -> Result<u64, FeeError> {
+        Ok(self.parent.fee()? + self.child.fee()?)
+    }
+}
+
+/// Constructs child PSBT spending outputs of the parent, returning it together
+/// with the parent transaction id.
+fn construct_child(
+    descriptor: &Descriptor<DerivationAccount>,
+    parent: &Psbt,
+    child_tx: &PackageTx,
+    parent_spends: &[InputDescriptor],
+    fee_policy: &MaxFeePolicy,
+    tx_resolver: &impl ResolveTx,
+) -> Result<(Psbt, Txid), Error> {
+    let parent = parent.extract_signed_tx()?;
+    let parent_txid = parent.txid();
+    let inputs = parent_spends
+        .iter()
+        .cloned()
+        .map(|mut input| {
+            input.outpoint.txid = parent_txid;
+            input
+        })
+        .chain(child_tx.inputs.iter().cloned())
+        .collect::<Vec<_>>();
+    let resolver = PackageResolver {
+        parent,
+        parent_txid,
+        resolver: tx_resolver,
+    };
+    let child = Psbt::construct_with_policy(
+        descriptor,
+        &inputs,
+        &child_tx.outputs,
+        child_tx.change_index,
+        child_tx.fee,
+        fee_policy,
+        &resolver,
+    )?;
+    Ok((child, parent_txid))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::SECP256K1;
+    use bitcoin::Script;
+    use bitcoin_hd::SegmentIndexes;
+    use descriptors::derive::DeriveDescriptor;
+
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn child_refresh() {
+        let account = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(account).unwrap();
+        let funding_script =
+            DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(&descriptor, SECP256K1, [
+                UnhardenedIndex::zero(),
+                UnhardenedIndex::zero(),
+            ])
+            .unwrap()
+            .script_pubkey();
+        let funding = fixtures::tx([(100_000, funding_script)]);
+        let resolver: BTreeMap<Txid, Transaction> = bmap! { funding.txid() => funding.clone() };
+
+        let parent_tx = PackageTx {
+            inputs: vec![InputDescriptor::from_str(&format!("{}:0 /0/0", funding.txid())).unwrap()],
+            outputs: vec![(PubkeyScript::from(Script::new_op_return(&[])), 0)],
+            change_index: UnhardenedIndex::zero(),
+            fee: 200,
+        };
+        let child_tx = PackageTx {
+            inputs: vec![],
+            outputs: vec![],
+            change_index: UnhardenedIndex::one(),
+            fee: 1000,
+        };
+        let anchor = InputDescriptor::from_str(&format!(
+            "{}:{} /1/0",
+            Txid::all_zeros(),
+            parent_tx.change_vout()
+        ))
+        .unwrap();
+        let mut package = PsbtPackage::construct(
+            &descriptor,
+            &parent_tx,
+            &child_tx,
+            vec![anchor],
+            &MaxFeePolicy::default(),
+            &resolver,
+        )
+        .unwrap();
+
+        let parent_txid = package.parent_txid().unwrap();
+        assert!(package.is_child_current());
+        assert_eq!(package.dependencies(), vec![(
+            OutPoint::new(parent_txid, 1),
+            0
+        )]);
+        assert_eq!(package.package_fee(), Ok(1200));
+
+        assert!(!package.refresh(&resolver).unwrap());
+        let refreshed = package
+            .update_parent(
+                |parent| parent.inputs[0].final_script_sig = Some(Script::from(vec![0x51]).into()),
+                &resolver,
+            )
+            .unwrap();
+        assert!(refreshed);
+        assert_ne!(package.parent_txid(), Ok(parent_txid));
+        let parent_txid = package.parent_txid().unwrap();
+        assert_eq!(package.dependencies(), vec![(
+            OutPoint::new(parent_txid, 1),
+            0
+        )]);
+    }
+}
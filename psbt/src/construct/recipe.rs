@@ -0,0 +1,458 @@
+// Descriptor wallet library extending bitcoin & miniscript functionality
+// by LNP/BP Association (https://lnp-bp.org)
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Declarative PSBT construction recipes, which can be read from
+//! configuration files (with `serde` feature) instead of being built in code.
+//!
+//! Example of a recipe in JSON:
+//! ```json
+//! {
+//!   "inputs": "auto",
+//!   "recipients": [
+//!     { "address": "bc1qtkr96rhavl4z4ftxa4mewlvmgd8dnp6pe9nuht", "amount": 1645621 }
+//!   ],
+//!   "fee": 500,
+//!   "change": { "index": 3, "dust_limit": 546 },
+//!   "fee_deduction": "change",
+//!   "rbf": true,
+//!   "proprietary": ["output(0) DBC(1) 8536ba03:"]
+//! }
+//! ```
+//! Instead of `"auto"`, inputs may be given as a list of input descriptors
+//! (see [`InputDescriptor`]). Fee may be paid by the recipients instead of
+//! the change using `"proportional"` or `{ "outputs": [0, 2] }` fee
+//! deduction (see [`FeeDeduction`]).
+
+use bitcoin::{Address, OutPoint};
+use bitcoin_blockchain::locks::{LockTime, SeqNo};
+use bitcoin_hd::{DerivationAccount, UnhardenedIndex};
+use bitcoin_onchain::{ResolveTx, TxResolverError};
+use bitcoin_scripts::PubkeyScript;
+use descriptors::InputDescriptor;
+use miniscript::Descriptor;
+#[cfg(feature = "serde")]
+use serde_with::{As, DisplayFromStr};
+
+use super::{Error, FeeDeduction, FeeSplitError};
+use crate::{MaxFeePolicy, ProprietaryKeyDescriptor, ProprietaryKeyError, Psbt};
+
+/// Sequence number signalling replace-by-fee opt-in (BIP-125).
+const RBF_SEQ_NO: u32 = 0xFFFF_FFFD;
+
+/// Errors in constructing PSBT from a [`PsbtRecipe`].
+#[derive(Debug, Display, From)]
+#[display(doc_comments)]
+pub enum RecipeError {
+    /// {0}
+    #[from]
+    Construct(Error),
+
+    /// unable to resolve amount of the input {0}; transaction is not known
+    #[from]
+    ResolvingTx(TxResolverError),
+
+    /// input {0} spends unknown transaction output
+    OutputUnknown(OutPoint),
+
+    /// wallet coins with total amount of {available} sats are insufficient
+    /// to pay {required} sats to the recipients and miners
+    InsufficientFunds {
+        /// Total amount of the wallet coins
+        available: u64,
+
+        /// Amount required by the recipe
+        required: u64,
+    },
+
+    /// total amount paid by the recipe exceeds the maximum possible value
+    AmountOverflow,
+
+    /// {0}
+    #[from]
+    FeeSplit(FeeSplitError),
+
+    /// {0}
+    #[from]
+    ProprietaryKey(ProprietaryKeyError),
+}
+
+impl std::error::Error for RecipeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecipeError::Construct(err) => Some(err),
+            RecipeError::ResolvingTx(err) => Some(err),
+            RecipeError::OutputUnknown(_) => None,
+            RecipeError::InsufficientFunds { .. } => None,
+            RecipeError::AmountOverflow => None,
+            RecipeError::FeeSplit(err) => Some(err),
+            RecipeError::ProprietaryKey(err) => Some(err),
+        }
+    }
+}
+
+/// Inputs spent by a recipe transaction.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", try_from = "InputsRepr", into = "InputsRepr")
+)]
+pub enum InputSelection {
+    /// Select wallet coins automatically, largest first
+    Auto,
+
+    /// Spend the listed inputs
+    Explicit(Vec<InputDescriptor>),
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", untagged)]
+enum InputsRepr {
+    Keyword(String),
+    List(#[serde(with = "As::<Vec<DisplayFromStr>>")] Vec<InputDescriptor>),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<InputsRepr> for InputSelection {
+    type Error = String;
+
+    fn try_from(repr: InputsRepr) -> Result<Self, Self::Error> {
+        match repr {
+            InputsRepr::Keyword(keyword) if keyword == "auto" => Ok(InputSelection::Auto),
+            InputsRepr::Keyword(keyword) => Err(format!(
+                "unknown input selection `{}`; use either `auto` or a list of input descriptors",
+                keyword
+            )),
+            InputsRepr::List(inputs) => Ok(InputSelection::Explicit(inputs)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<InputSelection> for InputsRepr {
+    fn from(selection: InputSelection) -> Self {
+        match selection {
+            InputSelection::Auto => InputsRepr::Keyword(s!("auto")),
+            InputSelection::Explicit(inputs) => InputsRepr::List(inputs),
+        }
+    }
+}
+
+/// Payment to a recipient.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Recipient {
+    /// Recipient address
+    pub address: Address,
+
+    /// Amount paid, in satoshis
+    pub amount: u64,
+}
+
+/// Policy for the change output.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ChangePolicy {
+    /// Derivation index of the change address
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub index: UnhardenedIndex,
+
+    /// Change below this amount, in satoshis, is not worth an output and is
+    /// added to the fee instead
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dust_limit: u64,
+}
+
+/// Declarative specification of a transaction to construct.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct PsbtRecipe {
+    /// Spent inputs
+    pub inputs: InputSelection,
+
+    /// Transaction recipients
+    pub recipients: Vec<Recipient>,
+
+    /// Fee paid to the miners, in satoshis
+    pub fee: u64,
+
+    /// Change output policy
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub change: ChangePolicy,
+
+    /// Outputs paying the fee. Recipient outputs falling below the change
+    /// dust limit after the fee deduction are rejected.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fee_deduction: FeeDeduction,
+
+    /// Fallback lock time of the transaction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lock_time: Option<LockTime>,
+
+    /// Whether inputs using default sequence number should signal
+    /// replace-by-fee opt-in
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rbf: bool,
+
+    /// Proprietary keys added to the PSBT, for instance commitment data
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "As::<Vec<DisplayFromStr>>")
+    )]
+    pub proprietary: Vec<ProprietaryKeyDescriptor>,
+}
+
+impl PsbtRecipe {
+    /// Total amount required by the recipe: amounts paid to the recipients and
+    /// the fee, unless the fee is paid by the recipients.
+    ///
+    /// # Errors
+    ///
+    /// If the amount overflows 64-bit integer (which may happen only with a
+    /// malformed recipe).
+    pub fn required_amount(&self) -> Result<u64, RecipeError> {
+        let sent = self
+            .recipients
+            .iter()
+            .try_fold(0u64, |sum, recipient| sum.checked_add(recipient.amount))
+            .ok_or(RecipeError::AmountOverflow)?;
+        if self.fee_deduction.is_paid_by_recipients() {
+            Ok(sent)
+        } else {
+            sent.checked_add(self.fee).ok_or(RecipeError::AmountOverflow)
+        }
+    }
+
+    /// Constructs PSBT following the recipe (see
+    /// [`Psbt::construct_with_policy`]). `coins` are wallet coins with their
+    /// amounts, in satoshis, which are used for the automatic input
+    /// selection.
+    pub fn construct<'coins>(
+        &self,
+        descriptor: &Descriptor<DerivationAccount>,
+        coins: impl IntoIterator<Item = &'coins (InputDescriptor, u64)>,
+        fee_policy: &MaxFeePolicy,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, RecipeError> {
+        let required = self.required_amount()?;
+        let (mut inputs, available) = match &self.inputs {
+            InputSelection::Auto => select_coins(coins, required)?,
+            InputSelection::Explicit(inputs) => {
+                let mut available = 0u64;
+                for input in inputs {
+                    let tx = tx_resolver.resolve_tx(input.outpoint.txid)?;
+                    available += tx
+                        .output
+                        .get(input.outpoint.vout as usize)
+                        .ok_or(RecipeError::OutputUnknown(input.outpoint))?
+                        .value;
+                }
+                (inputs.clone(), available)
+            }
+        };
+        if available < required {
+            return Err(RecipeError::InsufficientFunds {
+                available,
+                required,
+            });
+        }
+
+        if self.rbf {
+            for input in inputs
+                .iter_mut()
+                .filter(|input| input.seq_no == SeqNo::unencumbered(true))
+            {
+                input.seq_no = SeqNo::from_consensus(RBF_SEQ_NO);
+            }
+        }
+
+        let mut fee = self.fee;
+        let change = available - required;
+        if change < self.change.dust_limit {
+            fee += change;
+        }
+
+        let mut outputs = self
+            .recipients
+            .iter()
+            .map(|recipient| {
+                (PubkeyScript::from(recipient.address.script_pubkey()), recipient.amount)
+            })
+            .collect::<Vec<_>>();
+        self.fee_deduction.deduct(&mut outputs, self.fee, self.change.dust_limit)?;
+        let mut psbt = Psbt::construct_with_policy(
+            descriptor,
+            &inputs,
+            &outputs,
+            self.change.index,
+            fee,
+            fee_policy,
+            tx_resolver,
+        )?;
+        if self.lock_time.is_some() {
+            psbt.fallback_locktime = self.lock_time;
+        }
+        for key in &self.proprietary {
+            psbt.insert_proprietary_key(key)?;
+        }
+        Ok(psbt)
+    }
+}
+
+/// Selects coins covering the `required` amount, largest coins first.
+fn select_coins<'coins>(
+    coins: impl IntoIterator<Item = &'coins (InputDescriptor, u64)>,
+    required: u64,
+) -> Result<(Vec<InputDescriptor>, u64), RecipeError> {
+    let mut coins = coins.into_iter().collect::<Vec<_>>();
+    coins.sort_by(|(a, a_amount), (b, b_amount)| {
+        b_amount.cmp(a_amount).then_with(|| a.outpoint.cmp(&b.outpoint))
+    });
+    let mut selected = vec![];
+    let mut total = 0u64;
+    for (input, amount) in coins {
+        if total >= required {
+            break;
+        }
+        total += amount;
+        selected.push(input.clone());
+    }
+    if total < required {
+        return Err(RecipeError::InsufficientFunds {
+            available: total,
+            required,
+        });
+    }
+    Ok((selected, total))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use bitcoin::secp256k1::SECP256K1;
+    use bitcoin::{Network, Transaction, Txid};
+    use bitcoin_hd::SegmentIndexes;
+    use descriptors::derive::DeriveDescriptor;
+
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn auto_inputs() {
+        let account = DerivationAccount::from_str(
+            "[d34db33f/84h/0h/0h]xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ/<0;1>/*",
+        )
+        .unwrap();
+        let descriptor = Descriptor::new_wpkh(account).unwrap();
+        let script = |index: u8| {
+            DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
+                &descriptor,
+                SECP256K1,
+                [UnhardenedIndex::zero(), UnhardenedIndex::from(index)],
+            )
+            .unwrap()
+            .script_pubkey()
+        };
+        let funding = fixtures::tx([(10_000, script(0)), (50_000, script(1))]);
+        let resolver: BTreeMap<Txid, Transaction> = bmap! { funding.txid() => funding.clone() };
+        let coins = [
+            (InputDescriptor::from_str(&format!("{}:0 /0/0", funding.txid())).unwrap(), 10_000),
+            (InputDescriptor::from_str(&format!("{}:1 /0/1", funding.txid())).unwrap(), 50_000),
+        ];
+
+        let address = Address::p2wpkh(
+            &bitcoin::PublicKey::from_str(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+            Network::Bitcoin,
+        )
+        .unwrap();
+        let mut recipe = PsbtRecipe {
+            inputs: InputSelection::Auto,
+            recipients: vec![Recipient {
+                address,
+                amount: 40_000,
+            }],
+            fee: 500,
+            change: ChangePolicy {
+                index: UnhardenedIndex::from(2u8),
+                dust_limit: 546,
+            },
+            fee_deduction: FeeDeduction::Change,
+            lock_time: None,
+            rbf: true,
+            proprietary: vec![
+                ProprietaryKeyDescriptor::from_str("output(0) DBC(1) :0102").unwrap()
+            ],
+        };
+        let psbt = recipe
+            .construct(&descriptor, &coins, &MaxFeePolicy::default(), &resolver)
+            .unwrap();
+        assert_eq!(psbt.inputs.len(), 1);
+        assert_eq!(psbt.inputs[0].previous_outpoint.vout, 1);
+        assert_eq!(psbt.inputs[0].sequence_number, Some(SeqNo::from_consensus(RBF_SEQ_NO)));
+        assert_eq!(psbt.outputs.len(), 2);
+        assert_eq!(psbt.outputs[1].amount, 9_500);
+        assert_eq!(psbt.outputs[0].proprietary.len(), 1);
+
+        recipe.recipients[0].amount = 49_200;
+        let psbt = recipe
+            .construct(&descriptor, &coins, &MaxFeePolicy::default(), &resolver)
+            .unwrap();
+        assert_eq!(psbt.outputs.len(), 1);
+        assert_eq!(psbt.fee(), Ok(800));
+
+        recipe.recipients[0].amount = 60_000;
+        assert!(matches!(
+            recipe.construct(&descriptor, &coins, &MaxFeePolicy::default(), &resolver),
+            Err(RecipeError::InsufficientFunds {
+                available: 60_000,
+                required: 60_500
+            })
+        ));
+
+        recipe.recipients[0].amount = u64::MAX - 100;
+        assert!(matches!(recipe.required_amount(), Err(RecipeError::AmountOverflow)));
+        assert!(matches!(
+            recipe.construct(&descriptor, &coins, &MaxFeePolicy::default(), &resolver),
+            Err(RecipeError::AmountOverflow)
+        ));
+
+        recipe.recipients[0].amount = 50_000;
+        recipe.fee_deduction = FeeDeduction::Proportional;
+        let psbt = recipe
+            .construct(&descriptor, &coins, &MaxFeePolicy::default(), &resolver)
+            .unwrap();
+        assert_eq!(psbt.inputs.len(), 1);
+        assert_eq!(psbt.outputs.len(), 1);
+        assert_eq!(psbt.outputs[0].amount, 49_500);
+        assert_eq!(psbt.fee(), Ok(500));
+    }
+}
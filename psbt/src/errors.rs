@@ -9,6 +9,7 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
+use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey, Fingerprint};
 use bitcoin::Txid;
 
 /// Errors during [`Input`](super::Input) construction from an unsigned
@@ -41,6 +42,49 @@ pub enum TxError {
     InvalidTxVersion(i32),
 }
 
+/// Errors computing transaction lock time from per-input lock time
+/// requirements (BIP-370).
+#[derive(
+    Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error
+)]
+#[display(doc_comments)]
+pub enum LockTimeError {
+    /// PSBT has no input #{0}
+    UnknownInput(usize),
+
+    /// input #{time_input} requires time-based lock time, while input
+    /// #{height_input} requires height-based lock time, so the transaction
+    /// can't satisfy both of them
+    Unsatisfiable {
+        /// Index of an input supporting only time-based lock time
+        time_input: usize,
+
+        /// Index of an input supporting only height-based lock time
+        height_input: usize,
+    },
+}
+
+/// Errors converting PSBT into version 0 representation (BIP-174), which
+/// requires the complete unsigned transaction (see
+/// [`Psbt::into_v0`](super::Psbt::into_v0)).
+#[derive(
+    Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error, From
+)]
+#[display(doc_comments)]
+pub enum PsbtV0Error {
+    /// PSBT has no inputs, while version 0 requires unsigned transaction
+    /// having at least one input
+    NoInputs,
+
+    /// input #{0} does not specify previous transaction output, which is
+    /// required for the unsigned transaction input
+    NoPreviousOutpoint(usize),
+
+    /// unable to compute the unsigned transaction lock time. {0}
+    #[from]
+    LockTime(LockTimeError),
+}
+
 /// Errors happening when PSBT or other resolver information does not match the
 /// structure of bitcoin transaction
 #[derive(
@@ -73,3 +117,49 @@ pub enum FeeError {
     /// Sum of inputs is less than sum of outputs
     InputsLessThanOutputs,
 }
+
+/// Errors happening when global extended public keys of the PSBT do not match
+/// the key derivation information from its inputs
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum XpubMismatchError {
+    /// global extended public key {xpub} has depth {depth} which does not
+    /// match the length of its derivation path {path}
+    DepthMismatch {
+        /// Extended public key from the global PSBT map
+        xpub: ExtendedPubKey,
+
+        /// Depth of the extended public key
+        depth: u8,
+
+        /// Derivation path provided for the key
+        path: DerivationPath,
+    },
+
+    /// input #{input} key with derivation path {path} from the master key
+    /// [{fingerprint}] is not derivable from any of the global extended public
+    /// keys with the same master fingerprint
+    NoMatchingXpub {
+        /// Index of the input
+        input: usize,
+
+        /// Master key fingerprint of the input key
+        fingerprint: Fingerprint,
+
+        /// Derivation path of the input key
+        path: DerivationPath,
+    },
+
+    /// input #{input} key with derivation path {path} does not match the key
+    /// derived from the global extended public key {xpub}
+    KeyMismatch {
+        /// Index of the input
+        input: usize,
+
+        /// Global extended public key used in derivation
+        xpub: ExtendedPubKey,
+
+        /// Derivation path of the input key
+        path: DerivationPath,
+    },
+}
@@ -0,0 +1,242 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Protection against absurd transaction fees, which may result from fee
+//! calculation bugs.
+//!
+//! The maximum fee computed by the constructor is recorded in a global
+//! proprietary PSBT key, so hardware signers can display it to the user.
+
+use crate::raw::ProprietaryKey;
+use crate::{FeeError, Psbt};
+
+/// Proprietary key prefix used for the fee policy data.
+pub const PSBT_FEE_POLICY_PREFIX: &[u8] = b"FEE";
+/// Proprietary global key subtype storing maximum fee allowed for the
+/// transaction, as 8-byte little-endian number of satoshis.
+pub const PSBT_GLOBAL_MAX_FEE: u8 = 0;
+
+/// Maximum absolute fee used by the default [`MaxFeePolicy`], in satoshis.
+pub const DEFAULT_MAX_FEE: u64 = 10_000_000;
+
+/// Errors happening when transaction fee is checked against [`MaxFeePolicy`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum FeePolicyError {
+    /// unable to compute transaction fee: {0}
+    #[from]
+    Fee(FeeError),
+
+    /// transaction fee of {fee} sats exceeds maximum of {max} sats allowed by
+    /// the fee policy
+    AbsurdFee {
+        /// Transaction fee
+        fee: u64,
+
+        /// Maximum fee allowed by the policy
+        max: u64,
+    },
+}
+
+/// Limits on the transaction fee. A fee is allowed if it satisfies all of the
+/// provided limits.
+///
+/// The default policy limits the fee to 0.1 BTC, matching Bitcoin Core
+/// `maxtxfee` default.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MaxFeePolicy {
+    /// Maximum absolute fee, in satoshis
+    pub absolute: Option<u64>,
+
+    /// Maximum fee as a percentage of the total amount of transaction outputs
+    pub percentage: Option<f64>,
+
+    /// Maximum fee rate, in satoshis per virtual byte
+    pub fee_rate: Option<f64>,
+}
+
+impl Default for MaxFeePolicy {
+    fn default() -> Self {
+        MaxFeePolicy {
+            absolute: Some(DEFAULT_MAX_FEE),
+            percentage: None,
+            fee_rate: None,
+        }
+    }
+}
+
+impl MaxFeePolicy {
+    /// Policy without limits
+    #[inline]
+    pub fn unlimited() -> MaxFeePolicy {
+        MaxFeePolicy {
+            absolute: None,
+            percentage: None,
+            fee_rate: None,
+        }
+    }
+
+    /// Computes maximum fee allowed for a transaction with the given total
+    /// amount of outputs and virtual size, or `None` if the policy has no
+    /// limits.
+    pub fn max_fee(&self, output_amount: u64, vsize: u64) -> Option<u64> {
+        [
+            self.absolute,
+            self.percentage
+                .map(|percentage| (output_amount as f64 * percentage / 100.0) as u64),
+            self.fee_rate.map(|fee_rate| (vsize as f64 * fee_rate) as u64),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Checks that the fee is allowed for a transaction with the given total
+    /// amount of outputs and virtual size.
+    pub fn check(&self, fee: u64, output_amount: u64, vsize: u64) -> Result<(), FeePolicyError> {
+        match self.max_fee(output_amount, vsize) {
+            Some(max) if fee > max => Err(FeePolicyError::AbsurdFee { fee, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Psbt {
+    /// Records maximum fee allowed for the transaction in the global
+    /// proprietary key.
+    pub fn set_max_fee(&mut self, max_fee: u64) {
+        self.proprietary.insert(
+            ProprietaryKey {
+                prefix: PSBT_FEE_POLICY_PREFIX.to_vec(),
+                subtype: PSBT_GLOBAL_MAX_FEE,
+                key: vec![],
+            },
+            max_fee.to_le_bytes().to_vec(),
+        );
+    }
+
+    /// Returns maximum fee recorded with [`Psbt::set_max_fee`], if any.
+    pub fn max_fee(&self) -> Option<u64> {
+        self.proprietary
+            .get(&ProprietaryKey {
+                prefix: PSBT_FEE_POLICY_PREFIX.to_vec(),
+                subtype: PSBT_GLOBAL_MAX_FEE,
+                key: vec![],
+            })
+            .and_then(|value| <[u8; 8]>::try_from(value.as_slice()).ok())
+            .map(u64::from_le_bytes)
+    }
+
+    /// Estimates virtual size of the signed transaction by adding weight of
+    /// the input satisfactions (script sigs, witnesses and segwit marker) to
+    /// the weight of the unsigned transaction.
+    pub fn estimate_vsize(&self, satisfaction_weight: usize) -> u64 {
+        // Transaction weight does not depend on the lock time value
+        let tx = self.unsigned_tx_with(self.lock_time());
+        ((tx.weight() + satisfaction_weight + 3) / 4) as u64
+    }
+
+    /// Computes maximum fee allowed for the transaction by the policy. The fee
+    /// rate limit is applied to the virtual size of the signed transaction
+    /// estimated with the provided `satisfaction_weight` of all inputs (see
+    /// [`Psbt::estimate_vsize`]); if the weight is unknown, zero can be used,
+    /// which makes the limit conservative.
+    pub fn policy_max_fee(&self, policy: &MaxFeePolicy, satisfaction_weight: usize) -> Option<u64> {
+        let output_amount = self.outputs.iter().map(|output| output.amount).sum();
+        policy.max_fee(output_amount, self.estimate_vsize(satisfaction_weight))
+    }
+
+    /// Checks transaction fee against both the maximum fee recorded in PSBT
+    /// and the provided policy; see [`Psbt::policy_max_fee`] for the meaning
+    /// of `satisfaction_weight`.
+    pub fn check_fee_policy(
+        &self,
+        policy: &MaxFeePolicy,
+        satisfaction_weight: usize,
+    ) -> Result<(), FeePolicyError> {
+        let fee = self.fee()?;
+        let max = [self.max_fee(), self.policy_max_fee(policy, satisfaction_weight)]
+            .into_iter()
+            .flatten()
+            .min();
+        match max {
+            Some(max) if fee > max => Err(FeePolicyError::AbsurdFee { fee, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::TxOut;
+
+    use super::*;
+    use crate::{fixtures, PsbtVersion};
+
+    #[test]
+    fn max_fee() {
+        assert_eq!(MaxFeePolicy::unlimited().max_fee(100_000, 200), None);
+        assert_eq!(MaxFeePolicy::default().max_fee(100_000, 200), Some(DEFAULT_MAX_FEE));
+        let policy = MaxFeePolicy {
+            absolute: Some(10_000),
+            percentage: Some(5.0),
+            fee_rate: Some(20.0),
+        };
+        assert_eq!(policy.max_fee(1_000_000, 200), Some(4_000));
+        assert_eq!(policy.max_fee(50_000, 200), Some(2_500));
+        assert_eq!(policy.max_fee(1_000_000, 1_000), Some(10_000));
+        assert_eq!(policy.check(4_001, 1_000_000, 200), Err(FeePolicyError::AbsurdFee {
+            fee: 4_001,
+            max: 4_000
+        }));
+        assert_eq!(policy.check(4_000, 1_000_000, 200), Ok(()));
+    }
+
+    #[test]
+    fn recorded_max_fee() {
+        let tx = fixtures::tx([(90_000, default!())]);
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: default!(),
+        });
+
+        assert_eq!(psbt.max_fee(), None);
+        assert_eq!(psbt.check_fee_policy(&MaxFeePolicy::unlimited(), 0), Ok(()));
+
+        let policy = MaxFeePolicy {
+            absolute: None,
+            percentage: None,
+            fee_rate: Some(100.0),
+        };
+        let vsize = psbt.to_unsigned_tx().unwrap().vsize() as u64;
+        assert_eq!(psbt.estimate_vsize(0), vsize);
+        assert_eq!(psbt.estimate_vsize(4 * 100), vsize + 100);
+        assert!(psbt.check_fee_policy(&policy, 0).is_err());
+        assert_eq!(psbt.policy_max_fee(&policy, 4 * 100), Some((vsize + 100) * 100));
+        assert_eq!(psbt.check_fee_policy(&policy, 4 * 100), Ok(()));
+
+        psbt.set_max_fee(5_000);
+        assert_eq!(psbt.max_fee(), Some(5_000));
+        assert_eq!(
+            psbt.check_fee_policy(&MaxFeePolicy::unlimited(), 0),
+            Err(FeePolicyError::AbsurdFee {
+                fee: 10_000,
+                max: 5_000
+            })
+        );
+    }
+}
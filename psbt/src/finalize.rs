@@ -0,0 +1,589 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Finalization of PSBT inputs according to the satisfaction templates (see
+//! [`SatisfactionTemplate`]), allowing spendings of arbitrary `wsh` and
+//! tapscript miniscript policies.
+
+use std::collections::BTreeMap;
+
+use amplify::Wrapper;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::util::bip32::KeySource;
+use bitcoin::util::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{EcdsaSig, SchnorrSig, Script, Sequence, Witness, XOnlyPublicKey};
+use bitcoin_blockchain::locks::LockTime;
+use descriptors::{SatisfactionTemplate, TemplatedInput};
+use miniscript::{
+    Miniscript, MiniscriptKey, Preimage32, Satisfier, ScriptContext, Segwitv0, Tap, Terminal,
+    ToPublicKey,
+};
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::raw::ProprietaryKey;
+use crate::{Input, Psbt};
+
+pub const PSBT_SATISFACTION_PREFIX: &[u8] = b"SATISFY";
+pub const PSBT_IN_SATISFACTION_TEMPLATE: u8 = 0;
+
+/// Errors finalizing PSBT inputs with satisfaction templates.
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum FinalizeError {
+    /// input #{0} contains invalid satisfaction template data
+    TemplateEncoding(usize),
+
+    /// input #{0} satisfaction template requires taproot leaf {1}, which is
+    /// not known to the PSBT
+    UnknownLeaf(usize, TapLeafHash),
+
+    /// input #{0} has no witness script or taproot internal key required for
+    /// templated satisfaction
+    NoScript(usize),
+
+    /// input #{0} script is not a valid miniscript. Details: {1}
+    Miniscript(usize, String),
+
+    /// input #{0} can't be satisfied with the signatures and preimages allowed
+    /// by its satisfaction template
+    Unsatisfiable(usize),
+
+    /// input #{0} is spent with taproot key path, but has no key signature
+    NoKeySig(usize),
+}
+
+impl Input {
+    /// Stores satisfaction template in the input proprietary key.
+    pub fn set_satisfaction_template(&mut self, template: &SatisfactionTemplate) {
+        self.proprietary.insert(
+            ProprietaryKey {
+                prefix: PSBT_SATISFACTION_PREFIX.to_vec(),
+                subtype: PSBT_IN_SATISFACTION_TEMPLATE,
+                key: vec![],
+            },
+            template
+                .strict_serialize()
+                .expect("in-memory strict encoding failure"),
+        );
+    }
+
+    /// Returns satisfaction template stored in the input proprietary key, if
+    /// any.
+    pub fn satisfaction_template(&self) -> Result<Option<SatisfactionTemplate>, FinalizeError> {
+        self.proprietary
+            .get(&ProprietaryKey {
+                prefix: PSBT_SATISFACTION_PREFIX.to_vec(),
+                subtype: PSBT_IN_SATISFACTION_TEMPLATE,
+                key: vec![],
+            })
+            .map(SatisfactionTemplate::strict_deserialize)
+            .transpose()
+            .map_err(|_| FinalizeError::TemplateEncoding(self.index))
+    }
+
+    /// Adds preimage to the input preimage fields matching hash locks of the
+    /// input witness script and taproot leaf scripts, such that it can be used
+    /// by the finalizer. Returns the number of the matched hash locks.
+    pub fn provide_preimage(&mut self, preimage: Preimage32) -> usize {
+        let mut locks = vec![];
+        if let Some(witness_script) = &self.witness_script {
+            let script = witness_script.as_inner();
+            if let Ok(ms) = Miniscript::<bitcoin::PublicKey, Segwitv0>::parse(script) {
+                locks.extend(hash_locks(&ms));
+            }
+        }
+        for (script, _) in self.tap_scripts.values() {
+            if let Ok(ms) = Miniscript::<XOnlyPublicKey, Tap>::parse(script) {
+                locks.extend(hash_locks(&ms));
+            }
+        }
+
+        let mut count = 0usize;
+        for lock in locks {
+            let matched = match lock {
+                HashLock::Sha256(hash) if hash == sha256::Hash::hash(&preimage) => {
+                    self.sha256_preimages.insert(hash, preimage.to_vec());
+                    true
+                }
+                HashLock::Hash256(hash) if hash == sha256d::Hash::hash(&preimage) => {
+                    self.hash256_preimages.insert(hash, preimage.to_vec());
+                    true
+                }
+                HashLock::Ripemd160(hash) if hash == ripemd160::Hash::hash(&preimage) => {
+                    self.ripemd160_preimages.insert(hash, preimage.to_vec());
+                    true
+                }
+                HashLock::Hash160(hash) if hash == hash160::Hash::hash(&preimage) => {
+                    self.hash160_preimages.insert(hash, preimage.to_vec());
+                    true
+                }
+                _ => false,
+            };
+            count += matched as usize;
+        }
+        count
+    }
+
+    /// Finalizes input by assembling witness from the collected signatures and
+    /// preimages according to the `template`. The `tx_version` and `lock_time`
+    /// are the transaction version and lock time used to check satisfaction of
+    /// relative and absolute timelocks.
+    pub fn finalize_with_template(
+        &mut self,
+        template: &SatisfactionTemplate,
+        tx_version: u32,
+        lock_time: LockTime,
+    ) -> Result<(), FinalizeError> {
+        let index = self.index;
+        let satisfier = TemplateSatisfier {
+            input: self,
+            template,
+            tx_version,
+            lock_time,
+        };
+        let miniscript_err =
+            |err: miniscript::Error| FinalizeError::Miniscript(index, err.to_string());
+
+        let witness = if let Some(leaf) = template.leaf {
+            let (control_block, (script, _)) = self
+                .tap_scripts
+                .iter()
+                .find(|(_, (script, version))| TapLeafHash::from_script(script, *version) == leaf)
+                .ok_or(FinalizeError::UnknownLeaf(index, leaf))?;
+            let ms = Miniscript::<XOnlyPublicKey, Tap>::parse(script).map_err(miniscript_err)?;
+            let mut stack = ms
+                .satisfy(&satisfier)
+                .map_err(|_| FinalizeError::Unsatisfiable(index))?;
+            stack.push(script.to_bytes());
+            stack.push(control_block.serialize());
+            stack
+        } else if let Some(witness_script) = &self.witness_script {
+            let ms = Miniscript::<bitcoin::PublicKey, Segwitv0>::parse(witness_script.as_inner())
+                .map_err(miniscript_err)?;
+            let mut stack = ms
+                .satisfy(&satisfier)
+                .map_err(|_| FinalizeError::Unsatisfiable(index))?;
+            stack.push(witness_script.as_inner().to_bytes());
+            stack
+        } else if let Some(internal_key) = self.tap_internal_key {
+            let origin = self
+                .tap_key_origins
+                .get(&internal_key)
+                .map(|(_, origin)| origin);
+            if !satisfier.is_signer(origin) {
+                return Err(FinalizeError::Unsatisfiable(index));
+            }
+            let sig = self.tap_key_sig.ok_or(FinalizeError::NoKeySig(index))?;
+            vec![sig.to_vec()]
+        } else {
+            return Err(FinalizeError::NoScript(index));
+        };
+
+        if template.leaf.is_none() {
+            if let Some(redeem_script) = &self.redeem_script {
+                let script_sig = Builder::new()
+                    .push_slice(redeem_script.as_inner().as_bytes())
+                    .into_script();
+                self.final_script_sig = Some(script_sig.into());
+            }
+        }
+        self.final_script_witness = Some(Witness::from_vec(witness));
+        self.finalize_tap_annex();
+        Ok(())
+    }
+}
+
+impl Psbt {
+    /// Stores satisfaction templates of the templated inputs in the PSBT
+    /// inputs spending the same outpoints (see
+    /// [`Input::set_satisfaction_template`]), returning the number of the
+    /// inputs which received a template. Empty templates are skipped.
+    pub fn set_satisfaction_templates<'t>(
+        &mut self,
+        templates: impl IntoIterator<Item = &'t TemplatedInput>,
+    ) -> usize {
+        let mut count = 0usize;
+        for templated in templates {
+            if templated.template.is_empty() {
+                continue;
+            }
+            for input in &mut self.inputs {
+                if input.previous_outpoint == templated.input.outpoint {
+                    input.set_satisfaction_template(&templated.template);
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// Finalizes all non-finalized inputs having satisfaction templates (see
+    /// [`Input::set_satisfaction_template`]), returning the number of the
+    /// finalized inputs. Inputs without templates are left intact and may be
+    /// finalized with the standard miniscript finalizer.
+    pub fn finalize_templated(&mut self) -> Result<usize, FinalizeError> {
+        let tx_version = self.tx_version;
+        let lock_time = self.lock_time();
+        let mut count = 0usize;
+        for input in &mut self.inputs {
+            if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+                continue;
+            }
+            if let Some(template) = input.satisfaction_template()? {
+                input.finalize_with_template(&template, tx_version, lock_time)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Adds preimage to all inputs having matching hash locks (see
+    /// [`Input::provide_preimage`]), returning the total number of the
+    /// matched hash locks.
+    pub fn provide_preimage(&mut self, preimage: Preimage32) -> usize {
+        self.inputs
+            .iter_mut()
+            .map(|input| input.provide_preimage(preimage))
+            .sum()
+    }
+}
+
+enum HashLock {
+    Sha256(sha256::Hash),
+    Hash256(sha256d::Hash),
+    Ripemd160(ripemd160::Hash),
+    Hash160(hash160::Hash),
+}
+
+fn hash_locks<Pk, Ctx>(ms: &Miniscript<Pk, Ctx>) -> Vec<HashLock>
+where
+    Pk: MiniscriptKey + ToPublicKey,
+    Ctx: ScriptContext,
+{
+    ms.iter()
+        .filter_map(|node| match &node.node {
+            Terminal::Sha256(hash) => Some(HashLock::Sha256(Pk::to_sha256(hash))),
+            Terminal::Hash256(hash) => Some(HashLock::Hash256(sha256d::Hash::from_inner(
+                Pk::to_hash256(hash).into_inner(),
+            ))),
+            Terminal::Ripemd160(hash) => Some(HashLock::Ripemd160(Pk::to_ripemd160(hash))),
+            Terminal::Hash160(hash) => Some(HashLock::Hash160(Pk::to_hash160(hash))),
+            _ => None,
+        })
+        .collect()
+}
+
+struct TemplateSatisfier<'a> {
+    input: &'a Input,
+    template: &'a SatisfactionTemplate,
+    tx_version: u32,
+    lock_time: LockTime,
+}
+
+impl<'a> TemplateSatisfier<'a> {
+    fn is_signer(&self, origin: Option<&KeySource>) -> bool {
+        self.template.signers.is_empty()
+            || origin
+                .map(|(fingerprint, _)| self.template.signers.contains(fingerprint))
+                .unwrap_or_default()
+    }
+
+    /// Returns preimage if it is allowed to be revealed by the template.
+    fn preimage(&self, preimage: Option<&Vec<u8>>) -> Option<Preimage32> {
+        let preimage = Preimage32::try_from(preimage?.as_slice()).ok()?;
+        self.template
+            .preimages
+            .contains(&sha256::Hash::hash(&preimage))
+            .then_some(preimage)
+    }
+}
+
+impl<'a, Pk> Satisfier<Pk> for TemplateSatisfier<'a>
+where
+    Pk: MiniscriptKey + ToPublicKey,
+{
+    fn lookup_ecdsa_sig(&self, pk: &Pk) -> Option<EcdsaSig> {
+        let pk = pk.to_public_key();
+        if !self.is_signer(self.input.bip32_derivation.get(&pk.inner)) {
+            return None;
+        }
+        self.input.partial_sigs.get(&pk).copied()
+    }
+
+    fn lookup_tap_leaf_script_sig(&self, pk: &Pk, leaf_hash: &TapLeafHash) -> Option<SchnorrSig> {
+        let pk = pk.to_x_only_pubkey();
+        if self.template.leaf != Some(*leaf_hash)
+            || !self.is_signer(
+                self.input
+                    .tap_key_origins
+                    .get(&pk)
+                    .map(|(_, origin)| origin),
+            )
+        {
+            return None;
+        }
+        self.input.tap_script_sigs.get(&(pk, *leaf_hash)).copied()
+    }
+
+    fn lookup_tap_control_block_map(
+        &self,
+    ) -> Option<&BTreeMap<ControlBlock, (Script, LeafVersion)>> {
+        Some(&self.input.tap_scripts)
+    }
+
+    fn lookup_sha256(&self, hash: &Pk::Sha256) -> Option<Preimage32> {
+        self.preimage(self.input.sha256_preimages.get(&Pk::to_sha256(hash)))
+    }
+
+    fn lookup_hash256(&self, hash: &Pk::Hash256) -> Option<Preimage32> {
+        let hash = sha256d::Hash::from_inner(Pk::to_hash256(hash).into_inner());
+        self.preimage(self.input.hash256_preimages.get(&hash))
+    }
+
+    fn lookup_ripemd160(&self, hash: &Pk::Ripemd160) -> Option<Preimage32> {
+        self.preimage(self.input.ripemd160_preimages.get(&Pk::to_ripemd160(hash)))
+    }
+
+    fn lookup_hash160(&self, hash: &Pk::Hash160) -> Option<Preimage32> {
+        self.preimage(self.input.hash160_preimages.get(&Pk::to_hash160(hash)))
+    }
+
+    fn check_older(&self, n: Sequence) -> bool {
+        let seq = Sequence(
+            self.input
+                .sequence_number
+                .unwrap_or_default()
+                .into_consensus(),
+        );
+        // BIP-68 relative timelocks are enforced only for transaction version 2+
+        self.tx_version >= 2
+            && seq.is_relative_lock_time()
+            && n.is_relative_lock_time()
+            && seq.is_height_locked() == n.is_height_locked()
+            && (seq.0 & 0xFFFF) >= (n.0 & 0xFFFF)
+    }
+
+    fn check_after(&self, n: bitcoin::LockTime) -> bool {
+        let seq = Sequence(
+            self.input
+                .sequence_number
+                .unwrap_or_default()
+                .into_consensus(),
+        );
+        let lock_time = bitcoin::LockTime::from_consensus(self.lock_time.into_consensus());
+        !seq.is_final()
+            && lock_time.is_same_unit(n)
+            && lock_time.to_consensus_u32() >= n.to_consensus_u32()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::{KeyPair, Message, Secp256k1, SecretKey};
+    use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+    use bitcoin::{EcdsaSighashType, SchnorrSighashType};
+    use bitcoin_blockchain::locks::SeqNo;
+    use bitcoin_scripts::WitnessScript;
+
+    use super::*;
+
+    #[test]
+    fn template_selects_branch() {
+        let secp = Secp256k1::new();
+        let keys = [[1u8; 32], [2u8; 32]].map(|sk| {
+            let sk = SecretKey::from_slice(&sk).unwrap();
+            (sk, bitcoin::PublicKey::new(sk.public_key(&secp)))
+        });
+        let preimage = [7u8; 32];
+        let hash = sha256::Hash::hash(&preimage);
+        let ms = Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str_insane(&format!(
+            "or_d(pk({}),and_v(v:pk({}),sha256({})))",
+            keys[0].1, keys[1].1, hash
+        ))
+        .unwrap();
+
+        let mut input = Input {
+            witness_script: Some(WitnessScript::from(ms.encode())),
+            ..default!()
+        };
+        let msg = Message::from_slice(&[3u8; 32]).unwrap();
+        for (index, (sk, pk)) in keys.iter().enumerate() {
+            let sig = EcdsaSig {
+                sig: secp.sign_ecdsa(&msg, sk),
+                hash_ty: EcdsaSighashType::All,
+            };
+            input.partial_sigs.insert(*pk, sig);
+            let fingerprint = Fingerprint::from(&[index as u8; 4][..]);
+            input
+                .bip32_derivation
+                .insert(pk.inner, (fingerprint, DerivationPath::master()));
+        }
+        input.sha256_preimages.insert(hash, preimage.to_vec());
+
+        let template = SatisfactionTemplate {
+            signers: bset![Fingerprint::from(&[1u8; 4][..])],
+            preimages: bset![hash],
+            leaf: None,
+        };
+        input.set_satisfaction_template(&template);
+        assert_eq!(input.satisfaction_template(), Ok(Some(template.clone())));
+
+        let mut hash_spend = input.clone();
+        hash_spend
+            .finalize_with_template(&template, 2, LockTime::default())
+            .unwrap();
+        let witness = hash_spend.final_script_witness.unwrap().to_vec();
+        assert_eq!(witness.len(), 4);
+        assert_eq!(witness[0], preimage.to_vec());
+
+        let mut key_spend = input.clone();
+        let template = SatisfactionTemplate {
+            signers: bset![Fingerprint::from(&[0u8; 4][..])],
+            ..default!()
+        };
+        key_spend
+            .finalize_with_template(&template, 2, LockTime::default())
+            .unwrap();
+        assert_eq!(key_spend.final_script_witness.unwrap().len(), 2);
+
+        let template = SatisfactionTemplate {
+            signers: bset![Fingerprint::from(&[1u8; 4][..])],
+            ..default!()
+        };
+        assert_eq!(
+            input.finalize_with_template(&template, 2, LockTime::default()),
+            Err(FinalizeError::Unsatisfiable(0))
+        );
+    }
+
+    #[test]
+    fn htlc_preimage() {
+        let secp = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let pk = bitcoin::PublicKey::new(sk.public_key(&secp));
+        let preimage = [9u8; 32];
+        let hash = hash160::Hash::hash(&preimage);
+        let ms = Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str_insane(&format!(
+            "and_v(v:pk({}),hash160({}))",
+            pk, hash
+        ))
+        .unwrap();
+
+        let mut input = Input {
+            witness_script: Some(WitnessScript::from(ms.encode())),
+            ..default!()
+        };
+        assert_eq!(input.provide_preimage([8u8; 32]), 0);
+        assert_eq!(input.provide_preimage(preimage), 1);
+        assert_eq!(input.hash160_preimages.get(&hash), Some(&preimage.to_vec()));
+        assert!(input.sha256_preimages.is_empty());
+
+        let msg = Message::from_slice(&[3u8; 32]).unwrap();
+        input.partial_sigs.insert(pk, EcdsaSig {
+            sig: secp.sign_ecdsa(&msg, &sk),
+            hash_ty: EcdsaSighashType::All,
+        });
+
+        assert_eq!(
+            input
+                .clone()
+                .finalize_with_template(&default!(), 2, LockTime::default()),
+            Err(FinalizeError::Unsatisfiable(0))
+        );
+        let template = SatisfactionTemplate {
+            preimages: bset![sha256::Hash::hash(&preimage)],
+            ..default!()
+        };
+        input
+            .finalize_with_template(&template, 2, LockTime::default())
+            .unwrap();
+        let witness = input.final_script_witness.unwrap().to_vec();
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness[0], preimage.to_vec());
+    }
+
+    #[test]
+    fn relative_timelock_requires_tx_version() {
+        let secp = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let pk = bitcoin::PublicKey::new(sk.public_key(&secp));
+        let ms = Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str_insane(&format!(
+            "and_v(v:pk({}),older(10))",
+            pk
+        ))
+        .unwrap();
+
+        let msg = Message::from_slice(&[3u8; 32]).unwrap();
+        let mut input = Input {
+            witness_script: Some(WitnessScript::from(ms.encode())),
+            sequence_number: Some(SeqNo::from_consensus(10)),
+            ..default!()
+        };
+        input.partial_sigs.insert(pk, EcdsaSig {
+            sig: secp.sign_ecdsa(&msg, &sk),
+            hash_ty: EcdsaSighashType::All,
+        });
+
+        assert_eq!(
+            input
+                .clone()
+                .finalize_with_template(&default!(), 1, LockTime::default()),
+            Err(FinalizeError::Unsatisfiable(0))
+        );
+        input
+            .finalize_with_template(&default!(), 2, LockTime::default())
+            .unwrap();
+        assert_eq!(input.final_script_witness.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn key_path_signers() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let (internal_key, _) = keypair.x_only_public_key();
+        let msg = Message::from_slice(&[3u8; 32]).unwrap();
+        let fingerprint = Fingerprint::from(&[1u8; 4][..]);
+
+        let mut input = Input {
+            tap_internal_key: Some(internal_key),
+            tap_key_sig: Some(SchnorrSig {
+                sig: secp.sign_schnorr_no_aux_rand(&msg, &keypair),
+                hash_ty: SchnorrSighashType::Default,
+            }),
+            ..default!()
+        };
+        input.tap_key_origins.insert(
+            internal_key,
+            (vec![], (fingerprint, DerivationPath::master())),
+        );
+
+        let template = SatisfactionTemplate {
+            signers: bset![Fingerprint::from(&[2u8; 4][..])],
+            ..default!()
+        };
+        assert_eq!(
+            input
+                .clone()
+                .finalize_with_template(&template, 2, LockTime::default()),
+            Err(FinalizeError::Unsatisfiable(0))
+        );
+
+        let template = SatisfactionTemplate {
+            signers: bset![fingerprint],
+            ..default!()
+        };
+        input
+            .finalize_with_template(&template, 2, LockTime::default())
+            .unwrap();
+        assert_eq!(input.final_script_witness.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,34 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Transaction fixtures shared by the unit tests.
+
+use bitcoin::{OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut};
+
+/// Constructs version 2 transaction without lock time, spending a single
+/// null outpoint into the outputs with the provided values and scripts.
+pub(crate) fn tx(outputs: impl IntoIterator<Item = (u64, Script)>) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::default(),
+            ..TxIn::default()
+        }],
+        output: outputs
+            .into_iter()
+            .map(|(value, script_pubkey)| TxOut {
+                value,
+                script_pubkey,
+            })
+            .collect(),
+    }
+}
@@ -14,15 +14,21 @@ use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
 use amplify::hex::{FromHex, ToHex};
-use bitcoin::util::bip32::{ExtendedPubKey, KeySource};
-use bitcoin::{consensus, Transaction, Txid};
-use bitcoin_blockchain::locks::LockTime;
+use bitcoin::secp256k1::{self, Secp256k1, Verification};
+use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey, Fingerprint, KeySource};
+use bitcoin::{consensus, Transaction, Txid, XOnlyPublicKey};
+use bitcoin_blockchain::locks::{LockHeight, LockTime, LockTimestamp};
+#[cfg(feature = "bitcoin_onchain")]
+use bitcoin_onchain::{CoinControl, CoinControlError};
 #[cfg(feature = "serde")]
 use serde_with::{hex::Hex, As, Same};
 
 use crate::serialize::{Deserialize, Serialize};
 use crate::v0::PsbtV0;
-use crate::{raw, Error, FeeError, Input, Output, PsbtVersion, TxError};
+use crate::{
+    raw, Error, FeeError, Input, LockTimeError, Output, PsbtV0Error, PsbtVersion, TxError,
+    XpubMismatchError,
+};
 
 // TODO: Do manual serde and strict encoding implementation to check the
 //       deserialized values
@@ -101,32 +107,118 @@ impl Psbt {
         })
     }
 
+    /// Computes transaction lock time from the per-input lock time
+    /// requirements and the fallback lock time following BIP-370 rules (see
+    /// [`Psbt::try_lock_time`]). If the input requirements are mutually
+    /// unsatisfiable, returns the fallback lock time.
     pub fn lock_time(&self) -> LockTime {
-        let required_time_locktime = self
+        self.try_lock_time()
+            .unwrap_or_else(|_| self.fallback_locktime.unwrap_or_default())
+    }
+
+    /// Computes transaction lock time from the per-input lock time
+    /// requirements and the fallback lock time following BIP-370 rules:
+    /// - if no inputs have lock time requirements, the fallback lock time (or
+    ///   zero) is used;
+    /// - otherwise, the lock time type supported by all inputs with
+    ///   requirements is chosen, preferring height-based lock time when both
+    ///   types are possible, and the maximum of the required values is used.
+    ///
+    /// # Errors
+    ///
+    /// If some input requires only time-based lock time, while some other
+    /// input requires only height-based lock time.
+    pub fn try_lock_time(&self) -> Result<LockTime, LockTimeError> {
+        let constrained = self
             .inputs
             .iter()
-            .filter_map(|input| input.required_time_locktime)
-            .max();
-        let required_height_locktime = self
-            .inputs
+            .filter(|input| {
+                input.required_time_locktime.is_some() || input.required_height_locktime.is_some()
+            })
+            .collect::<Vec<_>>();
+        if constrained.is_empty() {
+            return Ok(self.fallback_locktime.unwrap_or_default());
+        }
+
+        if let Some(height) = constrained
             .iter()
-            .filter_map(|input| input.required_height_locktime)
-            .max();
-
-        match (
-            required_time_locktime,
-            required_height_locktime,
-            self.fallback_locktime,
-        ) {
-            (None, None, fallback) => fallback.unwrap_or_default(),
-            (Some(lock), None, _) => lock.into(),
-            (None, Some(lock), _) => lock.into(),
-            (Some(lock1), Some(_lock2), Some(fallback)) if fallback.is_time_based() => lock1.into(),
-            (Some(_lock1), Some(lock2), Some(fallback)) if fallback.is_height_based() => {
-                lock2.into()
-            }
-            (Some(lock1), Some(_lock2), _) => lock1.into(),
+            .map(|input| input.required_height_locktime)
+            .collect::<Option<Vec<_>>>()
+            .and_then(|heights| heights.into_iter().max())
+        {
+            return Ok(height.into());
+        }
+        if let Some(time) = constrained
+            .iter()
+            .map(|input| input.required_time_locktime)
+            .collect::<Option<Vec<_>>>()
+            .and_then(|times| times.into_iter().max())
+        {
+            return Ok(time.into());
         }
+
+        let input_index = |time_only: bool| {
+            constrained
+                .iter()
+                .find(|input| {
+                    input.required_time_locktime.is_some() == time_only
+                        && input.required_height_locktime.is_some() != time_only
+                })
+                .map(|input| input.index())
+                .expect("both lock time types are unsupported by some inputs")
+        };
+        Err(LockTimeError::Unsatisfiable {
+            time_input: input_index(true),
+            height_input: input_index(false),
+        })
+    }
+
+    /// Sets minimal block height which the input requires to be set as the
+    /// transaction lock time.
+    ///
+    /// # Errors
+    ///
+    /// If there is no input with the given index or the requirement can't be
+    /// satisfied together with requirements of other inputs. In the latter
+    /// case the PSBT is not modified.
+    pub fn set_required_height_locktime(
+        &mut self,
+        index: usize,
+        height: Option<LockHeight>,
+    ) -> Result<(), LockTimeError> {
+        let input = self
+            .inputs
+            .get_mut(index)
+            .ok_or(LockTimeError::UnknownInput(index))?;
+        let prev = std::mem::replace(&mut input.required_height_locktime, height);
+        self.try_lock_time().map(|_| ()).map_err(|err| {
+            self.inputs[index].required_height_locktime = prev;
+            err
+        })
+    }
+
+    /// Sets minimal timestamp which the input requires to be set as the
+    /// transaction lock time.
+    ///
+    /// # Errors
+    ///
+    /// If there is no input with the given index or the requirement can't be
+    /// satisfied together with requirements of other inputs. In the latter
+    /// case the PSBT is not modified.
+    pub fn set_required_time_locktime(
+        &mut self,
+        index: usize,
+        time: Option<LockTimestamp>,
+    ) -> Result<(), LockTimeError> {
+        let input = self
+            .inputs
+            .get_mut(index)
+            .ok_or(LockTimeError::UnknownInput(index))?;
+        let prev = std::mem::replace(&mut input.required_time_locktime, time);
+        self.try_lock_time().map(|_| ()).map_err(|err| {
+            self.inputs[index].required_time_locktime = prev;
+            err
+        })
     }
 
     pub(crate) fn tx_version(&self) -> i32 { i32::from_be_bytes(self.tx_version.to_be_bytes()) }
@@ -150,34 +242,53 @@ impl Psbt {
 
     /// Returns transaction ID for an unsigned transaction. For SegWit
     /// transactions this is equal to the signed transaction id.
+    ///
+    /// The transaction uses the same lock time as PSBT version 0 serialization
+    /// (see [`Psbt::lock_time`]), such that the id is always defined.
     #[inline]
-    pub fn to_txid(&self) -> Txid { self.to_unsigned_tx().txid() }
+    pub fn to_txid(&self) -> Txid { self.unsigned_tx_with(self.lock_time()).txid() }
+
+    /// Constructs transaction with empty `scriptSig` and `witness`.
+    ///
+    /// # Errors
+    ///
+    /// If the input lock time requirements are mutually unsatisfiable (see
+    /// [`Psbt::try_lock_time`]).
+    pub fn to_unsigned_tx(&self) -> Result<Transaction, LockTimeError> {
+        Ok(self.unsigned_tx_with(self.try_lock_time()?))
+    }
 
-    /// Constructs transaction with empty `scriptSig` and `witness`
-    pub fn to_unsigned_tx(&self) -> Transaction {
+    /// Returns transaction with empty `scriptSig` and `witness`.
+    ///
+    /// # Errors
+    ///
+    /// If the input lock time requirements are mutually unsatisfiable (see
+    /// [`Psbt::try_lock_time`]).
+    pub fn into_unsigned_tx(self) -> Result<Transaction, LockTimeError> {
         let version = self.tx_version();
 
-        let lock_time = bitcoin::PackedLockTime(self.lock_time().into_consensus());
+        let lock_time = bitcoin::PackedLockTime(self.try_lock_time()?.into_consensus());
 
         let tx_inputs = self.inputs.iter().map(Input::to_unsigned_txin).collect();
-        let tx_outputs = self.outputs.iter().map(Output::to_txout).collect();
+        let tx_outputs = self.outputs.into_iter().map(Output::into_txout).collect();
 
-        Transaction {
+        Ok(Transaction {
             version,
             lock_time,
             input: tx_inputs,
             output: tx_outputs,
-        }
+        })
     }
 
-    /// Returns transaction with empty `scriptSig` and `witness`
-    pub fn into_unsigned_tx(self) -> Transaction {
+    /// Constructs transaction with empty `scriptSig` and `witness` using the
+    /// provided lock time.
+    pub(crate) fn unsigned_tx_with(&self, lock_time: LockTime) -> Transaction {
         let version = self.tx_version();
 
-        let lock_time = bitcoin::PackedLockTime(self.lock_time().into_consensus());
+        let lock_time = bitcoin::PackedLockTime(lock_time.into_consensus());
 
         let tx_inputs = self.inputs.iter().map(Input::to_unsigned_txin).collect();
-        let tx_outputs = self.outputs.into_iter().map(Output::into_txout).collect();
+        let tx_outputs = self.outputs.iter().map(Output::to_txout).collect();
 
         Transaction {
             version,
@@ -189,16 +300,66 @@ impl Psbt {
 
     /// Extract the (partially) signed transaction from this PSBT by filling in
     /// the available signature information in place.
+    ///
+    /// # Errors
+    ///
+    /// If the input lock time requirements are mutually unsatisfiable (see
+    /// [`Psbt::try_lock_time`]).
     #[inline]
-    pub fn extract_signed_tx(&self) -> Transaction {
-        let mut tx: Transaction = self.to_unsigned_tx();
+    pub fn extract_signed_tx(&self) -> Result<Transaction, LockTimeError> {
+        let mut tx: Transaction = self.to_unsigned_tx()?;
 
         for (vin, psbtin) in tx.input.iter_mut().zip(self.inputs.iter()) {
             vin.script_sig = psbtin.final_script_sig.clone().unwrap_or_default().into();
             vin.witness = psbtin.final_script_witness.clone().unwrap_or_default();
         }
 
-        tx
+        Ok(tx)
+    }
+
+    /// Converts PSBT into version 2 (BIP-370). The unsigned transaction is
+    /// already decomposed into global transaction version, fallback locktime
+    /// and per-input and per-output data, so the conversion always succeeds
+    /// and preserves all PSBT data.
+    pub fn into_v2(mut self) -> Psbt {
+        self.psbt_version = PsbtVersion::V2;
+        self
+    }
+
+    /// Converts PSBT into version 0 (BIP-174) following BIP-370 rules for
+    /// reconstructing the unsigned transaction: transaction locktime is
+    /// computed from the per-input locktime requirements and the fallback
+    /// locktime, and stored as the fallback locktime, since version 0 can't
+    /// represent per-input requirements. Proprietary and unknown keys are
+    /// preserved.
+    ///
+    /// # Errors
+    ///
+    /// If the PSBT lacks data required to construct unsigned transaction or
+    /// the input lock time requirements are mutually unsatisfiable.
+    pub fn into_v0(mut self) -> Result<Psbt, PsbtV0Error> {
+        if self.inputs.is_empty() {
+            return Err(PsbtV0Error::NoInputs);
+        }
+        if let Some(input) = self
+            .inputs
+            .iter()
+            .find(|input| input.previous_outpoint.is_null())
+        {
+            return Err(PsbtV0Error::NoPreviousOutpoint(input.index()));
+        }
+
+        let lock_time = self.try_lock_time()?;
+        self.fallback_locktime = match lock_time.into_consensus() {
+            0 => None,
+            _ => Some(lock_time),
+        };
+        for input in &mut self.inputs {
+            input.required_time_locktime = None;
+            input.required_height_locktime = None;
+        }
+        self.psbt_version = PsbtVersion::V0;
+        Ok(self)
     }
 
     /// Combines this [`Psbt`] with `other` PSBT as described by BIP 174.
@@ -211,6 +372,120 @@ impl Psbt {
         first.combine(other.into())?;
         Ok(first.into())
     }
+
+    /// Adds extended public key with its origin to the global PSBT map,
+    /// returning previous key source if the key was already present.
+    ///
+    /// Errors if the depth of the extended key does not match the length of
+    /// the derivation path from the key source.
+    pub fn set_xpub(
+        &mut self,
+        xpub: ExtendedPubKey,
+        source: KeySource,
+    ) -> Result<Option<KeySource>, XpubMismatchError> {
+        if xpub.depth as usize != source.1.len() {
+            return Err(XpubMismatchError::DepthMismatch {
+                xpub,
+                depth: xpub.depth,
+                path: source.1,
+            });
+        }
+        Ok(self.xpub.insert(xpub, source))
+    }
+
+    /// Returns global extended public keys originating from the master key with
+    /// the provided fingerprint.
+    pub fn get_xpubs(
+        &self,
+        master_fingerprint: Fingerprint,
+    ) -> impl Iterator<Item = (&ExtendedPubKey, &DerivationPath)> + '_ {
+        self.xpub
+            .iter()
+            .filter(move |(_, (fingerprint, _))| *fingerprint == master_fingerprint)
+            .map(|(xpub, (_, path))| (xpub, path))
+    }
+
+    /// Checks that global extended public keys are consistent with BIP32 key
+    /// derivations of all PSBT inputs.
+    ///
+    /// For each input key, which master fingerprint is present among global
+    /// xpubs, there must be a global xpub with a derivation path being prefix
+    /// of the key derivation path, and the key derived from that xpub must
+    /// match the input key. Input keys originating from master keys which are
+    /// not listed in the global xpub map are ignored.
+    pub fn validate_xpubs<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Result<(), XpubMismatchError> {
+        for (xpub, (_, path)) in &self.xpub {
+            if xpub.depth as usize != path.len() {
+                return Err(XpubMismatchError::DepthMismatch {
+                    xpub: *xpub,
+                    depth: xpub.depth,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        for (input, psbtin) in self.inputs.iter().enumerate() {
+            for (pubkey, (fingerprint, path)) in &psbtin.bip32_derivation {
+                if let Some((xpub, derived)) =
+                    self.derive_xpub_key(secp, input, *fingerprint, path)?
+                {
+                    if derived != *pubkey {
+                        return Err(XpubMismatchError::KeyMismatch {
+                            input,
+                            xpub,
+                            path: path.clone(),
+                        });
+                    }
+                }
+            }
+            for (pubkey, (_, (fingerprint, path))) in &psbtin.tap_key_origins {
+                if let Some((xpub, derived)) =
+                    self.derive_xpub_key(secp, input, *fingerprint, path)?
+                {
+                    if XOnlyPublicKey::from(derived) != *pubkey {
+                        return Err(XpubMismatchError::KeyMismatch {
+                            input,
+                            xpub,
+                            path: path.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Derives public key with the provided origin from a matching global
+    /// xpub. Returns `None` if there is no global xpub with the same master
+    /// fingerprint.
+    fn derive_xpub_key<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        input: usize,
+        fingerprint: Fingerprint,
+        path: &DerivationPath,
+    ) -> Result<Option<(ExtendedPubKey, secp256k1::PublicKey)>, XpubMismatchError> {
+        let mut xpubs = self.get_xpubs(fingerprint).peekable();
+        if xpubs.peek().is_none() {
+            return Ok(None);
+        }
+        xpubs
+            .find_map(|(xpub, xpub_path)| {
+                let terminal = path.as_ref().strip_prefix(xpub_path.as_ref())?.to_vec();
+                let derived = xpub.derive_pub(secp, &terminal).ok()?;
+                Some((*xpub, derived.public_key))
+            })
+            .map(Some)
+            .ok_or_else(|| XpubMismatchError::NoMatchingXpub {
+                input,
+                fingerprint,
+                path: path.clone(),
+            })
+    }
 }
 
 impl From<PsbtV0> for Psbt {
@@ -309,8 +584,23 @@ impl FromStr for Psbt {
     }
 }
 
+#[cfg(feature = "bitcoin_onchain")]
+impl Psbt {
+    /// Reserves coins spent by the PSBT in the wallet coin control state, such
+    /// that they are not selected by other transactions until the PSBT is
+    /// published or abandoned (see [`CoinControl::release`]).
+    pub fn reserve_coins(&self, coin_control: &mut CoinControl) -> Result<(), CoinControlError> {
+        coin_control.reserve(
+            self.to_txid(),
+            self.inputs.iter().map(|input| input.previous_outpoint),
+        )
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use bitcoin::util::bip32::ExtendedPrivKey;
+
     use super::*;
 
     #[test]
@@ -340,4 +630,170 @@ mod test {
         assert_eq!(psbt, psbt_prime);
         assert_eq!(hex, hex_prime);
     }
+
+    #[test]
+    fn version_conversion() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::{OutPoint, PackedLockTime, TxIn, TxOut};
+        use bitcoin_blockchain::locks::LockHeight;
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([1u8; 32]), 0),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut::default()],
+        };
+        let mut psbt = Psbt::with(tx.clone(), PsbtVersion::V0).unwrap();
+        let key = raw::ProprietaryKey {
+            prefix: b"test".to_vec(),
+            subtype: 0,
+            key: vec![],
+        };
+        psbt.proprietary.insert(key.clone(), vec![1]);
+        psbt.inputs[0].proprietary.insert(key.clone(), vec![2]);
+        psbt.outputs[0].proprietary.insert(key, vec![3]);
+
+        let v2 = psbt.clone().into_v2();
+        assert_eq!(v2.psbt_version, PsbtVersion::V2);
+        assert_eq!(v2.to_unsigned_tx(), Ok(tx));
+        assert_eq!(v2.into_v0(), Ok(psbt.clone()));
+
+        let mut v2 = psbt.into_v2();
+        v2.inputs[0].required_height_locktime = LockHeight::from_height(800_000);
+        let v0 = v2.clone().into_v0().unwrap();
+        assert_eq!(v0.inputs[0].locktime(), None);
+        assert_eq!(v0.fallback_locktime, Some(LockTime::from(800_000)));
+        assert_eq!(v0.lock_time(), v2.lock_time());
+        assert_eq!(v0.to_unsigned_tx(), v2.to_unsigned_tx());
+
+        v2.inputs[0].previous_outpoint = OutPoint::null();
+        assert_eq!(
+            v2.clone().into_v0(),
+            Err(PsbtV0Error::NoPreviousOutpoint(0))
+        );
+        v2.inputs.clear();
+        assert_eq!(v2.into_v0(), Err(PsbtV0Error::NoInputs));
+    }
+
+    #[test]
+    fn locktime_aggregation() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::{OutPoint, PackedLockTime, TxIn, TxOut};
+
+        let txin = |vout| TxIn {
+            previous_output: OutPoint::new(Txid::from_inner([1u8; 32]), vout),
+            ..TxIn::default()
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![txin(0), txin(1), txin(2)],
+            output: vec![TxOut::default()],
+        };
+        let mut psbt = Psbt::with(tx, PsbtVersion::V2).unwrap();
+        psbt.fallback_locktime = Some(LockTime::from(700_000));
+        assert_eq!(psbt.try_lock_time(), Ok(LockTime::from(700_000)));
+
+        let time = LockTimestamp::from_unix_timestamp(1_700_000_000);
+        psbt.set_required_time_locktime(0, time).unwrap();
+        // Input 1 must support time-based lock time before it may also
+        // support height-based one, since input 0 requires time
+        psbt.set_required_time_locktime(1, time).unwrap();
+        psbt.set_required_height_locktime(1, LockHeight::from_height(800_000))
+            .unwrap();
+        assert_eq!(psbt.try_lock_time(), Ok(LockTime::from(1_700_000_000)));
+
+        psbt.set_required_height_locktime(0, LockHeight::from_height(810_000))
+            .unwrap();
+        assert_eq!(psbt.try_lock_time(), Ok(LockTime::from(810_000)));
+
+        assert_eq!(
+            psbt.set_required_height_locktime(2, LockHeight::from_height(820_000)),
+            Ok(())
+        );
+        assert_eq!(psbt.lock_time(), LockTime::from(820_000));
+        psbt.set_required_height_locktime(2, None).unwrap();
+        psbt.set_required_height_locktime(0, None).unwrap();
+        assert_eq!(
+            psbt.set_required_height_locktime(2, LockHeight::from_height(820_000)),
+            Err(LockTimeError::Unsatisfiable {
+                time_input: 0,
+                height_input: 2
+            })
+        );
+        assert_eq!(psbt.inputs[2].required_height_locktime, None);
+        assert_eq!(
+            psbt.set_required_time_locktime(3, time),
+            Err(LockTimeError::UnknownInput(3))
+        );
+
+        psbt.inputs[2].required_height_locktime = LockHeight::from_height(820_000);
+        let err = LockTimeError::Unsatisfiable {
+            time_input: 0,
+            height_input: 2,
+        };
+        assert_eq!(psbt.to_unsigned_tx(), Err(err));
+        assert_eq!(psbt.extract_signed_tx(), Err(err));
+        assert_eq!(psbt.into_v0(), Err(PsbtV0Error::LockTime(err)));
+    }
+
+    #[test]
+    fn xpub_validation() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(bitcoin::Network::Bitcoin, &[1u8; 32]).unwrap();
+        let master_fp = master.fingerprint(&secp);
+        let account_path = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let account_xpriv = master.derive_priv(&secp, &account_path).unwrap();
+        let account_xpub = ExtendedPubKey::from_priv(&secp, &account_xpriv);
+
+        let key_path = DerivationPath::from_str("m/84'/0'/0'/0/1").unwrap();
+        let key_xpriv = master.derive_priv(&secp, &key_path).unwrap();
+        let pubkey = ExtendedPubKey::from_priv(&secp, &key_xpriv).public_key;
+
+        let mut psbt = Psbt::default();
+        assert!(psbt
+            .set_xpub(account_xpub, (master_fp, DerivationPath::master()))
+            .is_err());
+        assert_eq!(
+            psbt.set_xpub(account_xpub, (master_fp, account_path.clone())),
+            Ok(None)
+        );
+        assert_eq!(psbt.get_xpubs(master_fp).count(), 1);
+
+        let mut input = Input::default();
+        input
+            .bip32_derivation
+            .insert(pubkey, (master_fp, key_path.clone()));
+        psbt.inputs.push(input);
+        assert_eq!(psbt.validate_xpubs(&secp), Ok(()));
+
+        let other_path = DerivationPath::from_str("m/84'/0'/1'/0/1").unwrap();
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(pubkey, (master_fp, other_path.clone()));
+        assert_eq!(
+            psbt.validate_xpubs(&secp),
+            Err(XpubMismatchError::NoMatchingXpub {
+                input: 0,
+                fingerprint: master_fp,
+                path: other_path
+            })
+        );
+
+        let wrong_path = DerivationPath::from_str("m/84'/0'/0'/0/2").unwrap();
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(pubkey, (master_fp, wrong_path.clone()));
+        assert_eq!(
+            psbt.validate_xpubs(&secp),
+            Err(XpubMismatchError::KeyMismatch {
+                input: 0,
+                xpub: account_xpub,
+                path: wrong_path
+            })
+        );
+    }
 }
@@ -9,10 +9,19 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-//! Lexicographic sorting functions.
+//! Lexicographic sorting functions and deterministic shuffling of
+//! transaction inputs and outputs.
+//!
+//! PSBT inputs and outputs are large structures, so instead of sorting them
+//! directly they are ordered by computing a permutation of their indexes,
+//! which is then applied in place moving each item only once. This keeps
+//! sorting PSBTs with thousands of inputs free of allocations proportional
+//! to the size of the PSBT maps.
 
 use std::cmp::Ordering;
 
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::{self, secp256k1, Transaction, TxIn, TxOut};
 
 use crate::v0::PsbtV0;
@@ -60,34 +69,25 @@ impl LexOrder for Vec<(TxOut, crate::v0::OutputV0)> {
 impl LexOrder for PsbtV0 {
     fn lex_order(&mut self) {
         let tx = &mut self.unsigned_tx;
-        let mut inputs = tx
-            .input
-            .clone()
-            .into_iter()
-            .zip(self.inputs.clone().into_iter())
-            .collect::<Vec<(_, _)>>();
-        inputs.sort_by_key(|(k, _)| k.previous_output);
-
-        let mut outputs = tx
-            .output
-            .clone()
-            .into_iter()
-            .zip(self.outputs.clone().into_iter())
-            .collect::<Vec<(_, _)>>();
-        outputs.lex_order();
-
-        let (in_tx, in_map): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
-        let (out_tx, out_map): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
-        tx.input = in_tx;
-        tx.output = out_tx;
-        self.inputs = in_map;
-        self.outputs = out_map;
+
+        let permutation = sort_permutation(&tx.input, |a, b| {
+            a.previous_output.cmp(&b.previous_output)
+        });
+        apply_permutation(&mut tx.input, &permutation);
+        apply_permutation(&mut self.inputs, &permutation);
+
+        let permutation = sort_permutation(&tx.output, txout_cmp);
+        apply_permutation(&mut tx.output, &permutation);
+        apply_permutation(&mut self.outputs, &permutation);
     }
 }
 
 impl LexOrder for Vec<Input> {
     fn lex_order(&mut self) {
-        self.sort_by_key(|input| input.previous_outpoint);
+        let permutation = sort_permutation(self, |a, b| {
+            a.previous_outpoint.cmp(&b.previous_outpoint)
+        });
+        apply_permutation(self, &permutation);
         for (index, input) in self.iter_mut().enumerate() {
             input.index = index;
         }
@@ -96,7 +96,8 @@ impl LexOrder for Vec<Input> {
 
 impl LexOrder for Vec<Output> {
     fn lex_order(&mut self) {
-        self.sort_by(psbtout_cmp);
+        let permutation = sort_permutation(self, psbtout_cmp);
+        apply_permutation(self, &permutation);
         for (index, output) in self.iter_mut().enumerate() {
             output.index = index;
         }
@@ -110,6 +111,42 @@ impl LexOrder for Psbt {
     }
 }
 
+/// Computes stable permutation putting `items` in the order defined by
+/// `cmp`: item at position `i` of the ordered sequence is the item at
+/// position `permutation[i]` of the original one.
+fn sort_permutation<T>(items: &[T], mut cmp: impl FnMut(&T, &T) -> Ordering) -> Vec<usize> {
+    let mut permutation = (0..items.len()).collect::<Vec<_>>();
+    permutation.sort_by(|a, b| cmp(&items[*a], &items[*b]));
+    permutation
+}
+
+/// Reorders `items` in place according to the `permutation` (see
+/// [`sort_permutation`]) by following its cycles, such that each item is
+/// swapped into its final position at most once.
+///
+/// # Panics
+///
+/// If the permutation length does not match the number of items.
+fn apply_permutation<T>(items: &mut [T], permutation: &[usize]) {
+    assert_eq!(items.len(), permutation.len(), "permutation length mismatch");
+    let mut visited = vec![false; items.len()];
+    for start in 0..items.len() {
+        if visited[start] {
+            continue;
+        }
+        let mut current = start;
+        loop {
+            visited[current] = true;
+            let next = permutation[current];
+            if next == start {
+                break;
+            }
+            items.swap(current, next);
+            current = next;
+        }
+    }
+}
+
 fn txout_cmp(left: &TxOut, right: &TxOut) -> Ordering {
     match (left.value, right.value) {
         (l, r) if l < r => Ordering::Less,
@@ -125,3 +162,215 @@ fn psbtout_cmp(left: &Output, right: &Output) -> Ordering {
         _ => left.script.cmp(&right.script),
     }
 }
+
+/// Ordering of transaction inputs and outputs.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum TxOrdering {
+    /// Lexicographic ordering defined by BIP-69
+    #[default]
+    Lexicographic,
+
+    /// Deterministic shuffling with the provided seed. If the seed is not
+    /// given, it is derived from the transaction data with
+    /// [`Psbt::shuffle_seed`].
+    Shuffled(Option<sha256::Hash>),
+}
+
+/// Deterministic shuffling which can be independently reproduced by all
+/// parties knowing the seed.
+///
+/// Items are first put in lexicographic order and then permuted with
+/// Fisher-Yates shuffle driven by hashes of the seed, so the result does not
+/// depend on the original item order.
+pub trait ShuffleOrder: LexOrder {
+    fn shuffle_order(&mut self, seed: sha256::Hash);
+
+    fn shuffled(mut self, seed: sha256::Hash) -> Self
+    where
+        Self: Sized,
+    {
+        self.shuffle_order(seed);
+        self
+    }
+}
+
+impl ShuffleOrder for Vec<Input> {
+    fn shuffle_order(&mut self, seed: sha256::Hash) {
+        self.lex_order();
+        shuffle(self, seed, b"inputs");
+        for (index, input) in self.iter_mut().enumerate() {
+            input.index = index;
+        }
+    }
+}
+
+impl ShuffleOrder for Vec<Output> {
+    fn shuffle_order(&mut self, seed: sha256::Hash) {
+        self.lex_order();
+        shuffle(self, seed, b"outputs");
+        for (index, output) in self.iter_mut().enumerate() {
+            output.index = index;
+        }
+    }
+}
+
+impl ShuffleOrder for Psbt {
+    fn shuffle_order(&mut self, seed: sha256::Hash) {
+        self.inputs.shuffle_order(seed);
+        self.outputs.shuffle_order(seed);
+    }
+}
+
+impl Psbt {
+    /// Derives shuffling seed from the spent outpoints and transaction
+    /// outputs, which does not depend on their current order. All cosigners
+    /// having the same unsigned transaction derive the same seed.
+    pub fn shuffle_seed(&self) -> sha256::Hash {
+        let mut outpoints = self
+            .inputs
+            .iter()
+            .map(|input| input.previous_outpoint)
+            .collect::<Vec<_>>();
+        outpoints.sort();
+        let mut outputs = self
+            .outputs
+            .iter()
+            .map(|output| (output.amount, &output.script))
+            .collect::<Vec<_>>();
+        outputs.sort();
+
+        let mut engine = tagged_engine();
+        for outpoint in outpoints {
+            outpoint
+                .consensus_encode(&mut engine)
+                .expect("hash engines don't error");
+        }
+        for (amount, script) in outputs {
+            engine.input(&amount.to_le_bytes());
+            script
+                .consensus_encode(&mut engine)
+                .expect("hash engines don't error");
+        }
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// Orders inputs and outputs, returning the seed used for shuffling (if
+    /// any), which may be published for the ordering to be audited.
+    pub fn apply_ordering(&mut self, ordering: TxOrdering) -> Option<sha256::Hash> {
+        match ordering {
+            TxOrdering::Lexicographic => {
+                self.lex_order();
+                None
+            }
+            TxOrdering::Shuffled(seed) => {
+                let seed = seed.unwrap_or_else(|| self.shuffle_seed());
+                self.shuffle_order(seed);
+                Some(seed)
+            }
+        }
+    }
+}
+
+fn tagged_engine() -> sha256::HashEngine {
+    let tag = sha256::Hash::hash(b"descriptor-wallet:shuffle");
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine
+}
+
+fn shuffle<T>(items: &mut [T], seed: sha256::Hash, domain: &[u8]) {
+    for i in (1..items.len()).rev() {
+        let mut engine = tagged_engine();
+        engine.input(&seed[..]);
+        engine.input(domain);
+        engine.input(&(i as u64).to_le_bytes());
+        let hash = sha256::Hash::from_engine(engine);
+        let mut rand = [0u8; 8];
+        rand.copy_from_slice(&hash[..8]);
+        let j = u64::from_le_bytes(rand) % (i as u64 + 1);
+        items.swap(i, j as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{OutPoint, Script, Txid};
+
+    use super::*;
+
+    fn psbt(count: u32) -> Psbt {
+        let txid = Txid::hash(b"tx");
+        Psbt {
+            inputs: (0..count)
+                .map(|vout| Input {
+                    previous_outpoint: OutPoint::new(txid, vout),
+                    ..default!()
+                })
+                .collect(),
+            outputs: (0..count)
+                .map(|amount| Output {
+                    amount: amount as u64 * 1000,
+                    script: Script::new_op_return(&[amount as u8]).into(),
+                    ..default!()
+                })
+                .collect(),
+            ..default!()
+        }
+    }
+
+    #[test]
+    fn deterministic_shuffle() {
+        let mut psbt = psbt(8);
+        let mut reversed = psbt.clone();
+        reversed.inputs.reverse();
+        reversed.outputs.reverse();
+        assert_eq!(psbt.shuffle_seed(), reversed.shuffle_seed());
+
+        let seed = psbt.apply_ordering(TxOrdering::Shuffled(None));
+        assert_eq!(seed, reversed.apply_ordering(TxOrdering::Shuffled(None)));
+        assert_eq!(psbt, reversed);
+        assert!(psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .all(|(index, input)| input.index == index));
+
+        let mut lex = psbt.clone();
+        assert_eq!(lex.apply_ordering(TxOrdering::Lexicographic), None);
+        assert_ne!(psbt.inputs, lex.inputs);
+        let other = lex.clone().shuffled(sha256::Hash::hash(b"other seed"));
+        assert_ne!(psbt.inputs, other.inputs);
+    }
+
+    #[test]
+    fn permutation() {
+        let items = vec![3u8, 1, 4, 1, 5, 9, 2, 6];
+        let permutation = sort_permutation(&items, u8::cmp);
+        assert_eq!(permutation, vec![1, 3, 6, 0, 2, 4, 7, 5]);
+        let mut sorted = items.clone();
+        apply_permutation(&mut sorted, &permutation);
+        assert_eq!(sorted, vec![1, 1, 2, 3, 4, 5, 6, 9]);
+
+        let mut psbt = psbt(64);
+        psbt.inputs.reverse();
+        psbt.outputs.swap(3, 40);
+        let mut expected = psbt.clone();
+        expected.inputs.sort_by_key(|input| input.previous_outpoint);
+        expected.outputs.sort_by(psbtout_cmp);
+        psbt.lex_order();
+        assert_eq!(
+            psbt.inputs.iter().map(|input| input.previous_outpoint).collect::<Vec<_>>(),
+            expected.inputs.iter().map(|input| input.previous_outpoint).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            psbt.outputs.iter().map(|output| output.amount).collect::<Vec<_>>(),
+            expected.outputs.iter().map(|output| output.amount).collect::<Vec<_>>()
+        );
+        assert!(psbt
+            .outputs
+            .iter()
+            .enumerate()
+            .all(|(index, output)| output.index == index));
+    }
+}
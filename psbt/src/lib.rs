@@ -16,14 +16,23 @@
 //! PSBT bitcoin library, providing all PSBT functionality from [`bitcoin`]
 //! library, plus
 //! - constructor, supporting miniscript-based descriptors, input descriptors,
-//!   all sighash types, spendings from P2C, S2C-tweaked inputs ([`construct`]);
+//!   all sighash types, spendings from P2C-tweaked inputs ([`construct`]);
 //! - advanced signer, supporting pre-segwit, bare and nested segwit v0, taproot
 //!   key and path spendings, different forms of tweaks & commitments, all
 //!   sighash types ([`sign`]);
-//! - commitment-related features: managing tapret-, P2C and S2C-related
-//!   proprietary keys;
-//! - utility methods for fee computing, lexicographic reordering etc;
+//! - commitment-related features: managing P2C-related proprietary keys;
+//! - utility methods for fee computing, lexicographic reordering, detection
+//!   of conflicts with unconfirmed transactions, minimization for hardware
+//!   signers etc;
 //! - command-line utility for editing PSBT data (WIP).
+//!
+//! Construction and verification of commitments embedded into transactions
+//! is out of the scope of this library and is provided by BP deterministic
+//! bitcoin commitments and `commit_verify` libraries. This includes:
+//! - sign-to-contract (S2C) commitments made with signature nonces;
+//! - tapret commitments and their proofs, including proving commitments of
+//!   already finalized transactions;
+//! - LNPBP-4 multi-protocol commitment trees and their merkle proofs.
 
 #[macro_use]
 extern crate amplify;
@@ -35,9 +44,18 @@ extern crate strict_encoding;
 #[cfg(feature = "miniscript")]
 extern crate miniscript_crate as miniscript;
 
+#[cfg(feature = "construct")]
+mod accounts;
+pub mod annex;
 mod errors;
+mod fee_policy;
+#[cfg(test)]
+mod fixtures;
+#[cfg(all(feature = "descriptors", feature = "miniscript"))]
+mod finalize;
 mod global;
 mod input;
+mod minimize;
 mod output;
 pub mod p2c;
 
@@ -45,12 +63,28 @@ pub mod p2c;
 pub mod construct;
 pub mod lex_order;
 mod proprietary;
+#[cfg(feature = "bitcoin_onchain")]
+mod rbf;
+#[cfg(feature = "bitcoin_onchain")]
+mod repair;
+#[cfg(feature = "construct")]
+mod reserves;
 #[cfg(feature = "sign")]
 pub mod sign;
+mod stream;
+mod taproot;
 
+pub use annex::{AnnexPrefixError, PSBT_ANNEX_PREFIX, PSBT_IN_TAP_ANNEX};
 pub use bitcoin::psbt::raw::ProprietaryKey;
 pub use bitcoin::psbt::{raw, serialize, Error, PsbtParseError, PsbtSighashType};
-pub use errors::{FeeError, InputMatchError, TxError, TxinError};
+pub use errors::{
+    FeeError, InputMatchError, LockTimeError, PsbtV0Error, TxError, TxinError, XpubMismatchError,
+};
+pub use fee_policy::{
+    FeePolicyError, MaxFeePolicy, DEFAULT_MAX_FEE, PSBT_FEE_POLICY_PREFIX, PSBT_GLOBAL_MAX_FEE,
+};
+#[cfg(all(feature = "descriptors", feature = "miniscript"))]
+pub use finalize::{FinalizeError, PSBT_IN_SATISFACTION_TEMPLATE, PSBT_SATISFACTION_PREFIX};
 pub use global::Psbt;
 pub use input::Input;
 pub use output::Output;
@@ -63,6 +97,14 @@ pub use p2c::{PSBT_IN_P2C_TWEAK, PSBT_P2C_PREFIX};
 pub use proprietary::{
     ProprietaryKeyDescriptor, ProprietaryKeyError, ProprietaryKeyLocation, ProprietaryKeyType,
 };
+#[cfg(feature = "bitcoin_onchain")]
+pub use rbf::{MempoolConflict, MempoolConflicts, DEFAULT_INCREMENTAL_RELAY_FEE};
+#[cfg(feature = "bitcoin_onchain")]
+pub use repair::{InputRepair, RepairAction, RepairError};
+#[cfg(feature = "construct")]
+pub use reserves::{challenge_outpoint, ReservesError, RESERVES_CHALLENGE_PREFIX};
+pub use stream::{DecodeLimits, PsbtDecoder, StreamDecodeError};
+pub use taproot::TaprootInputError;
 
 /// Version of the PSBT (V0 stands for BIP174-defined version; V2 - for BIP370).
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default)]
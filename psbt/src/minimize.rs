@@ -0,0 +1,227 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Minimization of PSBTs for a specific signer, reducing their size to fit
+//! QR-code and SD-card limits of airgapped hardware devices.
+//!
+//! Minimized PSBT contains only data required by the signer to produce its
+//! signatures. Once signed, it must be merged back into the original PSBT
+//! with [`Psbt::merge_minimized`], which restores the stripped data.
+
+use std::collections::BTreeMap;
+
+use bitcoin::util::bip32::{Fingerprint, KeySource};
+use bitcoin::util::taproot::TapLeafHash;
+
+use crate::raw::ProprietaryKey;
+use crate::{
+    Error, Input, Output, Psbt, PSBT_ANNEX_PREFIX, PSBT_FEE_POLICY_PREFIX, PSBT_P2C_PREFIX,
+};
+
+/// Prefixes of proprietary keys which are required for signing and are kept
+/// in minimized PSBTs.
+const SIGNING_PREFIXES: [&[u8]; 3] = [PSBT_P2C_PREFIX, PSBT_ANNEX_PREFIX, PSBT_FEE_POLICY_PREFIX];
+
+fn retain_signing_keys(proprietary: &mut BTreeMap<ProprietaryKey, Vec<u8>>) {
+    proprietary.retain(|key, _| SIGNING_PREFIXES.contains(&key.prefix.as_slice()));
+}
+
+impl Psbt {
+    /// Creates a copy of the PSBT containing only the data required by the
+    /// signer with the master key `fingerprint`. The copy does not contain:
+    /// - extended public keys, key derivations and signatures of other
+    ///   cosigners;
+    /// - tapscripts which can't be signed by the signer;
+    /// - hash preimages and proprietary keys not used for signing (P2C tweaks,
+    ///   annexes and fee policy are kept);
+    /// - unknown keys;
+    /// - `non_witness_utxo` of taproot inputs, for which `witness_utxo` is
+    ///   sufficient since taproot signatures commit to all spent amounts.
+    ///
+    /// Segwit v0 inputs keep `non_witness_utxo`, since hardware devices
+    /// require it to protect against the fee overpayment attack.
+    ///
+    /// The stripped data are restored by [`Psbt::merge_minimized`].
+    pub fn minimize_for_signer(&self, fingerprint: Fingerprint) -> Psbt {
+        let mut psbt = self.clone();
+        psbt.xpub.retain(|_, (fp, _)| *fp == fingerprint);
+        retain_signing_keys(&mut psbt.proprietary);
+        psbt.unknown.clear();
+        for input in &mut psbt.inputs {
+            input.minimize_for_signer(fingerprint);
+        }
+        for output in &mut psbt.outputs {
+            output.minimize_for_signer(fingerprint);
+        }
+        psbt
+    }
+
+    /// Merges PSBT `signed` by a signer from the PSBT produced by
+    /// [`Psbt::minimize_for_signer`] into the original PSBT, restoring all
+    /// the data which were stripped during minimization.
+    ///
+    /// # Errors
+    ///
+    /// If the signed PSBT does not spend the same transaction as the
+    /// original one.
+    #[inline]
+    pub fn merge_minimized(self, signed: Psbt) -> Result<Psbt, Error> { self.combine(signed) }
+}
+
+impl Input {
+    fn minimize_for_signer(&mut self, fingerprint: Fingerprint) {
+        let is_own = |(fp, _): &KeySource| *fp == fingerprint;
+
+        self.bip32_derivation.retain(|_, source| is_own(source));
+        let bip32_derivation = &self.bip32_derivation;
+        self.partial_sigs
+            .retain(|pk, _| bip32_derivation.contains_key(&pk.inner));
+
+        self.tap_key_origins.retain(|_, (_, source)| is_own(source));
+        let tap_key_origins = &self.tap_key_origins;
+        self.tap_script_sigs
+            .retain(|(pk, _), _| tap_key_origins.contains_key(pk));
+        self.tap_scripts.retain(|_, (script, version)| {
+            let leaf_hash = TapLeafHash::from_script(script, *version);
+            tap_key_origins
+                .values()
+                .any(|(leaf_hashes, _)| leaf_hashes.contains(&leaf_hash))
+        });
+
+        if self
+            .witness_utxo
+            .as_ref()
+            .map_or(false, |txout| txout.script_pubkey.is_v1_p2tr())
+        {
+            self.non_witness_utxo = None;
+        }
+
+        self.ripemd160_preimages.clear();
+        self.sha256_preimages.clear();
+        self.hash160_preimages.clear();
+        self.hash256_preimages.clear();
+        retain_signing_keys(&mut self.proprietary);
+        self.unknown.clear();
+    }
+}
+
+impl Output {
+    fn minimize_for_signer(&mut self, fingerprint: Fingerprint) {
+        self.bip32_derivation.retain(|_, (fp, _)| *fp == fingerprint);
+        self.tap_key_origins.retain(|_, (_, (fp, _))| *fp == fingerprint);
+        retain_signing_keys(&mut self.proprietary);
+        self.unknown.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{ecdsa, PublicKey, Secp256k1, SecretKey};
+    use bitcoin::util::schnorr::TweakedPublicKey;
+    use bitcoin::{
+        EcdsaSig, EcdsaSighashType, OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut,
+    };
+
+    use super::*;
+    use crate::PsbtVersion;
+
+    #[test]
+    fn minimize() {
+        let secp = Secp256k1::new();
+        let pk = |no: u8| {
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[no; 32]).unwrap())
+        };
+        let own = Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]);
+        let other = Fingerprint::from(&[0xde, 0xad, 0xbe, 0xef][..]);
+        let prev_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_v1_p2tr_tweaked(
+                    TweakedPublicKey::dangerous_assume_tweaked(pk(3).x_only_public_key().0),
+                ),
+            }],
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev_tx.txid(), 0),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let foreign_key = ProprietaryKey {
+            prefix: b"FOREIGN".to_vec(),
+            subtype: 0,
+            key: vec![],
+        };
+        let p2c_key = ProprietaryKey {
+            prefix: PSBT_P2C_PREFIX.to_vec(),
+            subtype: 0,
+            key: vec![],
+        };
+
+        let mut other_tx = tx.clone();
+        other_tx.output[0].value = 8_000;
+
+        let mut original = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        let input = &mut original.inputs[0];
+        input.witness_utxo = Some(prev_tx.output[0].clone());
+        input.non_witness_utxo = Some(prev_tx);
+        input.bip32_derivation = bmap! {
+            pk(1) => (own, default!()),
+            pk(2) => (other, default!())
+        };
+        input.sha256_preimages = bmap! { Hash::hash(b"preimage") => b"preimage".to_vec() };
+        input.proprietary = bmap! {
+            foreign_key => vec![],
+            p2c_key.clone() => vec![]
+        };
+        original.outputs[0].bip32_derivation = bmap! { pk(2) => (other, default!()) };
+
+        let mut minimized = original.minimize_for_signer(own);
+        let input = &minimized.inputs[0];
+        assert_eq!(input.bip32_derivation.keys().collect::<Vec<_>>(), vec![&pk(1)]);
+        assert_eq!(input.non_witness_utxo, None);
+        assert!(input.witness_utxo.is_some());
+        assert!(input.sha256_preimages.is_empty());
+        assert_eq!(input.proprietary.keys().collect::<Vec<_>>(), vec![&p2c_key]);
+        assert!(minimized.outputs[0].bip32_derivation.is_empty());
+        assert_eq!(minimized.to_txid(), original.to_txid());
+
+        let sig = EcdsaSig {
+            sig: ecdsa::Signature::from_compact(&[1u8; 64]).unwrap(),
+            hash_ty: EcdsaSighashType::All,
+        };
+        minimized.inputs[0]
+            .partial_sigs
+            .insert(bitcoin::PublicKey::new(pk(1)), sig);
+
+        let merged = original.clone().merge_minimized(minimized).unwrap();
+        let input = &merged.inputs[0];
+        assert_eq!(input.partial_sigs.len(), 1);
+        assert_eq!(input.bip32_derivation, original.inputs[0].bip32_derivation);
+        assert_eq!(input.non_witness_utxo, original.inputs[0].non_witness_utxo);
+        assert_eq!(input.sha256_preimages, original.inputs[0].sha256_preimages);
+        assert_eq!(input.proprietary, original.inputs[0].proprietary);
+        assert_eq!(merged.outputs[0].bip32_derivation, original.outputs[0].bip32_derivation);
+
+        let other_psbt = Psbt::with(other_tx, PsbtVersion::V0).unwrap();
+        assert!(original.merge_minimized(other_psbt).is_err());
+    }
+}
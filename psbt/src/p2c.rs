@@ -14,12 +14,15 @@
 //! Processing proprietary PSBT keys related to pay-to-contract (P2C)
 //! commitments.
 
-use amplify::Slice32;
+use std::collections::BTreeMap;
+
+use amplify::{Slice32, Wrapper};
+use bitcoin::hashes::{sha256, Hash};
 use bitcoin::secp256k1;
-use bitcoin::secp256k1::PublicKey;
+use bitcoin::secp256k1::{Parity, PublicKey};
 
 use crate::raw::ProprietaryKey;
-use crate::Input;
+use crate::{Input, Psbt};
 
 pub const PSBT_P2C_PREFIX: &[u8] = b"P2C";
 pub const PSBT_IN_P2C_TWEAK: u8 = 0;
@@ -71,3 +74,75 @@ impl Input {
         )
     }
 }
+
+impl Psbt {
+    /// Fills in P2C tweaks for the inputs spending outputs with tweaked keys.
+    /// The tweaks are keyed by the original (untweaked) public keys, with
+    /// taproot keys represented by their even-parity full public key. Returns
+    /// number of inputs which received a tweak.
+    pub fn set_p2c_tweaks(&mut self, tweaks: &BTreeMap<PublicKey, sha256::Hash>) -> usize {
+        let mut count = 0;
+        for input in &mut self.inputs {
+            let tweak = input
+                .bip32_derivation
+                .keys()
+                .copied()
+                .chain(
+                    input
+                        .tap_key_origins
+                        .keys()
+                        .map(|xonly| xonly.public_key(Parity::Even)),
+                )
+                .find_map(|pubkey| tweaks.get(&pubkey).map(|tweak| (pubkey, *tweak)));
+            if let Some((pubkey, tweak)) = tweak {
+                input.set_p2c_tweak(pubkey, Slice32::from_inner(tweak.into_inner()));
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+
+    use super::*;
+
+    #[test]
+    fn p2c_tweaks() {
+        let secp = Secp256k1::new();
+        let pubkey = |sk: u8| SecretKey::from_slice(&[sk; 32]).unwrap().public_key(&secp);
+        let origin = (Fingerprint::default(), DerivationPath::master());
+        let tweak = sha256::Hash::hash(b"contract");
+
+        let mut psbt = Psbt::default();
+        let mut input = Input::default();
+        input.bip32_derivation.insert(pubkey(1), origin.clone());
+        psbt.inputs.push(input);
+        let mut input = Input {
+            index: 1,
+            ..default!()
+        };
+        let (xonly, _) = pubkey(2).x_only_public_key();
+        input.tap_key_origins.insert(xonly, (vec![], origin.clone()));
+        psbt.inputs.push(input);
+        let mut input = Input {
+            index: 2,
+            ..default!()
+        };
+        input.bip32_derivation.insert(pubkey(3), origin);
+        psbt.inputs.push(input);
+
+        let even = xonly.public_key(Parity::Even);
+        let tweaks = bmap! { pubkey(1) => tweak, even => tweak };
+        assert_eq!(psbt.set_p2c_tweaks(&tweaks), 2);
+
+        let tweak = Slice32::from_inner(tweak.into_inner());
+        assert_eq!(psbt.inputs[0].p2c_tweak(pubkey(1)), Some(tweak));
+        assert_eq!(psbt.inputs[0].p2c_tweak(pubkey(3)), None);
+        assert_eq!(psbt.inputs[1].p2c_tweak(even), Some(tweak));
+        assert_eq!(psbt.inputs[2].p2c_tweak(pubkey(3)), None);
+    }
+}
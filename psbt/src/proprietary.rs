@@ -11,10 +11,12 @@
 
 use core::fmt::{self, Display, Formatter};
 use core::str::FromStr;
+use std::collections::BTreeMap;
 
 use amplify::hex::{FromHex, ToHex};
 
 use crate::raw::ProprietaryKey;
+use crate::Psbt;
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error)]
 #[display(doc_comments)]
@@ -37,9 +39,9 @@ pub enum ProprietaryKeyError {
     /// parts:
     /// 1) key location, in form of `input(no)`, `output(no)`, or `global`;
     /// 2) key type, in form of `prefix(no)`;
-    /// 3) key-value pair, in form of `key:value`, where both key and value
-    ///    must be hexadecimal bytestrings; one of them may be omitted
-    ///    (for instance, `:value` or `key:`).
+    /// 3) key-value pair, in form of `key:value`, where both key and value must
+    ///    be hexadecimal bytestrings; one of them may be omitted (for instance,
+    ///    `:value` or `key:`).
     ///
     /// If the proprietary key does not have associated data, the third part of
     /// the descriptor must be fully omitted.
@@ -53,6 +55,7 @@ pub enum ProprietaryKeyError {
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
 pub enum ProprietaryKeyLocation {
     #[display("global")]
     Global,
@@ -83,6 +86,7 @@ impl FromStr for ProprietaryKeyLocation {
 }
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
 #[display("{prefix}({subtype})")]
 pub struct ProprietaryKeyType {
     pub prefix: String,
@@ -107,6 +111,7 @@ impl FromStr for ProprietaryKeyType {
 
 // --proprietary-key "input(1) DBC(1) 8536ba03:~"
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[derive(StrictEncode, StrictDecode)]
 pub struct ProprietaryKeyDescriptor {
     pub location: ProprietaryKeyLocation,
     pub ty: ProprietaryKeyType,
@@ -185,3 +190,178 @@ impl Display for ProprietaryKeyDescriptor {
         )
     }
 }
+
+impl Psbt {
+    /// Inserts proprietary key defined by the descriptor into the global,
+    /// input or output map of the PSBT, replacing existing value.
+    ///
+    /// # Errors
+    ///
+    /// If the input or output referenced by the descriptor does not exist.
+    pub fn insert_proprietary_key(
+        &mut self,
+        key: &ProprietaryKeyDescriptor,
+    ) -> Result<(), ProprietaryKeyError> {
+        let value = key.value.as_ref().cloned().unwrap_or_default();
+        let map = match key.location {
+            ProprietaryKeyLocation::Global => &mut self.proprietary,
+            ProprietaryKeyLocation::Input(pos) => {
+                let len = self.inputs.len();
+                &mut self
+                    .inputs
+                    .get_mut(pos as usize)
+                    .ok_or(ProprietaryKeyError::InputOutOfRange(pos, len))?
+                    .proprietary
+            }
+            ProprietaryKeyLocation::Output(pos) => {
+                let len = self.outputs.len();
+                &mut self
+                    .outputs
+                    .get_mut(pos as usize)
+                    .ok_or(ProprietaryKeyError::OutputOutOfRange(pos, len))?
+                    .proprietary
+            }
+        };
+        map.insert(key.into(), value);
+        Ok(())
+    }
+
+    /// Removes proprietary keys from the global, input and output maps of the
+    /// PSBT. If `prefix` is provided, only keys with that prefix are removed;
+    /// otherwise all proprietary keys are removed.
+    ///
+    /// Returns number of removed keys.
+    pub fn strip_proprietary(&mut self, prefix: Option<&[u8]>) -> usize {
+        self.retain_proprietary(|_, key, _| match prefix {
+            Some(prefix) => key.prefix.as_slice() != prefix,
+            None => false,
+        })
+    }
+
+    /// Retains only those proprietary keys for which the `filter` returns
+    /// `true`. The filter receives location of the key within the PSBT, the
+    /// key itself and the value associated with the key.
+    ///
+    /// Returns number of removed keys.
+    pub fn retain_proprietary<F>(&mut self, mut filter: F) -> usize
+    where
+        F: FnMut(ProprietaryKeyLocation, &ProprietaryKey, &[u8]) -> bool,
+    {
+        let mut count = 0usize;
+        let mut retain = |location, map: &mut BTreeMap<ProprietaryKey, Vec<u8>>| {
+            let len = map.len();
+            map.retain(|key, value| filter(location, key, value));
+            count += len - map.len();
+        };
+        retain(ProprietaryKeyLocation::Global, &mut self.proprietary);
+        for (index, input) in self.inputs.iter_mut().enumerate() {
+            retain(
+                ProprietaryKeyLocation::Input(index as u16),
+                &mut input.proprietary,
+            );
+        }
+        for (index, output) in self.outputs.iter_mut().enumerate() {
+            retain(
+                ProprietaryKeyLocation::Output(index as u16),
+                &mut output.proprietary,
+            );
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+    use strict_encoding::{StrictDecode, StrictEncode};
+
+    use super::*;
+
+    fn key_descriptor() -> impl Strategy<Value = ProprietaryKeyDescriptor> {
+        (
+            prop_oneof![
+                Just(ProprietaryKeyLocation::Global),
+                any::<u16>().prop_map(ProprietaryKeyLocation::Input),
+                any::<u16>().prop_map(ProprietaryKeyLocation::Output),
+            ],
+            "[A-Za-z0-9]{0,16}",
+            any::<u8>(),
+            option::of(vec(any::<u8>(), 0..64)),
+            option::of(vec(any::<u8>(), 0..64)),
+        )
+            .prop_map(
+                |(location, prefix, subtype, key, value)| ProprietaryKeyDescriptor {
+                    location,
+                    ty: ProprietaryKeyType { prefix, subtype },
+                    key,
+                    value,
+                },
+            )
+    }
+
+    fn key(prefix: &[u8], subtype: u8) -> ProprietaryKey {
+        ProprietaryKey {
+            prefix: prefix.to_vec(),
+            subtype,
+            key: vec![],
+        }
+    }
+
+    fn psbt() -> Psbt {
+        let mut psbt = Psbt::default();
+        psbt.proprietary.insert(key(b"TAPRET", 0), vec![]);
+        psbt.proprietary.insert(key(b"LNPBP4", 1), vec![1]);
+        let mut input = crate::Input::default();
+        input.proprietary.insert(key(b"P2C", 0), vec![2]);
+        psbt.inputs.push(input);
+        let mut output = crate::Output::default();
+        output.proprietary.insert(key(b"TAPRET", 1), vec![3]);
+        psbt.outputs.push(output);
+        psbt
+    }
+
+    #[test]
+    fn strip_proprietary() {
+        let mut psbt = psbt();
+        assert_eq!(psbt.strip_proprietary(Some(b"TAPRET")), 2);
+        assert_eq!(psbt.proprietary.len(), 1);
+        assert_eq!(psbt.inputs[0].proprietary.len(), 1);
+        assert!(psbt.outputs[0].proprietary.is_empty());
+
+        assert_eq!(psbt.strip_proprietary(None), 2);
+        assert!(psbt.proprietary.is_empty());
+        assert!(psbt.inputs[0].proprietary.is_empty());
+    }
+
+    #[test]
+    fn retain_proprietary() {
+        let mut psbt = psbt();
+        let removed = psbt.retain_proprietary(|location, key, _| {
+            location == ProprietaryKeyLocation::Input(0) || key.prefix == b"LNPBP4"
+        });
+        assert_eq!(removed, 2);
+        assert_eq!(psbt.proprietary.len(), 1);
+        assert_eq!(psbt.inputs[0].proprietary.len(), 1);
+        assert!(psbt.outputs[0].proprietary.is_empty());
+    }
+
+    #[test]
+    fn descriptor_strict_encoding() {
+        let descriptor = ProprietaryKeyDescriptor::from_str("input(1) DBC(1) 8536ba03:01").unwrap();
+        let data = descriptor.strict_serialize().unwrap();
+        assert_eq!(
+            ProprietaryKeyDescriptor::strict_deserialize(data).unwrap(),
+            descriptor
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn descriptor_strict_roundtrip(descriptor in key_descriptor()) {
+            let data = descriptor.strict_serialize().unwrap();
+            prop_assert_eq!(ProprietaryKeyDescriptor::strict_deserialize(data)?, descriptor);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Detection of PSBT inputs conflicting with unconfirmed transactions and
+//! computation of the fee required to replace them according to BIP-125.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use bitcoin::{OutPoint, Transaction, Txid};
+use bitcoin_onchain::{ResolveMempool, TxResolverError};
+
+use crate::Psbt;
+
+/// Default incremental relay fee rate used by Bitcoin Core, in sats per
+/// virtual byte.
+pub const DEFAULT_INCREMENTAL_RELAY_FEE: u64 = 1;
+
+/// Unconfirmed transaction which will be evicted from the mempool by a
+/// transaction spending the PSBT inputs.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub struct MempoolConflict {
+    /// Indexes of the PSBT inputs spending the same outputs as the
+    /// transaction. Empty for descendants of the directly conflicting
+    /// transactions.
+    pub inputs: BTreeSet<usize>,
+
+    /// Fee paid by the transaction, in sats
+    pub fee: u64,
+
+    /// Virtual size of the transaction
+    pub vsize: u64,
+
+    /// Whether the transaction explicitly signals replaceability by having
+    /// an input with the sequence number below `0xFFFFFFFE`
+    pub signals_rbf: bool,
+}
+
+impl MempoolConflict {
+    fn with(tx: &Transaction, fee: u64) -> MempoolConflict {
+        MempoolConflict {
+            inputs: empty!(),
+            fee,
+            vsize: tx.vsize() as u64,
+            signals_rbf: tx.is_explicitly_rbf(),
+        }
+    }
+
+    /// Fee rate of the transaction, in sats per virtual byte.
+    #[inline]
+    pub fn fee_rate(&self) -> f64 { self.fee as f64 / self.vsize as f64 }
+}
+
+/// Unconfirmed transactions conflicting with the PSBT inputs, returned by
+/// [`Psbt::mempool_conflicts`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "camelCase")
+)]
+pub struct MempoolConflicts {
+    /// Transactions spending the same outputs as the PSBT inputs
+    pub direct: BTreeMap<Txid, MempoolConflict>,
+
+    /// Unconfirmed descendants of the directly conflicting transactions,
+    /// which will be evicted from the mempool together with them
+    pub descendants: BTreeMap<Txid, MempoolConflict>,
+}
+
+impl MempoolConflicts {
+    /// Detects whether there are no conflicting transactions.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.direct.is_empty() }
+
+    /// Detects whether all directly conflicting transactions signal
+    /// replaceability. Inherited signaling from the unconfirmed ancestors is
+    /// not taken into account, matching the Bitcoin Core behaviour.
+    pub fn is_replaceable(&self) -> bool {
+        self.direct.values().all(|conflict| conflict.signals_rbf)
+    }
+
+    /// Returns ids of the conflicting transactions which do not signal
+    /// replaceability.
+    pub fn non_replaceable(&self) -> BTreeSet<Txid> {
+        self.direct
+            .iter()
+            .filter(|(_, conflict)| !conflict.signals_rbf)
+            .map(|(txid, _)| *txid)
+            .collect()
+    }
+
+    /// Total fee of all transactions which will be evicted from the mempool,
+    /// including the descendants of the directly conflicting transactions.
+    pub fn evicted_fee(&self) -> u64 {
+        self.direct
+            .values()
+            .chain(self.descendants.values())
+            .map(|conflict| conflict.fee)
+            .sum()
+    }
+
+    /// Computes the minimum fee of a replacement transaction with virtual
+    /// size `vsize`, which is required by BIP-125 rules. The fee must pay
+    /// for all evicted transactions plus the replacement bandwidth at
+    /// `incremental_fee_rate` (in sats per virtual byte; see
+    /// [`DEFAULT_INCREMENTAL_RELAY_FEE`]), and the replacement fee rate must
+    /// exceed fee rates of all directly conflicting transactions.
+    ///
+    /// Returns zero if there are no conflicts.
+    pub fn min_replacement_fee(&self, vsize: u64, incremental_fee_rate: u64) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        let bip125_fee = self.evicted_fee() + incremental_fee_rate * vsize;
+        let fee_rate_fee = self
+            .direct
+            .values()
+            .map(|conflict| {
+                (conflict.fee as u128 * vsize as u128 / conflict.vsize as u128) as u64 + 1
+            })
+            .max()
+            .unwrap_or_default();
+        bip125_fee.max(fee_rate_fee)
+    }
+}
+
+impl Psbt {
+    /// Detects unconfirmed transactions from the `mempool` spending the same
+    /// outputs as the PSBT inputs, together with their descendants.
+    ///
+    /// # Errors
+    ///
+    /// If the mempool can't be queried.
+    pub fn mempool_conflicts(
+        &self,
+        mempool: &impl ResolveMempool,
+    ) -> Result<MempoolConflicts, TxResolverError> {
+        let mut conflicts = MempoolConflicts::default();
+        let mut queue = VecDeque::new();
+
+        for (index, input) in self.inputs.iter().enumerate() {
+            let (tx, fee) = match mempool.resolve_mempool_spender(input.previous_outpoint)? {
+                Some(spender) => spender,
+                None => continue,
+            };
+            let txid = tx.txid();
+            conflicts
+                .direct
+                .entry(txid)
+                .or_insert_with(|| {
+                    queue.push_back(tx.clone());
+                    MempoolConflict::with(&tx, fee)
+                })
+                .inputs
+                .insert(index);
+        }
+
+        while let Some(tx) = queue.pop_front() {
+            let txid = tx.txid();
+            for vout in 0..tx.output.len() as u32 {
+                let outpoint = OutPoint::new(txid, vout);
+                let (child, fee) = match mempool.resolve_mempool_spender(outpoint)? {
+                    Some(spender) => spender,
+                    None => continue,
+                };
+                let child_txid = child.txid();
+                if conflicts.direct.contains_key(&child_txid)
+                    || conflicts.descendants.contains_key(&child_txid)
+                {
+                    continue;
+                }
+                conflicts
+                    .descendants
+                    .insert(child_txid, MempoolConflict::with(&child, fee));
+                queue.push_back(child);
+            }
+        }
+
+        Ok(conflicts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{PackedLockTime, Script, Sequence, TxIn, TxOut};
+
+    use super::*;
+    use crate::PsbtVersion;
+
+    fn tx(inputs: &[OutPoint], sequence: u32, outputs: usize) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    sequence: Sequence(sequence),
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: Script::new(),
+                };
+                outputs
+            ],
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        let prevout = |no: u8| OutPoint::new(Txid::from_inner([no; 32]), 0);
+        let replaceable = tx(&[prevout(1), prevout(2)], 0xFFFFFFFD, 2);
+        let child = tx(&[OutPoint::new(replaceable.txid(), 1)], 0xFFFFFFFF, 1);
+        let final_tx = tx(&[prevout(3)], 0xFFFFFFFF, 1);
+        let mempool = bmap! {
+            replaceable.txid() => (replaceable.clone(), 1_000),
+            child.txid() => (child.clone(), 500),
+            final_tx.txid() => (final_tx.clone(), 300)
+        };
+
+        let psbt = Psbt::with(
+            tx(&[prevout(2), prevout(4), prevout(1)], 0, 1),
+            PsbtVersion::V0,
+        )
+        .unwrap();
+        let conflicts = psbt.mempool_conflicts(&mempool).unwrap();
+        assert_eq!(conflicts.direct.len(), 1);
+        assert_eq!(conflicts.direct[&replaceable.txid()].inputs, bset! { 0, 2 });
+        assert!(conflicts.direct[&replaceable.txid()].signals_rbf);
+        assert_eq!(conflicts.descendants.keys().collect::<Vec<_>>(), vec![
+            &child.txid()
+        ]);
+        assert!(conflicts.is_replaceable());
+        assert_eq!(conflicts.evicted_fee(), 1_500);
+        assert_eq!(
+            conflicts.min_replacement_fee(100, DEFAULT_INCREMENTAL_RELAY_FEE),
+            1_600
+        );
+        // Conflicting transaction has 110 vbytes, so its fee rate dominates
+        assert_eq!(conflicts.min_replacement_fee(1_000, 0), 9_090 + 1);
+
+        let psbt = Psbt::with(tx(&[prevout(3)], 0, 1), PsbtVersion::V0).unwrap();
+        let conflicts = psbt.mempool_conflicts(&mempool).unwrap();
+        assert!(!conflicts.is_replaceable());
+        assert_eq!(conflicts.non_replaceable(), bset! { final_tx.txid() });
+        assert!(conflicts.descendants.is_empty());
+
+        let psbt = Psbt::with(tx(&[prevout(4)], 0, 1), PsbtVersion::V0).unwrap();
+        let conflicts = psbt.mempool_conflicts(&mempool).unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            conflicts.min_replacement_fee(200, DEFAULT_INCREMENTAL_RELAY_FEE),
+            0
+        );
+    }
+}
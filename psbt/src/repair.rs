@@ -0,0 +1,394 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Lenient matching of PSBT inputs with the outputs they spend, repairing
+//! missing or inconsistent `witness_utxo` and `non_witness_utxo` fields, and
+//! population of these fields from a transaction source.
+
+#[cfg(feature = "async")]
+use std::collections::BTreeSet;
+
+use amplify::Wrapper;
+use bitcoin::Script;
+use bitcoin_onchain::ResolveTx;
+#[cfg(feature = "async")]
+use bitcoin_onchain::ResolveTxAsync;
+
+use crate::{Input, InputMatchError, Psbt};
+
+/// Modification made to a PSBT input during lenient prevout matching.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub enum RepairAction {
+    /// missing `non_witness_utxo` was added from the transaction source
+    NonWitnessUtxoAdded,
+
+    /// `non_witness_utxo` not matching the input txid was replaced with the
+    /// transaction from the source
+    NonWitnessUtxoReplaced,
+
+    /// missing `witness_utxo` for the witness output was added from the spent
+    /// transaction
+    WitnessUtxoAdded,
+
+    /// `witness_utxo` not matching the output of the spent transaction was
+    /// replaced with that output
+    WitnessUtxoReplaced,
+}
+
+/// Modification made to a specific PSBT input during lenient prevout
+/// matching.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display("input #{input}: {action}")]
+pub struct InputRepair {
+    /// Index of the repaired input
+    pub input: usize,
+
+    /// Modification made to the input
+    pub action: RepairAction,
+}
+
+/// Error happening when PSBT input can't be matched with the spent output
+/// even in lenient mode.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error)]
+#[display("unable to match input #{input} with the output it spends: {error}")]
+pub struct RepairError {
+    /// Index of the input
+    pub input: usize,
+
+    /// Matching error for the input
+    pub error: InputMatchError,
+}
+
+impl Input {
+    /// Matches the input with the output it spends by txid and vout, even if
+    /// `witness_utxo` and `non_witness_utxo` fields disagree on presence.
+    /// Missing or inconsistent fields are repaired using transactions from
+    /// `tx_source`.
+    ///
+    /// # Returns
+    ///
+    /// List of modifications made to the input, which is empty if the input
+    /// was consistent.
+    ///
+    /// # Errors
+    ///
+    /// If the spent output can be found neither in the input nor in the
+    /// transaction source.
+    pub fn repair_prevout(
+        &mut self,
+        tx_source: &impl ResolveTx,
+    ) -> Result<Vec<RepairAction>, InputMatchError> {
+        let outpoint = self.previous_outpoint;
+        let mut actions = vec![];
+
+        let matching = matches!(&self.non_witness_utxo, Some(tx) if tx.txid() == outpoint.txid);
+        if !matching {
+            if let Ok(tx) = tx_source.resolve_tx(outpoint.txid) {
+                if tx.txid() == outpoint.txid {
+                    actions.push(match self.non_witness_utxo {
+                        None => RepairAction::NonWitnessUtxoAdded,
+                        Some(_) => RepairAction::NonWitnessUtxoReplaced,
+                    });
+                    self.non_witness_utxo = Some(tx);
+                }
+            }
+        }
+
+        let prevout = match &self.non_witness_utxo {
+            Some(tx) if tx.txid() == outpoint.txid => Some(
+                tx.output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+                    .ok_or(InputMatchError::UnmatchedInputNumber(outpoint.vout))?,
+            ),
+            _ => None,
+        };
+
+        match (prevout, self.witness_utxo.clone()) {
+            (Some(prevout), Some(txout)) if txout != prevout => {
+                self.witness_utxo = Some(prevout);
+                actions.push(RepairAction::WitnessUtxoReplaced);
+            }
+            (Some(prevout), None) if self.is_witness_spending(&prevout.script_pubkey) => {
+                self.witness_utxo = Some(prevout);
+                actions.push(RepairAction::WitnessUtxoAdded);
+            }
+            (None, None) => {
+                return Err(match self.non_witness_utxo {
+                    None => InputMatchError::NoInputTx,
+                    Some(_) => InputMatchError::NoTxidMatch(outpoint.txid),
+                })
+            }
+            _ => {}
+        }
+
+        Ok(actions)
+    }
+
+    /// Attaches data on the spent output required by signers: full previous
+    /// transaction (`non_witness_utxo`) for all inputs except taproot ones,
+    /// and `witness_utxo` for segwit and nested segwit inputs. The data
+    /// already present in the input are kept; missing previous transaction is
+    /// taken from `tx_source`.
+    ///
+    /// # Returns
+    ///
+    /// List of fields added to the input.
+    ///
+    /// # Errors
+    ///
+    /// If the previous transaction is required, but can't be resolved, or it
+    /// does not match the input.
+    pub fn populate_prevout(
+        &mut self,
+        tx_source: &(impl ResolveTx + ?Sized),
+    ) -> Result<Vec<RepairAction>, InputMatchError> {
+        let outpoint = self.previous_outpoint;
+        let tx = match (&self.non_witness_utxo, &self.witness_utxo) {
+            (Some(tx), _) if tx.txid() == outpoint.txid => tx.clone(),
+            (Some(_), _) => return Err(InputMatchError::NoTxidMatch(outpoint.txid)),
+            (None, Some(txout)) if txout.script_pubkey.is_v1_p2tr() => return Ok(vec![]),
+            (None, _) => tx_source
+                .resolve_tx(outpoint.txid)
+                .ok()
+                .filter(|tx| tx.txid() == outpoint.txid)
+                .ok_or(InputMatchError::NoInputTx)?,
+        };
+        let prevout = tx
+            .output
+            .get(outpoint.vout as usize)
+            .cloned()
+            .ok_or(InputMatchError::UnmatchedInputNumber(outpoint.vout))?;
+
+        let mut actions = vec![];
+        if self.non_witness_utxo.is_none() && !prevout.script_pubkey.is_v1_p2tr() {
+            self.non_witness_utxo = Some(tx);
+            actions.push(RepairAction::NonWitnessUtxoAdded);
+        }
+        if self.witness_utxo.is_none() && self.is_witness_spending(&prevout.script_pubkey) {
+            self.witness_utxo = Some(prevout);
+            actions.push(RepairAction::WitnessUtxoAdded);
+        }
+        Ok(actions)
+    }
+
+    fn is_witness_spending(&self, script_pubkey: &Script) -> bool {
+        script_pubkey.is_witness_program()
+            || self.witness_script.is_some()
+            || (script_pubkey.is_p2sh()
+                && matches!(&self.redeem_script, Some(s) if s.as_inner().is_witness_program()))
+    }
+}
+
+impl Psbt {
+    /// Repairs all PSBT inputs with [`Input::repair_prevout`], reporting the
+    /// modifications made.
+    pub fn repair_prevouts(
+        &mut self,
+        tx_source: &impl ResolveTx,
+    ) -> Result<Vec<InputRepair>, RepairError> {
+        let mut repairs = vec![];
+        for input in &mut self.inputs {
+            let index = input.index();
+            let actions = input
+                .repair_prevout(tx_source)
+                .map_err(|error| RepairError {
+                    input: index,
+                    error,
+                })?;
+            repairs.extend(actions.into_iter().map(|action| InputRepair {
+                input: index,
+                action,
+            }));
+        }
+        Ok(repairs)
+    }
+}
+
+impl Psbt {
+    /// Attaches spent output data required by signers to all PSBT inputs with
+    /// [`Input::populate_prevout`], reporting the added fields. Any transaction
+    /// source may be used, including onchain backends and local transaction
+    /// maps.
+    pub fn populate_prevouts(
+        &mut self,
+        tx_source: &(impl ResolveTx + ?Sized),
+    ) -> Result<Vec<InputRepair>, RepairError> {
+        let mut populated = vec![];
+        for input in &mut self.inputs {
+            let index = input.index();
+            let actions = input
+                .populate_prevout(tx_source)
+                .map_err(|error| RepairError {
+                    input: index,
+                    error,
+                })?;
+            populated.extend(actions.into_iter().map(|action| InputRepair {
+                input: index,
+                action,
+            }));
+        }
+        Ok(populated)
+    }
+
+    /// Attaches spent output data to all PSBT inputs like
+    /// [`Psbt::populate_prevouts`], resolving the previous transactions missing
+    /// in the inputs with an async transaction source.
+    #[cfg(feature = "async")]
+    pub async fn populate_prevouts_async(
+        &mut self,
+        tx_source: &impl ResolveTxAsync,
+    ) -> Result<Vec<InputRepair>, RepairError> {
+        let txids = self
+            .inputs
+            .iter()
+            .filter(|input| {
+                input.non_witness_utxo.is_none()
+                    && !matches!(
+                        &input.witness_utxo, Some(txout) if txout.script_pubkey.is_v1_p2tr()
+                    )
+            })
+            .map(|input| input.previous_outpoint.txid)
+            .collect::<BTreeSet<_>>();
+        let mut txes = bmap! {};
+        for txid in txids {
+            // Unresolved transactions are reported by the input population
+            if let Ok(tx) = tx_source.resolve_tx_async(txid).await {
+                txes.insert(txid, tx);
+            }
+        }
+        self.populate_prevouts(&txes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, PackedLockTime, Transaction, TxIn, TxOut, WPubkeyHash};
+
+    use super::*;
+    use crate::PsbtVersion;
+
+    fn tx(previous_output: OutPoint, script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[test]
+    fn repair_prevout() {
+        let spk = Script::new_v0_p2wpkh(&WPubkeyHash::hash(b"key"));
+        let prev_tx = tx(OutPoint::default(), spk.clone());
+        let txid = prev_tx.txid();
+        let tx_source = bmap! { txid => prev_tx.clone() };
+        let mut psbt = Psbt::with(tx(OutPoint::new(txid, 0), spk), PsbtVersion::V0).unwrap();
+
+        assert_eq!(psbt.repair_prevouts(&tx_source).unwrap(), vec![
+            InputRepair {
+                input: 0,
+                action: RepairAction::NonWitnessUtxoAdded
+            },
+            InputRepair {
+                input: 0,
+                action: RepairAction::WitnessUtxoAdded
+            },
+        ]);
+        assert_eq!(psbt.inputs[0].non_witness_utxo, Some(prev_tx.clone()));
+        assert_eq!(psbt.inputs[0].witness_utxo, Some(prev_tx.output[0].clone()));
+        assert_eq!(psbt.repair_prevouts(&tx_source).unwrap(), vec![]);
+
+        psbt.inputs[0].witness_utxo = Some(TxOut::default());
+        assert_eq!(psbt.inputs[0].repair_prevout(&tx_source).unwrap(), vec![
+            RepairAction::WitnessUtxoReplaced
+        ]);
+        assert_eq!(psbt.fee(), Ok(0));
+
+        psbt.inputs[0].non_witness_utxo = Some(tx(OutPoint::default(), Script::new()));
+        psbt.inputs[0].witness_utxo = None;
+        assert_eq!(psbt.inputs[0].repair_prevout(&BTreeMap::new()), Err(
+            InputMatchError::NoTxidMatch(txid)
+        ));
+        assert_eq!(psbt.inputs[0].repair_prevout(&tx_source).unwrap(), vec![
+            RepairAction::NonWitnessUtxoReplaced,
+            RepairAction::WitnessUtxoAdded
+        ]);
+    }
+
+    #[test]
+    fn populate_prevouts() {
+        let nested = Script::new_v0_p2wpkh(&WPubkeyHash::hash(b"nested"));
+        let prev_tx = Transaction {
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: Script::new_p2pkh(&bitcoin::PubkeyHash::hash(b"legacy")),
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: Script::new_p2sh(&nested.script_hash()),
+                },
+            ],
+            ..tx(OutPoint::default(), Script::new())
+        };
+        let txid = prev_tx.txid();
+        let spending = Transaction {
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint::new(txid, 0),
+                    ..TxIn::default()
+                },
+                TxIn {
+                    previous_output: OutPoint::new(txid, 1),
+                    ..TxIn::default()
+                },
+            ],
+            ..tx(OutPoint::default(), Script::new())
+        };
+        let mut psbt = Psbt::with(spending, PsbtVersion::V0).unwrap();
+        psbt.inputs[1].redeem_script = Some(nested.into());
+
+        let tx_source: &dyn ResolveTx = &bmap! { txid => prev_tx.clone() };
+        assert_eq!(psbt.clone().populate_prevouts(&BTreeMap::new()), Err(RepairError {
+            input: 0,
+            error: InputMatchError::NoInputTx
+        }));
+        assert_eq!(psbt.populate_prevouts(tx_source).unwrap(), vec![
+            InputRepair {
+                input: 0,
+                action: RepairAction::NonWitnessUtxoAdded
+            },
+            InputRepair {
+                input: 1,
+                action: RepairAction::NonWitnessUtxoAdded
+            },
+            InputRepair {
+                input: 1,
+                action: RepairAction::WitnessUtxoAdded
+            },
+        ]);
+        assert_eq!(psbt.inputs[0].witness_utxo, None);
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(prev_tx.output[1].clone()));
+        assert_eq!(psbt.populate_prevouts(tx_source).unwrap(), vec![]);
+        assert_eq!(psbt.fee(), Ok(20_000));
+    }
+}
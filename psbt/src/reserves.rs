@@ -0,0 +1,367 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Proof-of-reserves PSBTs (in the spirit of BIP-127 and BIP-322).
+//!
+//! Proof is a transaction which can't ever be mined: its first input spends
+//! a non-existing "challenge" output, which transaction id commits to the
+//! proof message. The rest of the inputs spend all of the wallet UTXOs, and
+//! the only output carries their total amount. Signing the UTXO inputs with
+//! `SIGHASH_ALL` commits signatures to the challenge, and thus to the
+//! message, proving control over the coins at the time of signing.
+//!
+//! Challenge input has zero amount and empty `scriptPubkey` and is never
+//! signed.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use amplify::Wrapper;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::sighash::Prevouts;
+use bitcoin::{EcdsaSighashType, OutPoint, SchnorrSighashType, Script, TxOut, Txid, Witness};
+use bitcoin_blockchain::locks::SeqNo;
+use bitcoin_hd::{DerivationAccount, SegmentIndexes, UnhardenedIndex};
+use bitcoin_onchain::ResolveTx;
+use bitcoin_scripts::PubkeyScript;
+use descriptors::InputDescriptor;
+use miniscript::interpreter::{Interpreter, KeySigPair, SatisfiedConstraint};
+use miniscript::Descriptor;
+
+use crate::construct::Error;
+use crate::{Input, LockTimeError, MaxFeePolicy, Output, Psbt};
+
+/// Prefix of the message committed to by the challenge input transaction id.
+pub const RESERVES_CHALLENGE_PREFIX: &str = "Proof-of-Reserves: ";
+
+/// Errors constructing and verifying proofs of reserves.
+#[derive(Debug, Display, From)]
+#[display(doc_comments)]
+pub enum ReservesError {
+    /// unable to construct proof of reserves. {0}
+    #[from]
+    Construct(Error),
+
+    /// proof of reserves does not contain any coins
+    NoCoins,
+
+    /// first input of the proof does not commit to the message
+    ChallengeMismatch,
+
+    /// proof of reserves must have a single output
+    InvalidOutput,
+
+    /// proof output declares {declared} sats, while the total amount of the
+    /// coins is {actual} sats
+    AmountMismatch {
+        /// Amount of the proof output
+        declared: u64,
+
+        /// Total amount of the proven coins
+        actual: u64,
+    },
+
+    /// coin {0} is included into the proof multiple times
+    DuplicateCoin(OutPoint),
+
+    /// proof spends the challenge outpoint {0} as a coin
+    ChallengeCoin(OutPoint),
+
+    /// coin {0} is not present in the UTXO set
+    UtxoUnknown(OutPoint),
+
+    /// spent output information for coin {0} does not match the UTXO set
+    UtxoMismatch(OutPoint),
+
+    /// input #{0} of the proof is not finalized
+    NotFinalized(usize),
+
+    /// input #{0} is signed with a signature hash type other than
+    /// SIGHASH_ALL
+    InvalidSighash(usize),
+
+    /// input #{0} does not satisfy the spending conditions. {1}
+    InvalidSatisfaction(usize, miniscript::interpreter::Error),
+
+    /// unable to construct the proof transaction. {0}
+    #[from]
+    LockTime(LockTimeError),
+}
+
+impl std::error::Error for ReservesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReservesError::Construct(err) => Some(err),
+            ReservesError::InvalidSatisfaction(_, err) => Some(err),
+            ReservesError::LockTime(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Computes outpoint spent by the challenge input of the proof for a given
+/// message.
+pub fn challenge_outpoint(message: &str) -> OutPoint {
+    let mut data = RESERVES_CHALLENGE_PREFIX.as_bytes().to_vec();
+    data.extend(message.as_bytes());
+    OutPoint::new(Txid::from_hash(sha256d::Hash::hash(&data)), 0)
+}
+
+fn challenge_txout() -> TxOut {
+    TxOut {
+        value: 0,
+        script_pubkey: Script::new(),
+    }
+}
+
+impl Psbt {
+    /// Constructs proof of reserves for the `message`, spending the provided
+    /// wallet coins. The proof must be signed and finalized for all inputs
+    /// except the first (challenge) one.
+    pub fn proof_of_reserves<'coins>(
+        descriptor: &Descriptor<DerivationAccount>,
+        coins: impl IntoIterator<Item = &'coins InputDescriptor>,
+        message: &str,
+        tx_resolver: &impl ResolveTx,
+    ) -> Result<Psbt, ReservesError> {
+        let coins = coins
+            .into_iter()
+            .map(|coin| InputDescriptor {
+                sighash_type: EcdsaSighashType::All,
+                ..coin.clone()
+            })
+            .collect::<Vec<_>>();
+        if coins.is_empty() {
+            return Err(ReservesError::NoCoins);
+        }
+        let mut outpoints = BTreeSet::new();
+        if let Some(coin) = coins.iter().find(|coin| !outpoints.insert(coin.outpoint)) {
+            return Err(ReservesError::DuplicateCoin(coin.outpoint));
+        }
+
+        // All the funds go to the change output, which is replaced with the
+        // proof output
+        let mut psbt = Psbt::construct_with_policy(
+            descriptor,
+            &coins,
+            &[] as &[(PubkeyScript, u64)],
+            UnhardenedIndex::zero(),
+            0,
+            &MaxFeePolicy::default(),
+            tx_resolver,
+        )?;
+        let total = psbt.outputs.iter().map(|output| output.amount).sum();
+        psbt.outputs = vec![Output {
+            index: 0,
+            amount: total,
+            script: Script::new_op_return(&[]).into(),
+            ..default!()
+        }];
+
+        for input in &mut psbt.inputs {
+            input.index += 1;
+        }
+        psbt.inputs.insert(0, Input {
+            index: 0,
+            previous_outpoint: challenge_outpoint(message),
+            sequence_number: Some(SeqNo::unencumbered(true)),
+            witness_utxo: Some(challenge_txout()),
+            ..default!()
+        });
+        Ok(psbt)
+    }
+
+    /// Verifies finalized proof of reserves for the `message` against the
+    /// UTXO set, checking that all the coins are unspent, their spending
+    /// conditions are satisfied with `SIGHASH_ALL` signatures, and the proof
+    /// output declares their total amount. Each coin must be included into
+    /// the proof only once.
+    ///
+    /// # Returns
+    ///
+    /// Total amount of the proven coins.
+    pub fn verify_reserves<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        message: &str,
+        utxo_set: &BTreeMap<OutPoint, TxOut>,
+    ) -> Result<u64, ReservesError> {
+        let (challenge, coins) = self.inputs.split_first().ok_or(ReservesError::NoCoins)?;
+        if coins.is_empty() {
+            return Err(ReservesError::NoCoins);
+        }
+        let challenge_prevout = challenge_outpoint(message);
+        if challenge.previous_outpoint != challenge_prevout {
+            return Err(ReservesError::ChallengeMismatch);
+        }
+        let declared = match self.outputs.as_slice() {
+            [output] => output.amount,
+            _ => return Err(ReservesError::InvalidOutput),
+        };
+
+        let mut prevouts = vec![challenge_txout()];
+        let mut outpoints = BTreeSet::new();
+        for input in coins {
+            let outpoint = input.previous_outpoint;
+            if outpoint == challenge_prevout {
+                return Err(ReservesError::ChallengeCoin(outpoint));
+            }
+            if !outpoints.insert(outpoint) {
+                return Err(ReservesError::DuplicateCoin(outpoint));
+            }
+            let utxo = utxo_set
+                .get(&outpoint)
+                .ok_or(ReservesError::UtxoUnknown(outpoint))?;
+            if matches!(input.input_prevout(), Ok(prevout) if prevout != utxo) {
+                return Err(ReservesError::UtxoMismatch(outpoint));
+            }
+            prevouts.push(utxo.clone());
+        }
+        let actual = prevouts.iter().map(|prevout| prevout.value).sum();
+        if declared != actual {
+            return Err(ReservesError::AmountMismatch { declared, actual });
+        }
+
+        let tx = self.to_unsigned_tx()?;
+        let all_prevouts = Prevouts::All(prevouts.as_slice());
+        let empty_witness = Witness::default();
+        let empty_script = Script::new();
+        for (index, input) in self.inputs.iter().enumerate().skip(1) {
+            if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+                return Err(ReservesError::NotFinalized(index));
+            }
+            let script_sig = input
+                .final_script_sig
+                .as_ref()
+                .map(Wrapper::as_inner)
+                .unwrap_or(&empty_script);
+            let witness = input.final_script_witness.as_ref().unwrap_or(&empty_witness);
+            let interpreter = Interpreter::from_txdata(
+                &prevouts[index].script_pubkey,
+                script_sig,
+                witness,
+                tx.input[index].sequence,
+                tx.lock_time.into(),
+            )
+            .map_err(|err| ReservesError::InvalidSatisfaction(index, err))?;
+            for constraint in interpreter.iter(secp, &tx, index, &all_prevouts) {
+                let key_sig = match constraint
+                    .map_err(|err| ReservesError::InvalidSatisfaction(index, err))?
+                {
+                    SatisfiedConstraint::PublicKey { key_sig } => key_sig,
+                    SatisfiedConstraint::PublicKeyHash { key_sig, .. } => key_sig,
+                    _ => continue,
+                };
+                let sighash_all = match key_sig {
+                    KeySigPair::Ecdsa(_, sig) => sig.hash_ty == EcdsaSighashType::All,
+                    KeySigPair::Schnorr(_, sig) => matches!(
+                        sig.hash_ty,
+                        SchnorrSighashType::All | SchnorrSighashType::Default
+                    ),
+                };
+                if !sighash_all {
+                    return Err(ReservesError::InvalidSighash(index));
+                }
+            }
+        }
+
+        Ok(actual)
+    }
+}
+
+#[cfg(all(test, feature = "sign"))]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::psbt::PartiallySignedTransaction;
+    use bitcoin::secp256k1::SECP256K1;
+    use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin::Network;
+    use descriptors::derive::DeriveDescriptor;
+    use miniscript::psbt::PsbtExt;
+
+    use super::*;
+    use crate::fixtures;
+    use crate::sign::{MemoryKeyProvider, MemorySigningAccount, SignAll};
+
+    #[test]
+    fn proof_of_reserves() {
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let master_id = ExtendedPubKey::from_priv(SECP256K1, &master).identifier();
+        let derivation = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let account_xpriv = master.derive_priv(SECP256K1, &derivation).unwrap();
+        let account = MemorySigningAccount::with(SECP256K1, master_id, derivation, account_xpriv);
+        let descriptor = Descriptor::new_wpkh(account.to_account()).unwrap();
+
+        let terminal = [UnhardenedIndex::zero(), UnhardenedIndex::one()];
+        let script_pubkey = DeriveDescriptor::<bitcoin::PublicKey>::derive_descriptor(
+            &descriptor,
+            SECP256K1,
+            terminal,
+        )
+        .unwrap()
+        .script_pubkey();
+        let funding = fixtures::tx([(100_000, script_pubkey)]);
+        let outpoint = OutPoint::new(funding.txid(), 0);
+        let resolver = bmap! { funding.txid() => funding.clone() };
+        let coin = InputDescriptor::from_str(&format!("{} /0/1", outpoint)).unwrap();
+
+        let mut psbt =
+            Psbt::proof_of_reserves(&descriptor, [&coin], "audit 2022", &resolver).unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        assert_eq!(psbt.inputs[0].previous_outpoint, challenge_outpoint("audit 2022"));
+        assert_eq!(psbt.outputs.len(), 1);
+        assert_eq!(psbt.outputs[0].amount, 100_000);
+
+        let utxo_set = bmap! { outpoint => funding.output[0].clone() };
+        assert!(matches!(
+            psbt.verify_reserves(SECP256K1, "audit 2022", &utxo_set),
+            Err(ReservesError::NotFinalized(1))
+        ));
+
+        let mut provider = MemoryKeyProvider::with(SECP256K1, false);
+        provider.add_account(account);
+        assert_eq!(psbt.sign_all(&provider).unwrap(), 1);
+        let mut v0 = PartiallySignedTransaction::from(psbt);
+        v0.finalize_inp_mut(SECP256K1, 1).unwrap();
+        let psbt = Psbt::from(v0);
+
+        assert_eq!(psbt.verify_reserves(SECP256K1, "audit 2022", &utxo_set).unwrap(), 100_000);
+        assert!(matches!(
+            psbt.verify_reserves(SECP256K1, "audit 2023", &utxo_set),
+            Err(ReservesError::ChallengeMismatch)
+        ));
+        assert!(matches!(
+            psbt.verify_reserves(SECP256K1, "audit 2022", &bmap! {}),
+            Err(ReservesError::UtxoUnknown(_))
+        ));
+
+        // The same signed coin repeated twice must not double the reserves
+        let mut duplicated = psbt.clone();
+        let mut copy = duplicated.inputs[1].clone();
+        copy.index = 2;
+        duplicated.inputs.push(copy);
+        duplicated.outputs[0].amount = 200_000;
+        assert!(matches!(
+            duplicated.verify_reserves(SECP256K1, "audit 2022", &utxo_set),
+            Err(ReservesError::DuplicateCoin(dup)) if dup == outpoint
+        ));
+
+        let mut challenged = psbt;
+        let mut challenge = challenged.inputs[0].clone();
+        challenge.index = 2;
+        challenged.inputs.push(challenge);
+        assert!(matches!(
+            challenged.verify_reserves(SECP256K1, "audit 2022", &utxo_set),
+            Err(ReservesError::ChallengeCoin(_))
+        ));
+    }
+}
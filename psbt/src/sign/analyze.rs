@@ -0,0 +1,376 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Dry-run of the signer, reporting which inputs can be signed with the keys
+//! from a [`SecretProvider`] without producing signatures.
+
+use std::collections::BTreeSet;
+
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::secp256k1::{Parity, Signing, XOnlyPublicKey};
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{EcdsaSighashType, PublicKey, SchnorrSighashType};
+use bitcoin_scripts::PubkeyScript;
+use descriptors::CompositeDescrType;
+
+use super::SecretProvider;
+use crate::{Input, Psbt};
+
+/// Key which can be used to sign an input.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum SignableKey {
+    /// ECDSA key for pre-taproot spendings
+    #[display("ecdsa({0})")]
+    Ecdsa(PublicKey),
+
+    /// BIP-340 key for taproot key path spending
+    #[display("tr({0})")]
+    TaprootKey(XOnlyPublicKey),
+
+    /// BIP-340 key for taproot script path spending via a leaf script
+    #[display("tr({0}, {1})")]
+    TaprootScript(XOnlyPublicKey, TapLeafHash),
+}
+
+/// Sighash type which will be used for signing an input.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum InputSighash {
+    /// Sighash type for pre-taproot ECDSA signatures
+    #[display(inner)]
+    Ecdsa(EcdsaSighashType),
+
+    /// Sighash type for taproot BIP-340 signatures
+    #[display(inner)]
+    Schnorr(SchnorrSighashType),
+
+    /// Non-standard sighash type, which will make signer to fail
+    #[display("non-standard({0:#x})")]
+    NonStandard(u32),
+}
+
+/// Fields which must be present in the PSBT input for the signer to produce
+/// signatures.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum MissingField {
+    /// Neither `witness_utxo` nor `non_witness_utxo` is present
+    #[display("witness_utxo")]
+    WitnessUtxo,
+
+    /// `non_witness_utxo` required for pre-segwit spending is absent
+    #[display("non_witness_utxo")]
+    NonWitnessUtxo,
+
+    /// `redeem_script` required for P2SH spending is absent
+    #[display("redeem_script")]
+    RedeemScript,
+
+    /// `witness_script` required for P2WSH spending is absent
+    #[display("witness_script")]
+    WitnessScript,
+
+    /// `tap_internal_key` required for taproot key path spending is absent
+    #[display("tap_internal_key")]
+    TapInternalKey,
+
+    /// `tap_leaf_script` for a leaf listed in the key origin data is absent
+    #[display("tap_leaf_script({0})")]
+    TapLeafScript(TapLeafHash),
+
+    /// Taproot spending with sighash type committing to all inputs requires
+    /// spent outputs of the other inputs, which are absent
+    #[display("taproot_prevouts")]
+    TaprootPrevouts,
+}
+
+/// Result of signability analysis for a single PSBT input.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct InputAnalysis {
+    /// Index of the input
+    pub index: usize,
+
+    /// Keys known to the provider which can sign the input
+    pub keys: BTreeSet<SignableKey>,
+
+    /// Sighash type which will be used; `None` if the spent output is unknown
+    pub sighash: Option<InputSighash>,
+
+    /// Fields which must be added to the input before it can be signed
+    pub missing: BTreeSet<MissingField>,
+}
+
+impl InputAnalysis {
+    /// Detects whether the signer will produce signatures for the input.
+    #[inline]
+    pub fn is_signable(&self) -> bool {
+        !self.keys.is_empty()
+            && self.missing.is_empty()
+            && !matches!(self.sighash, None | Some(InputSighash::NonStandard(_)))
+    }
+}
+
+/// Result of signability analysis for the whole PSBT, produced by
+/// [`Psbt::analyze_signing`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct SigningAnalysis {
+    /// Per-input analysis results
+    pub inputs: Vec<InputAnalysis>,
+}
+
+impl SigningAnalysis {
+    /// Returns indexes of the inputs which will be signed.
+    pub fn signable_inputs(&self) -> Vec<usize> {
+        self.inputs
+            .iter()
+            .filter(|input| input.is_signable())
+            .map(|input| input.index)
+            .collect()
+    }
+
+    /// Detects whether the provider has keys for at least one input, i.e.
+    /// whether it makes sense to unlock the keys for signing.
+    #[inline]
+    pub fn has_keys(&self) -> bool { self.inputs.iter().any(|input| !input.keys.is_empty()) }
+}
+
+impl Psbt {
+    /// Analyzes which inputs can be signed with the keys from the provider,
+    /// which sighash types will be used and which fields are missing for the
+    /// signing, without producing signatures.
+    ///
+    /// The analysis uses only public key queries of the provider
+    /// ([`SecretProvider::has_key`] and [`SecretProvider::standalone_pubkeys`])
+    /// and never accesses its secret keys, so it may be run before the keys
+    /// are unlocked.
+    pub fn analyze_signing<C: Signing>(
+        &self,
+        provider: &impl SecretProvider<C>,
+    ) -> SigningAnalysis {
+        let prevouts_complete = self.inputs.iter().all(|input| input.input_prevout().is_ok());
+        SigningAnalysis {
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| input.analyze_signing(provider, prevouts_complete))
+                .collect(),
+        }
+    }
+}
+
+impl Input {
+    fn analyze_signing<C: Signing>(
+        &self,
+        provider: &impl SecretProvider<C>,
+        prevouts_complete: bool,
+    ) -> InputAnalysis {
+        let mut analysis = InputAnalysis {
+            index: self.index(),
+            keys: empty!(),
+            sighash: None,
+            missing: empty!(),
+        };
+
+        let prevout = match self.input_prevout() {
+            Ok(prevout) => prevout,
+            Err(_) => {
+                analysis.missing.insert(MissingField::WitnessUtxo);
+                return analysis;
+            }
+        };
+        let script_pubkey = PubkeyScript::from(prevout.script_pubkey.clone());
+
+        if !script_pubkey.is_v1_p2tr() {
+            analysis.sighash = Some(match self.sighash_type.map(|sht| sht.ecdsa_hash_ty()) {
+                None => InputSighash::Ecdsa(EcdsaSighashType::All),
+                Some(Ok(sighash_type)) => InputSighash::Ecdsa(sighash_type),
+                Some(Err(err)) => InputSighash::NonStandard(err.0),
+            });
+
+            for (pubkey, (fingerprint, derivation)) in &self.bip32_derivation {
+                if provider.has_key(*fingerprint, derivation, *pubkey) {
+                    analysis.keys.insert(SignableKey::Ecdsa(PublicKey::new(*pubkey)));
+                }
+            }
+            for pubkey in provider.standalone_pubkeys() {
+                if matches!(self.uses_key(pubkey), Ok(true)) {
+                    analysis.keys.insert(SignableKey::Ecdsa(pubkey));
+                }
+            }
+
+            match CompositeDescrType::deduce(
+                &script_pubkey,
+                self.redeem_script.as_ref(),
+                self.witness_script.is_some(),
+            ) {
+                Err(_) if script_pubkey.is_p2sh() && self.redeem_script.is_none() => {
+                    analysis.missing.insert(MissingField::RedeemScript);
+                }
+                Ok(CompositeDescrType::Wsh) | Ok(CompositeDescrType::ShWsh)
+                    if self.witness_script.is_none() =>
+                {
+                    analysis.missing.insert(MissingField::WitnessScript);
+                }
+                Ok(CompositeDescrType::Bare)
+                | Ok(CompositeDescrType::Pk)
+                | Ok(CompositeDescrType::Pkh)
+                | Ok(CompositeDescrType::Sh)
+                    if self.non_witness_utxo.is_none() =>
+                {
+                    analysis.missing.insert(MissingField::NonWitnessUtxo);
+                }
+                _ => {}
+            }
+            return analysis;
+        }
+
+        let sighash_type = match self.sighash_type.map(|sht| sht.schnorr_hash_ty()) {
+            None => SchnorrSighashType::Default,
+            Some(Ok(sighash_type)) => sighash_type,
+            Some(Err(_)) => {
+                let sighash_type = self.sighash_type.expect("option unwrapped above").to_u32();
+                analysis.sighash = Some(InputSighash::NonStandard(sighash_type));
+                SchnorrSighashType::Default
+            }
+        };
+        analysis.sighash.get_or_insert(InputSighash::Schnorr(sighash_type));
+        if !prevouts_complete
+            && matches!(
+                sighash_type,
+                SchnorrSighashType::All
+                    | SchnorrSighashType::None
+                    | SchnorrSighashType::Single
+                    | SchnorrSighashType::Default
+            )
+        {
+            analysis.missing.insert(MissingField::TaprootPrevouts);
+        }
+
+        let leaf_hashes = self
+            .tap_scripts
+            .values()
+            .map(|(script, leaf_ver)| TapLeafHash::from_script(script, *leaf_ver))
+            .collect::<BTreeSet<_>>();
+        let mut add_key = |pubkey: XOnlyPublicKey, leaves: &[TapLeafHash]| {
+            if self.tap_internal_key == Some(pubkey) || provider.use_musig() {
+                analysis.keys.insert(SignableKey::TaprootKey(pubkey));
+            }
+            for leaf_hash in leaves {
+                if leaf_hashes.contains(leaf_hash) {
+                    analysis.keys.insert(SignableKey::TaprootScript(pubkey, *leaf_hash));
+                } else {
+                    analysis.missing.insert(MissingField::TapLeafScript(*leaf_hash));
+                }
+            }
+            if self.tap_internal_key.is_none() && leaves.is_empty() {
+                analysis.missing.insert(MissingField::TapInternalKey);
+            }
+        };
+
+        for (pubkey, (leaves, (fingerprint, derivation))) in &self.tap_key_origins {
+            if provider.has_key(*fingerprint, derivation, pubkey.public_key(Parity::Even)) {
+                add_key(*pubkey, leaves);
+            }
+        }
+        for pubkey in provider.standalone_pubkeys() {
+            let pubkey = XOnlyPublicKey::from(pubkey.inner);
+            if self.tap_key_origins.contains_key(&pubkey) {
+                continue;
+            }
+            let leaves = self
+                .tap_scripts
+                .values()
+                .filter(|(script, _)| {
+                    script.instructions().any(|instruction| {
+                        matches!(instruction, Ok(Instruction::PushBytes(data))
+                            if data == &pubkey.serialize()[..])
+                    })
+                })
+                .map(|(script, leaf_ver)| TapLeafHash::from_script(script, *leaf_ver))
+                .collect::<Vec<_>>();
+            if self.tap_internal_key == Some(pubkey) || !leaves.is_empty() {
+                add_key(pubkey, &leaves);
+            }
+        }
+
+        analysis
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::{Address, Network, Script, TxIn, TxOut};
+
+    use super::*;
+    use crate::fixtures;
+    use crate::sign::KeyMap;
+    use crate::PsbtVersion;
+
+    #[test]
+    fn analyze() {
+        let secp = Secp256k1::new();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_raw(SecretKey::from_slice(&[0x42; 32]).unwrap(), true);
+
+        let mut tx = fixtures::tx([(9_000, Script::new())]);
+        tx.input.push(TxIn::default());
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Address::p2wpkh(&pubkey, Network::Bitcoin).unwrap().script_pubkey(),
+        });
+
+        let analysis = psbt.analyze_signing(&key_map);
+        assert_eq!(analysis.signable_inputs(), vec![0]);
+        assert!(analysis.has_keys());
+        assert_eq!(analysis.inputs[0].keys, bset! { SignableKey::Ecdsa(pubkey) });
+        assert_eq!(
+            analysis.inputs[0].sighash,
+            Some(InputSighash::Ecdsa(EcdsaSighashType::All))
+        );
+        assert_eq!(analysis.inputs[1].missing, bset! { MissingField::WitnessUtxo });
+
+        let internal_key = XOnlyPublicKey::from(pubkey.inner);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Script::new_v1_p2tr(&secp, internal_key, None),
+        });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+        let analysis = psbt.analyze_signing(&key_map);
+        assert_eq!(analysis.inputs[0].keys, bset! { SignableKey::TaprootKey(internal_key) });
+        assert_eq!(analysis.inputs[0].missing, bset! { MissingField::TaprootPrevouts });
+        assert!(analysis.signable_inputs().is_empty());
+    }
+}
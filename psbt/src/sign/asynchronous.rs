@@ -0,0 +1,71 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Async signer interface, for signers accessed over network or device
+//! connections from async runtimes.
+
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use bitcoin::secp256k1::{Signing, Verification};
+
+use super::{SecretProvider, SignAll, SignError};
+use crate::Psbt;
+
+/// Signer which can be used from async code: remote signing service,
+/// hardware device or local [`SecretProvider`] wrapped into [`LocalSigner`].
+#[async_trait]
+pub trait SignAsync {
+    /// Signs all PSBT inputs which the signer has keys for; see
+    /// [`SignAll::sign_all`].
+    ///
+    /// # Returns
+    ///
+    /// Number of created signatures.
+    async fn sign_async(&self, psbt: &mut Psbt) -> Result<usize, SignError>;
+}
+
+/// Adaptor of a local [`SecretProvider`] to the [`SignAsync`] interface.
+/// Signing with local keys does not block on I/O, so it is performed in place.
+pub struct LocalSigner<C: Signing + Verification, P: SecretProvider<C>> {
+    provider: P,
+    _phantom: PhantomData<C>,
+}
+
+impl<C: Signing + Verification, P: SecretProvider<C>> LocalSigner<C, P> {
+    /// Wraps secret provider.
+    #[inline]
+    pub fn with(provider: P) -> Self {
+        LocalSigner {
+            provider,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns wrapped secret provider.
+    #[inline]
+    pub fn provider(&self) -> &P { &self.provider }
+
+    /// Releases wrapped secret provider.
+    #[inline]
+    pub fn into_provider(self) -> P { self.provider }
+}
+
+#[async_trait]
+impl<C, P> SignAsync for LocalSigner<C, P>
+where
+    C: Signing + Verification + Sync,
+    P: SecretProvider<C> + Sync,
+{
+    async fn sign_async(&self, psbt: &mut Psbt) -> Result<usize, SignError> {
+        psbt.sign_all(&self.provider)
+    }
+}
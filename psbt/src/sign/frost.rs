@@ -0,0 +1,590 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Experimental FROST threshold Schnorr signing of taproot key-path
+//! spendings.
+//!
+//! The resulting signature is a plain BIP-340 signature, indistinguishable
+//! from a single-sig one. Signing proceeds in rounds coordinated through
+//! PSBT input proprietary keys:
+//! 1. each participant publishes nonce commitments with
+//!    [`FrostKeyShare::commit`];
+//! 2. once the PSBT has commitments from at least threshold participants,
+//!    each of them publishes a signature share with
+//!    [`FrostKeyShare::sign_share`];
+//! 3. any party aggregates the shares into the input `tap_key_sig` with
+//!    [`aggregate`].
+//!
+//! Key shares are produced by a trusted dealer ([`FrostKeyShare::generate`])
+//! or imported from an external key generation procedure.
+//!
+//! NB: The implementation is not audited and does not follow any specific
+//! FROST standardization draft, so the key shares and nonce commitments are
+//! not interoperable with other FROST implementations.
+
+use std::collections::BTreeMap;
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::schnorr::TapTweak;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::secp256k1::{
+    self, schnorr, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey, Signing,
+    Verification, XOnlyPublicKey,
+};
+use bitcoin::util::sighash::{self, Prevouts, SighashCache};
+use bitcoin::util::taproot::TapTweakHash;
+use bitcoin::SchnorrSig;
+
+use crate::raw::ProprietaryKey;
+use crate::{Input, InputMatchError, LockTimeError, Psbt};
+
+/// Proprietary key prefix used for FROST signing data.
+pub const PSBT_FROST_PREFIX: &[u8] = b"FROST";
+/// Proprietary input key subtype for participant nonce commitments. The key
+/// data is 2-byte big-endian participant index; the value is a pair of
+/// compressed public keys for hiding and binding nonces.
+pub const PSBT_IN_FROST_COMMITMENT: u8 = 0;
+/// Proprietary input key subtype for participant signature shares. The key
+/// data is 2-byte big-endian participant index; the value is a 32-byte
+/// scalar.
+pub const PSBT_IN_FROST_SHARE: u8 = 1;
+
+/// Errors happening during FROST key generation and signing.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum FrostError {
+    /// threshold {threshold} must be non-zero and must not exceed number of
+    /// participants {participants}
+    InvalidThreshold { threshold: u16, participants: u16 },
+
+    /// PSBT does not have input #{0}
+    NoInput(usize),
+
+    /// taproot internal key of input #{0} does not match FROST group key
+    GroupKeyMismatch(usize),
+
+    /// input #{input} has invalid FROST data for participant #{participant}
+    InvalidData { input: usize, participant: u16 },
+
+    /// input #{0} has no FROST nonce commitments
+    NoCommitments(usize),
+
+    /// input #{input} has no nonce commitment of participant #{participant}
+    /// matching the participant nonces
+    NoCommitment { input: usize, participant: u16 },
+
+    /// input #{input} has nonce commitments from {present} participants, while
+    /// signing requires at least {threshold}
+    InsufficientCommitments {
+        input: usize,
+        present: usize,
+        threshold: u16,
+    },
+
+    /// input #{input} has no signature share from participant #{participant}
+    NoShare { input: usize, participant: u16 },
+
+    /// aggregated signature for input #{0} is invalid
+    InvalidSignature(usize),
+
+    /// spent transaction does not match input prevout reference. {0}
+    #[from]
+    Match(InputMatchError),
+
+    /// unable to compute signature hash. {0}
+    #[from]
+    Sighash(sighash::Error),
+
+    /// unable to construct the transaction for signing. {0}
+    #[from]
+    LockTime(LockTimeError),
+
+    /// scalar arithmetic produced zero value, which has negligible probability
+    /// unless participants are malicious
+    ZeroScalar,
+}
+
+impl From<secp256k1::Error> for FrostError {
+    fn from(_: secp256k1::Error) -> Self { FrostError::ZeroScalar }
+}
+
+/// Secret key share of a FROST participant.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct FrostKeyShare {
+    /// Participant index, starting from 1
+    pub index: u16,
+
+    /// Number of participants required for signing
+    pub threshold: u16,
+
+    /// Participant secret share of the group key
+    pub secret_share: SecretKey,
+
+    /// Group public key, which is used as a taproot internal key
+    pub group_key: PublicKey,
+}
+
+/// Secret nonces of a participant for a single signing session. The nonces
+/// are consumed by [`FrostKeyShare::sign_share`] and must never be reused.
+pub struct FrostNonces {
+    hiding: SecretKey,
+    binding: SecretKey,
+}
+
+impl FrostKeyShare {
+    /// Generates key shares for `participants` parties with trusted dealer,
+    /// returning also commitments to the dealer polynomial, which allow
+    /// participants to verify their shares with [`FrostKeyShare::verify`].
+    pub fn generate<C: Signing, R: Rng + ?Sized>(
+        secp: &Secp256k1<C>,
+        threshold: u16,
+        participants: u16,
+        rng: &mut R,
+    ) -> Result<(Vec<FrostKeyShare>, Vec<PublicKey>), FrostError> {
+        if threshold == 0 || threshold > participants {
+            return Err(FrostError::InvalidThreshold {
+                threshold,
+                participants,
+            });
+        }
+        let coefficients = (0..threshold).map(|_| SecretKey::new(rng)).collect::<Vec<_>>();
+        let commitments = coefficients
+            .iter()
+            .map(|coefficient| PublicKey::from_secret_key(secp, coefficient))
+            .collect::<Vec<_>>();
+        let shares = (1..=participants)
+            .map(|index| {
+                let mut iter = coefficients.iter().rev();
+                let mut share = *iter.next().expect("threshold is non-zero");
+                for coefficient in iter {
+                    share = share
+                        .mul_tweak(&index_scalar(index))?
+                        .add_tweak(&Scalar::from(*coefficient))?;
+                }
+                Ok(FrostKeyShare {
+                    index,
+                    threshold,
+                    secret_share: share,
+                    group_key: commitments[0],
+                })
+            })
+            .collect::<Result<_, FrostError>>()?;
+        Ok((shares, commitments))
+    }
+
+    /// Verifies the key share against commitments to the dealer polynomial.
+    pub fn verify<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        commitments: &[PublicKey],
+    ) -> bool {
+        if commitments.len() != self.threshold as usize
+            || commitments.first() != Some(&self.group_key)
+        {
+            return false;
+        }
+        let mut iter = commitments.iter().rev();
+        let mut expected = *iter.next().expect("threshold is non-zero");
+        for commitment in iter {
+            expected = match expected
+                .mul_tweak(secp, &index_scalar(self.index))
+                .and_then(|point| point.combine(commitment))
+            {
+                Ok(point) => point,
+                Err(_) => return false,
+            };
+        }
+        PublicKey::from_secret_key(secp, &self.secret_share) == expected
+    }
+
+    /// Taproot internal key corresponding to the group key.
+    #[inline]
+    pub fn internal_key(&self) -> XOnlyPublicKey { self.group_key.x_only_public_key().0 }
+
+    /// Generates secret nonces for signing PSBT input and publishes their
+    /// commitments in the input proprietary key.
+    pub fn commit<C: Signing, R: Rng + ?Sized>(
+        &self,
+        secp: &Secp256k1<C>,
+        psbt: &mut Psbt,
+        input_index: usize,
+        rng: &mut R,
+    ) -> Result<FrostNonces, FrostError> {
+        let input = self.input_mut(psbt, input_index)?;
+        let nonces = FrostNonces {
+            hiding: SecretKey::new(rng),
+            binding: SecretKey::new(rng),
+        };
+        let mut value = PublicKey::from_secret_key(secp, &nonces.hiding)
+            .serialize()
+            .to_vec();
+        value.extend(PublicKey::from_secret_key(secp, &nonces.binding).serialize());
+        input
+            .proprietary
+            .insert(frost_key(PSBT_IN_FROST_COMMITMENT, self.index), value);
+        Ok(nonces)
+    }
+
+    /// Computes participant signature share for PSBT input and publishes it in
+    /// the input proprietary key.
+    pub fn sign_share<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        psbt: &mut Psbt,
+        input_index: usize,
+        nonces: FrostNonces,
+    ) -> Result<(), FrostError> {
+        self.input_mut(psbt, input_index)?;
+        let session = Session::with(secp, psbt, input_index)?;
+
+        let own_commitment = (
+            PublicKey::from_secret_key(secp, &nonces.hiding),
+            PublicKey::from_secret_key(secp, &nonces.binding),
+        );
+        if session.commitments.get(&self.index) != Some(&own_commitment) {
+            return Err(FrostError::NoCommitment {
+                input: input_index,
+                participant: self.index,
+            });
+        }
+        if session.commitments.len() < self.threshold as usize {
+            return Err(FrostError::InsufficientCommitments {
+                input: input_index,
+                present: session.commitments.len(),
+                threshold: self.threshold,
+            });
+        }
+
+        let binding_factor = session.binding_factors[&self.index];
+        let binding_nonce = nonces.binding.mul_tweak(&Scalar::from(binding_factor))?;
+        let mut nonce = nonces.hiding.add_tweak(&Scalar::from(binding_nonce))?;
+        if session.nonce_parity == Parity::Odd {
+            nonce = nonce.negate();
+        }
+
+        let mut key_part = self
+            .secret_share
+            .mul_tweak(&Scalar::from(session.lagrange_coefficient(self.index)?))?
+            .mul_tweak(&Scalar::from(session.challenge))?;
+        if self.group_key.x_only_public_key().1 != session.output_parity {
+            key_part = key_part.negate();
+        }
+        let share = nonce.add_tweak(&Scalar::from(key_part))?;
+
+        psbt.inputs[input_index].proprietary.insert(
+            frost_key(PSBT_IN_FROST_SHARE, self.index),
+            share.secret_bytes().to_vec(),
+        );
+        Ok(())
+    }
+
+    fn input_mut<'psbt>(
+        &self,
+        psbt: &'psbt mut Psbt,
+        input_index: usize,
+    ) -> Result<&'psbt mut Input, FrostError> {
+        let input = psbt
+            .inputs
+            .get_mut(input_index)
+            .ok_or(FrostError::NoInput(input_index))?;
+        if input.tap_internal_key != Some(self.internal_key()) {
+            return Err(FrostError::GroupKeyMismatch(input_index));
+        }
+        Ok(input)
+    }
+}
+
+/// Aggregates signature shares of all participants which have published
+/// nonce commitments into the taproot key-path signature of PSBT input,
+/// removing FROST proprietary keys from the input.
+pub fn aggregate<C: Verification>(
+    secp: &Secp256k1<C>,
+    psbt: &mut Psbt,
+    input_index: usize,
+) -> Result<(), FrostError> {
+    let session = Session::with(secp, psbt, input_index)?;
+    let input = &mut psbt.inputs[input_index];
+
+    let mut sum = session.tweak.mul_tweak(&Scalar::from(session.challenge))?;
+    if session.output_parity == Parity::Odd {
+        sum = sum.negate();
+    }
+    for participant in session.commitments.keys() {
+        let share = input
+            .proprietary
+            .get(&frost_key(PSBT_IN_FROST_SHARE, *participant))
+            .ok_or(FrostError::NoShare {
+                input: input_index,
+                participant: *participant,
+            })?;
+        let share = SecretKey::from_slice(share).map_err(|_| FrostError::InvalidData {
+            input: input_index,
+            participant: *participant,
+        })?;
+        sum = sum.add_tweak(&Scalar::from(share))?;
+    }
+
+    let mut data = session.nonce.serialize().to_vec();
+    data.extend(sum.secret_bytes());
+    let sig = schnorr::Signature::from_slice(&data).expect("fixed length signature");
+    secp.verify_schnorr(&sig, &session.message, &session.output_key)
+        .map_err(|_| FrostError::InvalidSignature(input_index))?;
+
+    input.tap_key_sig = Some(SchnorrSig {
+        sig,
+        hash_ty: session.sighash_type,
+    });
+    input
+        .proprietary
+        .retain(|key, _| key.prefix.as_slice() != PSBT_FROST_PREFIX);
+    Ok(())
+}
+
+/// Public data of a FROST signing session for a single PSBT input.
+struct Session {
+    message: Message,
+    sighash_type: bitcoin::SchnorrSighashType,
+    commitments: BTreeMap<u16, (PublicKey, PublicKey)>,
+    binding_factors: BTreeMap<u16, SecretKey>,
+    nonce: XOnlyPublicKey,
+    nonce_parity: Parity,
+    output_key: XOnlyPublicKey,
+    output_parity: Parity,
+    tweak: SecretKey,
+    challenge: SecretKey,
+}
+
+impl Session {
+    fn with<C: Verification>(
+        secp: &Secp256k1<C>,
+        psbt: &Psbt,
+        input_index: usize,
+    ) -> Result<Session, FrostError> {
+        let input = psbt
+            .inputs
+            .get(input_index)
+            .ok_or(FrostError::NoInput(input_index))?;
+        let internal_key = input
+            .tap_internal_key
+            .ok_or(FrostError::GroupKeyMismatch(input_index))?;
+
+        let tx = psbt.to_unsigned_tx()?;
+        let prevouts = psbt
+            .inputs
+            .iter()
+            .map(|input| input.input_prevout().cloned())
+            .collect::<Result<Vec<_>, _>>()?;
+        let sighash_type = input.schnorr_hash_ty()?;
+        let sighash = SighashCache::new(&tx).taproot_signature_hash(
+            input_index,
+            &Prevouts::All(&prevouts),
+            input.tap_annex(),
+            None,
+            sighash_type,
+        )?;
+        let message = Message::from_slice(&sighash[..]).expect("taproot sighash is broken");
+
+        let mut commitments = bmap! {};
+        for (key, value) in &input.proprietary {
+            if key.prefix.as_slice() != PSBT_FROST_PREFIX
+                || key.subtype != PSBT_IN_FROST_COMMITMENT
+            {
+                continue;
+            }
+            let participant = <[u8; 2]>::try_from(key.key.as_slice())
+                .map(u16::from_be_bytes)
+                .map_err(|_| FrostError::InvalidData {
+                    input: input_index,
+                    participant: 0,
+                })?;
+            let err = || FrostError::InvalidData {
+                input: input_index,
+                participant,
+            };
+            if value.len() != 66 || participant == 0 {
+                return Err(err());
+            }
+            let hiding = PublicKey::from_slice(&value[..33]).map_err(|_| err())?;
+            let binding = PublicKey::from_slice(&value[33..]).map_err(|_| err())?;
+            commitments.insert(participant, (hiding, binding));
+        }
+
+        if commitments.is_empty() {
+            return Err(FrostError::NoCommitments(input_index));
+        }
+
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"FROST/binding");
+        engine.input(&message[..]);
+        for (participant, (hiding, binding)) in &commitments {
+            engine.input(&participant.to_be_bytes());
+            engine.input(&hiding.serialize());
+            engine.input(&binding.serialize());
+        }
+        let commitments_hash = sha256::Hash::from_engine(engine);
+
+        let mut binding_factors = bmap! {};
+        let mut nonce_points = vec![];
+        for (participant, (hiding, binding)) in &commitments {
+            let mut engine = sha256::Hash::engine();
+            engine.input(&commitments_hash[..]);
+            engine.input(&participant.to_be_bytes());
+            let binding_factor = SecretKey::from_slice(&sha256::Hash::from_engine(engine)[..])
+                .expect("negligible probability");
+            binding_factors.insert(*participant, binding_factor);
+            let binding_point = binding.mul_tweak(secp, &Scalar::from(binding_factor))?;
+            nonce_points.push(hiding.combine(&binding_point)?);
+        }
+        let nonce_points = nonce_points.iter().collect::<Vec<_>>();
+        let (nonce, nonce_parity) = PublicKey::combine_keys(&nonce_points)?.x_only_public_key();
+
+        let (output_key, output_parity) = internal_key.tap_tweak(secp, input.tap_merkle_root);
+        let output_key = output_key.to_inner();
+        let tweak = TapTweakHash::from_key_and_tweak(internal_key, input.tap_merkle_root);
+        let tweak = SecretKey::from_slice(&tweak[..]).expect("negligible probability");
+
+        let tag = sha256::Hash::hash(b"BIP0340/challenge");
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&nonce.serialize());
+        engine.input(&output_key.serialize());
+        engine.input(&message[..]);
+        let challenge = SecretKey::from_slice(&sha256::Hash::from_engine(engine)[..])
+            .expect("negligible probability");
+
+        Ok(Session {
+            message,
+            sighash_type,
+            commitments,
+            binding_factors,
+            nonce,
+            nonce_parity,
+            output_key,
+            output_parity,
+            tweak,
+            challenge,
+        })
+    }
+
+    /// Computes Lagrange coefficient of the participant for the set of
+    /// participants which have published commitments.
+    fn lagrange_coefficient(&self, participant: u16) -> Result<SecretKey, FrostError> {
+        let negated = SecretKey::from_slice(&index_scalar(participant).to_be_bytes())?.negate();
+        let mut numerator = secp256k1::ONE_KEY;
+        let mut denominator = secp256k1::ONE_KEY;
+        for other in self.commitments.keys().filter(|other| **other != participant) {
+            let other = SecretKey::from_slice(&index_scalar(*other).to_be_bytes())?;
+            numerator = numerator.mul_tweak(&Scalar::from(other))?;
+            let difference = other.add_tweak(&Scalar::from(negated))?;
+            denominator = denominator.mul_tweak(&Scalar::from(difference))?;
+        }
+        Ok(numerator.mul_tweak(&Scalar::from(invert(denominator)?))?)
+    }
+}
+
+fn frost_key(subtype: u8, participant: u16) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_FROST_PREFIX.to_vec(),
+        subtype,
+        key: participant.to_be_bytes().to_vec(),
+    }
+}
+
+fn index_scalar(index: u16) -> Scalar {
+    let mut bytes = [0u8; 32];
+    bytes[30..].copy_from_slice(&index.to_be_bytes());
+    Scalar::from_be_bytes(bytes).expect("small integer")
+}
+
+/// Computes modular inverse of a scalar as `x^(n-2) mod n`.
+fn invert(scalar: SecretKey) -> Result<SecretKey, FrostError> {
+    // Curve order minus 2
+    const EXPONENT: [u8; 32] = [
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFE, 0xBA, 0xAE, 0xDC, 0xE6, 0xAF, 0x48, 0xA0, 0x3B, 0xBF, 0xD2, 0x5E, 0x8C, 0xD0, 0x36,
+        0x41, 0x3F,
+    ];
+    let mut result = secp256k1::ONE_KEY;
+    for byte in EXPONENT {
+        for bit in (0..8).rev() {
+            result = result.mul_tweak(&Scalar::from(result))?;
+            if byte >> bit & 1 == 1 {
+                result = result.mul_tweak(&Scalar::from(scalar))?;
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::rand::thread_rng;
+    use bitcoin::{Script, TxOut};
+
+    use super::*;
+    use crate::{fixtures, PsbtVersion};
+
+    #[test]
+    fn inversion() {
+        let scalar = SecretKey::from_slice(&index_scalar(7).to_be_bytes()).unwrap();
+        let inverse = invert(scalar).unwrap();
+        assert_eq!(scalar.mul_tweak(&Scalar::from(inverse)).unwrap(), secp256k1::ONE_KEY);
+    }
+
+    #[test]
+    fn keygen() {
+        let secp = Secp256k1::new();
+        let mut rng = thread_rng();
+        let (shares, commitments) = FrostKeyShare::generate(&secp, 2, 3, &mut rng).unwrap();
+        assert_eq!(shares.len(), 3);
+        assert!(shares.iter().all(|share| share.verify(&secp, &commitments)));
+        let mut forged = shares[0].clone();
+        forged.secret_share = shares[1].secret_share;
+        assert!(!forged.verify(&secp, &commitments));
+        assert!(FrostKeyShare::generate(&secp, 4, 3, &mut rng).is_err());
+    }
+
+    #[test]
+    fn threshold_signing() {
+        let secp = Secp256k1::new();
+        let mut rng = thread_rng();
+        let (shares, _) = FrostKeyShare::generate(&secp, 2, 3, &mut rng).unwrap();
+        let internal_key = shares[0].internal_key();
+
+        let tx = fixtures::tx([(9_000, Script::new())]);
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Script::new_v1_p2tr(&secp, internal_key, None),
+        });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+
+        let nonces1 = shares[0].commit(&secp, &mut psbt, 0, &mut rng).unwrap();
+        assert!(matches!(
+            shares[0].sign_share(&secp, &mut psbt, 0, nonces1),
+            Err(FrostError::InsufficientCommitments { .. })
+        ));
+
+        let nonces1 = shares[0].commit(&secp, &mut psbt, 0, &mut rng).unwrap();
+        let nonces3 = shares[2].commit(&secp, &mut psbt, 0, &mut rng).unwrap();
+        shares[0].sign_share(&secp, &mut psbt, 0, nonces1).unwrap();
+        assert!(matches!(
+            aggregate(&secp, &mut psbt, 0),
+            Err(FrostError::NoShare { participant: 3, .. })
+        ));
+        shares[2].sign_share(&secp, &mut psbt, 0, nonces3).unwrap();
+        aggregate(&secp, &mut psbt, 0).unwrap();
+
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+        assert!(psbt.inputs[0].proprietary.is_empty());
+    }
+}
@@ -14,6 +14,7 @@
 
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
+use std::fmt::{self, Debug, Formatter};
 use std::hash::Hasher;
 
 use bitcoin::secp256k1::{KeyPair, PublicKey, Secp256k1, SecretKey, Signing, XOnlyPublicKey};
@@ -25,7 +26,8 @@ use bitcoin_hd::{Bip43, DerivationStandard};
 #[cfg(feature = "miniscript")]
 use miniscript::Descriptor;
 
-use super::{SecretProvider, SecretProviderError};
+use super::{derive_seckey, SecretGuard, SecretProvider, SecretProviderError};
+use crate::MaxFeePolicy;
 
 /// Account-specific extended private key, kept in memory with information about
 /// account path derivation from the master key.
@@ -34,15 +36,29 @@ use super::{SecretProvider, SecretProviderError};
 /// an extended public key corresponding to the account-level extended private
 /// key (i.e. not master extended key, but a key at account-level derivation
 /// path).
-#[derive(Clone, Getters, Debug, Display)]
+///
+/// The extended private key is erased from memory once the account is
+/// dropped; the account can't be cloned and exposes only guarded copies of
+/// its secret keys.
+#[derive(Display)]
 #[display("m[{master_id}]/{derivation}=[{account_xpub}]")]
 pub struct MemorySigningAccount {
     master_id: XpubIdentifier,
     derivation: DerivationPath,
-    account_xpriv: ExtendedPrivKey,
+    account_xpriv: SecretGuard<ExtendedPrivKey>,
     account_xpub: ExtendedPubKey,
 }
 
+impl Debug for MemorySigningAccount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemorySigningAccount")
+            .field("master_id", &self.master_id)
+            .field("derivation", &self.derivation)
+            .field("account_xpub", &self.account_xpub)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Ord for MemorySigningAccount {
     #[inline]
     fn cmp(&self, other: &Self) -> Ordering { self.account_xpub.cmp(&other.account_xpub) }
@@ -74,11 +90,28 @@ impl MemorySigningAccount {
         MemorySigningAccount {
             master_id,
             derivation: derivation.into(),
-            account_xpriv,
             account_xpub: ExtendedPubKey::from_priv(secp, &account_xpriv),
+            account_xpriv: SecretGuard::new(account_xpriv),
         }
     }
 
+    #[inline]
+    pub fn master_id(&self) -> &XpubIdentifier { &self.master_id }
+
+    #[inline]
+    pub fn derivation(&self) -> &DerivationPath { &self.derivation }
+
+    #[inline]
+    pub fn account_xpub(&self) -> &ExtendedPubKey { &self.account_xpub }
+
+    /// Returns copy of the account extended private key (for instance, for
+    /// exporting it to a key file or displaying it to the user), which is
+    /// erased once dropped.
+    #[inline]
+    pub fn export_xpriv(&self) -> SecretGuard<ExtendedPrivKey> {
+        SecretGuard::new(*self.account_xpriv)
+    }
+
     #[inline]
     pub fn master_fingerprint(&self) -> Fingerprint {
         Fingerprint::from(&self.master_id[..4])
@@ -97,12 +130,9 @@ impl MemorySigningAccount {
         &self,
         secp: &Secp256k1<C>,
         derivation: &DerivationPath,
-    ) -> SecretKey {
-        let xpriv = self
-            .account_xpriv
-            .derive_priv(secp, derivation)
-            .expect("ExtendedPrivKey integrity issue");
-        xpriv.private_key
+    ) -> SecretGuard<SecretKey> {
+        derive_seckey(secp, &self.account_xpriv, derivation)
+            .expect("ExtendedPrivKey integrity issue")
     }
 
     #[inline]
@@ -110,8 +140,11 @@ impl MemorySigningAccount {
         &self,
         secp: &Secp256k1<C>,
         derivation: &DerivationPath,
-    ) -> KeyPair {
-        KeyPair::from_secret_key(secp, &self.derive_seckey(secp, derivation))
+    ) -> SecretGuard<KeyPair> {
+        SecretGuard::new(KeyPair::from_secret_key(
+            secp,
+            &self.derive_seckey(secp, derivation),
+        ))
     }
 
     #[inline]
@@ -165,6 +198,8 @@ where
     secp: &'secp Secp256k1<C>,
     /// Participate keys from this provider in musigs
     musig: bool,
+    /// Fee policy enforced when signing; `None` disables fee protection
+    fee_policy: Option<MaxFeePolicy>,
 }
 
 impl<'secp, C> MemoryKeyProvider<'secp, C>
@@ -176,9 +211,16 @@ where
             accounts: default!(),
             secp,
             musig,
+            fee_policy: Some(MaxFeePolicy::default()),
         }
     }
 
+    /// Sets fee policy enforced when signing; `None` overrides fee protection.
+    #[inline]
+    pub fn set_fee_policy(&mut self, fee_policy: Option<MaxFeePolicy>) {
+        self.fee_policy = fee_policy;
+    }
+
     #[inline]
     pub fn add_account(&mut self, account: MemorySigningAccount) -> bool {
         self.accounts.insert(account)
@@ -208,7 +250,7 @@ where
         fingerprint: Fingerprint,
         derivation: &DerivationPath,
         pubkey: PublicKey,
-    ) -> Result<SecretKey, SecretProviderError> {
+    ) -> Result<SecretGuard<SecretKey>, SecretProviderError> {
         for account in &self.accounts {
             let derivation = if account.account_fingerprint() == fingerprint {
                 derivation.clone()
@@ -244,14 +286,19 @@ where
         fingerprint: Fingerprint,
         derivation: &DerivationPath,
         pubkey: XOnlyPublicKey,
-    ) -> Result<KeyPair, SecretProviderError> {
+    ) -> Result<SecretGuard<KeyPair>, SecretProviderError> {
         let mut data: Vec<u8> = vec![0x02];
         data.extend(pubkey.serialize().iter());
         let pk = PublicKey::from_slice(&data).expect("fixed size slice");
         let seckey = self.secret_key(fingerprint, derivation, pk)?;
-        Ok(KeyPair::from_secret_key(self.secp, &seckey))
+        Ok(SecretGuard::new(KeyPair::from_secret_key(
+            self.secp, &seckey,
+        )))
     }
 
     #[inline]
     fn use_musig(&self) -> bool { self.musig }
+
+    #[inline]
+    fn fee_policy(&self) -> Option<MaxFeePolicy> { self.fee_policy }
 }
@@ -0,0 +1,433 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Encrypted container for seeds and account extended private keys.
+//!
+//! Key file consists of a header and a ciphertext. The header contains
+//! [`KEYFILE_MAGIC`] bytes, 8-bit format version, 8-bit kind of the secret
+//! ([`SecretKind`]), Argon2id parameters (memory cost in KiB, number of
+//! iterations and parallelism, each as 32-bit little-endian integers), 16-byte
+//! salt and 24-byte nonce. The secret is encrypted with XChaCha20-Poly1305
+//! using key derived from the password with Argon2id; the header is
+//! authenticated as associated data.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::{self, RngCore};
+use bitcoin::secp256k1::{Secp256k1, Signing};
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey};
+use bitcoin::XpubIdentifier;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+use super::{MemorySigningAccount, SecretGuard};
+
+/// Magic bytes starting key file.
+pub const KEYFILE_MAGIC: [u8; 4] = *b"DWKF";
+
+/// Most recent version of the key file format, used for writing key files.
+pub const KEYFILE_VERSION: u8 = 1;
+
+/// Maximum Argon2id memory cost (in KiB) accepted when reading key files,
+/// protecting from exhausting the system memory with a malformed file.
+pub const MAX_KDF_MEMORY: u32 = 1024 * 1024;
+
+const HEADER_LEN: usize = 4 + 1 + 1 + 4 * 3 + 16 + 24;
+
+/// Errors creating, reading and unlocking key files.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum KeyFileError {
+    /// I/O error. {0}
+    #[from]
+    Io(io::Error),
+
+    /// the data are not a key file
+    InvalidMagic,
+
+    /// key file has version {0}, which is not supported; please upgrade the
+    /// software
+    UnsupportedVersion(u8),
+
+    /// key file contains unknown kind of secret {0}
+    UnknownKind(u8),
+
+    /// invalid key derivation parameters. {0}
+    #[from]
+    Kdf(argon2::Error),
+
+    /// key file requires {0} KiB of memory for key derivation, which exceeds
+    /// the limit of 1 GiB
+    KdfMemoryLimit(u32),
+
+    /// wrong password or corrupted key file
+    WrongPassword,
+
+    /// key file contains {0} instead of the requested secret
+    KindMismatch(SecretKind),
+
+    /// key file contains invalid account data
+    InvalidAccount,
+}
+
+/// Kind of the secret stored in a key file.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[repr(u8)]
+pub enum SecretKind {
+    /// Seed entropy
+    #[display("seed")]
+    Seed = 1,
+
+    /// Account extended private key with its derivation information
+    #[display("signing account")]
+    Account = 2,
+}
+
+impl TryFrom<u8> for SecretKind {
+    type Error = KeyFileError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(SecretKind::Seed),
+            2 => Ok(SecretKind::Account),
+            unknown => Err(KeyFileError::UnknownKind(unknown)),
+        }
+    }
+}
+
+/// Parameters of Argon2id key derivation function.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct KdfParams {
+    /// Memory cost, in KiB
+    pub memory: u32,
+    /// Number of iterations
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// Parameters recommended by OWASP for Argon2id.
+    fn default() -> Self {
+        KdfParams {
+            memory: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Encrypted key file; see [module-level documentation](self) for the
+/// details of the format.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct KeyFile {
+    kind: SecretKind,
+    kdf: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl KeyFile {
+    /// Encrypts the secret with the password using default key derivation
+    /// parameters.
+    #[inline]
+    pub fn create(kind: SecretKind, secret: &[u8], password: &str) -> Result<Self, KeyFileError> {
+        KeyFile::create_with(kind, secret, password, KdfParams::default())
+    }
+
+    /// Encrypts the secret with the password using custom key derivation
+    /// parameters.
+    pub fn create_with(
+        kind: SecretKind,
+        secret: &[u8],
+        password: &str,
+        kdf: KdfParams,
+    ) -> Result<Self, KeyFileError> {
+        let mut key_file = KeyFile {
+            kind,
+            kdf,
+            salt: [0u8; 16],
+            nonce: [0u8; 24],
+            ciphertext: vec![],
+        };
+        key_file.seal(secret, password)?;
+        Ok(key_file)
+    }
+
+    /// Encrypts signing account data with the password using default key
+    /// derivation parameters.
+    #[inline]
+    pub fn create_account(
+        account: &MemorySigningAccount,
+        password: &str,
+    ) -> Result<Self, KeyFileError> {
+        KeyFile::create_account_with(account, password, KdfParams::default())
+    }
+
+    /// Encrypts signing account data with the password using custom key
+    /// derivation parameters.
+    pub fn create_account_with(
+        account: &MemorySigningAccount,
+        password: &str,
+        kdf: KdfParams,
+    ) -> Result<Self, KeyFileError> {
+        let mut secret = Zeroizing::new(account.master_id()[..].to_vec());
+        secret.extend(account.export_xpriv().encode());
+        for child in account.derivation() {
+            secret.extend(u32::from(*child).to_le_bytes());
+        }
+        KeyFile::create_with(SecretKind::Account, &secret, password, kdf)
+    }
+
+    /// Returns kind of the secret stored in the file.
+    #[inline]
+    pub fn kind(&self) -> SecretKind { self.kind }
+
+    /// Returns key derivation parameters used by the file.
+    #[inline]
+    pub fn kdf_params(&self) -> KdfParams { self.kdf }
+
+    /// Decrypts the secret with the password.
+    pub fn unlock(&self, password: &str) -> Result<Zeroizing<Vec<u8>>, KeyFileError> {
+        let cipher = self.cipher(password)?;
+        let header = self.header();
+        cipher
+            .decrypt(XNonce::from_slice(&self.nonce), Payload {
+                msg: &self.ciphertext,
+                aad: &header,
+            })
+            .map(Zeroizing::new)
+            .map_err(|_| KeyFileError::WrongPassword)
+    }
+
+    /// Decrypts seed entropy with the password.
+    pub fn unlock_seed(&self, password: &str) -> Result<Zeroizing<Vec<u8>>, KeyFileError> {
+        if self.kind != SecretKind::Seed {
+            return Err(KeyFileError::KindMismatch(self.kind));
+        }
+        self.unlock(password)
+    }
+
+    /// Decrypts signing account with the password.
+    pub fn unlock_account<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        password: &str,
+    ) -> Result<MemorySigningAccount, KeyFileError> {
+        if self.kind != SecretKind::Account {
+            return Err(KeyFileError::KindMismatch(self.kind));
+        }
+        let secret = self.unlock(password)?;
+        if secret.len() < 20 + 78 || (secret.len() - 20 - 78) % 4 != 0 {
+            return Err(KeyFileError::InvalidAccount);
+        }
+        let master_id =
+            XpubIdentifier::from_slice(&secret[..20]).map_err(|_| KeyFileError::InvalidAccount)?;
+        let xpriv = ExtendedPrivKey::decode(&secret[20..98])
+            .map(SecretGuard::new)
+            .map_err(|_| KeyFileError::InvalidAccount)?;
+        let derivation = secret[98..]
+            .chunks(4)
+            .map(|chunk| {
+                ChildNumber::from(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            })
+            .collect::<Vec<_>>();
+        Ok(MemorySigningAccount::with(
+            secp, master_id, derivation, *xpriv,
+        ))
+    }
+
+    /// Re-encrypts the secret with a new password, using fresh salt and nonce.
+    pub fn change_password(&mut self, old: &str, new: &str) -> Result<(), KeyFileError> {
+        let secret = self.unlock(old)?;
+        self.seal(&secret, new)
+    }
+
+    fn seal(&mut self, secret: &[u8], password: &str) -> Result<(), KeyFileError> {
+        rand::thread_rng().fill_bytes(&mut self.salt);
+        rand::thread_rng().fill_bytes(&mut self.nonce);
+        let cipher = self.cipher(password)?;
+        let header = self.header();
+        self.ciphertext = cipher
+            .encrypt(XNonce::from_slice(&self.nonce), Payload {
+                msg: secret,
+                aad: &header,
+            })
+            .expect("XChaCha20-Poly1305 encryption of in-memory data");
+        Ok(())
+    }
+
+    fn cipher(&self, password: &str) -> Result<XChaCha20Poly1305, KeyFileError> {
+        let params = Params::new(
+            self.kdf.memory,
+            self.kdf.iterations,
+            self.kdf.parallelism,
+            Some(32),
+        )?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+            password.as_bytes(),
+            &self.salt,
+            &mut key[..],
+        )?;
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&key[..])))
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend(KEYFILE_MAGIC);
+        header.push(KEYFILE_VERSION);
+        header.push(self.kind as u8);
+        header.extend(self.kdf.memory.to_le_bytes());
+        header.extend(self.kdf.iterations.to_le_bytes());
+        header.extend(self.kdf.parallelism.to_le_bytes());
+        header.extend(self.salt);
+        header.extend(self.nonce);
+        header
+    }
+
+    /// Writes key file data, returning the number of bytes written.
+    pub fn save(&self, mut writer: impl Write) -> Result<usize, KeyFileError> {
+        let mut data = self.header();
+        data.extend(&self.ciphertext);
+        writer.write_all(&data)?;
+        Ok(data.len())
+    }
+
+    /// Reads key file data, verifying its header.
+    pub fn load(mut reader: impl Read) -> Result<Self, KeyFileError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        if data.len() < HEADER_LEN || !data.starts_with(&KEYFILE_MAGIC) {
+            return Err(KeyFileError::InvalidMagic);
+        }
+        if data[4] == 0 || data[4] > KEYFILE_VERSION {
+            return Err(KeyFileError::UnsupportedVersion(data[4]));
+        }
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+        };
+        let mut key_file = KeyFile {
+            kind: SecretKind::try_from(data[5])?,
+            kdf: KdfParams {
+                memory: u32_at(6),
+                iterations: u32_at(10),
+                parallelism: u32_at(14),
+            },
+            salt: [0u8; 16],
+            nonce: [0u8; 24],
+            ciphertext: data[HEADER_LEN..].to_vec(),
+        };
+        if key_file.kdf.memory > MAX_KDF_MEMORY {
+            return Err(KeyFileError::KdfMemoryLimit(key_file.kdf.memory));
+        }
+        key_file.salt.copy_from_slice(&data[18..34]);
+        key_file.nonce.copy_from_slice(&data[34..HEADER_LEN]);
+        Ok(key_file)
+    }
+
+    /// Writes key file to the disk; see [`KeyFile::save`].
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<usize, KeyFileError> {
+        self.save(fs::File::create(path)?)
+    }
+
+    /// Reads key file from the disk; see [`KeyFile::load`].
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, KeyFileError> {
+        KeyFile::load(fs::File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey};
+    use bitcoin::Network;
+
+    use super::*;
+
+    // Weak parameters keeping tests fast
+    const KDF: KdfParams = KdfParams {
+        memory: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn seed_roundtrip() {
+        let seed = [0x5Au8; 16];
+        let mut key_file = KeyFile::create_with(SecretKind::Seed, &seed, "secret", KDF).unwrap();
+        assert_eq!(&key_file.unlock_seed("secret").unwrap()[..], &seed);
+        assert!(matches!(
+            key_file.unlock("wrong"),
+            Err(KeyFileError::WrongPassword)
+        ));
+
+        let mut data = vec![];
+        let len = key_file.save(&mut data).unwrap();
+        assert_eq!(len, HEADER_LEN + seed.len() + 16);
+        assert_eq!(&data[..4], b"DWKF");
+        assert_eq!(KeyFile::load(data.as_slice()).unwrap(), key_file);
+
+        let mut tampered = data.clone();
+        tampered[6] ^= 0x01;
+        assert!(matches!(
+            KeyFile::load(tampered.as_slice()).unwrap().unlock("secret"),
+            Err(KeyFileError::WrongPassword)
+        ));
+        assert!(matches!(
+            KeyFile::load(&data[1..]),
+            Err(KeyFileError::InvalidMagic)
+        ));
+
+        let mut excessive = data.clone();
+        excessive[6..10].copy_from_slice(&(MAX_KDF_MEMORY + 1).to_le_bytes());
+        assert!(matches!(
+            KeyFile::load(excessive.as_slice()),
+            Err(KeyFileError::KdfMemoryLimit(memory)) if memory == MAX_KDF_MEMORY + 1
+        ));
+
+        key_file.change_password("secret", "new").unwrap();
+        assert!(matches!(
+            key_file.unlock("secret"),
+            Err(KeyFileError::WrongPassword)
+        ));
+        assert_eq!(&key_file.unlock_seed("new").unwrap()[..], &seed);
+        assert!(matches!(
+            key_file.unlock_account(&Secp256k1::new(), "new"),
+            Err(KeyFileError::KindMismatch(SecretKind::Seed))
+        ));
+    }
+
+    #[test]
+    fn account_roundtrip() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let master_id = ExtendedPubKey::from_priv(&secp, &master).identifier();
+        let derivation = DerivationPath::from(vec![
+            ChildNumber::Hardened { index: 86 },
+            ChildNumber::Hardened { index: 0 },
+        ]);
+        let account_xpriv = master.derive_priv(&secp, &derivation).unwrap();
+        let account = MemorySigningAccount::with(&secp, master_id, derivation, account_xpriv);
+
+        let key_file = KeyFile::create_account_with(&account, "secret", KDF).unwrap();
+        assert_eq!(key_file.kind(), SecretKind::Account);
+        let unlocked = key_file.unlock_account(&secp, "secret").unwrap();
+        assert_eq!(unlocked.account_xpub(), account.account_xpub());
+        assert_eq!(unlocked.derivation(), account.derivation());
+        assert_eq!(unlocked.master_id(), account.master_id());
+    }
+}
@@ -0,0 +1,288 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+use std::collections::BTreeMap;
+
+use bitcoin::secp256k1::{
+    KeyPair, PublicKey, Secp256k1, SecretKey, Signing, XOnlyPublicKey, SECP256K1,
+};
+use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+use bitcoin::{Address, Network, PrivateKey, Script};
+
+use super::{SecretGuard, SecretProvider, SecretProviderError};
+use crate::MaxFeePolicy;
+
+/// Errors adding keys to [`KeyMap`].
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum KeyMapError {
+    /// invalid WIF-encoded private key. {0}
+    #[from]
+    Wif(bitcoin::util::key::Error),
+
+    /// private key does not correspond to address {0}
+    AddressMismatch(Address),
+}
+
+/// Provider of signing keys which have no BIP-32 derivation information, like
+/// keys from swept paper wallets or imported single keys. Keys are matched
+/// with PSBT inputs by their public keys and the scripts of spent outputs.
+#[derive(Debug)]
+pub struct KeyMap<'secp, C>
+where
+    C: Signing,
+{
+    keys: BTreeMap<bitcoin::PublicKey, SecretGuard<PrivateKey>>,
+    secp: &'secp Secp256k1<C>,
+    /// Fee policy enforced when signing; `None` disables fee protection
+    fee_policy: Option<MaxFeePolicy>,
+}
+
+impl<'secp, C> KeyMap<'secp, C>
+where
+    C: Signing,
+{
+    /// Constructs empty key map using the provided secp256k1 context and the
+    /// default fee policy (see [`MaxFeePolicy::default`]).
+    pub fn with(secp: &'secp Secp256k1<C>) -> Self {
+        Self {
+            keys: default!(),
+            secp,
+            fee_policy: Some(MaxFeePolicy::default()),
+        }
+    }
+
+    /// Sets fee policy enforced when signing; `None` overrides fee protection.
+    #[inline]
+    pub fn set_fee_policy(&mut self, fee_policy: Option<MaxFeePolicy>) {
+        self.fee_policy = fee_policy;
+    }
+
+    /// Adds private key, returning the corresponding public key.
+    pub fn insert(&mut self, key: PrivateKey) -> bitcoin::PublicKey {
+        let pubkey = key.public_key(self.secp);
+        self.keys.insert(pubkey, SecretGuard::new(key));
+        pubkey
+    }
+
+    /// Adds WIF-encoded private key, returning the corresponding public key.
+    pub fn insert_wif(&mut self, wif: &str) -> Result<bitcoin::PublicKey, KeyMapError> {
+        Ok(self.insert(PrivateKey::from_wif(wif)?))
+    }
+
+    /// Adds raw secret key, returning the corresponding public key. Flag
+    /// `compressed` defines which form of the public key is used in the spent
+    /// scripts.
+    pub fn insert_raw(&mut self, seckey: SecretKey, compressed: bool) -> bitcoin::PublicKey {
+        self.insert(PrivateKey {
+            compressed,
+            network: Network::Bitcoin,
+            inner: seckey,
+        })
+    }
+
+    /// Adds private key for the given address, checking that the address
+    /// belongs to the key.
+    pub fn insert_for_address(
+        &mut self,
+        address: &Address,
+        key: PrivateKey,
+    ) -> Result<bitcoin::PublicKey, KeyMapError> {
+        let pubkey = key.public_key(self.secp);
+        if !key_scripts(pubkey).contains(&address.script_pubkey()) {
+            return Err(KeyMapError::AddressMismatch(address.clone()));
+        }
+        Ok(self.insert(key))
+    }
+
+    /// Returns private key for the given public key, if known.
+    #[inline]
+    pub fn get(&self, pubkey: &bitcoin::PublicKey) -> Option<&PrivateKey> {
+        self.keys.get(pubkey).map(|key| &**key)
+    }
+
+    /// Returns private key controlling single-key output with the given
+    /// scriptPubkey (P2PK, P2PKH, P2WPKH, P2WPKH-in-P2SH or BIP-86 P2TR), if
+    /// known.
+    pub fn get_by_script(&self, script_pubkey: &Script) -> Option<&PrivateKey> {
+        self.keys.iter().find_map(|(pubkey, key)| {
+            key_scripts(*pubkey)
+                .contains(script_pubkey)
+                .then_some(&**key)
+        })
+    }
+
+    /// Returns private key for the given address, if known.
+    #[inline]
+    pub fn get_by_address(&self, address: &Address) -> Option<&PrivateKey> {
+        self.get_by_script(&address.script_pubkey())
+    }
+
+    /// Number of keys in the map.
+    #[inline]
+    pub fn len(&self) -> usize { self.keys.len() }
+
+    /// Detects whether the map has no keys.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.keys.is_empty() }
+}
+
+/// Lists scriptPubkeys of single-key outputs controlled by a key.
+fn key_scripts(pubkey: bitcoin::PublicKey) -> Vec<Script> {
+    let mut scripts = vec![
+        Script::new_p2pk(&pubkey),
+        Script::new_p2pkh(&pubkey.pubkey_hash()),
+    ];
+    if let Some(wpubkey_hash) = pubkey.wpubkey_hash() {
+        let p2wpkh = Script::new_v0_p2wpkh(&wpubkey_hash);
+        scripts.push(p2wpkh.to_p2sh());
+        scripts.push(p2wpkh);
+    }
+    if pubkey.compressed {
+        let internal_key = XOnlyPublicKey::from(pubkey.inner);
+        scripts.push(Script::new_v1_p2tr(SECP256K1, internal_key, None));
+    }
+    scripts
+}
+
+impl<'secp, C> SecretProvider<C> for KeyMap<'secp, C>
+where
+    C: Signing,
+{
+    #[inline]
+    fn secp_context(&self) -> &Secp256k1<C> { self.secp }
+
+    fn secret_key(
+        &self,
+        fingerprint: Fingerprint,
+        _derivation: &DerivationPath,
+        pubkey: PublicKey,
+    ) -> Result<SecretGuard<SecretKey>, SecretProviderError> {
+        // Keys are matched ignoring their parity, as required by
+        // `SecretProvider::has_key` which relies on this method
+        self.keys
+            .iter()
+            .find(|(key, _)| key.inner.serialize()[1..] == pubkey.serialize()[1..])
+            .map(|(_, key)| SecretGuard::new(key.inner))
+            .ok_or(SecretProviderError::AccountUnknown(fingerprint, pubkey))
+    }
+
+    fn key_pair(
+        &self,
+        fingerprint: Fingerprint,
+        _derivation: &DerivationPath,
+        pubkey: XOnlyPublicKey,
+    ) -> Result<SecretGuard<KeyPair>, SecretProviderError> {
+        self.keys
+            .values()
+            .map(|key| SecretGuard::new(KeyPair::from_secret_key(self.secp, &key.inner)))
+            .find(|keypair| keypair.x_only_public_key().0 == pubkey)
+            .ok_or_else(|| {
+                let mut data: Vec<u8> = vec![0x02];
+                data.extend(pubkey.serialize().iter());
+                let pk = PublicKey::from_slice(&data).expect("fixed size slice");
+                SecretProviderError::AccountUnknown(fingerprint, pk)
+            })
+    }
+
+    #[inline]
+    fn use_musig(&self) -> bool { false }
+
+    #[inline]
+    fn fee_policy(&self) -> Option<MaxFeePolicy> { self.fee_policy }
+
+    #[inline]
+    fn standalone_keys(&self) -> Vec<SecretGuard<PrivateKey>> {
+        self.keys
+            .values()
+            .map(|key| SecretGuard::new(**key))
+            .collect()
+    }
+
+    #[inline]
+    fn standalone_pubkeys(&self) -> Vec<bitcoin::PublicKey> { self.keys.keys().copied().collect() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // Test vector from Bitcoin wiki page on WIF
+    const WIF: &str = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+
+    #[test]
+    fn insert_and_lookup() {
+        let secp = Secp256k1::new();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_wif(WIF).unwrap();
+        assert!(!pubkey.compressed);
+        assert_eq!(key_map.len(), 1);
+
+        let address = Address::p2pkh(&pubkey, Network::Bitcoin);
+        assert_eq!(key_map.get_by_address(&address), key_map.get(&pubkey));
+        assert!(key_map.get_by_address(&address).is_some());
+
+        let seckey = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let compressed = key_map.insert_raw(seckey, true);
+        let address = Address::p2wpkh(&compressed, Network::Bitcoin).unwrap();
+        assert_eq!(key_map.get_by_address(&address).unwrap().inner, seckey);
+        assert_eq!(
+            key_map
+                .secret_key(
+                    Fingerprint::default(),
+                    &DerivationPath::master(),
+                    compressed.inner
+                )
+                .map(|seckey| *seckey),
+            Ok(seckey)
+        );
+        // Secret key controls public keys of both parities
+        let negated = compressed.inner.negate(&secp);
+        let master = DerivationPath::master();
+        assert!(key_map.has_key(Fingerprint::default(), &master, negated));
+        assert!(key_map
+            .secret_key(Fingerprint::default(), &master, negated)
+            .is_ok());
+
+        let other = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2").unwrap();
+        assert_eq!(
+            key_map.insert_for_address(&other, PrivateKey::from_wif(WIF).unwrap()),
+            Err(KeyMapError::AddressMismatch(other))
+        );
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn sign_without_derivation() {
+        use bitcoin::TxOut;
+
+        use crate::sign::SignAll;
+        use crate::{fixtures, Psbt, PsbtVersion};
+
+        let secp = Secp256k1::new();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_raw(SecretKey::from_slice(&[0x42; 32]).unwrap(), true);
+
+        let tx = fixtures::tx([(9_000, Script::new())]);
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Address::p2wpkh(&pubkey, Network::Bitcoin)
+                .unwrap()
+                .script_pubkey(),
+        });
+
+        assert_eq!(psbt.sign_all(&key_map).unwrap(), 1);
+        assert!(psbt.inputs[0].partial_sigs.contains_key(&pubkey));
+    }
+}
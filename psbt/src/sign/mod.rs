@@ -18,12 +18,41 @@
 
 use bitcoin::secp256k1::{KeyPair, PublicKey, Secp256k1, SecretKey, Signing, XOnlyPublicKey};
 use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+use bitcoin::PrivateKey;
 
+use crate::MaxFeePolicy;
+
+#[cfg(feature = "miniscript")]
+mod analyze;
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "frost")]
+pub mod frost;
 mod inmem;
+#[cfg(feature = "keyfile")]
+mod keyfile;
+mod keymap;
+#[cfg(feature = "miniscript")]
+pub mod policy;
+#[cfg(feature = "remote")]
+pub mod remote;
+mod secret;
 #[cfg(feature = "miniscript")]
 mod signer;
 
+#[cfg(feature = "miniscript")]
+pub use analyze::{InputAnalysis, InputSighash, MissingField, SignableKey, SigningAnalysis};
+#[cfg(feature = "async")]
+pub use asynchronous::{LocalSigner, SignAsync};
 pub use inmem::{MemoryKeyProvider, MemorySigningAccount};
+#[cfg(feature = "keyfile")]
+pub use keyfile::{
+    KdfParams, KeyFile, KeyFileError, SecretKind, KEYFILE_MAGIC, KEYFILE_VERSION, MAX_KDF_MEMORY,
+};
+pub use keymap::{KeyMap, KeyMapError};
+pub use secret::{derive_seckey, Erase, SecretGuard};
+#[cfg(feature = "keyfile")]
+pub use secret::{EncryptedKeyStore, KeyStoreError};
 #[cfg(feature = "miniscript")]
 pub use signer::{SignAll, SignError, SignInputError};
 
@@ -46,7 +75,8 @@ pub trait SecretProvider<C: Signing> {
     fn secp_context(&self) -> &Secp256k1<C>;
 
     /// Returns secret key matching provided public key by iterating over all
-    /// extended private keys having the provided fingerprint.
+    /// extended private keys having the provided fingerprint. The key is
+    /// erased from memory once the returned guard is dropped.
     ///
     /// # Error
     ///
@@ -63,10 +93,11 @@ pub trait SecretProvider<C: Signing> {
         fingerprint: Fingerprint,
         derivation: &DerivationPath,
         pubkey: PublicKey,
-    ) -> Result<SecretKey, SecretProviderError>;
+    ) -> Result<SecretGuard<SecretKey>, SecretProviderError>;
 
     /// Returns BIP-340 key pair matching provided public key by iterating over
-    /// all extended private keys having the provided fingerprint.
+    /// all extended private keys having the provided fingerprint. The key pair
+    /// is erased from memory once the returned guard is dropped.
     ///
     /// # Error
     ///
@@ -83,9 +114,47 @@ pub trait SecretProvider<C: Signing> {
         fingerprint: Fingerprint,
         derivation: &DerivationPath,
         pubkey: XOnlyPublicKey,
-    ) -> Result<KeyPair, SecretProviderError>;
+    ) -> Result<SecretGuard<KeyPair>, SecretProviderError>;
+
+    /// Detects whether the provider controls the secret key for the public key
+    /// derived from the extended key with the provided fingerprint, without
+    /// exposing the secret key. Since a secret key controls both public keys
+    /// with the same x coordinate, keys are matched ignoring their parity, and
+    /// BIP-340 keys may be queried with their even-parity representation.
+    ///
+    /// Providers which require user interaction (like a passphrase) before
+    /// unlocking their secret keys should answer this query without the
+    /// interaction. The default implementation looks up the secret key with
+    /// [`SecretProvider::secret_key`] and erases it right away.
+    #[inline]
+    fn has_key(
+        &self,
+        fingerprint: Fingerprint,
+        derivation: &DerivationPath,
+        pubkey: PublicKey,
+    ) -> bool {
+        self.secret_key(fingerprint, derivation, pubkey).is_ok()
+    }
 
     /// Returns whether keys returned by this provider can be used for creating
     /// aggregated Schnorr signatures.
     fn use_musig(&self) -> bool;
+
+    /// Returns fee policy which must be satisfied by transactions signed with
+    /// the keys from this provider. `None` overrides the fee protection,
+    /// allowing to sign transactions with any fee.
+    #[inline]
+    fn fee_policy(&self) -> Option<MaxFeePolicy> { Some(MaxFeePolicy::default()) }
+
+    /// Returns keys which have no BIP-32 derivation information. The signer
+    /// uses them for the inputs whose spent scripts contain the keys, even if
+    /// the inputs do not provide key derivation data. The keys are guarded,
+    /// such that their copies are erased once the signer drops them.
+    #[inline]
+    fn standalone_keys(&self) -> Vec<SecretGuard<PrivateKey>> { vec![] }
+
+    /// Returns public keys of the keys from [`SecretProvider::standalone_keys`]
+    /// without exposing the secret keys.
+    #[inline]
+    fn standalone_pubkeys(&self) -> Vec<bitcoin::PublicKey> { vec![] }
 }
@@ -0,0 +1,534 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Signing policy engine, evaluating rules which must be satisfied by a
+//! transaction before the signer releases its signatures.
+//!
+//! Policy rules include destination whitelist, per-transaction and rolling
+//! spending limits, change-to-self verification and sighash restrictions.
+//! A transaction violating the policy is refused with [`PolicyDenial`],
+//! which is machine-readable and can be reported back to the PSBT creator.
+
+use std::collections::BTreeSet;
+
+use amplify::Wrapper;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::schnorr::TapTweak;
+use bitcoin::secp256k1::{Parity, Signing, Verification};
+use bitcoin::{PublicKey, Script};
+
+use super::{SecretProvider, SignAll, SignError};
+use crate::{Output, Psbt, PsbtSighashType};
+
+/// Reason for refusing to sign a transaction by the [`SigningPolicy`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", tag = "reason", rename_all = "camelCase")
+)]
+#[display(doc_comments)]
+pub enum PolicyDenial {
+    /// output #{output} pays to `{script_pubkey}`, which is not in the
+    /// whitelist of allowed destinations
+    DestinationNotWhitelisted {
+        /// Index of the output
+        output: usize,
+
+        /// Destination scriptPubkey
+        script_pubkey: Script,
+    },
+
+    /// transaction spends {amount} sats to external destinations, exceeding
+    /// per-transaction limit of {limit} sats
+    TransactionLimit {
+        /// Amount spent by the transaction
+        amount: u64,
+
+        /// Per-transaction spending limit
+        limit: u64,
+    },
+
+    /// transaction spends {amount} sats to external destinations, which
+    /// together with {spent} sats spent during last {period} seconds exceeds
+    /// rolling limit of {limit} sats
+    RollingLimit {
+        /// Amount spent by the transaction
+        amount: u64,
+
+        /// Amount spent during the rolling period before the transaction
+        spent: u64,
+
+        /// Rolling spending limit
+        limit: u64,
+
+        /// Duration of the rolling period, in seconds
+        period: u64,
+    },
+
+    /// total amount spent by the transaction outputs exceeds the maximum
+    /// possible value
+    AmountOverflow,
+
+    /// output #{output} provides derivation information for the signer keys,
+    /// but its scriptPubkey is not controlled by these keys
+    ChangeUnverified {
+        /// Index of the output
+        output: usize,
+    },
+
+    /// transaction has no change output verified to be controlled by the
+    /// signer
+    NoChange,
+
+    /// input #{input} uses sighash type {sighash_type}, which is not allowed
+    /// by the policy
+    SighashNotAllowed {
+        /// Index of the input
+        input: usize,
+
+        /// Sighash type requested by the input
+        sighash_type: PsbtSighashType,
+    },
+}
+
+impl PolicyDenial {
+    /// Returns stable machine-readable code of the denial reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PolicyDenial::DestinationNotWhitelisted { .. } => "destination_not_whitelisted",
+            PolicyDenial::TransactionLimit { .. } => "transaction_limit",
+            PolicyDenial::RollingLimit { .. } => "rolling_limit",
+            PolicyDenial::AmountOverflow => "amount_overflow",
+            PolicyDenial::ChangeUnverified { .. } => "change_unverified",
+            PolicyDenial::NoChange => "no_change",
+            PolicyDenial::SighashNotAllowed { .. } => "sighash_not_allowed",
+        }
+    }
+}
+
+/// Limit on the amount spent to external destinations during a rolling time
+/// period.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct RollingLimit {
+    /// Maximum amount spent during the period, in satoshis
+    pub amount: u64,
+
+    /// Duration of the period, in seconds
+    pub period: u64,
+}
+
+/// History of past spendings approved by the [`SigningPolicy`], used to
+/// enforce [`RollingLimit`]. Must be persisted by the signer between the
+/// signing sessions.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct SpendingLedger {
+    /// Spent amounts with the UNIX timestamps of the spendings
+    records: Vec<(u64, u64)>,
+}
+
+impl SpendingLedger {
+    /// Records amount spent at the given UNIX timestamp.
+    #[inline]
+    pub fn record(&mut self, timestamp: u64, amount: u64) { self.records.push((timestamp, amount)) }
+
+    /// Computes total amount spent after the given UNIX timestamp (inclusive),
+    /// saturating at `u64::MAX`.
+    pub fn spent_since(&self, timestamp: u64) -> u64 {
+        self.records
+            .iter()
+            .filter(|(time, _)| *time >= timestamp)
+            .fold(0u64, |sum, (_, amount)| sum.saturating_add(*amount))
+    }
+
+    /// Removes records made before the given UNIX timestamp.
+    #[inline]
+    pub fn prune(&mut self, timestamp: u64) { self.records.retain(|(time, _)| *time >= timestamp) }
+}
+
+/// Transaction properties approved by the [`SigningPolicy`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct PolicyApproval {
+    /// Amount spent to external destinations, in satoshis
+    pub spent: u64,
+
+    /// Indexes of outputs verified to be controlled by the signer
+    pub change_outputs: BTreeSet<usize>,
+}
+
+/// Rules which must be satisfied by a transaction before it gets signed.
+///
+/// Outputs are considered to be change only if their scriptPubkeys are
+/// verified to be controlled by the signer keys, using the output derivation
+/// information; all other outputs are external destinations. The default
+/// policy has no restrictions.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct SigningPolicy {
+    /// Allowed external destinations; `None` allows any destination
+    pub whitelist: Option<BTreeSet<Script>>,
+
+    /// Maximum amount spent to external destinations by a single transaction
+    pub transaction_limit: Option<u64>,
+
+    /// Maximum amount spent to external destinations during rolling period
+    pub rolling_limit: Option<RollingLimit>,
+
+    /// Requires transaction to have at least one verified change output
+    pub require_change: bool,
+
+    /// Refuses to sign transactions with outputs which provide derivation
+    /// information for the signer keys, but are not controlled by them
+    pub verify_change: bool,
+
+    /// Allowed sighash types; `None` allows all sighash types. Inputs without
+    /// sighash type use the default one and are always allowed.
+    pub sighash_types: Option<BTreeSet<PsbtSighashType>>,
+}
+
+impl SigningPolicy {
+    /// Evaluates policy rules against the transaction at UNIX timestamp `now`,
+    /// using spending history from the `ledger`.
+    ///
+    /// The ledger is not updated; on a successful signing the caller must
+    /// record [`PolicyApproval::spent`] amount with [`SpendingLedger::record`].
+    pub fn evaluate<C>(
+        &self,
+        psbt: &Psbt,
+        provider: &impl SecretProvider<C>,
+        ledger: &SpendingLedger,
+        now: u64,
+    ) -> Result<PolicyApproval, PolicyDenial>
+    where
+        C: Signing + Verification,
+    {
+        if let Some(allowed) = &self.sighash_types {
+            for input in &psbt.inputs {
+                match input.sighash_type {
+                    Some(sighash_type) if !allowed.contains(&sighash_type) => {
+                        return Err(PolicyDenial::SighashNotAllowed {
+                            input: input.index(),
+                            sighash_type,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut approval = PolicyApproval::default();
+        for output in &psbt.outputs {
+            match output_ownership(output, provider) {
+                Ownership::Verified => {
+                    approval.change_outputs.insert(output.index());
+                    continue;
+                }
+                Ownership::Unverified if self.verify_change => {
+                    return Err(PolicyDenial::ChangeUnverified {
+                        output: output.index(),
+                    })
+                }
+                Ownership::Unverified | Ownership::External => {}
+            }
+            if let Some(whitelist) = &self.whitelist {
+                if !whitelist.contains(output.script.as_inner()) {
+                    return Err(PolicyDenial::DestinationNotWhitelisted {
+                        output: output.index(),
+                        script_pubkey: output.script.to_inner(),
+                    });
+                }
+            }
+            approval.spent = approval
+                .spent
+                .checked_add(output.amount)
+                .ok_or(PolicyDenial::AmountOverflow)?;
+        }
+
+        if self.require_change && approval.change_outputs.is_empty() {
+            return Err(PolicyDenial::NoChange);
+        }
+
+        let amount = approval.spent;
+        if let Some(limit) = self.transaction_limit {
+            if amount > limit {
+                return Err(PolicyDenial::TransactionLimit { amount, limit });
+            }
+        }
+        if let Some(RollingLimit {
+            amount: limit,
+            period,
+        }) = self.rolling_limit
+        {
+            let spent = ledger.spent_since(now.saturating_sub(period));
+            if !matches!(spent.checked_add(amount), Some(total) if total <= limit) {
+                return Err(PolicyDenial::RollingLimit {
+                    amount,
+                    spent,
+                    limit,
+                    period,
+                });
+            }
+        }
+
+        Ok(approval)
+    }
+}
+
+impl Psbt {
+    /// Signs all PSBT inputs with [`SignAll::sign_all`] if the transaction
+    /// satisfies the signing policy at UNIX timestamp `now`. If any signatures
+    /// were created, records the spent amount into the `ledger`.
+    ///
+    /// # Errors
+    ///
+    /// Signing is refused with [`SignError::Policy`] providing the denial
+    /// reason if the transaction violates the policy.
+    pub fn sign_all_with_policy<C>(
+        &mut self,
+        provider: &impl SecretProvider<C>,
+        policy: &SigningPolicy,
+        ledger: &mut SpendingLedger,
+        now: u64,
+    ) -> Result<usize, SignError>
+    where
+        C: Signing + Verification,
+    {
+        let approval = policy.evaluate(self, provider, ledger, now)?;
+        let signature_count = self.sign_all(provider)?;
+        if signature_count > 0 {
+            ledger.record(now, approval.spent);
+        }
+        Ok(signature_count)
+    }
+}
+
+/// Whether an output is controlled by the signer.
+enum Ownership {
+    /// Output has no derivation information for the signer keys
+    External,
+    /// Output has derivation information for the signer keys, but its
+    /// scriptPubkey does not commit to them
+    Unverified,
+    /// Output scriptPubkey is verified to be controlled by the signer keys
+    Verified,
+}
+
+fn output_ownership<C>(output: &Output, provider: &impl SecretProvider<C>) -> Ownership
+where
+    C: Signing + Verification,
+{
+    let secp = provider.secp_context();
+    let script_pubkey = output.script.as_inner();
+    let mut ownership = Ownership::External;
+
+    for (pubkey, (fingerprint, derivation)) in &output.bip32_derivation {
+        if !provider.has_key(*fingerprint, derivation, *pubkey) {
+            continue;
+        }
+        let pubkey = PublicKey::new(*pubkey);
+        let wpubkey_hash = pubkey.wpubkey_hash().expect("compressed key");
+        let p2wpkh = Script::new_v0_p2wpkh(&wpubkey_hash);
+        let key_data = pubkey.to_bytes();
+        let script_commits = output
+            .witness_script
+            .as_ref()
+            .map(|witness_script| {
+                let p2wsh = witness_script.as_inner().to_v0_p2wsh();
+                (*script_pubkey == p2wsh || *script_pubkey == p2wsh.to_p2sh())
+                    && pushes_key(witness_script.as_inner(), &key_data)
+            })
+            .or_else(|| {
+                output.redeem_script.as_ref().map(|redeem_script| {
+                    *script_pubkey == redeem_script.as_inner().to_p2sh()
+                        && (*redeem_script.as_inner() == p2wpkh
+                            || pushes_key(redeem_script.as_inner(), &key_data))
+                })
+            })
+            .unwrap_or(false);
+        if script_commits
+            || *script_pubkey == Script::new_p2pk(&pubkey)
+            || *script_pubkey == Script::new_p2pkh(&pubkey.pubkey_hash())
+            || *script_pubkey == p2wpkh
+        {
+            return Ownership::Verified;
+        }
+        ownership = Ownership::Unverified;
+    }
+
+    for (pubkey, (_, (fingerprint, derivation))) in &output.tap_key_origins {
+        if !provider.has_key(*fingerprint, derivation, pubkey.public_key(Parity::Even)) {
+            continue;
+        }
+        let internal_key = match output.tap_internal_key {
+            Some(internal_key) => internal_key,
+            None => {
+                ownership = Ownership::Unverified;
+                continue;
+            }
+        };
+        let spend_info = match &output.tap_tree {
+            None => Some(None),
+            Some(tree) => tree
+                .clone()
+                .into_builder()
+                .finalize(secp, internal_key)
+                .ok()
+                .map(|spend_info| spend_info.merkle_root()),
+        };
+        // We do not verify presence of the key in the leaf scripts: the output
+        // controlled by the internal key is always spendable by the signer
+        let verified = *pubkey == internal_key
+            && spend_info.map_or(false, |merkle_root| {
+                let output_key = internal_key.tap_tweak(secp, merkle_root).0;
+                *script_pubkey == Script::new_v1_p2tr_tweaked(output_key)
+            });
+        if verified {
+            return Ownership::Verified;
+        }
+        ownership = Ownership::Unverified;
+    }
+
+    ownership
+}
+
+fn pushes_key(script: &Script, key_data: &[u8]) -> bool {
+    script.instructions().any(
+        |instruction| matches!(instruction, Ok(Instruction::PushBytes(data)) if data == key_data),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::EcdsaSighashType;
+
+    use super::*;
+    use crate::sign::KeyMap;
+    use crate::{fixtures, PsbtVersion};
+
+    fn psbt(outputs: &[(u64, Script)]) -> Psbt {
+        Psbt::with(fixtures::tx(outputs.iter().cloned()), PsbtVersion::V0).unwrap()
+    }
+
+    #[test]
+    fn limits_and_whitelist() {
+        let secp = Secp256k1::new();
+        let key_map = KeyMap::with(&secp);
+        let destination = Script::from(vec![0x51]);
+        let other = Script::from(vec![0x52]);
+        let psbt = psbt(&[(6_000, destination.clone()), (3_000, other.clone())]);
+
+        let mut ledger = SpendingLedger::default();
+        let mut policy = SigningPolicy::default();
+        assert_eq!(
+            policy
+                .evaluate(&psbt, &key_map, &ledger, 1000)
+                .unwrap()
+                .spent,
+            9_000
+        );
+
+        policy.transaction_limit = Some(8_000);
+        assert_eq!(
+            policy.evaluate(&psbt, &key_map, &ledger, 1000),
+            Err(PolicyDenial::TransactionLimit {
+                amount: 9_000,
+                limit: 8_000
+            })
+        );
+
+        policy.transaction_limit = None;
+        policy.rolling_limit = Some(RollingLimit {
+            amount: 10_000,
+            period: 100,
+        });
+        ledger.record(850, 5_000);
+        assert!(policy.evaluate(&psbt, &key_map, &ledger, 1000).is_ok());
+        ledger.record(950, 5_000);
+        let denial = policy.evaluate(&psbt, &key_map, &ledger, 1000).unwrap_err();
+        assert_eq!(denial.code(), "rolling_limit");
+
+        ledger.record(960, u64::MAX);
+        let denial = policy.evaluate(&psbt, &key_map, &ledger, 1000).unwrap_err();
+        assert_eq!(denial.code(), "rolling_limit");
+
+        policy.rolling_limit = None;
+        let overflow = self::psbt(&[(u64::MAX, destination.clone()), (1, other.clone())]);
+        assert_eq!(
+            policy.evaluate(&overflow, &key_map, &ledger, 1000),
+            Err(PolicyDenial::AmountOverflow)
+        );
+
+        policy.whitelist = Some(bset! { destination });
+        assert_eq!(
+            policy.evaluate(&psbt, &key_map, &ledger, 1000),
+            Err(PolicyDenial::DestinationNotWhitelisted {
+                output: 1,
+                script_pubkey: other
+            })
+        );
+    }
+
+    #[test]
+    fn change_and_sighash() {
+        let secp = Secp256k1::new();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_raw(SecretKey::from_slice(&[0x42; 32]).unwrap(), true);
+        let change = Script::new_v0_p2wpkh(&pubkey.wpubkey_hash().unwrap());
+        let mut psbt = psbt(&[(6_000, Script::from(vec![0x51])), (3_000, change)]);
+
+        let mut policy = SigningPolicy {
+            require_change: true,
+            verify_change: true,
+            ..default!()
+        };
+        assert_eq!(
+            policy.evaluate(&psbt, &key_map, &default!(), 0),
+            Err(PolicyDenial::NoChange)
+        );
+
+        psbt.outputs[1]
+            .bip32_derivation
+            .insert(pubkey.inner, (default!(), default!()));
+        let approval = policy.evaluate(&psbt, &key_map, &default!(), 0).unwrap();
+        assert_eq!(approval.spent, 6_000);
+        assert_eq!(approval.change_outputs, bset! { 1 });
+
+        psbt.outputs[1].script = Script::from(vec![0x53]).into();
+        assert_eq!(
+            policy.evaluate(&psbt, &key_map, &default!(), 0),
+            Err(PolicyDenial::ChangeUnverified { output: 1 })
+        );
+
+        psbt.inputs[0].sighash_type = Some(EcdsaSighashType::None.into());
+        policy.sighash_types = Some(bset! { EcdsaSighashType::All.into() });
+        assert!(matches!(
+            policy.evaluate(&psbt, &key_map, &default!(), 0),
+            Err(PolicyDenial::SighashNotAllowed { input: 0, .. })
+        ));
+    }
+}
@@ -0,0 +1,966 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Remote signing protocol, allowing a watch-only wallet to request
+//! signatures from a signer running on another device (for instance a
+//! phone), in the spirit of Nostr NIP-46.
+//!
+//! The requester sends [`SignRequest`] containing PSBT and hints on the
+//! origins of the keys which should sign it; the signer replies with
+//! [`SignResponse`] carrying only the created signatures
+//! ([`PartialSignatures`]), which are merged back into the requester PSBT.
+//!
+//! Protocol is transport-agnostic: messages are opaque byte frames passed
+//! through [`Transport`]. [`EncryptedChannel`] is a reference implementation
+//! of an end-to-end encrypted channel between the parties identified by
+//! secp256k1 keys: each frame is encrypted with XChaCha20-Poly1305 under the
+//! key derived from ECDH shared secret, and consists of a 24-byte nonce
+//! followed by the ciphertext. Sender public key is authenticated as
+//! associated data, preventing reflection of the messages back to the sender.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::rand::{self, RngCore};
+use bitcoin::secp256k1::{
+    Message, PublicKey, Secp256k1, SecretKey, Signing, Verification, XOnlyPublicKey, SECP256K1,
+};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource};
+use bitcoin::util::sighash::{Prevouts, SighashCache};
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{EcdsaSig, SchnorrSig, Transaction, TxOut};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+use super::{SecretProvider, SignAll, SignError};
+use crate::serialize::{Deserialize, Serialize};
+use crate::{Input, LockTimeError, MaxFeePolicy, Psbt};
+
+/// Tag used in deriving channel encryption key from ECDH shared secret.
+pub const REMOTE_SIGNER_TAG: &[u8] = b"DescriptorWallet:remote-signer";
+
+const MSG_REQUEST: u8 = 1;
+const MSG_RESPONSE: u8 = 2;
+
+/// Errors of the remote signing protocol.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum RemoteSignError {
+    /// transport error. {0}
+    #[from]
+    Io(io::Error),
+
+    /// unable to decrypt message: it is corrupted or was not encrypted for
+    /// this channel
+    Decryption,
+
+    /// malformed remote signer message
+    InvalidMessage,
+
+    /// malformed PSBT in the remote signer request. {0}
+    #[from]
+    Psbt(bitcoin::consensus::encode::Error),
+
+    /// remote party sent a request while a response was expected, or vice
+    /// versa
+    UnexpectedMessage,
+
+    /// response to request #{found} was received while waiting for response
+    /// to request #{expected}
+    IdMismatch {
+        /// Id of the sent request
+        expected: u64,
+
+        /// Id of the request in the received response
+        found: u64,
+    },
+
+    /// remote signer rejected the request: {0}
+    Rejected(String),
+
+    /// response contains signatures for input #{0} while PSBT has only {1}
+    /// inputs
+    InputOutOfRange(usize, usize),
+
+    /// response contains signature for input #{0} by a key which is not used
+    /// by the input
+    UnexpectedKey(usize),
+
+    /// response contains signature for input #{0} which is not valid for the
+    /// input sighash
+    InvalidSignature(usize),
+
+    /// response contains taproot key path signature for input #{0}, which
+    /// already has a different one
+    SignatureConflict(usize),
+
+    /// request #{0} was already served; replayed requests are rejected
+    Replayed(u64),
+
+    /// unable to construct the transaction for signature verification. {0}
+    #[from]
+    LockTime(LockTimeError),
+}
+
+/// Request for the remote signer.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SignRequest {
+    /// Request id, repeated by the signer in the response
+    pub id: u64,
+
+    /// PSBT to sign
+    pub psbt: Psbt,
+
+    /// Origins of the keys which are expected to sign the PSBT: master key
+    /// fingerprint and derivation path prefix. If empty, signer may sign with
+    /// any of its keys.
+    pub origins: Vec<KeySource>,
+}
+
+/// Signatures created by a signer for a single PSBT input.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct InputSignatures {
+    /// ECDSA signatures
+    pub partial_sigs: BTreeMap<bitcoin::PublicKey, EcdsaSig>,
+
+    /// Taproot key path spending signature
+    pub tap_key_sig: Option<SchnorrSig>,
+
+    /// Taproot script path spending signatures
+    pub tap_script_sigs: BTreeMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig>,
+}
+
+impl InputSignatures {
+    /// Detects whether there are no signatures.
+    pub fn is_empty(&self) -> bool {
+        self.partial_sigs.is_empty()
+            && self.tap_key_sig.is_none()
+            && self.tap_script_sigs.is_empty()
+    }
+
+    /// Counts signatures.
+    pub fn len(&self) -> usize {
+        self.partial_sigs.len() + self.tap_key_sig.iter().count() + self.tap_script_sigs.len()
+    }
+}
+
+/// Signatures created by a signer for a PSBT, indexed by the input number.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PartialSignatures(pub BTreeMap<usize, InputSignatures>);
+
+impl PartialSignatures {
+    /// Extracts signatures present in the `signed` PSBT but not in the
+    /// `original` PSBT.
+    pub fn diff(original: &Psbt, signed: &Psbt) -> PartialSignatures {
+        let mut sigs = BTreeMap::new();
+        for (index, input) in signed.inputs.iter().enumerate() {
+            let prev = original.inputs.get(index);
+            let input_sigs = InputSignatures {
+                partial_sigs: input
+                    .partial_sigs
+                    .iter()
+                    .filter(|(pk, sig)| prev.and_then(|p| p.partial_sigs.get(pk)) != Some(sig))
+                    .map(|(pk, sig)| (*pk, *sig))
+                    .collect(),
+                tap_key_sig: input
+                    .tap_key_sig
+                    .filter(|_| prev.and_then(|p| p.tap_key_sig) != input.tap_key_sig),
+                tap_script_sigs: input
+                    .tap_script_sigs
+                    .iter()
+                    .filter(|(k, sig)| prev.and_then(|p| p.tap_script_sigs.get(k)) != Some(sig))
+                    .map(|(k, sig)| (*k, *sig))
+                    .collect(),
+            };
+            if !input_sigs.is_empty() {
+                sigs.insert(index, input_sigs);
+            }
+        }
+        PartialSignatures(sigs)
+    }
+
+    /// Counts signatures.
+    pub fn len(&self) -> usize { self.0.values().map(InputSignatures::len).sum() }
+
+    /// Detects whether there are no signatures.
+    pub fn is_empty(&self) -> bool { self.0.values().all(InputSignatures::is_empty) }
+
+    /// Removes signatures made by keys which origins are not derived from any
+    /// of the `origins`, using key origin information from `psbt`.
+    pub fn retain_origins(&mut self, psbt: &Psbt, origins: &[KeySource]) {
+        let matches = |key_source: Option<&KeySource>| {
+            key_source.map_or(false, |(fingerprint, path)| {
+                origins.iter().any(|(origin_fp, origin_path)| {
+                    origin_fp == fingerprint && path.as_ref().starts_with(origin_path.as_ref())
+                })
+            })
+        };
+        for (index, sigs) in &mut self.0 {
+            let input = match psbt.inputs.get(*index) {
+                Some(input) => input,
+                None => {
+                    *sigs = default!();
+                    continue;
+                }
+            };
+            sigs.partial_sigs
+                .retain(|pk, _| matches(input.bip32_derivation.get(&pk.inner)));
+            let internal_key = input.tap_internal_key;
+            if !matches(
+                internal_key
+                    .and_then(|pk| input.tap_key_origins.get(&pk))
+                    .map(|(_, key_source)| key_source),
+            ) {
+                sigs.tap_key_sig = None;
+            }
+            sigs.tap_script_sigs.retain(|(pk, _), _| {
+                matches(
+                    input
+                        .tap_key_origins
+                        .get(pk)
+                        .map(|(_, key_source)| key_source),
+                )
+            });
+        }
+        self.0.retain(|_, sigs| !sigs.is_empty());
+    }
+
+    /// Adds signatures to the PSBT inputs, checking that each signing key is
+    /// present in the input key origins or used by its scripts and that each
+    /// signature is valid for the input sighash. Taproot key path signature
+    /// already present in the input is never replaced.
+    ///
+    /// # Returns
+    ///
+    /// Number of added signatures.
+    pub fn apply(&self, psbt: &mut Psbt) -> Result<usize, RemoteSignError> {
+        let len = psbt.inputs.len();
+        let tx = psbt.to_unsigned_tx()?;
+        let mut sig_hasher = SighashCache::new(&tx);
+        let spent = psbt
+            .inputs
+            .iter()
+            .map(|input| input.input_prevout().cloned())
+            .collect::<Result<Vec<_>, _>>()
+            .ok();
+        for (index, sigs) in &self.0 {
+            let input = psbt
+                .inputs
+                .get(*index)
+                .ok_or(RemoteSignError::InputOutOfRange(*index, len))?;
+            let known = sigs.partial_sigs.keys().all(|pk| {
+                input.bip32_derivation.contains_key(&pk.inner)
+                    || input.uses_key(*pk).unwrap_or_default()
+            }) && (sigs.tap_key_sig.is_none() || input.tap_internal_key.is_some())
+                && sigs
+                    .tap_script_sigs
+                    .keys()
+                    .all(|(pk, _)| input.tap_key_origins.contains_key(pk));
+            if !known {
+                return Err(RemoteSignError::UnexpectedKey(*index));
+            }
+            if !verify_signatures(input, sigs, &mut sig_hasher, spent.as_deref()) {
+                return Err(RemoteSignError::InvalidSignature(*index));
+            }
+            if sigs.tap_key_sig.is_some()
+                && input.tap_key_sig.is_some()
+                && input.tap_key_sig != sigs.tap_key_sig
+            {
+                return Err(RemoteSignError::SignatureConflict(*index));
+            }
+        }
+        for (index, sigs) in &self.0 {
+            let input = &mut psbt.inputs[*index];
+            input.partial_sigs.extend(sigs.partial_sigs.clone());
+            if input.tap_key_sig.is_none() {
+                input.tap_key_sig = sigs.tap_key_sig;
+            }
+            input.tap_script_sigs.extend(sigs.tap_script_sigs.clone());
+        }
+        Ok(self.len())
+    }
+}
+
+/// Verifies signatures against the input sighashes. Taproot sighashes
+/// committing to all inputs require `spent` outputs of all transaction inputs.
+fn verify_signatures(
+    input: &Input,
+    sigs: &InputSignatures,
+    sig_hasher: &mut SighashCache<&Transaction>,
+    spent: Option<&[TxOut]>,
+) -> bool {
+    let index = input.index();
+    let prevout = match input.input_prevout() {
+        Ok(prevout) => prevout.clone(),
+        Err(_) => return false,
+    };
+
+    let ecdsa_valid = sigs.partial_sigs.iter().all(|(pk, sig)| {
+        match input.ecdsa_sighash(sig_hasher, sig.hash_ty) {
+            Ok(Some(sighash)) => {
+                let msg = Message::from_slice(&sighash[..]).expect("sighash is 32 bytes");
+                SECP256K1.verify_ecdsa(&msg, &sig.sig, &pk.inner).is_ok()
+            }
+            _ => false,
+        }
+    });
+    if !ecdsa_valid {
+        return false;
+    }
+
+    let prevouts = match spent {
+        Some(spent) => Prevouts::All(spent),
+        None => Prevouts::One(index, prevout.clone()),
+    };
+    let mut verify_schnorr = |sig: &SchnorrSig, pk: &XOnlyPublicKey, leaf: Option<TapLeafHash>| {
+        sig_hasher
+            .taproot_signature_hash(
+                index,
+                &prevouts,
+                input.tap_annex(),
+                leaf.map(|leaf_hash| (leaf_hash, 0xFFFFFFFF)),
+                sig.hash_ty,
+            )
+            .map(|sighash| {
+                let msg = Message::from_slice(&sighash[..]).expect("sighash is 32 bytes");
+                SECP256K1.verify_schnorr(&sig.sig, &msg, pk).is_ok()
+            })
+            .unwrap_or_default()
+    };
+
+    if let Some(sig) = &sigs.tap_key_sig {
+        let script_pubkey = &prevout.script_pubkey;
+        let output_key = if script_pubkey.is_v1_p2tr() {
+            XOnlyPublicKey::from_slice(&script_pubkey[2..34]).ok()
+        } else {
+            None
+        };
+        if !output_key
+            .map(|output_key| verify_schnorr(sig, &output_key, None))
+            .unwrap_or_default()
+        {
+            return false;
+        }
+    }
+    sigs.tap_script_sigs
+        .iter()
+        .all(|((pk, leaf_hash), sig)| verify_schnorr(sig, pk, Some(*leaf_hash)))
+}
+
+/// Response of the remote signer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SignResponse {
+    /// Id of the request
+    pub id: u64,
+
+    /// Created signatures or the reason for rejecting the request
+    pub result: Result<PartialSignatures, String>,
+}
+
+/// Message of the remote signing protocol.
+#[derive(Clone, PartialEq, Debug, From)]
+pub enum RemoteMessage {
+    /// Request from the watch-only wallet
+    #[from]
+    Request(SignRequest),
+
+    /// Response from the signer
+    #[from]
+    Response(SignResponse),
+}
+
+impl RemoteMessage {
+    /// Serializes message into a byte frame.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut e = vec![];
+        match self {
+            RemoteMessage::Request(req) => {
+                write(&mut e, &MSG_REQUEST);
+                write(&mut e, &req.id);
+                write(&mut e, &req.psbt.serialize());
+                write_len(&mut e, req.origins.len());
+                for (fingerprint, path) in &req.origins {
+                    write(&mut e, &fingerprint.as_bytes().to_vec());
+                    write_len(&mut e, path.as_ref().len());
+                    for step in path {
+                        write(&mut e, &u32::from(*step));
+                    }
+                }
+            }
+            RemoteMessage::Response(resp) => {
+                write(&mut e, &MSG_RESPONSE);
+                write(&mut e, &resp.id);
+                match &resp.result {
+                    Err(reason) => {
+                        write(&mut e, &0u8);
+                        write(&mut e, &reason.as_bytes().to_vec());
+                    }
+                    Ok(sigs) => {
+                        write(&mut e, &1u8);
+                        write_len(&mut e, sigs.0.len());
+                        for (index, sigs) in &sigs.0 {
+                            write(&mut e, &(*index as u32));
+                            write_len(&mut e, sigs.partial_sigs.len());
+                            for (pk, sig) in &sigs.partial_sigs {
+                                write(&mut e, &pk.to_bytes());
+                                write(&mut e, &sig.to_vec());
+                            }
+                            let tap_key_sig = sigs.tap_key_sig.map(|sig| sig.to_vec());
+                            write(&mut e, &tap_key_sig.unwrap_or_default());
+                            write_len(&mut e, sigs.tap_script_sigs.len());
+                            for ((pk, leaf_hash), sig) in &sigs.tap_script_sigs {
+                                write(&mut e, &pk.serialize().to_vec());
+                                write(&mut e, &leaf_hash.into_inner().to_vec());
+                                write(&mut e, &sig.to_vec());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        e
+    }
+
+    /// Deserializes message from a byte frame.
+    pub fn deserialize(mut data: &[u8]) -> Result<RemoteMessage, RemoteSignError> {
+        let d = &mut data;
+        let msg = match read::<u8>(d)? {
+            MSG_REQUEST => {
+                let id = read(d)?;
+                let psbt = Psbt::deserialize(&read::<Vec<u8>>(d)?)?;
+                let mut origins = vec![];
+                for _ in 0..read_len(d)? {
+                    let fingerprint = read::<Vec<u8>>(d)?;
+                    if fingerprint.len() != 4 {
+                        return Err(RemoteSignError::InvalidMessage);
+                    }
+                    let mut path = vec![];
+                    for _ in 0..read_len(d)? {
+                        path.push(ChildNumber::from(read::<u32>(d)?));
+                    }
+                    origins.push((
+                        Fingerprint::from(fingerprint.as_slice()),
+                        DerivationPath::from(path),
+                    ));
+                }
+                RemoteMessage::Request(SignRequest { id, psbt, origins })
+            }
+            MSG_RESPONSE => {
+                let id = read(d)?;
+                let result =
+                    match read::<u8>(d)? {
+                        0 => Err(String::from_utf8(read(d)?)
+                            .map_err(|_| RemoteSignError::InvalidMessage)?),
+                        1 => {
+                            let mut sigs = BTreeMap::new();
+                            for _ in 0..read_len(d)? {
+                                let index = read::<u32>(d)? as usize;
+                                let mut input_sigs = InputSignatures::default();
+                                for _ in 0..read_len(d)? {
+                                    let pk = bitcoin::PublicKey::from_slice(&read::<Vec<u8>>(d)?)
+                                        .map_err(|_| RemoteSignError::InvalidMessage)?;
+                                    let sig = EcdsaSig::from_slice(&read::<Vec<u8>>(d)?)
+                                        .map_err(|_| RemoteSignError::InvalidMessage)?;
+                                    input_sigs.partial_sigs.insert(pk, sig);
+                                }
+                                let tap_key_sig = read::<Vec<u8>>(d)?;
+                                if !tap_key_sig.is_empty() {
+                                    input_sigs.tap_key_sig = Some(
+                                        SchnorrSig::from_slice(&tap_key_sig)
+                                            .map_err(|_| RemoteSignError::InvalidMessage)?,
+                                    );
+                                }
+                                for _ in 0..read_len(d)? {
+                                    let pk = XOnlyPublicKey::from_slice(&read::<Vec<u8>>(d)?)
+                                        .map_err(|_| RemoteSignError::InvalidMessage)?;
+                                    let leaf_hash = TapLeafHash::from_slice(&read::<Vec<u8>>(d)?)
+                                        .map_err(|_| RemoteSignError::InvalidMessage)?;
+                                    let sig = SchnorrSig::from_slice(&read::<Vec<u8>>(d)?)
+                                        .map_err(|_| RemoteSignError::InvalidMessage)?;
+                                    input_sigs.tap_script_sigs.insert((pk, leaf_hash), sig);
+                                }
+                                sigs.insert(index, input_sigs);
+                            }
+                            Ok(PartialSignatures(sigs))
+                        }
+                        _ => return Err(RemoteSignError::InvalidMessage),
+                    };
+                RemoteMessage::Response(SignResponse { id, result })
+            }
+            _ => return Err(RemoteSignError::InvalidMessage),
+        };
+        if !d.is_empty() {
+            return Err(RemoteSignError::InvalidMessage);
+        }
+        Ok(msg)
+    }
+}
+
+fn write(e: &mut Vec<u8>, val: &impl Encodable) {
+    val.consensus_encode(e)
+        .expect("in-memory encoders does not error");
+}
+
+fn write_len(e: &mut Vec<u8>, len: usize) { write(e, &VarInt(len as u64)) }
+
+fn read<T: Decodable>(d: &mut &[u8]) -> Result<T, RemoteSignError> {
+    T::consensus_decode(d).map_err(|_| RemoteSignError::InvalidMessage)
+}
+
+fn read_len(d: &mut &[u8]) -> Result<u64, RemoteSignError> { read::<VarInt>(d).map(|len| len.0) }
+
+/// Transport delivering byte frames between the remote signing parties:
+/// relay connection, websocket, QR code exchange etc.
+pub trait Transport {
+    /// Sends a frame to the remote party.
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Waits for the next frame from the remote party.
+    fn receive_frame(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/// End-to-end encrypted channel with a remote party over some [`Transport`].
+pub struct EncryptedChannel<T: Transport> {
+    transport: T,
+    cipher: XChaCha20Poly1305,
+    local_pubkey: PublicKey,
+    remote_pubkey: PublicKey,
+    /// Ids of the requests received by the signer side of the channel
+    served: BTreeSet<u64>,
+}
+
+impl<T: Transport> EncryptedChannel<T> {
+    /// Opens channel with the remote party identified by `remote_pubkey`,
+    /// using local identity key `local_key`.
+    pub fn with<C: Signing>(
+        secp: &Secp256k1<C>,
+        transport: T,
+        local_key: &SecretKey,
+        remote_pubkey: PublicKey,
+    ) -> Self {
+        let shared_secret = SharedSecret::new(&remote_pubkey, local_key);
+        let mut engine = sha256::Hash::engine();
+        engine.input(REMOTE_SIGNER_TAG);
+        engine.input(&shared_secret.secret_bytes());
+        let key = Zeroizing::new(sha256::Hash::from_engine(engine).into_inner());
+        EncryptedChannel {
+            transport,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key.as_slice())),
+            local_pubkey: PublicKey::from_secret_key(secp, local_key),
+            remote_pubkey,
+            served: empty!(),
+        }
+    }
+
+    /// Returns public key of the remote party.
+    #[inline]
+    pub fn remote_pubkey(&self) -> PublicKey { self.remote_pubkey }
+
+    /// Releases underlying transport.
+    #[inline]
+    pub fn into_transport(self) -> T { self.transport }
+
+    /// Encrypts and sends message to the remote party.
+    pub fn send(&mut self, msg: &RemoteMessage) -> Result<(), RemoteSignError> {
+        let mut nonce = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let plaintext = Zeroizing::new(msg.serialize());
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload {
+                msg: &plaintext,
+                aad: &self.local_pubkey.serialize(),
+            })
+            .expect("message size is within cipher limits");
+        let mut frame = nonce.to_vec();
+        frame.extend(ciphertext);
+        self.transport.send_frame(&frame)?;
+        Ok(())
+    }
+
+    /// Receives and decrypts message from the remote party.
+    pub fn receive(&mut self) -> Result<RemoteMessage, RemoteSignError> {
+        let frame = self.transport.receive_frame()?;
+        if frame.len() < 24 {
+            return Err(RemoteSignError::Decryption);
+        }
+        let (nonce, ciphertext) = frame.split_at(24);
+        let plaintext = Zeroizing::new(
+            self.cipher
+                .decrypt(XNonce::from_slice(nonce), Payload {
+                    msg: ciphertext,
+                    aad: &self.remote_pubkey.serialize(),
+                })
+                .map_err(|_| RemoteSignError::Decryption)?,
+        );
+        RemoteMessage::deserialize(&plaintext)
+    }
+
+    /// Sends PSBT to the remote signer and merges returned signatures into
+    /// it.
+    ///
+    /// # Returns
+    ///
+    /// Number of signatures added by the remote signer.
+    pub fn request_signatures(
+        &mut self,
+        id: u64,
+        psbt: &mut Psbt,
+        origins: Vec<KeySource>,
+    ) -> Result<usize, RemoteSignError> {
+        self.send(&RemoteMessage::Request(SignRequest {
+            id,
+            psbt: psbt.clone(),
+            origins,
+        }))?;
+        let response = match self.receive()? {
+            RemoteMessage::Response(response) => response,
+            RemoteMessage::Request(_) => return Err(RemoteSignError::UnexpectedMessage),
+        };
+        if response.id != id {
+            return Err(RemoteSignError::IdMismatch {
+                expected: id,
+                found: response.id,
+            });
+        }
+        response
+            .result
+            .map_err(RemoteSignError::Rejected)?
+            .apply(psbt)
+    }
+
+    /// Waits for a signing request and, if the request PSBT fee satisfies
+    /// `fee_policy` and the request is approved by the `approve` callback
+    /// (for instance, after showing the transaction to the user), signs it
+    /// with keys from `provider` and sends back the created signatures.
+    /// Rejections and errors in signing are reported to the remote party and
+    /// are not returned.
+    ///
+    /// # Returns
+    ///
+    /// Request which was served.
+    ///
+    /// # Errors
+    ///
+    /// Requests re-using id of an already received request are rejected with
+    /// [`RemoteSignError::Replayed`], and are not passed to `approve`.
+    pub fn serve_request<C>(
+        &mut self,
+        provider: &impl SecretProvider<C>,
+        fee_policy: &MaxFeePolicy,
+        approve: impl FnOnce(&SignRequest) -> bool,
+    ) -> Result<SignRequest, RemoteSignError>
+    where
+        C: Signing + Verification,
+    {
+        let request = match self.receive()? {
+            RemoteMessage::Request(request) => request,
+            RemoteMessage::Response(_) => return Err(RemoteSignError::UnexpectedMessage),
+        };
+        if !self.served.insert(request.id) {
+            let err = RemoteSignError::Replayed(request.id);
+            self.send(&RemoteMessage::Response(SignResponse {
+                id: request.id,
+                result: Err(err.to_string()),
+            }))?;
+            return Err(err);
+        }
+        let result = match request.psbt.check_fee_policy(fee_policy, 0) {
+            Err(err) => Err(err.to_string()),
+            Ok(()) if !approve(&request) => Err(s!("request was declined by the signer")),
+            Ok(()) => request.sign(provider).map_err(|err| err.to_string()),
+        };
+        let response = SignResponse {
+            id: request.id,
+            result,
+        };
+        self.send(&RemoteMessage::Response(response))?;
+        Ok(request)
+    }
+}
+
+impl SignRequest {
+    /// Signs requested PSBT with keys from `provider`, returning created
+    /// signatures made by keys matching request origins.
+    pub fn sign<C>(&self, provider: &impl SecretProvider<C>) -> Result<PartialSignatures, SignError>
+    where
+        C: Signing + Verification,
+    {
+        let mut signed = self.psbt.clone();
+        signed.sign_all(provider)?;
+        let mut sigs = PartialSignatures::diff(&self.psbt, &signed);
+        if !self.origins.is_empty() {
+            sigs.retain_origins(&self.psbt, &self.origins);
+        }
+        Ok(sigs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    use bitcoin::{Address, Network, Script, TxOut};
+
+    use super::*;
+    use crate::sign::KeyMap;
+    use crate::{fixtures, PsbtVersion};
+
+    struct MemoryTransport(Sender<Vec<u8>>, Receiver<Vec<u8>>);
+
+    impl Transport for MemoryTransport {
+        fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.0
+                .send(frame.to_vec())
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn receive_frame(&mut self) -> io::Result<Vec<u8>> {
+            self.1.recv().map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn transport_pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        (MemoryTransport(a_tx, b_rx), MemoryTransport(b_tx, a_rx))
+    }
+
+    #[test]
+    fn remote_signing() {
+        let secp = Secp256k1::new();
+        let wallet_key = SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let signer_key = SecretKey::from_slice(&[0x02; 32]).unwrap();
+        let signing_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let pubkey = bitcoin::PublicKey::new(PublicKey::from_secret_key(&secp, &signing_key));
+
+        let tx = fixtures::tx([(9_000, Script::new())]);
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Address::p2wpkh(&pubkey, Network::Bitcoin)
+                .unwrap()
+                .script_pubkey(),
+        });
+
+        let (wallet_transport, signer_transport) = transport_pair();
+        let signer_pubkey = PublicKey::from_secret_key(&secp, &signer_key);
+        let wallet_pubkey = PublicKey::from_secret_key(&secp, &wallet_key);
+        let signer = thread::spawn(move || {
+            let secp = Secp256k1::new();
+            let mut key_map = KeyMap::with(&secp);
+            key_map.insert_raw(signing_key, true);
+            let mut channel =
+                EncryptedChannel::with(&secp, signer_transport, &signer_key, wallet_pubkey);
+            channel
+                .serve_request(&key_map, &MaxFeePolicy::default(), |request| {
+                    request.psbt.inputs.len() == 1
+                })
+                .unwrap()
+                .id
+        });
+
+        let mut channel =
+            EncryptedChannel::with(&secp, wallet_transport, &wallet_key, signer_pubkey);
+        assert_eq!(channel.request_signatures(7, &mut psbt, vec![]).unwrap(), 1);
+        assert_eq!(signer.join().unwrap(), 7);
+        assert!(psbt.inputs[0].partial_sigs.contains_key(&pubkey));
+
+        let (mut wallet_transport, signer_transport) = transport_pair();
+        let mut channel =
+            EncryptedChannel::with(&secp, signer_transport, &signer_key, wallet_pubkey);
+        wallet_transport.send_frame(&[0u8; 64]).unwrap();
+        assert!(matches!(
+            channel.receive(),
+            Err(RemoteSignError::Decryption)
+        ));
+    }
+
+    fn test_psbt(script_pubkey: Script) -> Psbt {
+        let tx = fixtures::tx([(9_000, Script::new())]);
+        let mut psbt = Psbt::with(tx, PsbtVersion::V0).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey,
+        });
+        psbt
+    }
+
+    #[test]
+    fn serve_rejections() {
+        let secp = Secp256k1::new();
+        let wallet_key = SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let signer_key = SecretKey::from_slice(&[0x02; 32]).unwrap();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_raw(SecretKey::from_slice(&[0x42; 32]).unwrap(), true);
+        let psbt = test_psbt(
+            Address::p2wpkh(&pubkey, Network::Bitcoin)
+                .unwrap()
+                .script_pubkey(),
+        );
+
+        let (wallet_transport, signer_transport) = transport_pair();
+        let mut wallet = EncryptedChannel::with(
+            &secp,
+            wallet_transport,
+            &wallet_key,
+            PublicKey::from_secret_key(&secp, &signer_key),
+        );
+        let mut signer = EncryptedChannel::with(
+            &secp,
+            signer_transport,
+            &signer_key,
+            PublicKey::from_secret_key(&secp, &wallet_key),
+        );
+        let rejected = |wallet: &mut EncryptedChannel<MemoryTransport>, id: u64| {
+            matches!(
+                wallet.receive().unwrap(),
+                RemoteMessage::Response(SignResponse { id: resp_id, result: Err(_) })
+                    if resp_id == id
+            )
+        };
+        let request = |id: u64| {
+            RemoteMessage::from(SignRequest {
+                id,
+                psbt: psbt.clone(),
+                origins: vec![],
+            })
+        };
+
+        wallet.send(&request(1)).unwrap();
+        signer
+            .serve_request(&key_map, &MaxFeePolicy::default(), |_| false)
+            .unwrap();
+        assert!(rejected(&mut wallet, 1));
+
+        let policy = MaxFeePolicy {
+            absolute: Some(500),
+            ..MaxFeePolicy::unlimited()
+        };
+        wallet.send(&request(2)).unwrap();
+        signer
+            .serve_request(&key_map, &policy, |_| unreachable!())
+            .unwrap();
+        assert!(rejected(&mut wallet, 2));
+
+        wallet.send(&request(2)).unwrap();
+        assert!(matches!(
+            signer.serve_request(&key_map, &MaxFeePolicy::default(), |_| true),
+            Err(RemoteSignError::Replayed(2))
+        ));
+        assert!(rejected(&mut wallet, 2));
+    }
+
+    #[test]
+    fn apply_verifies_signatures() {
+        let secp = Secp256k1::new();
+        let mut key_map = KeyMap::with(&secp);
+        let pubkey = key_map.insert_raw(SecretKey::from_slice(&[0x42; 32]).unwrap(), true);
+        let other_key = SecretKey::from_slice(&[0x43; 32]).unwrap();
+
+        let mut psbt = test_psbt(
+            Address::p2wpkh(&pubkey, Network::Bitcoin)
+                .unwrap()
+                .script_pubkey(),
+        );
+        let request = SignRequest {
+            id: 1,
+            psbt: psbt.clone(),
+            origins: vec![],
+        };
+        let sigs = request.sign(&key_map).unwrap();
+        let mut forged = sigs.clone();
+        let sig = forged
+            .0
+            .get_mut(&0)
+            .unwrap()
+            .partial_sigs
+            .get_mut(&pubkey)
+            .unwrap();
+        sig.sig = secp.sign_ecdsa(&Message::from_slice(&[1u8; 32]).unwrap(), &other_key);
+        assert!(matches!(
+            forged.apply(&mut psbt.clone()),
+            Err(RemoteSignError::InvalidSignature(0))
+        ));
+        assert_eq!(sigs.apply(&mut psbt).unwrap(), 1);
+
+        let internal_key = XOnlyPublicKey::from(pubkey.inner);
+        let mut psbt = test_psbt(Script::new_v1_p2tr(&secp, internal_key, None));
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+        let request = SignRequest {
+            id: 2,
+            psbt: psbt.clone(),
+            origins: vec![],
+        };
+        let sigs = request.sign(&key_map).unwrap();
+        assert!(sigs.0[&0].tap_key_sig.is_some());
+
+        let mut signed = psbt.clone();
+        let mut forged = sigs.clone();
+        let mut sig = forged.0[&0].tap_key_sig.unwrap();
+        sig.sig = secp.sign_schnorr_no_aux_rand(
+            &Message::from_slice(&[1u8; 32]).unwrap(),
+            &bitcoin::secp256k1::KeyPair::from_secret_key(&secp, &other_key),
+        );
+        forged.0.get_mut(&0).unwrap().tap_key_sig = Some(sig);
+        assert!(matches!(
+            forged.apply(&mut signed),
+            Err(RemoteSignError::InvalidSignature(0))
+        ));
+
+        signed.inputs[0].tap_key_sig = Some(sig);
+        assert!(matches!(
+            sigs.apply(&mut signed),
+            Err(RemoteSignError::SignatureConflict(0))
+        ));
+        assert_eq!(signed.inputs[0].tap_key_sig, Some(sig));
+        assert_eq!(sigs.apply(&mut psbt).unwrap(), 1);
+    }
+
+    #[test]
+    fn message_serialization() {
+        let psbt = Psbt::with(fixtures::tx([]), PsbtVersion::V0).unwrap();
+        let request = RemoteMessage::from(SignRequest {
+            id: 1,
+            psbt,
+            origins: vec![(
+                Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]),
+                "m/84'/0'".parse().unwrap(),
+            )],
+        });
+        assert_eq!(
+            RemoteMessage::deserialize(&request.serialize()).unwrap(),
+            request
+        );
+
+        let response = RemoteMessage::from(SignResponse {
+            id: 1,
+            result: Err("user declined".to_owned()),
+        });
+        assert_eq!(
+            RemoteMessage::deserialize(&response.serialize()).unwrap(),
+            response
+        );
+
+        let mut data = response.serialize();
+        data.push(0);
+        assert!(matches!(
+            RemoteMessage::deserialize(&data),
+            Err(RemoteSignError::InvalidMessage)
+        ));
+    }
+}
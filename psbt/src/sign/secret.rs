@@ -0,0 +1,263 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Handling of secret key material: erasure of keys from memory once they are
+//! not needed anymore and encrypted in-memory storage of signing accounts,
+//! which are decrypted only for the duration of a signing session.
+
+#[cfg(feature = "keyfile")]
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+
+use bitcoin::secp256k1::{KeyPair, Secp256k1, SecretKey, Signing, ONE_KEY, SECP256K1};
+use bitcoin::util::bip32::{self, ChainCode, ChildNumber, DerivationPath, ExtendedPrivKey};
+#[cfg(feature = "keyfile")]
+use bitcoin::util::bip32::{ExtendedPubKey, Fingerprint};
+use bitcoin::{Network, PrivateKey};
+use zeroize::{DefaultIsZeroes, ZeroizeOnDrop, Zeroizing};
+
+#[cfg(feature = "keyfile")]
+use super::{KeyFile, MemoryKeyProvider, MemorySigningAccount};
+#[cfg(feature = "keyfile")]
+use crate::MaxFeePolicy;
+
+/// Secret key material which is erased from memory by overwriting it with a
+/// non-secret placeholder value.
+pub trait Erase: Copy {
+    /// Returns non-secret value replacing the erased secret.
+    fn placeholder() -> Self;
+}
+
+impl Erase for SecretKey {
+    #[inline]
+    fn placeholder() -> Self { ONE_KEY }
+}
+
+impl Erase for KeyPair {
+    #[inline]
+    fn placeholder() -> Self { KeyPair::from_secret_key(SECP256K1, &ONE_KEY) }
+}
+
+impl Erase for PrivateKey {
+    #[inline]
+    fn placeholder() -> Self { PrivateKey::new(ONE_KEY, Network::Bitcoin) }
+}
+
+impl Erase for ExtendedPrivKey {
+    #[inline]
+    fn placeholder() -> Self {
+        ExtendedPrivKey {
+            network: Network::Bitcoin,
+            depth: 0,
+            parent_fingerprint: default!(),
+            child_number: ChildNumber::Normal { index: 0 },
+            private_key: ONE_KEY,
+            chain_code: ChainCode::from(&[0u8; 32][..]),
+        }
+    }
+}
+
+/// Secret zeroized by [`Zeroizing`] with its placeholder value.
+#[derive(Copy, Clone)]
+struct Erasable<T: Erase>(T);
+
+impl<T: Erase> Default for Erasable<T> {
+    #[inline]
+    fn default() -> Self { Erasable(T::placeholder()) }
+}
+
+impl<T: Erase> DefaultIsZeroes for Erasable<T> {}
+
+/// Container erasing the secret it holds when dropped.
+pub struct SecretGuard<T: Erase>(Zeroizing<Erasable<T>>);
+
+impl<T: Erase> SecretGuard<T> {
+    /// Takes ownership over the secret.
+    #[inline]
+    pub fn new(secret: T) -> Self { SecretGuard(Zeroizing::new(Erasable(secret))) }
+}
+
+impl<T: Erase> Deref for SecretGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target { &self.0 .0 }
+}
+
+impl<T: Erase> DerefMut for SecretGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 .0 }
+}
+
+impl<T: Erase> Debug for SecretGuard<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { f.write_str("SecretGuard(..)") }
+}
+
+impl<T: Erase> ZeroizeOnDrop for SecretGuard<T> {}
+
+/// Derives secret key from the extended private key, erasing all
+/// intermediate extended keys produced during the derivation.
+pub fn derive_seckey<C: Signing>(
+    secp: &Secp256k1<C>,
+    xpriv: &ExtendedPrivKey,
+    derivation: &DerivationPath,
+) -> Result<SecretGuard<SecretKey>, bip32::Error> {
+    let mut xpriv = SecretGuard::new(*xpriv);
+    for child in derivation {
+        *xpriv = xpriv.ckd_priv(secp, *child)?;
+    }
+    Ok(SecretGuard::new(xpriv.private_key))
+}
+
+/// Errors unlocking [`EncryptedKeyStore`].
+#[cfg(feature = "keyfile")]
+#[derive(
+    Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error
+)]
+#[display(doc_comments)]
+pub enum KeyStoreError {
+    /// wrong password for the account with fingerprint {0}
+    WrongPassword(Fingerprint),
+}
+
+/// Storage of signing accounts keeping extended private keys encrypted with a
+/// password in the [`KeyFile`] format. Private keys are decrypted only when
+/// the store is unlocked for a signing session (see
+/// [`EncryptedKeyStore::unlock`]); the session erases them from memory once
+/// dropped.
+#[cfg(feature = "keyfile")]
+#[derive(Clone, Debug)]
+pub struct EncryptedKeyStore {
+    accounts: BTreeMap<ExtendedPubKey, KeyFile>,
+    /// Participate keys from this store in musigs
+    musig: bool,
+    /// Fee policy enforced by signing sessions
+    fee_policy: Option<MaxFeePolicy>,
+}
+
+#[cfg(feature = "keyfile")]
+impl EncryptedKeyStore {
+    /// Constructs empty key store. Keys from the store participate in musigs
+    /// if `musig` is set; signing sessions enforce the default fee policy
+    /// (see [`EncryptedKeyStore::set_fee_policy`]).
+    pub fn with(musig: bool) -> Self {
+        EncryptedKeyStore {
+            accounts: none!(),
+            musig,
+            fee_policy: Some(MaxFeePolicy::default()),
+        }
+    }
+
+    /// Sets fee policy enforced by signing sessions; `None` overrides fee
+    /// protection.
+    #[inline]
+    pub fn set_fee_policy(&mut self, fee_policy: Option<MaxFeePolicy>) {
+        self.fee_policy = fee_policy;
+    }
+
+    /// Encrypts account private key with the password and adds it to the
+    /// store. Returns `false` if the account was already present.
+    pub fn add_account(&mut self, account: MemorySigningAccount, password: &str) -> bool {
+        let key_file = KeyFile::create_account(&account, password)
+            .expect("default key derivation parameters are valid");
+        self.accounts
+            .insert(*account.account_xpub(), key_file)
+            .is_none()
+    }
+
+    /// Number of accounts in the store.
+    #[inline]
+    pub fn len(&self) -> usize { self.accounts.len() }
+
+    /// Detects whether the store has no accounts.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.accounts.is_empty() }
+
+    /// Decrypts all accounts with the password, returning key provider for a
+    /// signing session. Decrypted keys are erased when the provider is
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// If the password does not match any of the accounts.
+    pub fn unlock<'secp, C: Signing>(
+        &self,
+        secp: &'secp Secp256k1<C>,
+        password: &str,
+    ) -> Result<MemoryKeyProvider<'secp, C>, KeyStoreError> {
+        let mut provider = MemoryKeyProvider::with(secp, self.musig);
+        provider.set_fee_policy(self.fee_policy);
+        for (xpub, key_file) in &self.accounts {
+            let account = key_file
+                .unlock_account(secp, password)
+                .map_err(|_| KeyStoreError::WrongPassword(xpub.fingerprint()))?;
+            provider.add_account(account);
+        }
+        Ok(provider)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zeroize::Zeroize;
+
+    use super::*;
+    #[cfg(feature = "keyfile")]
+    use crate::sign::SecretProvider;
+
+    #[test]
+    fn erase() {
+        let mut seckey = Erasable(SecretKey::from_slice(&[0x42; 32]).unwrap());
+        seckey.zeroize();
+        assert_eq!(seckey.0, ONE_KEY);
+
+        let mut xpriv =
+            Erasable(ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap());
+        xpriv.zeroize();
+        assert_eq!(xpriv.0.private_key, ONE_KEY);
+        assert_eq!(xpriv.0.chain_code, ChainCode::from(&[0u8; 32][..]));
+    }
+
+    #[test]
+    #[cfg(feature = "keyfile")]
+    fn encrypted_store() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        let master_id = ExtendedPubKey::from_priv(&secp, &master).identifier();
+        let derivation = DerivationPath::from(vec![bip32::ChildNumber::Hardened { index: 84 }]);
+        let account_xpriv = master.derive_priv(&secp, &derivation).unwrap();
+        let account = MemorySigningAccount::with(&secp, master_id, derivation, account_xpriv);
+        let fingerprint = account.account_fingerprint();
+
+        let mut store = EncryptedKeyStore::with(false);
+        assert!(store.add_account(account, "secret"));
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.unlock(&secp, "wrong").unwrap_err(),
+            KeyStoreError::WrongPassword(fingerprint)
+        );
+
+        let session = store.unlock(&secp, "secret").unwrap();
+        let path = DerivationPath::from(vec![bip32::ChildNumber::Normal { index: 1 }]);
+        let expected = account_xpriv.derive_priv(&secp, &path).unwrap().private_key;
+        assert_eq!(
+            session
+                .secret_key(fingerprint, &path, expected.public_key(&secp))
+                .map(|seckey| *seckey),
+            Ok(expected)
+        );
+        assert_eq!(
+            derive_seckey(&secp, &account_xpriv, &path).map(|seckey| *seckey),
+            Ok(expected)
+        );
+    }
+}
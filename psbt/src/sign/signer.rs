@@ -19,31 +19,49 @@
 use core::ops::Deref;
 
 use amplify::Wrapper;
+use bitcoin::blockdata::script::Instruction;
 use bitcoin::hashes::Hash;
 use bitcoin::schnorr::TapTweak;
 use bitcoin::secp256k1::{self, KeyPair, Signing, Verification, XOnlyPublicKey};
 use bitcoin::util::address::WitnessVersion;
-use bitcoin::util::sighash::{self, Prevouts, ScriptPath, SighashCache};
+use bitcoin::util::sighash::{self, Prevouts, SighashCache};
 use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::{
     EcdsaSig, EcdsaSighashType, PubkeyHash, PublicKey, SchnorrSig, SchnorrSighashType, Script,
-    Transaction, TxOut,
+    Sighash, Transaction, TxOut,
 };
-use bitcoin_scripts::{PubkeyScript, RedeemScript};
+use bitcoin_onchain::ResolveTx;
+use bitcoin_scripts::{PubkeyScript, RedeemScript, WitnessScript};
 use descriptors::{self, CompositeDescrType, DeductionError};
 use miniscript::{Miniscript, ToPublicKey};
 
-use super::SecretProvider;
-use crate::{Input, InputMatchError, Psbt};
+use super::policy::PolicyDenial;
+use super::{SecretGuard, SecretProvider};
+use crate::{FeePolicyError, Input, InputMatchError, InputRepair, LockTimeError, Psbt};
 
 /// Errors happening during whole PSBT signing process
-#[derive(Debug, Display, Error)]
-#[display("failed to sign input #{input_index} because {error}")]
-pub struct SignError {
-    /// Signing error originating from a specific transaction input
-    pub error: SignInputError,
-    /// Index of the transaction input that has generated a error
-    pub input_index: usize,
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum SignError {
+    /// failed to sign input #{input_index} because {error}
+    Input {
+        /// Signing error originating from a specific transaction input
+        error: SignInputError,
+        /// Index of the transaction input that has generated a error
+        input_index: usize,
+    },
+
+    /// refusing to sign transaction since {0}
+    #[from]
+    FeePolicy(FeePolicyError),
+
+    /// signing policy denies the transaction: {0}
+    #[from]
+    Policy(PolicyDenial),
+
+    /// unable to construct the transaction for signing. {0}
+    #[from]
+    LockTime(LockTimeError),
 }
 
 /// Errors happening during PSBT input signing process
@@ -179,7 +197,28 @@ impl From<DeductionError> for SignInputError {
 impl SignError {
     #[inline]
     pub fn with_input_no(error: SignInputError, input_index: usize) -> SignError {
-        SignError { error, input_index }
+        SignError::Input { error, input_index }
+    }
+
+    /// Returns index of the failed input, if the error is specific to an
+    /// input. Allows to access data of the input-specific errors, which
+    /// were the only kind of [`SignError`] before it became an enum.
+    #[inline]
+    pub fn input_index(&self) -> Option<usize> {
+        match self {
+            SignError::Input { input_index, .. } => Some(*input_index),
+            _ => None,
+        }
+    }
+
+    /// Returns signing error of a specific input, if any (see
+    /// [`SignError::input_index`]).
+    #[inline]
+    pub fn input_error(&self) -> Option<&SignInputError> {
+        match self {
+            SignError::Input { error, .. } => Some(error),
+            _ => None,
+        }
     }
 }
 
@@ -198,6 +237,12 @@ pub trait SignAll {
     /// individual signatures created for different P2TR script spending paths,
     /// i.e. a transaction with one P2TR input having a single key may result
     /// in multiple signatures, one per each listed spending P2TR leaf.
+    ///
+    /// # Errors
+    ///
+    /// Signing is refused with [`SignError::FeePolicy`] if the transaction
+    /// fee exceeds the maximum recorded in PSBT or allowed by the provider
+    /// fee policy (see [`SecretProvider::fee_policy`]).
     fn sign_all<C>(&mut self, provider: &impl SecretProvider<C>) -> Result<usize, SignError>
     where
         C: Signing + Verification;
@@ -208,7 +253,7 @@ impl SignAll for Psbt {
         &mut self,
         provider: &impl SecretProvider<C>,
     ) -> Result<usize, SignError> {
-        let tx = self.clone().into_unsigned_tx();
+        let tx = self.to_unsigned_tx()?;
         let mut signature_count = 0usize;
         let mut sig_hasher = SighashCache::new(&tx);
 
@@ -225,6 +270,12 @@ impl SignAll for Psbt {
             .collect::<Result<Vec<_>, _>>()?;
         let prevouts = Prevouts::All(txout_list.as_ref());
 
+        if let Some(fee_policy) = provider.fee_policy() {
+            // Signer does not know the descriptors, so the fee rate limit is
+            // applied to the unsigned transaction size
+            self.check_fee_policy(&fee_policy, 0)?;
+        }
+
         for input in &mut self.inputs {
             let count = input
                 .sign_input_pretr(provider, &mut sig_hasher)
@@ -242,6 +293,29 @@ impl SignAll for Psbt {
     }
 }
 
+impl Psbt {
+    /// Lenient version of [`SignAll::sign_all`], which before signing matches
+    /// inputs with the outputs they spend by txid and vout, repairing missing
+    /// or inconsistent `witness_utxo` and `non_witness_utxo` fields with
+    /// transactions from `tx_source` (see [`Psbt::repair_prevouts`]).
+    ///
+    /// # Returns
+    ///
+    /// Number of created signatures and the list of modifications made to the
+    /// inputs.
+    pub fn sign_all_lenient<C: Signing + Verification>(
+        &mut self,
+        provider: &impl SecretProvider<C>,
+        tx_source: &impl ResolveTx,
+    ) -> Result<(usize, Vec<InputRepair>), SignError> {
+        let repairs = self
+            .repair_prevouts(tx_source)
+            .map_err(|err| SignError::with_input_no(err.error.into(), err.input))?;
+        let signature_count = self.sign_all(provider)?;
+        Ok((signature_count, repairs))
+    }
+}
+
 impl Input {
     /// Signs a single PSBT input using all known keys provided by
     /// [`SecretProvider`]. This includes signing legacy and segwit inputs
@@ -273,6 +347,17 @@ impl Input {
                 Err(_) => continue,
             };
 
+            if self.sign_input_with(provider, sig_hasher, PublicKey::new(pubkey), seckey)? {
+                signature_count += 1;
+            }
+        }
+
+        for key in provider.standalone_keys() {
+            let pubkey = key.public_key(provider.secp_context());
+            if self.bip32_derivation.contains_key(&pubkey.inner) || !self.uses_key(pubkey)? {
+                continue;
+            }
+            let seckey = SecretGuard::new(key.inner);
             if self.sign_input_with(provider, sig_hasher, pubkey, seckey)? {
                 signature_count += 1;
             }
@@ -281,6 +366,36 @@ impl Input {
         Ok(signature_count)
     }
 
+    /// Detects whether the spent output script or the input redeem or witness
+    /// scripts contain the key.
+    pub(super) fn uses_key(&self, pubkey: PublicKey) -> Result<bool, SignInputError> {
+        let script_pubkey = &self.input_prevout()?.script_pubkey;
+        if *script_pubkey == Script::new_p2pk(&pubkey)
+            || *script_pubkey == Script::new_p2pkh(&pubkey.pubkey_hash())
+        {
+            return Ok(true);
+        }
+        if let Some(wpubkey_hash) = pubkey.wpubkey_hash() {
+            let p2wpkh = Script::new_v0_p2wpkh(&wpubkey_hash);
+            if *script_pubkey == p2wpkh
+                || self.redeem_script.as_ref().map(RedeemScript::as_inner) == Some(&p2wpkh)
+            {
+                return Ok(true);
+            }
+        }
+        let key_data = pubkey.to_bytes();
+        Ok(self
+            .witness_script
+            .as_ref()
+            .map(WitnessScript::as_inner)
+            .into_iter()
+            .chain(self.redeem_script.as_ref().map(RedeemScript::as_inner))
+            .flat_map(Script::instructions)
+            .any(|instruction| {
+                matches!(instruction, Ok(Instruction::PushBytes(data)) if data == key_data)
+            }))
+    }
+
     /// Signs a single PSBT input using all known keys provided by
     /// [`SecretProvider`] for P2TR input spending, including both key- and
     /// script-path spendings.
@@ -320,18 +435,46 @@ impl Input {
             )?;
         }
 
+        for key in provider.standalone_keys() {
+            let keypair = SecretGuard::new(KeyPair::from_secret_key(
+                provider.secp_context(),
+                &key.inner,
+            ));
+            let pubkey = keypair.x_only_public_key().0;
+            if self.tap_key_origins.contains_key(&pubkey) {
+                continue;
+            }
+            let leaves = self
+                .tap_scripts
+                .values()
+                .filter(|(script, _)| {
+                    script.instructions().any(|instruction| {
+                        matches!(instruction, Ok(Instruction::PushBytes(data))
+                            if data == &pubkey.serialize()[..])
+                    })
+                })
+                .map(|(script, leaf_ver)| TapLeafHash::from_script(script, *leaf_ver))
+                .collect::<Vec<_>>();
+            if self.tap_internal_key != Some(pubkey) && leaves.is_empty() {
+                continue;
+            }
+            signature_count += self.sign_taproot_input_with(
+                provider, sig_hasher, pubkey, keypair, &leaves, prevouts,
+            )?;
+        }
+
         Ok(signature_count)
     }
 
-    fn sign_input_with<C, R>(
-        &mut self,
-        provider: &impl SecretProvider<C>,
+    /// Computes sighash for ECDSA signature of the input with the given
+    /// sighash type, checking that the input scripts match the spent output.
+    /// Returns `None` for taproot inputs, which do not use ECDSA signatures.
+    pub(super) fn ecdsa_sighash<R>(
+        &self,
         sig_hasher: &mut SighashCache<R>,
-        pubkey: secp256k1::PublicKey,
-        mut seckey: secp256k1::SecretKey,
-    ) -> Result<bool, SignInputError>
+        sighash_type: EcdsaSighashType,
+    ) -> Result<Option<Sighash>, SignInputError>
     where
-        C: Signing,
         R: Deref<Target = Transaction>,
     {
         // Extract & check previous output information
@@ -344,17 +487,6 @@ impl Input {
         let witness_script = self.witness_script.as_ref();
         let redeem_script = self.redeem_script.as_ref();
 
-        // Compute sighash
-        let sighash_type = self
-            .sighash_type
-            .map(|sht| sht.ecdsa_hash_ty())
-            .transpose()
-            .map_err(|err| SignInputError::NonStandardSighashType {
-                sighash_type: err.0,
-                index,
-            })?
-            .unwrap_or(EcdsaSighashType::All);
-
         let descr_type =
             CompositeDescrType::deduce(&script_pubkey, redeem_script, witness_script.is_some())?;
         let sighash = match (descr_type, witness_script) {
@@ -374,10 +506,7 @@ impl Input {
             {
                 return Err(SignInputError::ScriptPubkeyMismatch)
             }
-            (CompositeDescrType::Tr, _) => {
-                // skipping taproot spendings: they are handled by a separate function
-                return Ok(false);
-            }
+            (CompositeDescrType::Tr, _) => return Ok(None),
             (CompositeDescrType::Wpkh, _) | (CompositeDescrType::ShWpkh, _) => {
                 let pubkey_hash = PubkeyHash::from_slice(&script_pubkey[2..22])
                     .expect("PubkeyHash hash length failure");
@@ -397,14 +526,46 @@ impl Input {
                 sig_hasher.legacy_signature_hash(index, &script_pubkey, sighash_type.to_u32())?
             }
         };
+        Ok(Some(sighash))
+    }
+
+    fn sign_input_with<C, R>(
+        &mut self,
+        provider: &impl SecretProvider<C>,
+        sig_hasher: &mut SighashCache<R>,
+        mut pubkey: PublicKey,
+        mut seckey: SecretGuard<secp256k1::SecretKey>,
+    ) -> Result<bool, SignInputError>
+    where
+        C: Signing,
+        R: Deref<Target = Transaction>,
+    {
+        // Compute sighash
+        let index = self.index();
+        let sighash_type = self
+            .sighash_type
+            .map(|sht| sht.ecdsa_hash_ty())
+            .transpose()
+            .map_err(|err| SignInputError::NonStandardSighashType {
+                sighash_type: err.0,
+                index,
+            })?
+            .unwrap_or(EcdsaSighashType::All);
+        let sighash = match self.ecdsa_sighash(sig_hasher, sighash_type)? {
+            Some(sighash) => sighash,
+            // skipping taproot spendings: they are handled by a separate function
+            None => return Ok(false),
+        };
 
-        // Apply past P2C tweaks
-        if let Some(tweak) = self.p2c_tweak(pubkey) {
+        // Apply past P2C tweaks; the signature is made for the tweaked key used
+        // by the spent output
+        if let Some(tweak) = self.p2c_tweak(pubkey.inner) {
             let tweak = secp256k1::Scalar::from_be_bytes(tweak.into_inner())
                 .expect("negligible probability");
-            seckey = seckey
+            *seckey = seckey
                 .add_tweak(&tweak)
                 .map_err(|_| SignInputError::P2cTweak)?;
+            pubkey = PublicKey::new(seckey.public_key(provider.secp_context()));
         }
 
         // Do the signature
@@ -417,7 +578,7 @@ impl Input {
         let mut partial_sig = signature.serialize_der().to_vec();
         partial_sig.push(sighash_type as u8);
         self.partial_sigs.insert(
-            bitcoin::PublicKey::new(pubkey),
+            pubkey,
             EcdsaSig::from_slice(&partial_sig).expect("serialize_der failure"),
         );
 
@@ -428,8 +589,8 @@ impl Input {
         &mut self,
         provider: &impl SecretProvider<C>,
         sig_hasher: &mut SighashCache<R>,
-        pubkey: XOnlyPublicKey,
-        mut keypair: KeyPair,
+        mut pubkey: XOnlyPublicKey,
+        mut keypair: SecretGuard<KeyPair>,
         leaves: &[TapLeafHash],
         prevouts: &Prevouts<TxOut>,
     ) -> Result<usize, SignInputError>
@@ -474,13 +635,14 @@ impl Input {
             return Err(SignInputError::TaprootPrevoutsMissed);
         }
 
-        // Apply past P2C tweaks
+        // Apply past P2C tweaks; tapscripts use the tweaked key
         if let Some(tweak) = self.p2c_tweak(pubkey.to_public_key().inner) {
             let tweak = secp256k1::Scalar::from_be_bytes(tweak.into_inner())
                 .expect("negligible probability");
-            keypair = keypair
+            *keypair = keypair
                 .add_xonly_tweak(provider.secp_context(), &tweak)
                 .map_err(|_| SignInputError::P2cTweak)?;
+            pubkey = keypair.x_only_public_key().0;
         }
 
         // Sign taproot script spendings
@@ -494,10 +656,12 @@ impl Input {
                 if pk != pubkey {
                     continue;
                 }
-                let sighash = sig_hasher.taproot_script_spend_signature_hash(
+                let sighash = sig_hasher.taproot_signature_hash(
                     index,
                     prevouts,
-                    ScriptPath::with_defaults(script),
+                    self.tap_annex(),
+                    // No OP_CODESEPARATOR is executed by miniscript
+                    Some((tapleaf_hash, 0xFFFFFFFF)),
                     sighash_type,
                 )?;
                 let signature = provider.secp_context().sign_schnorr(
@@ -515,13 +679,22 @@ impl Input {
         }
 
         // Sign taproot key spendings
-        let sighash =
-            sig_hasher.taproot_signature_hash(index, prevouts, None, None, sighash_type)?;
-        let tweaked_keypair = keypair.tap_tweak(provider.secp_context(), self.tap_merkle_root);
+        let sighash = sig_hasher.taproot_signature_hash(
+            index,
+            prevouts,
+            self.tap_annex(),
+            None,
+            sighash_type,
+        )?;
+        let tweaked_keypair = SecretGuard::new(
+            keypair
+                .tap_tweak(provider.secp_context(), self.tap_merkle_root)
+                .to_inner(),
+        );
         let signature = provider.secp_context().sign_schnorr(
             &bitcoin::secp256k1::Message::from_slice(&sighash[..])
                 .expect("taproot Sighash generation is broken"),
-            &tweaked_keypair.to_inner(),
+            &tweaked_keypair,
         );
 
         match self.tap_key_sig {
@@ -0,0 +1,486 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Streaming PSBT decoder, which reads PSBT key-value maps one by one from a
+//! [`Read`] source instead of buffering the whole PSBT, allowing processing of
+//! PSBTs with thousands of inputs on memory-constrained devices.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read};
+
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::{self, Decodable, Encodable};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
+use bitcoin::Transaction;
+
+use crate::v0::{InputV0, OutputV0, PsbtV0};
+use crate::{raw, Input, Output, Psbt};
+
+const PSBT_MAGIC: [u8; 5] = *b"psbt\xff";
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_XPUB: u8 = 0x01;
+// Global keys defined by BIP-370 for PSBT version 2 only
+const PSBT_GLOBAL_V2_KEYS: [u8; 5] = [0x02, 0x03, 0x04, 0x05, 0x06];
+const PSBT_GLOBAL_VERSION: u8 = 0xFB;
+const PSBT_GLOBAL_PROPRIETARY: u8 = 0xFC;
+
+/// Errors decoding PSBT with [`PsbtDecoder`].
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum StreamDecodeError {
+    /// I/O error. {0}
+    #[from]
+    Io(io::Error),
+
+    /// invalid PSBT data. {0}
+    #[from]
+    Encoding(consensus::encode::Error),
+
+    /// the data are not a PSBT: invalid magic bytes
+    InvalidMagic,
+
+    /// PSBT key of {0} bytes exceeds the limit of {1} bytes
+    KeyTooLong(u64, usize),
+
+    /// PSBT value of {0} bytes exceeds the limit of {1} bytes
+    ValueTooLarge(u64, usize),
+
+    /// PSBT map contains more than {0} key-value pairs
+    TooManyPairs(usize),
+
+    /// PSBT has {0} inputs, exceeding the limit of {1} inputs
+    TooManyInputs(usize, usize),
+
+    /// PSBT has {0} outputs, exceeding the limit of {1} outputs
+    TooManyOutputs(usize, usize),
+
+    /// PSBT version {0} is not supported by the streaming decoder
+    UnsupportedVersion(u32),
+
+    /// PSBT global map of version 0 contains key {0} defined for version 2
+    /// only
+    V2Key(raw::Key),
+
+    /// PSBT global map has no unsigned transaction
+    NoUnsignedTx,
+
+    /// PSBT decoder was already used to read inputs or outputs, so the whole
+    /// PSBT can't be collected
+    PartiallyRead,
+
+    /// PSBT global map contains repeated key {0}
+    RepeatedKey(raw::Key),
+
+    /// PSBT global map contains invalid value for the key {0}
+    InvalidValue(raw::Key),
+}
+
+/// Limits applied by [`PsbtDecoder`] to the decoded data, protecting from
+/// memory exhaustion by malformed or malicious PSBTs.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct DecodeLimits {
+    /// Maximum length of a key (excluding key type byte), in bytes
+    pub max_key_len: usize,
+
+    /// Maximum length of a value, in bytes
+    pub max_value_len: usize,
+
+    /// Maximum number of key-value pairs in a single map
+    pub max_map_pairs: usize,
+
+    /// Maximum number of transaction inputs
+    pub max_inputs: usize,
+
+    /// Maximum number of transaction outputs
+    pub max_outputs: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_key_len: 4096,
+            max_value_len: consensus::encode::MAX_VEC_SIZE,
+            max_map_pairs: 1024,
+            max_inputs: 100_000,
+            max_outputs: 100_000,
+        }
+    }
+}
+
+/// Streaming PSBT decoder.
+///
+/// The decoder reads the global map on construction; inputs and outputs are
+/// read one by one with [`PsbtDecoder::next_input`] and
+/// [`PsbtDecoder::next_output`], such that only a single map is kept in
+/// memory at a time.
+pub struct PsbtDecoder<R: Read> {
+    reader: R,
+    limits: DecodeLimits,
+    tx: Transaction,
+    header: Psbt,
+    next_input: usize,
+    next_output: usize,
+}
+
+impl<R: Read> PsbtDecoder<R> {
+    /// Constructs decoder reading PSBT magic bytes and global map from the
+    /// `reader`.
+    pub fn new(mut reader: R, limits: DecodeLimits) -> Result<Self, StreamDecodeError> {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != PSBT_MAGIC {
+            return Err(StreamDecodeError::InvalidMagic);
+        }
+
+        let mut tx = None;
+        let mut v0 = PsbtV0 {
+            unsigned_tx: Transaction {
+                version: 2,
+                lock_time: bitcoin::PackedLockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            },
+            version: 0,
+            xpub: none!(),
+            proprietary: none!(),
+            unknown: none!(),
+            inputs: vec![],
+            outputs: vec![],
+        };
+        let mut keys = BTreeSet::new();
+        let mut v2_key = None;
+        for pair in read_map(&mut reader, limits)? {
+            let raw::Pair { key, value } = pair;
+            if !keys.insert(key.clone()) {
+                return Err(StreamDecodeError::RepeatedKey(key));
+            }
+            let invalid = || StreamDecodeError::InvalidValue(key.clone());
+            match key.type_value {
+                PSBT_GLOBAL_UNSIGNED_TX if key.key.is_empty() => {
+                    tx = Some(consensus::deserialize::<Transaction>(&value)?);
+                }
+                PSBT_GLOBAL_XPUB => {
+                    let xpub = ExtendedPubKey::decode(&key.key).map_err(|_| invalid())?;
+                    if value.len() < 4 || value.len() % 4 != 0 {
+                        return Err(invalid());
+                    }
+                    let fingerprint = Fingerprint::from(&value[..4]);
+                    let path = value[4..]
+                        .chunks(4)
+                        .map(|chunk| {
+                            ChildNumber::from(u32::from_le_bytes([
+                                chunk[0], chunk[1], chunk[2], chunk[3],
+                            ]))
+                        })
+                        .collect::<Vec<_>>();
+                    v0.xpub
+                        .insert(xpub, (fingerprint, DerivationPath::from(path)));
+                }
+                PSBT_GLOBAL_VERSION if key.key.is_empty() => {
+                    let version = <[u8; 4]>::try_from(value.as_slice()).map_err(|_| invalid())?;
+                    v0.version = u32::from_le_bytes(version);
+                }
+                PSBT_GLOBAL_PROPRIETARY => {
+                    let prop_key =
+                        raw::ProprietaryKey::try_from(key.clone()).map_err(|_| invalid())?;
+                    v0.proprietary.insert(prop_key, value);
+                }
+                type_value if PSBT_GLOBAL_V2_KEYS.contains(&type_value) => {
+                    v2_key.get_or_insert(key);
+                }
+                _ => {
+                    v0.unknown.insert(key, value);
+                }
+            }
+        }
+
+        if v0.version != 0 {
+            return Err(StreamDecodeError::UnsupportedVersion(v0.version));
+        }
+        if let Some(key) = v2_key {
+            return Err(StreamDecodeError::V2Key(key));
+        }
+        let tx = tx.ok_or(StreamDecodeError::NoUnsignedTx)?;
+        if tx.input.len() > limits.max_inputs {
+            return Err(StreamDecodeError::TooManyInputs(
+                tx.input.len(),
+                limits.max_inputs,
+            ));
+        }
+        if tx.output.len() > limits.max_outputs {
+            return Err(StreamDecodeError::TooManyOutputs(
+                tx.output.len(),
+                limits.max_outputs,
+            ));
+        }
+        // With no input and output maps the conversion produces PSBT with
+        // global data only
+        v0.unsigned_tx = tx.clone();
+        let header = Psbt::from(v0);
+
+        Ok(PsbtDecoder {
+            reader,
+            limits,
+            tx,
+            header,
+            next_input: 0,
+            next_output: 0,
+        })
+    }
+
+    /// Returns PSBT global data with no inputs and outputs.
+    #[inline]
+    pub fn header(&self) -> &Psbt { &self.header }
+
+    /// Returns unsigned transaction from the PSBT global map.
+    #[inline]
+    pub fn unsigned_tx(&self) -> &Transaction { &self.tx }
+
+    /// Returns number of the PSBT inputs.
+    #[inline]
+    pub fn input_count(&self) -> usize { self.tx.input.len() }
+
+    /// Returns number of the PSBT outputs.
+    #[inline]
+    pub fn output_count(&self) -> usize { self.tx.output.len() }
+
+    /// Reads next input map, returning `None` once all inputs are read.
+    pub fn next_input(&mut self) -> Result<Option<Input>, StreamDecodeError> {
+        let index = self.next_input;
+        let txin = match self.tx.input.get(index) {
+            Some(txin) => txin.clone(),
+            None => return Ok(None),
+        };
+        let v0 =
+            InputV0::consensus_decode(&mut encode_map(read_map(&mut self.reader, self.limits)?)?)?;
+        self.next_input += 1;
+        Ok(Some(Input::with(index, v0, txin)))
+    }
+
+    /// Reads next output map, returning `None` once all outputs are read.
+    /// Input maps which were not read yet are skipped.
+    pub fn next_output(&mut self) -> Result<Option<Output>, StreamDecodeError> {
+        while self.next_input < self.input_count() {
+            read_map(&mut self.reader, self.limits)?;
+            self.next_input += 1;
+        }
+        let index = self.next_output;
+        let txout = match self.tx.output.get(index) {
+            Some(txout) => txout.clone(),
+            None => return Ok(None),
+        };
+        let v0 =
+            OutputV0::consensus_decode(&mut encode_map(read_map(&mut self.reader, self.limits)?)?)?;
+        self.next_output += 1;
+        Ok(Some(Output::with(index, v0, txout)))
+    }
+
+    /// Reads all inputs and outputs, returning the complete PSBT.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StreamDecodeError::PartiallyRead`] if some of the inputs or
+    /// outputs were already read with [`PsbtDecoder::next_input`] or
+    /// [`PsbtDecoder::next_output`].
+    pub fn into_psbt(mut self) -> Result<Psbt, StreamDecodeError> {
+        if self.next_input != 0 || self.next_output != 0 {
+            return Err(StreamDecodeError::PartiallyRead);
+        }
+        let mut inputs = Vec::with_capacity(self.input_count());
+        while let Some(input) = self.next_input()? {
+            inputs.push(input);
+        }
+        let mut outputs = Vec::with_capacity(self.output_count());
+        while let Some(output) = self.next_output()? {
+            outputs.push(output);
+        }
+        let mut psbt = self.header;
+        psbt.inputs = inputs;
+        psbt.outputs = outputs;
+        Ok(psbt)
+    }
+}
+
+impl Psbt {
+    /// Decodes PSBT from the reader with the given limits, without buffering
+    /// the whole PSBT data; see [`PsbtDecoder`].
+    #[inline]
+    pub fn decode_stream(
+        reader: impl Read,
+        limits: DecodeLimits,
+    ) -> Result<Psbt, StreamDecodeError> {
+        PsbtDecoder::new(reader, limits)?.into_psbt()
+    }
+}
+
+/// Reads key-value pairs of a single map up to (and including) the map
+/// separator.
+fn read_map(
+    reader: &mut impl Read,
+    limits: DecodeLimits,
+) -> Result<Vec<raw::Pair>, StreamDecodeError> {
+    let mut pairs = vec![];
+    loop {
+        let VarInt(key_len) = VarInt::consensus_decode(reader)?;
+        if key_len == 0 {
+            return Ok(pairs);
+        }
+        if pairs.len() >= limits.max_map_pairs {
+            return Err(StreamDecodeError::TooManyPairs(limits.max_map_pairs));
+        }
+        if key_len - 1 > limits.max_key_len as u64 {
+            return Err(StreamDecodeError::KeyTooLong(
+                key_len - 1,
+                limits.max_key_len,
+            ));
+        }
+        let type_value = u8::consensus_decode(reader)?;
+        let mut key = vec![0u8; key_len as usize - 1];
+        reader.read_exact(&mut key)?;
+
+        let VarInt(value_len) = VarInt::consensus_decode(reader)?;
+        if value_len > limits.max_value_len as u64 {
+            return Err(StreamDecodeError::ValueTooLarge(
+                value_len,
+                limits.max_value_len,
+            ));
+        }
+        let mut value = vec![0u8; value_len as usize];
+        reader.read_exact(&mut value)?;
+
+        pairs.push(raw::Pair {
+            key: raw::Key { type_value, key },
+            value,
+        });
+    }
+}
+
+/// Encodes map pairs with the map separator, such that the map can be decoded
+/// with the consensus decoders for the PSBT maps.
+fn encode_map(pairs: Vec<raw::Pair>) -> Result<io::Cursor<Vec<u8>>, io::Error> {
+    let mut data = vec![];
+    for pair in pairs {
+        pair.consensus_encode(&mut data)?;
+    }
+    data.push(0x00);
+    Ok(io::Cursor::new(data))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // BIP-174 test vector with a non-witness UTXO and finalized input
+    const PSBT_HEX: &str = "\
+        70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566\
+        cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a91\
+        4d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a914\
+        3545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda50101000\
+        00000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f\
+        9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985fffff\
+        fff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b4\
+        0100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff020\
+        0c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac\
+        72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d0587024\
+        7304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a\
+        5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02\
+        db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e\
+        7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0\
+        c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20\
+        167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4\
+        ea169393380734464f84f2ab300000000000000";
+
+    #[test]
+    fn stream_decoding() {
+        let mut psbt = Psbt::from_str(PSBT_HEX).unwrap();
+        psbt.proprietary.insert(
+            raw::ProprietaryKey {
+                prefix: b"TEST".to_vec(),
+                subtype: 1,
+                key: vec![2],
+            },
+            vec![3],
+        );
+        let data = crate::serialize::Serialize::serialize(&psbt);
+
+        let decoded = Psbt::decode_stream(data.as_slice(), DecodeLimits::default()).unwrap();
+        assert_eq!(decoded, psbt);
+
+        let mut decoder = PsbtDecoder::new(data.as_slice(), DecodeLimits::default()).unwrap();
+        assert_eq!(decoder.input_count(), 1);
+        assert_eq!(decoder.header().proprietary, psbt.proprietary);
+        assert!(decoder.header().inputs.is_empty());
+        let output = decoder.next_output().unwrap().unwrap();
+        assert_eq!(output, psbt.outputs[0]);
+        assert!(decoder.next_input().unwrap().is_none());
+        assert!(matches!(
+            decoder.into_psbt(),
+            Err(StreamDecodeError::PartiallyRead)
+        ));
+
+        let limits = DecodeLimits {
+            max_outputs: 1,
+            ..default!()
+        };
+        assert!(matches!(
+            PsbtDecoder::new(data.as_slice(), limits),
+            Err(StreamDecodeError::TooManyOutputs(2, 1))
+        ));
+        let limits = DecodeLimits {
+            max_value_len: 64,
+            ..default!()
+        };
+        assert!(matches!(
+            Psbt::decode_stream(data.as_slice(), limits),
+            Err(StreamDecodeError::ValueTooLarge(_, 64))
+        ));
+        assert!(matches!(
+            Psbt::decode_stream(&data[1..], DecodeLimits::default()),
+            Err(StreamDecodeError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn version_2() {
+        let psbt = Psbt::from_str(PSBT_HEX).unwrap();
+        let data = crate::serialize::Serialize::serialize(&psbt);
+        // Global map pair to insert right after the magic bytes
+        let pair = |type_value: u8, value: &[u8]| {
+            let mut data = vec![0x01, type_value, value.len() as u8];
+            data.extend(value);
+            data
+        };
+        let with_pair = |pair: Vec<u8>| {
+            let mut modified = data[..5].to_vec();
+            modified.extend(pair);
+            modified.extend(&data[5..]);
+            modified
+        };
+
+        let v2 = with_pair(pair(PSBT_GLOBAL_VERSION, &2u32.to_le_bytes()));
+        assert!(matches!(
+            Psbt::decode_stream(v2.as_slice(), DecodeLimits::default()),
+            Err(StreamDecodeError::UnsupportedVersion(2))
+        ));
+
+        // PSBT_GLOBAL_TX_VERSION
+        let v2_key = with_pair(pair(0x02, &2u32.to_le_bytes()));
+        assert!(matches!(
+            Psbt::decode_stream(v2_key.as_slice(), DecodeLimits::default()),
+            Err(StreamDecodeError::V2Key(raw::Key {
+                type_value: 0x02,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Consistency checks for taproot-related PSBT input fields.
+
+use bitcoin::schnorr::{TapTweak, TweakedPublicKey};
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::{Script, XOnlyPublicKey};
+
+use crate::{Input, InputMatchError};
+
+/// Inconsistencies between taproot-related fields of a PSBT input.
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum TaprootInputError {
+    /// spent transaction does not match input prevout reference. {0}
+    #[from]
+    Match(InputMatchError),
+
+    /// input spends non-taproot output with scriptPubkey `{0}`
+    NonTaprootPrevout(Script),
+
+    /// input does not provide taproot internal key
+    NoInternalKey,
+
+    /// output key {expected} constructed from the input internal key and
+    /// merkle root does not match key {actual} from the spent scriptPubkey
+    OutputKeyMismatch {
+        /// Key constructed from PSBT input fields
+        expected: XOnlyPublicKey,
+
+        /// Key from the spent scriptPubkey
+        actual: XOnlyPublicKey,
+    },
+
+    /// control block for leaf script `{0}` uses internal key different from
+    /// the input internal key
+    ControlBlockKeyMismatch(Script),
+
+    /// control block for leaf script `{0}` does not commit to the output key
+    ControlBlockCommitment(Script),
+
+    /// input is expected to be a BIP-86 key-only spending, but it provides
+    /// taproot script tree data
+    ScriptTreePresent,
+}
+
+impl Input {
+    /// Reconstructs taproot output key from `tap_internal_key` and
+    /// `tap_merkle_root` fields, if the internal key is present.
+    pub fn taproot_output_key<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Option<TweakedPublicKey> {
+        self.tap_internal_key
+            .map(|internal_key| internal_key.tap_tweak(secp, self.tap_merkle_root).0)
+    }
+
+    /// Verifies that `tap_internal_key`, `tap_merkle_root` and `tap_scripts`
+    /// fields are consistent with each other and with the spent P2TR
+    /// scriptPubkey.
+    pub fn verify_taproot<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Result<(), TaprootInputError> {
+        let script_pubkey = &self.input_prevout()?.script_pubkey;
+        if !script_pubkey.is_v1_p2tr() {
+            return Err(TaprootInputError::NonTaprootPrevout(script_pubkey.clone()));
+        }
+        let actual = XOnlyPublicKey::from_slice(&script_pubkey[2..])
+            .map_err(|_| TaprootInputError::NonTaprootPrevout(script_pubkey.clone()))?;
+        let internal_key = self
+            .tap_internal_key
+            .ok_or(TaprootInputError::NoInternalKey)?;
+        let expected = self
+            .taproot_output_key(secp)
+            .expect("internal key presence checked above")
+            .to_inner();
+        if expected != actual {
+            return Err(TaprootInputError::OutputKeyMismatch { expected, actual });
+        }
+        for (control_block, (script, _)) in &self.tap_scripts {
+            if control_block.internal_key != internal_key {
+                return Err(TaprootInputError::ControlBlockKeyMismatch(script.clone()));
+            }
+            if !control_block.verify_taproot_commitment(secp, actual, script) {
+                return Err(TaprootInputError::ControlBlockCommitment(script.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies that the input spends BIP-86 output, i.e. the spent output key
+    /// is the BIP-341 tweak of `tap_internal_key` with empty merkle root and
+    /// the input has no script tree data. Errors indicate PSBT taproot fields
+    /// populated incorrectly by the PSBT creator.
+    pub fn verify_bip86<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Result<(), TaprootInputError> {
+        if self.tap_merkle_root.is_some() || !self.tap_scripts.is_empty() {
+            return Err(TaprootInputError::ScriptTreePresent);
+        }
+        self.verify_taproot(secp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{KeyPair, SecretKey};
+    use bitcoin::util::taproot::{TapBranchHash, TaprootBuilder};
+    use bitcoin::TxOut;
+
+    use super::*;
+
+    #[test]
+    fn verify_taproot() {
+        let secp = Secp256k1::new();
+        let seckey = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let internal_key = KeyPair::from_secret_key(&secp, &seckey).x_only_public_key().0;
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, Script::from(vec![0x51]))
+            .unwrap()
+            .finalize(&secp, internal_key)
+            .unwrap();
+        let mut input = Input {
+            witness_utxo: Some(TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_v1_p2tr_tweaked(spend_info.output_key()),
+            }),
+            ..default!()
+        };
+
+        assert_eq!(input.verify_taproot(&secp), Err(TaprootInputError::NoInternalKey));
+        input.tap_internal_key = Some(internal_key);
+        input.tap_merkle_root = spend_info.merkle_root();
+        assert_eq!(input.taproot_output_key(&secp), Some(spend_info.output_key()));
+        input.tap_scripts = spend_info
+            .as_script_map()
+            .keys()
+            .map(|key| (spend_info.control_block(key).unwrap(), key.clone()))
+            .collect();
+        assert_eq!(input.verify_taproot(&secp), Ok(()));
+        assert_eq!(input.verify_bip86(&secp), Err(TaprootInputError::ScriptTreePresent));
+
+        input.tap_merkle_root = Some(TapBranchHash::hash(b"other tree"));
+        assert!(matches!(
+            input.verify_taproot(&secp),
+            Err(TaprootInputError::OutputKeyMismatch { .. })
+        ));
+
+        input.tap_merkle_root = None;
+        input.tap_scripts.clear();
+        assert!(matches!(
+            input.verify_bip86(&secp),
+            Err(TaprootInputError::OutputKeyMismatch { .. })
+        ));
+        input.witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Script::new_v1_p2tr(&secp, internal_key, None),
+        });
+        assert_eq!(input.verify_bip86(&secp), Ok(()));
+    }
+}
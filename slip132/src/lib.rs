@@ -33,6 +33,10 @@ use bitcoin::util::base58;
 use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
 use bitcoin::Network;
 
+mod params;
+
+pub use params::{ChainParams, ChainParamsError, ChainRegistry};
+
 /// Magical version bytes for xpub: bitcoin mainnet public key for P2PKH or P2SH
 pub const VERSION_MAGIC_XPUB: [u8; 4] = [0x04, 0x88, 0xB2, 0x1E];
 /// Magical version bytes for xprv: bitcoin mainnet private key for P2PKH or
@@ -278,7 +282,12 @@ impl KeyVersion {
 }
 
 /// Default resolver knowing native [`bitcoin::network::constants::Network`]
-/// and BIP 32 and SLIP 132-defined key applications with [`KeyApplication`]
+/// and BIP 32 and SLIP 132-defined key applications with [`KeyApplication`].
+///
+/// SLIP 132 defines versions only for mainnet and testnet: all networks from
+/// testnet family (testnet3, testnet4, signets and regtest) resolve into the
+/// testnet versions, and the testnet versions are always parsed as
+/// [`Network::Testnet`].
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct DefaultResolver;
 
@@ -1109,6 +1118,20 @@ mod test {
         assert!(DefaultResolver::is_prv(&KeyVersion([0, 0, 0, 0])).is_none());
     }
 
+    #[test]
+    fn default_resolver_testnet_family() {
+        for network in [Network::Testnet, Network::Signet, Network::Regtest] {
+            assert_eq!(
+                DefaultResolver::resolve(network, KeyApplication::SegWit, false),
+                KeyVersion(VERSION_MAGIC_VPUB)
+            );
+            assert_eq!(
+                DefaultResolver::resolve(network, KeyApplication::SegWitMultisig, true),
+                KeyVersion(VERSION_MAGIC_VPRV_MULTISIG)
+            );
+        }
+    }
+
     #[test]
     fn default_resolver_network() {
         assert_eq!(
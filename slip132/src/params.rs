@@ -0,0 +1,407 @@
+// Wallet-level libraries for bitcoin protocol by LNP/BP Association
+//
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// This software is distributed without any warranty.
+//
+// You should have received a copy of the Apache-2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Chain parameters defining address prefixes, extended key versions and
+//! derivation coin type for bitcoin networks and their forks.
+
+use std::collections::BTreeMap;
+
+use bitcoin::bech32::{self, FromBase32, ToBase32};
+use bitcoin::hashes::Hash;
+use bitcoin::util::address::{self, Payload, WitnessVersion};
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::{Network, PubkeyHash, Script, ScriptHash};
+
+use crate::{
+    Error, KeyVersion, VERSION_MAGIC_TPRV, VERSION_MAGIC_TPUB, VERSION_MAGIC_XPRV,
+    VERSION_MAGIC_XPUB,
+};
+
+/// Errors using chain parameters for address and extended key encodings.
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum ChainParamsError {
+    /// invalid address. {0}
+    #[from]
+    Address(address::Error),
+
+    /// invalid extended key. {0}
+    #[from]
+    Key(Error),
+
+    /// address uses bech32 prefix `{0}` which does not belong to the chain
+    ForeignHrp(String),
+
+    /// address uses base58 version byte {0:#04x} which does not belong to the
+    /// chain
+    ForeignPrefix(u8),
+
+    /// extended key uses version bytes {0:#06X?} which do not belong to the
+    /// chain
+    ForeignKeyVersion([u8; 4]),
+
+    /// chain with name `{0}` is already registered
+    DuplicateChain(String),
+}
+
+/// Parameters of a bitcoin chain used for encoding addresses and extended
+/// keys and for constructing derivation paths. Allows working with forks,
+/// regtest variants and custom signets without patching constants.
+///
+/// Consensus-level rules are defined by the [`ChainParams::network`], which
+/// also defines whether the chain belongs to the mainnet or testnet family
+/// (testnet family keys use `tpub`-like versions in PSBTs).
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ChainParams {
+    /// Lowercase name of the chain
+    pub name: String,
+
+    /// Bitcoin network whose consensus rules are followed by the chain
+    pub network: Network,
+
+    /// Base58 version byte for P2PKH addresses
+    pub p2pkh_prefix: u8,
+
+    /// Base58 version byte for P2SH addresses
+    pub p2sh_prefix: u8,
+
+    /// Human-readable part of bech32 segwit addresses
+    pub bech32_hrp: String,
+
+    /// Version bytes for BIP-32 extended public keys
+    pub xpub_version: KeyVersion,
+
+    /// Version bytes for BIP-32 extended private keys
+    pub xprv_version: KeyVersion,
+
+    /// Coin type used in BIP-43 derivation paths (non-hardened value)
+    pub coin_type: u32,
+}
+
+impl ChainParams {
+    fn with_network(name: &str, network: Network, bech32_hrp: &str) -> ChainParams {
+        let (p2pkh_prefix, p2sh_prefix, xpub, xprv, coin_type) = match network {
+            Network::Bitcoin => (0x00, 0x05, VERSION_MAGIC_XPUB, VERSION_MAGIC_XPRV, 0),
+            _ => (0x6f, 0xc4, VERSION_MAGIC_TPUB, VERSION_MAGIC_TPRV, 1),
+        };
+        ChainParams {
+            name: name.to_owned(),
+            network,
+            p2pkh_prefix,
+            p2sh_prefix,
+            bech32_hrp: bech32_hrp.to_owned(),
+            xpub_version: KeyVersion::from_bytes(xpub),
+            xprv_version: KeyVersion::from_bytes(xprv),
+            coin_type,
+        }
+    }
+
+    /// Parameters of bitcoin mainnet.
+    pub fn bitcoin() -> ChainParams { ChainParams::with_network("bitcoin", Network::Bitcoin, "bc") }
+
+    /// Parameters of bitcoin testnet3.
+    pub fn testnet() -> ChainParams { ChainParams::with_network("testnet", Network::Testnet, "tb") }
+
+    /// Parameters of bitcoin testnet4 (BIP-94).
+    pub fn testnet4() -> ChainParams {
+        ChainParams::with_network("testnet4", Network::Testnet, "tb")
+    }
+
+    /// Parameters of the default bitcoin signet.
+    pub fn signet() -> ChainParams { ChainParams::with_network("signet", Network::Signet, "tb") }
+
+    /// Parameters of bitcoin regtest.
+    pub fn regtest() -> ChainParams {
+        ChainParams::with_network("regtest", Network::Regtest, "bcrt")
+    }
+
+    /// Returns parameters of the standard chain for a given bitcoin network.
+    pub fn with(network: Network) -> ChainParams {
+        match network {
+            Network::Bitcoin => ChainParams::bitcoin(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Signet => ChainParams::signet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    /// Detects whether the chain belongs to testnet family, i.e. its keys
+    /// are serialized in PSBTs with testnet versions.
+    #[inline]
+    pub fn is_testnet(&self) -> bool { self.network != Network::Bitcoin }
+
+    /// Encodes address for the given `scriptPubkey`, if the script has an
+    /// address form.
+    pub fn encode_address(&self, script_pubkey: &Script) -> Option<String> {
+        Some(match Payload::from_script(script_pubkey).ok()? {
+            Payload::PubkeyHash(hash) => self.encode_base58(self.p2pkh_prefix, &hash[..]),
+            Payload::ScriptHash(hash) => self.encode_base58(self.p2sh_prefix, &hash[..]),
+            Payload::WitnessProgram { version, program } => {
+                let mut data = vec![bech32::u5::from(version)];
+                data.extend(program.to_base32());
+                bech32::encode(&self.bech32_hrp, data, version.bech32_variant()).ok()?
+            }
+        })
+    }
+
+    fn encode_base58(&self, prefix: u8, hash: &[u8]) -> String {
+        let mut data = Vec::with_capacity(21);
+        data.push(prefix);
+        data.extend(hash);
+        base58::check_encode_slice(&data)
+    }
+
+    /// Decodes address belonging to the chain into the `scriptPubkey`.
+    pub fn decode_address(&self, s: &str) -> Result<Script, ChainParamsError> {
+        let prefix = format!("{}1", self.bech32_hrp);
+        let is_bech32 = s
+            .get(..prefix.len())
+            .map(|start| start.eq_ignore_ascii_case(&prefix))
+            .unwrap_or_default();
+        if is_bech32 {
+            let (hrp, payload, variant) = bech32::decode(s).map_err(address::Error::from)?;
+            if hrp != self.bech32_hrp {
+                return Err(ChainParamsError::ForeignHrp(hrp));
+            }
+            let (version, program) = payload
+                .split_first()
+                .ok_or(address::Error::EmptyBech32Payload)?;
+            let version = WitnessVersion::try_from(*version)?;
+            let program = Vec::<u8>::from_base32(program).map_err(address::Error::from)?;
+            if program.len() < 2 || program.len() > 40 {
+                return Err(address::Error::InvalidWitnessProgramLength(program.len()).into());
+            }
+            if version == WitnessVersion::V0 && program.len() != 20 && program.len() != 32 {
+                return Err(address::Error::InvalidSegwitV0ProgramLength(program.len()).into());
+            }
+            let expected = version.bech32_variant();
+            if expected != variant {
+                return Err(address::Error::InvalidBech32Variant {
+                    expected,
+                    found: variant,
+                }
+                .into());
+            }
+            return Ok(Payload::WitnessProgram { version, program }.script_pubkey());
+        }
+
+        let data = base58::from_check(s).map_err(address::Error::from)?;
+        if data.len() != 21 {
+            return Err(address::Error::Base58(base58::Error::InvalidLength(data.len())).into());
+        }
+        let payload = match data[0] {
+            prefix if prefix == self.p2pkh_prefix => Payload::PubkeyHash(
+                PubkeyHash::from_slice(&data[1..]).expect("fixed length hash"),
+            ),
+            prefix if prefix == self.p2sh_prefix => Payload::ScriptHash(
+                ScriptHash::from_slice(&data[1..]).expect("fixed length hash"),
+            ),
+            prefix => return Err(ChainParamsError::ForeignPrefix(prefix)),
+        };
+        Ok(payload.script_pubkey())
+    }
+
+    fn standard_versions(&self) -> ([u8; 4], [u8; 4]) {
+        if self.is_testnet() {
+            (VERSION_MAGIC_TPUB, VERSION_MAGIC_TPRV)
+        } else {
+            (VERSION_MAGIC_XPUB, VERSION_MAGIC_XPRV)
+        }
+    }
+
+    /// Encodes extended public key using the chain version bytes.
+    pub fn encode_xpub(&self, xpub: &ExtendedPubKey) -> String {
+        let mut data = xpub.encode();
+        data[0..4].copy_from_slice(self.xpub_version.as_slice());
+        base58::check_encode_slice(&data)
+    }
+
+    /// Encodes extended private key using the chain version bytes.
+    pub fn encode_xprv(&self, xprv: &ExtendedPrivKey) -> String {
+        let mut data = xprv.encode();
+        data[0..4].copy_from_slice(self.xprv_version.as_slice());
+        base58::check_encode_slice(&data)
+    }
+
+    /// Decodes extended public key encoded with the chain version bytes.
+    pub fn decode_xpub(&self, s: &str) -> Result<ExtendedPubKey, ChainParamsError> {
+        let data = self.decode_xkey(s, self.xpub_version, self.standard_versions().0)?;
+        Ok(ExtendedPubKey::decode(&data).map_err(Error::from)?)
+    }
+
+    /// Decodes extended private key encoded with the chain version bytes.
+    pub fn decode_xprv(&self, s: &str) -> Result<ExtendedPrivKey, ChainParamsError> {
+        let data = self.decode_xkey(s, self.xprv_version, self.standard_versions().1)?;
+        Ok(ExtendedPrivKey::decode(&data).map_err(Error::from)?)
+    }
+
+    fn decode_xkey(
+        &self,
+        s: &str,
+        version: KeyVersion,
+        standard: [u8; 4],
+    ) -> Result<Vec<u8>, ChainParamsError> {
+        let mut data = base58::from_check(s).map_err(Error::from)?;
+        if data.len() != 78 {
+            return Err(Error::WrongExtendedKeyLength(data.len()).into());
+        }
+        if &data[0..4] != version.as_slice() {
+            let mut found = [0u8; 4];
+            found.copy_from_slice(&data[0..4]);
+            return Err(ChainParamsError::ForeignKeyVersion(found));
+        }
+        data[0..4].copy_from_slice(&standard);
+        Ok(data)
+    }
+}
+
+/// Registry of chain parameters indexed by the chain names. Initially
+/// contains standard bitcoin networks; user-defined chains are added with
+/// [`ChainRegistry::register`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChainRegistry(BTreeMap<String, ChainParams>);
+
+impl Default for ChainRegistry {
+    fn default() -> Self {
+        ChainRegistry(
+            [
+                ChainParams::bitcoin(),
+                ChainParams::testnet(),
+                ChainParams::testnet4(),
+                ChainParams::signet(),
+                ChainParams::regtest(),
+            ]
+            .into_iter()
+            .map(|params| (params.name.clone(), params))
+            .collect(),
+        )
+    }
+}
+
+impl ChainRegistry {
+    /// Constructs registry containing standard bitcoin networks.
+    #[inline]
+    pub fn new() -> ChainRegistry { ChainRegistry::default() }
+
+    /// Adds parameters of a user-defined chain. Fails if a chain with the
+    /// same (case-insensitive) name is already registered.
+    pub fn register(&mut self, mut params: ChainParams) -> Result<(), ChainParamsError> {
+        params.name = params.name.to_lowercase();
+        if self.0.contains_key(&params.name) {
+            return Err(ChainParamsError::DuplicateChain(params.name));
+        }
+        self.0.insert(params.name.clone(), params);
+        Ok(())
+    }
+
+    /// Returns parameters of the chain with a given (case-insensitive) name.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&ChainParams> { self.0.get(&name.to_lowercase()) }
+
+    /// Iterates over all registered chains ordered by their names.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &ChainParams> { self.0.values() }
+
+    /// Detects the chain to which the address belongs, returning the chain
+    /// parameters and the address `scriptPubkey`. If several chains share
+    /// the same address encoding (like testnet and signet) the first of them
+    /// in the name order is returned.
+    pub fn detect_address(&self, s: &str) -> Option<(&ChainParams, Script)> {
+        self.iter()
+            .find_map(|params| params.decode_address(s).ok().map(|script| (params, script)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::Address;
+
+    use super::*;
+
+    #[test]
+    fn standard_addresses() {
+        for addr in [
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+        ] {
+            let address = Address::from_str(addr).unwrap();
+            let params = ChainParams::bitcoin();
+            assert_eq!(params.encode_address(&address.script_pubkey()).unwrap(), addr);
+            assert_eq!(params.decode_address(addr).unwrap(), address.script_pubkey());
+        }
+
+        let address = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let script = address.script_pubkey();
+        assert_eq!(
+            ChainParams::regtest().encode_address(&script).unwrap(),
+            Address::from_script(&script, Network::Regtest).unwrap().to_string()
+        );
+        assert!(matches!(
+            ChainParams::bitcoin().decode_address("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"),
+            Err(ChainParamsError::Address(_))
+        ));
+    }
+
+    #[test]
+    fn custom_chain() {
+        let mut params = ChainParams::bitcoin();
+        params.name = s!("Litecoin");
+        params.p2pkh_prefix = 0x30;
+        params.p2sh_prefix = 0x32;
+        params.bech32_hrp = s!("ltc");
+        params.xpub_version = KeyVersion::from_bytes([0x01, 0x9d, 0xa4, 0x62]);
+        params.xprv_version = KeyVersion::from_bytes([0x01, 0x9d, 0x9c, 0xfe]);
+        params.coin_type = 2;
+
+        let mut registry = ChainRegistry::new();
+        registry.register(params.clone()).unwrap();
+        assert_eq!(
+            registry.register(params.clone()),
+            Err(ChainParamsError::DuplicateChain(s!("litecoin")))
+        );
+        let params = registry.get("LITECOIN").unwrap();
+
+        let script = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq")
+            .unwrap()
+            .script_pubkey();
+        let address = params.encode_address(&script).unwrap();
+        assert!(address.starts_with("ltc1q"));
+        assert_eq!(registry.detect_address(&address), Some((params, script)));
+
+        let script = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+            .unwrap()
+            .script_pubkey();
+        let address = params.encode_address(&script).unwrap();
+        assert!(address.starts_with('L'));
+        assert_eq!(params.decode_address(&address), Ok(script));
+        assert_eq!(
+            ChainParams::bitcoin().decode_address(&address),
+            Err(ChainParamsError::ForeignPrefix(0x30))
+        );
+
+        let xpub = ExtendedPubKey::from_str(
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        )
+        .unwrap();
+        let encoded = params.encode_xpub(&xpub);
+        assert!(encoded.starts_with("Ltub"));
+        assert_eq!(params.decode_xpub(&encoded), Ok(xpub));
+        assert_eq!(
+            ChainParams::bitcoin().decode_xpub(&encoded),
+            Err(ChainParamsError::ForeignKeyVersion([0x01, 0x9d, 0xa4, 0x62]))
+        );
+    }
+}
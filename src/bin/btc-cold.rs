@@ -30,7 +30,7 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{fmt, fs, io};
 
-use amplify::hex::ToHex;
+use amplify::hex::{FromHex, ToHex};
 use amplify::{IoError, Wrapper};
 use bitcoin::consensus::Encodable;
 use bitcoin::psbt::serialize::Serialize;
@@ -38,7 +38,7 @@ use bitcoin::psbt::PartiallySignedTransaction;
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::address;
 use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
-use bitcoin::{consensus, Address, Network};
+use bitcoin::{consensus, Address, EcdsaSighashType, Network};
 use bitcoin_blockchain::locks::LockTime;
 use bitcoin_hd::DeriveError;
 use bitcoin_onchain::UtxoResolverError;
@@ -52,14 +52,17 @@ use electrum_client::ElectrumApi;
 use miniscript::psbt::PsbtExt;
 use miniscript::{MiniscriptKey, TranslatePk};
 use miniscript_crate::Translator;
+use psbt::construct::{InputSelection, PsbtRecipe};
 use psbt::serialize::Deserialize;
-use psbt::{construct, ProprietaryKeyDescriptor, ProprietaryKeyError, ProprietaryKeyLocation};
+use psbt::{construct, LockTimeError, MaxFeePolicy, ProprietaryKeyDescriptor, ProprietaryKeyError};
 use slip132::{
     DefaultResolver, FromSlip132, KeyApplication, KeyVersion, ToSlip132, VersionResolver,
 };
-use wallet::descriptors::InputDescriptor;
+use wallet::descriptors::{
+    CompositeDescrType, CoreImport, CoreImportError, CoreTimestamp, InputDescriptor, TemplatedInput,
+};
 use wallet::hd::{DerivationAccount, SegmentIndexes, UnhardenedIndex};
-use wallet::onchain::ResolveDescriptor;
+use wallet::onchain::{Chain, PublicNetwork, ResolveDescriptor, ResolveScriptStats};
 use wallet::psbt::{Psbt, PsbtParseError};
 
 /// Command-line arguments
@@ -91,6 +94,15 @@ pub struct Args {
     /// Use Bitcoin Core descriptor representation.
     #[clap(long = "bitcoin-core-fmt", global = true)]
     pub bitcoin_core_fmt: bool,
+
+    /// Bitcoin network: `bitcoin`, `testnet`, `testnet4`, `signet`, `regtest`
+    /// or custom signet specified as `signet:<challenge script hex>`.
+    ///
+    /// Extended keys are unable to distinguish networks from testnet family,
+    /// so the option must be provided for all such networks except testnet3,
+    /// which is used by default for testnet keys.
+    #[clap(short = 'N', long, global = true)]
+    pub network: Option<Chain>,
     /*
     /// Bitcoin Core backend to use. If used, overrides `electrum_server`,
     /// which becomes unused.
@@ -143,7 +155,7 @@ pub enum Command {
 
         /// Show addresses using regtest prefix. Works only for testnet-based
         /// wallet descriptors.
-        #[clap(long = "regtest")]
+        #[clap(long = "regtest", conflicts_with = "network")]
         regtest: bool,
     },
 
@@ -173,10 +185,57 @@ pub enum Command {
 
         /// Display address using regtest prefix. Works only for testnet-based
         /// descriptors.
-        #[clap(long = "regtest")]
+        #[clap(long = "regtest", conflicts_with = "network")]
         regtest: bool,
     },
 
+    /// List addresses corresponding to the given descriptor wallet together
+    /// with their on-chain state read from a provided Electrum server
+    Balance {
+        /// Path to the read-only wallet file generated with `create` command
+        wallet_file: PathBuf,
+
+        /// Number of addresses to list
+        #[clap(short = 'n', long, default_value = "20")]
+        count: u16,
+
+        /// Number of addresses to skip
+        #[clap(short, long, default_value = "0")]
+        skip: u16,
+
+        /// Whether or not to show change addresses
+        #[clap(short = 'c', long = "change")]
+        show_change: bool,
+
+        /// Display address using regtest prefix. Works only for testnet-based
+        /// descriptors.
+        #[clap(long = "regtest", conflicts_with = "network")]
+        regtest: bool,
+    },
+
+    /// Export wallet descriptor as an argument for Bitcoin Core
+    /// `importdescriptors` RPC command, which creates watch-only Bitcoin Core
+    /// wallet.
+    CoreExport {
+        /// Path to the read-only wallet file generated with `create` command
+        wallet_file: PathBuf,
+
+        /// Height of the wallet birthday block. Bitcoin Core rescans the
+        /// blockchain starting from the time of this block, which is read
+        /// from the provided Electrum server. If the birthday is not given,
+        /// the whole blockchain is rescanned.
+        #[clap(short, long)]
+        birthday: Option<u32>,
+
+        /// Do not rescan the blockchain, since the wallet was never used.
+        #[clap(long, conflicts_with = "birthday")]
+        new: bool,
+
+        /// Last address index imported into Bitcoin Core wallet.
+        #[clap(short, long, default_value = "999")]
+        range: u32,
+    },
+
     /// Construct new PSBT.
     ///
     /// Checks that given UTXOs belong to the specified wallet descriptor.
@@ -207,7 +266,8 @@ automatic manner.
 
 Input descriptor format:
 
-`txid:vout deriv-terminal [fingerprint:tweak] [rbf|height|time] [sighashtype]`
+`txid:vout deriv-terminal [fingerprint:tweak] [rbf|height|time] [sighashtype]
+[satisfy(...)]`
 
 In the simplest forms, input descriptors are just UTXO outpuint and derivation
 terminal info used to create public key corresponding to the output descriptor.
@@ -217,7 +277,10 @@ this tweak can be provided as a hex value following fingerprint of the tweaked
 key account and `:` sign. The sequence number defaults to `0xFFFFFFFF`; custom
 sequence numbers may be specified via sequence number modifiers (see below).
 If the input should use `SIGHASH_TYPE` other than `SIGHASH_ALL` they may be
-specified at the end of input descriptor.
+specified at the end of input descriptor. Optional satisfaction template,
+listing signing keys (`key:fingerprint`), hashes of the revealed preimages
+(`hash:sha256`) and taproot leaf (`leaf:hash`), selects the branch used to
+finalize the input.
 
 Sequence number representations:
 - `rbf(SEQ)`: use replace-by-fee opt-in for this input;
@@ -236,7 +299,7 @@ SIGHASH_TYPE representations:
 - `SINGLE|ANYONECANPAY`
 "
         )]
-        inputs: Vec<InputDescriptor>,
+        inputs: Vec<TemplatedInput>,
 
         /// Addresses and amounts, separated by colon. Amounts are always in
         /// satoshis.
@@ -265,6 +328,39 @@ SIGHASH_TYPE representations:
         fee: u64,
     },
 
+    /// Construct new PSBT following recipe from a YAML or JSON file.
+    ///
+    /// Recipe specifies inputs (list of input descriptors or `auto` for
+    /// automatic selection from the wallet coins), recipients, fee, change
+    /// policy, lock time, RBF opt-in and proprietary keys.
+    Recipe {
+        /// Number of addresses of each of the wallet receive and change
+        /// branches scanned for the coins when inputs are selected
+        /// automatically
+        #[clap(short = 'n', long, default_value = "100")]
+        lookahead: u16,
+
+        /// Path to the read-only wallet file generated with `create` command
+        wallet_file: PathBuf,
+
+        /// Path to the recipe file
+        recipe_file: PathBuf,
+
+        /// Destination file to save constructed PSBT
+        psbt_file: PathBuf,
+    },
+
+    /// Combine PSBT files signed by different signers into a single PSBT
+    Combine {
+        /// Destination file to save the combined PSBT
+        #[clap(short = 'o', long = "output")]
+        output_file: PathBuf,
+
+        /// Files containing PSBTs for the same transaction
+        #[clap(required = true, num_args = 2..)]
+        psbt_files: Vec<PathBuf>,
+    },
+
     /// Try to finalize PSBT
     Finalize {
         /// Destination file to save binary transaction. If no file is given
@@ -273,12 +369,17 @@ SIGHASH_TYPE representations:
         tx_file: Option<PathBuf>,
 
         /// Publish the transaction to the network; optional argument allows
-        /// to specify some custom network (testnet, for instance).
+        /// to specify some custom network (testnet, for instance). If the
+        /// argument is not given, the network provided with `--network` option
+        /// or bitcoin mainnet is used.
         #[clap(long)]
-        publish: Option<Option<Network>>,
+        publish: Option<Option<Chain>>,
 
-        /// File containing fully-signed PSBT
-        psbt_file: PathBuf,
+        /// Files containing signed PSBTs. If multiple files are given, they
+        /// are combined before the finalization, such that each of them may
+        /// contain signatures from a different signer.
+        #[clap(required = true)]
+        psbt_files: Vec<PathBuf>,
     },
 
     /// Get info about extended public key data
@@ -296,10 +397,68 @@ SIGHASH_TYPE representations:
 
     /// Converts binary PSBT file into a Base58 representation printed to STDIN.
     Convert { file: PathBuf },
+
+    /// Remove proprietary keys from PSBT, for instance before passing it to
+    /// a hardware signer or a third party which does not support them.
+    Strip {
+        /// Prefixes of the proprietary keys to remove. If no prefix is given,
+        /// all proprietary keys are removed.
+        #[clap(long = "prefix")]
+        prefixes: Vec<String>,
+
+        /// Prefixes of the proprietary keys to keep; all other proprietary
+        /// keys are removed.
+        #[clap(short, long = "keep", conflicts_with = "prefixes")]
+        keep: Vec<String>,
+
+        /// Destination file to save the resulting PSBT. If no file is given
+        /// the source PSBT file is overwritten.
+        #[clap(short = 'o', long = "output")]
+        output_file: Option<PathBuf>,
+
+        /// File containing PSBT
+        psbt_file: PathBuf,
+    },
+
+    /// Add hash preimage to PSBT inputs having matching hash locks in their
+    /// witness or tapscript miniscripts, allowing finalization of HTLC-style
+    /// spending conditions.
+    Preimage {
+        /// Destination file to save the resulting PSBT. If no file is given
+        /// the source PSBT file is overwritten.
+        #[clap(short = 'o', long = "output")]
+        output_file: Option<PathBuf>,
+
+        /// File containing PSBT
+        psbt_file: PathBuf,
+
+        /// Hex-encoded 32-byte preimage
+        preimage: String,
+    },
 }
 
 impl Args {
-    fn electrum_client(&self, network: Network) -> Result<electrum::Client, electrum::Error> {
+    /// Detects network for the descriptor, using `--network` option if
+    /// provided, checking that it is consistent with the descriptor keys.
+    fn chain(
+        &self,
+        descriptor: &miniscript::Descriptor<DerivationAccount>,
+        regtest: bool,
+    ) -> Result<Chain, Error> {
+        match (&self.network, regtest) {
+            (Some(chain), false) => {
+                let key_network = descriptor.network(false)?;
+                let chain_network = chain.bitcoin_network();
+                if (key_network == Network::Bitcoin) != (chain_network == Network::Bitcoin) {
+                    return Err(DeriveError::InconsistentKeyNetwork.into());
+                }
+                Ok(chain.clone())
+            }
+            _ => Ok(Chain::from(descriptor.network(regtest)?)),
+        }
+    }
+
+    fn electrum_client(&self, network: &Chain) -> Result<electrum::Client, electrum::Error> {
         let electrum_url = format!(
             "{}:{}",
             self.electrum_server,
@@ -336,6 +495,19 @@ impl Args {
                 show_change,
                 regtest,
             } => self.address(wallet_file, *count, *skip, *show_change, *regtest),
+            Command::Balance {
+                wallet_file,
+                count,
+                skip,
+                show_change,
+                regtest,
+            } => self.balance(wallet_file, *count, *skip, *show_change, *regtest),
+            Command::CoreExport {
+                wallet_file,
+                birthday,
+                new,
+                range,
+            } => self.core_export(wallet_file, *birthday, *new, *range),
             Command::Construct {
                 locktime,
                 wallet_file,
@@ -355,20 +527,42 @@ impl Args {
                 *fee,
                 psbt_file,
             ),
-            Command::Finalize {
+            Command::Recipe {
+                lookahead,
+                wallet_file,
+                recipe_file,
                 psbt_file,
+            } => self.construct_recipe(wallet_file, recipe_file, *lookahead, psbt_file),
+            Command::Combine {
+                output_file,
+                psbt_files,
+            } => self.combine(psbt_files, output_file),
+            Command::Finalize {
+                psbt_files,
                 tx_file,
                 publish,
             } => self.finalize(
-                psbt_file,
+                psbt_files,
                 tx_file.as_ref(),
-                publish
-                    .as_ref()
-                    .copied()
-                    .map(|n| n.unwrap_or(Network::Bitcoin)),
+                publish.clone().map(|chain| {
+                    chain
+                        .or_else(|| self.network.clone())
+                        .unwrap_or(Chain::Public(PublicNetwork::Mainnet))
+                }),
             ),
             Command::Info { data } => self.info(data.as_str()),
             Command::Convert { file } => self.convert(file),
+            Command::Strip {
+                prefixes,
+                keep,
+                output_file,
+                psbt_file,
+            } => self.strip(psbt_file, output_file.as_deref(), prefixes, keep),
+            Command::Preimage {
+                output_file,
+                psbt_file,
+                preimage,
+            } => self.preimage(psbt_file, output_file.as_deref(), preimage),
         }
     }
 
@@ -440,24 +634,25 @@ impl Args {
         let descriptor_str = fs::read_to_string(path)?;
         let descriptor: miniscript::Descriptor<DerivationAccount> =
             miniscript::Descriptor::from_str(&descriptor_str)?;
+        let network = self.chain(&descriptor, regtest)?.bitcoin_network();
 
         println!(
             "{}\n{}\n",
             "\nWallet descriptor:".bright_white(),
-            descriptor.to_string_std(self.bitcoin_core_fmt)
+            descriptor.to_string_std(self.bitcoin_core_fmt, network)
         );
 
         if descriptor.derive_pattern_len()? != 2 {
             return Err(Error::DescriptorDerivePattern);
         }
         for index in skip..(skip + count) {
-            let address = descriptor.address(
+            let address = descriptor.address_on(
                 &secp,
                 [
                     UnhardenedIndex::from(u8::from(show_change)),
                     UnhardenedIndex::from(index),
                 ],
-                regtest,
+                network,
             )?;
 
             println!("{:>6} {}", format!("#{}", index).dimmed(), address);
@@ -468,6 +663,106 @@ impl Args {
         Ok(())
     }
 
+    fn balance(
+        &self,
+        path: &Path,
+        count: u16,
+        skip: u16,
+        show_change: bool,
+        regtest: bool,
+    ) -> Result<(), Error> {
+        let secp = Secp256k1::new();
+
+        let descriptor_str = fs::read_to_string(path)?;
+        let descriptor: miniscript::Descriptor<DerivationAccount> =
+            miniscript::Descriptor::from_str(&descriptor_str)?;
+
+        let network = self.chain(&descriptor, regtest)?;
+        let client = self.electrum_client(&network)?;
+
+        println!(
+            "{}\n{}\n",
+            "\nWallet descriptor:".bright_white(),
+            descriptor.to_string_std(self.bitcoin_core_fmt, network.bitcoin_network())
+        );
+
+        if descriptor.derive_pattern_len()? != 2 {
+            return Err(Error::DescriptorDerivePattern);
+        }
+        let case = UnhardenedIndex::from(u8::from(show_change));
+        let rows = (skip..(skip + count))
+            .map(|index| {
+                let pat = [case, UnhardenedIndex::from(index)];
+                Ok((
+                    index,
+                    descriptor.address_on(&secp, pat, network.bitcoin_network())?,
+                    descriptor.script_pubkey_pretr(&secp, pat)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, DeriveError>>()?;
+        let stats = client.resolve_script_stats(rows.iter().map(|(_, _, script)| script))?;
+
+        println!(
+            "{:>6} {:<8} {:<62} {:>16} {:>16}",
+            "#", "path", "address", "received, sats", "unspent, sats"
+        );
+        let (mut total_received, mut total_unspent) = (0u64, 0u64);
+        for ((index, address, _), stats) in rows.iter().zip(stats) {
+            let line = format!(
+                "{:>6} {:<8} {:<62} {:>16} {:>16}",
+                format!("#{}", index),
+                format!("{}/{}", case, index),
+                address,
+                stats.received,
+                stats.unspent
+            );
+            if stats.unspent > 0 {
+                println!("{}", line.bright_yellow());
+            } else if stats.is_used() {
+                println!("{}", line);
+            } else {
+                println!("{}", line.dimmed());
+            }
+            total_received += stats.received;
+            total_unspent += stats.unspent;
+        }
+
+        println!(
+            "\nTotal received {} sats, unspent {} sats\n",
+            total_received.to_string().bright_white(),
+            total_unspent.to_string().bright_yellow().underline()
+        );
+
+        Ok(())
+    }
+
+    fn core_export(
+        &self,
+        path: &Path,
+        birthday: Option<u32>,
+        new: bool,
+        range: u32,
+    ) -> Result<(), Error> {
+        let descriptor_str = fs::read_to_string(path)?;
+        let descriptor: miniscript::Descriptor<DerivationAccount> =
+            miniscript::Descriptor::from_str(&descriptor_str)?;
+
+        let block_time = |height: u32| -> Result<u32, Error> {
+            let network = self.chain(&descriptor, false)?;
+            let client = self.electrum_client(&network)?;
+            Ok(client.block_header(height as usize)?.time)
+        };
+        let import = if new {
+            CoreImport::with(&descriptor, CoreTimestamp::Now, range)?
+        } else {
+            CoreImport::with_birthday(&descriptor, birthday, block_time, range)?
+        };
+
+        println!("{}", import);
+
+        Ok(())
+    }
+
     fn check(&self, path: &Path, batch_size: u16, skip: u16, regtest: bool) -> Result<(), Error> {
         let secp = Secp256k1::new();
 
@@ -475,13 +770,13 @@ impl Args {
         let descriptor: miniscript::Descriptor<DerivationAccount> =
             miniscript::Descriptor::from_str(&descriptor_str)?;
 
-        let network = descriptor.network(regtest)?;
-        let client = self.electrum_client(network)?;
+        let network = self.chain(&descriptor, regtest)?;
+        let client = self.electrum_client(&network)?;
 
         println!(
             "{}\n{}\n",
             "\nWallet descriptor:".bright_white(),
-            descriptor.to_string_std(self.bitcoin_core_fmt)
+            descriptor.to_string_std(self.bitcoin_core_fmt, network.bitcoin_network())
         );
 
         let mut total = 0u64;
@@ -519,8 +814,9 @@ impl Args {
                     count += utxo_set.len();
 
                     let derive_term = format!("{}/{}", case, index);
+                    let address_network = network.bitcoin_network().into();
                     if let Some(address) =
-                        AddressCompat::from_script(&script.clone().into(), network.into())
+                        AddressCompat::from_script(&script.clone().into(), address_network)
                     {
                         println!(
                             "\n  {} address {}:",
@@ -615,7 +911,7 @@ impl Args {
         &self,
         wallet_path: &Path,
         lock_time: LockTime,
-        inputs: &[InputDescriptor],
+        inputs: &[TemplatedInput],
         outputs: &[AddressAmount],
         change_index: UnhardenedIndex,
         proprietary_keys: &[ProprietaryKeyDescriptor],
@@ -626,12 +922,12 @@ impl Args {
         let descriptor: miniscript::Descriptor<DerivationAccount> =
             miniscript::Descriptor::from_str(&descriptor_str)?;
 
-        let network = descriptor.network(false)?;
+        let network = self.chain(&descriptor, false)?;
         let electrum_url = format!(
             "{}:{}",
             self.electrum_server,
             self.electrum_port
-                .unwrap_or_else(|| default_electrum_port(network))
+                .unwrap_or_else(|| default_electrum_port(&network))
         );
         let client = electrum::Client::new(&electrum_url)?;
 
@@ -647,7 +943,10 @@ impl Args {
             electrum_url.yellow()
         );
 
-        let txid_set: BTreeSet<_> = inputs.iter().map(|input| input.outpoint.txid).collect();
+        let txid_set: BTreeSet<_> = inputs
+            .iter()
+            .map(|templated| templated.input.outpoint.txid)
+            .collect();
         let tx_map = client
             .batch_transaction_get(&txid_set)?
             .into_iter()
@@ -666,36 +965,81 @@ impl Args {
             })
             .collect::<Vec<_>>();
 
-        let mut psbt = Psbt::construct(&descriptor, inputs, &outputs, change_index, fee, &tx_map)?;
+        let mut psbt = Psbt::construct(
+            &descriptor,
+            inputs.iter().map(|templated| &templated.input),
+            &outputs,
+            change_index,
+            fee,
+            &tx_map,
+        )?;
         psbt.fallback_locktime = Some(lock_time);
+        psbt.set_satisfaction_templates(inputs);
 
         for key in proprietary_keys {
-            match key.location {
-                ProprietaryKeyLocation::Input(pos) if pos as usize >= psbt.inputs.len() => {
-                    return Err(ProprietaryKeyError::InputOutOfRange(pos, psbt.inputs.len()).into())
-                }
-                ProprietaryKeyLocation::Output(pos) if pos as usize >= psbt.outputs.len() => {
-                    return Err(
-                        ProprietaryKeyError::OutputOutOfRange(pos, psbt.inputs.len()).into(),
-                    )
-                }
-                ProprietaryKeyLocation::Global => {
-                    psbt.proprietary
-                        .insert(key.into(), key.value.as_ref().cloned().unwrap_or_default());
-                }
-                ProprietaryKeyLocation::Input(pos) => {
-                    psbt.inputs[pos as usize]
-                        .proprietary
-                        .insert(key.into(), key.value.as_ref().cloned().unwrap_or_default());
-                }
-                ProprietaryKeyLocation::Output(pos) => {
-                    psbt.outputs[pos as usize]
-                        .proprietary
-                        .insert(key.into(), key.value.as_ref().cloned().unwrap_or_default());
+            psbt.insert_proprietary_key(key)?;
+        }
+
+        fs::write(psbt_path, psbt.serialize())?;
+
+        println!("{} {}\n", "PSBT:".bright_white(), psbt);
+
+        Ok(())
+    }
+
+    fn construct_recipe(
+        &self,
+        wallet_path: &Path,
+        recipe_path: &Path,
+        lookahead: u16,
+        psbt_path: &Path,
+    ) -> Result<(), Error> {
+        let secp = Secp256k1::new();
+
+        let descriptor_str = fs::read_to_string(wallet_path)?;
+        let descriptor: miniscript::Descriptor<DerivationAccount> =
+            miniscript::Descriptor::from_str(&descriptor_str)?;
+        let recipe: PsbtRecipe = serde_yaml::from_str(&fs::read_to_string(recipe_path)?)?;
+
+        let network = self.chain(&descriptor, false)?;
+        let client = self.electrum_client(&network)?;
+
+        println!(
+            "{}\n{}\n",
+            "\nWallet descriptor:".bright_white(),
+            descriptor
+        );
+
+        let mut coins = vec![];
+        if recipe.inputs == InputSelection::Auto {
+            if descriptor.derive_pattern_len()? != 2 {
+                return Err(Error::DescriptorDerivePattern);
+            }
+            eprint!("Scanning wallet coins ... ");
+            for case in [UnhardenedIndex::zero(), UnhardenedIndex::one()] {
+                for (index, (_, utxo_set)) in client.resolve_descriptor_utxo(
+                    &secp,
+                    &descriptor,
+                    [case],
+                    UnhardenedIndex::zero(),
+                    lookahead as u32,
+                )? {
+                    coins.extend(utxo_set.into_iter().map(|utxo| {
+                        let input = InputDescriptor {
+                            outpoint: *utxo.outpoint(),
+                            terminal: vec![case, index].into(),
+                            seq_no: none!(),
+                            tweak: None,
+                            sighash_type: EcdsaSighashType::All,
+                        };
+                        (input, utxo.amount().to_sat())
+                    }));
                 }
             }
+            eprintln!("{}", "done\n".green());
         }
 
+        let psbt = recipe.construct(&descriptor, &coins, &MaxFeePolicy::default(), &client)?;
         fs::write(psbt_path, psbt.serialize())?;
 
         println!("{} {}\n", "PSBT:".bright_white(), psbt);
@@ -703,20 +1047,71 @@ impl Args {
         Ok(())
     }
 
+    fn read_combined(psbt_paths: &[PathBuf]) -> Result<Psbt, Error> {
+        let mut psbt: Option<Psbt> = None;
+        for path in psbt_paths {
+            let data = fs::read(path)?;
+            let other = Psbt::deserialize(&data)?;
+            psbt = Some(match psbt {
+                None => other,
+                Some(psbt) => psbt.combine(other)?,
+            });
+        }
+        Ok(psbt.expect("at least one PSBT file is required by the command-line parser"))
+    }
+
+    fn combine(&self, psbt_paths: &[PathBuf], output_path: &Path) -> Result<(), Error> {
+        let psbt = Self::read_combined(psbt_paths)?;
+        fs::write(output_path, psbt.serialize())?;
+
+        println!(
+            "Combined {} PSBT files into {}\n",
+            psbt_paths.len().to_string().bright_green(),
+            output_path.display()
+        );
+
+        Ok(())
+    }
+
     fn finalize(
         &self,
-        psbt_path: &Path,
+        psbt_paths: &[PathBuf],
         tx_path: Option<&PathBuf>,
-        publish: Option<Network>,
+        publish: Option<Chain>,
     ) -> Result<(), Error> {
         let secp = Secp256k1::new();
 
-        let data = fs::read(psbt_path)?;
-        let mut psbt = consensus::encode::deserialize::<PartiallySignedTransaction>(&data)?;
+        let mut psbt = Self::read_combined(psbt_paths)?;
+        psbt.finalize_templated()?;
 
-        psbt.finalize_mut(&secp).map_err(VecDisplay::from)?;
+        let mut psbt = PartiallySignedTransaction::from(psbt);
+        // Inputs finalized with satisfaction templates must be left intact
+        let pending = psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| {
+                input.final_script_witness.is_none() && input.final_script_sig.is_none()
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let errors = pending
+            .into_iter()
+            .filter_map(|index| psbt.finalize_inp_mut(&secp, index).err())
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(VecDisplay::from(errors).into());
+        }
 
-        let tx = psbt.extract_tx();
+        let mut psbt = Psbt::from(psbt);
+        psbt.finalize_tap_annexes();
+
+        let tx = psbt.extract_signed_tx()?;
+        eprintln!(
+            "{} {}\n",
+            "Transaction id:".bright_white(),
+            tx.txid().to_string().yellow()
+        );
 
         if let Some(tx_path) = tx_path {
             let mut file = fs::File::create(tx_path)?;
@@ -726,7 +1121,7 @@ impl Args {
         }
 
         if let Some(network) = publish {
-            let client = self.electrum_client(network)?;
+            let client = self.electrum_client(&network)?;
             client.transaction_broadcast(&tx)?;
             eprintln!(
                 "{} {} {}\n",
@@ -760,16 +1155,76 @@ impl Args {
         println!("\n{}\n", psbt);
         Ok(())
     }
-}
 
-fn default_electrum_port(network: Network) -> u16 {
-    match network {
-        Network::Bitcoin => 50001,
-        Network::Testnet => 60001,
-        Network::Signet | Network::Regtest => 60601,
+    fn strip(
+        &self,
+        psbt_path: &Path,
+        output_path: Option<&Path>,
+        prefixes: &[String],
+        keep: &[String],
+    ) -> Result<(), Error> {
+        let data = fs::read(psbt_path)?;
+        let mut psbt = Psbt::deserialize(&data)?;
+
+        let count = if !keep.is_empty() {
+            psbt.retain_proprietary(|_, key, _| {
+                keep.iter().any(|prefix| prefix.as_bytes() == key.prefix)
+            })
+        } else if prefixes.is_empty() {
+            psbt.strip_proprietary(None)
+        } else {
+            prefixes
+                .iter()
+                .map(|prefix| psbt.strip_proprietary(Some(prefix.as_bytes())))
+                .sum()
+        };
+
+        fs::write(output_path.unwrap_or(psbt_path), psbt.serialize())?;
+
+        println!(
+            "Removed {} proprietary keys\n",
+            count.to_string().bright_green()
+        );
+
+        Ok(())
+    }
+
+    fn preimage(
+        &self,
+        psbt_path: &Path,
+        output_path: Option<&Path>,
+        preimage: &str,
+    ) -> Result<(), Error> {
+        let preimage = Vec::<u8>::from_hex(preimage)
+            .ok()
+            .and_then(|data| <[u8; 32]>::try_from(data).ok())
+            .ok_or(Error::InvalidPreimage)?;
+
+        let data = fs::read(psbt_path)?;
+        let mut psbt = Psbt::deserialize(&data)?;
+
+        let count = psbt.provide_preimage(preimage);
+        if count == 0 {
+            eprintln!(
+                "{} no hash locks matching the preimage were found\n",
+                "Warning:".bright_yellow()
+            );
+            return Ok(());
+        }
+
+        fs::write(output_path.unwrap_or(psbt_path), psbt.serialize())?;
+
+        println!(
+            "Preimage added for {} hash locks\n",
+            count.to_string().bright_green()
+        );
+
+        Ok(())
     }
 }
 
+fn default_electrum_port(network: &Chain) -> u16 { network.electrum_port().unwrap_or(60601) }
+
 #[derive(Clone, PartialEq, Eq, Debug, Display, From)]
 #[display(doc_comments)]
 pub enum ParseError {
@@ -929,6 +1384,9 @@ pub enum Error {
     #[from]
     PsbtEncoding(consensus::encode::Error),
 
+    #[from]
+    PsbtCombine(bitcoin::psbt::Error),
+
     #[from]
     Miniscript(miniscript::Error),
 
@@ -950,6 +1408,12 @@ pub enum Error {
     #[from]
     PsbtConstruction(construct::Error),
 
+    #[from]
+    PsbtRecipe(construct::RecipeError),
+
+    #[from]
+    CoreExport(CoreImportError),
+
     /// can't finalize PSBT data due to following problem(s):
     ///
     /// {0}
@@ -957,6 +1421,11 @@ pub enum Error {
     #[from]
     PsbtFinalization(VecDisplay<miniscript::psbt::Error, true, '-', '\n'>),
 
+    /// can't finalize PSBT input according to its satisfaction template. {0}
+    #[display(doc_comments)]
+    #[from]
+    PsbtTemplateFinalization(psbt::FinalizeError),
+
     /// unrecognized number of wildcards in the descriptor derive pattern
     #[display(doc_comments)]
     DescriptorDerivePattern,
@@ -979,6 +1448,15 @@ pub enum Error {
     #[from]
     #[display(doc_comments)]
     PsbtProprietaryKey(ProprietaryKeyError),
+
+    /// can't extract transaction from PSBT. {0}
+    #[from]
+    #[display(doc_comments)]
+    PsbtLockTime(LockTimeError),
+
+    /// preimage must be a hex-encoded 32-byte string
+    #[display(doc_comments)]
+    InvalidPreimage,
 }
 
 // TODO: Move to amplify crate
@@ -1010,27 +1488,45 @@ where
 }
 
 trait ToStringStd {
-    fn to_string_std(&self, bitcoin_core_fmt: bool) -> String;
+    /// Formats descriptor either in Bitcoin Core format, or with extended
+    /// public keys encoded with SLIP-132 versions matching the descriptor
+    /// type and the network.
+    fn to_string_std(&self, bitcoin_core_fmt: bool, network: Network) -> String;
 }
 
 impl ToStringStd for miniscript::Descriptor<DerivationAccount> {
-    fn to_string_std(&self, bitcoin_core_fmt: bool) -> String {
-        struct StrTranslator;
+    fn to_string_std(&self, bitcoin_core_fmt: bool, network: Network) -> String {
+        struct StrTranslator {
+            bitcoin_core_fmt: bool,
+            slip132: Option<(KeyApplication, Network)>,
+        }
         impl Translator<DerivationAccount, String, Infallible> for StrTranslator {
             fn pk(&mut self, pk: &DerivationAccount) -> Result<String, Infallible> {
-                Ok(format!("{:#}", pk))
+                if self.bitcoin_core_fmt {
+                    return Ok(format!("{:#}", pk));
+                }
+                let s = pk.to_string();
+                Ok(match self.slip132 {
+                    Some((app, network)) => s.replace(
+                        &pk.account_xpub.to_string(),
+                        &pk.account_xpub.to_slip132_string(app, network),
+                    ),
+                    None => s,
+                })
             }
 
             miniscript::translate_hash_fail!(DerivationAccount, String, Infallible);
         }
 
-        if bitcoin_core_fmt {
-            self.translate_pk(&mut StrTranslator)
-                .expect("infallible")
-                .to_string()
-        } else {
-            self.to_string()
-        }
+        let slip132 = CompositeDescrType::from(self)
+            .key_application()
+            .map(|app| (app, network));
+        self.translate_pk(&mut StrTranslator {
+            bitcoin_core_fmt,
+            slip132,
+        })
+        .expect("infallible")
+        .to_string()
     }
 }
 
@@ -1040,3 +1536,13 @@ fn main() {
         eprintln!("{}: {}\n", "Error".bright_red(), err);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn command_line() { Args::command().debug_assert(); }
+}
@@ -24,17 +24,18 @@ extern crate miniscript_crate as miniscript;
 extern crate strict_encoding_crate as strict_encoding;
 
 use std::path::{Path, PathBuf};
+use std::io::Write;
 use std::str::FromStr;
 use std::{fs, io};
 
 use aes::cipher::generic_array::GenericArray;
-use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::cipher::{BlockDecrypt, KeyInit};
 use aes::{Aes256, Block};
-use amplify::hex::ToHex;
+use amplify::hex::{FromHex, ToHex};
 use amplify::IoError;
 use bip39::Mnemonic;
-use bitcoin::consensus::{self, Decodable, Encodable};
-use bitcoin::hashes::{sha256, Hash};
+use bitcoin::consensus::{self, Decodable};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::secp256k1::rand::RngCore;
 use bitcoin::secp256k1::{self, rand, Secp256k1, Signing};
 use bitcoin::util::bip32;
@@ -47,7 +48,10 @@ use hwi::HWIClient;
 use miniscript::Descriptor;
 use miniscript_crate::ForEachKey;
 use psbt::serialize::{Deserialize, Serialize};
-use psbt::sign::{MemoryKeyProvider, MemorySigningAccount, SignAll, SignError};
+use psbt::sign::{
+    KeyFile, KeyFileError, MemoryKeyProvider, MemorySigningAccount, SecretKind, SignAll, SignError,
+    KEYFILE_MAGIC,
+};
 use psbt::Psbt;
 use slip132::{KeyApplication, ToSlip132};
 use wallet::hd::standards::DerivationBlockchain;
@@ -149,20 +153,69 @@ fn decode(source: impl AsRef<[u8]>, password: &str) -> Vec<u8> {
     source
 }
 
-fn encode(source: impl AsRef<[u8]>, password: &str) -> Vec<u8> {
-    let key = sha256::Hash::hash(password.as_bytes());
-    let key = GenericArray::from_slice(key.as_inner());
-    let cipher = Aes256::new(key);
+/// Entropy provided by the user in addition to the OS randomness.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+struct UserEntropy {
+    /// Dice rolls, as digits from 1 to 6
+    dice: String,
+    /// Coin flips, as `H` and `T` letters
+    coins: String,
+    /// Arbitrary binary data
+    data: Vec<u8>,
+}
 
-    let mut source = source.as_ref().to_vec();
-    if source.len() % 16 != 0 {
-        panic!("data length for encoding must be proportional to 16")
+impl UserEntropy {
+    pub fn parse(
+        dice: Option<&str>,
+        coins: Option<&str>,
+        hex: Option<&str>,
+    ) -> Result<Self, Error> {
+        let dice = dice
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| match c {
+                '1'..='6' => Ok(c),
+                _ => Err(Error::InvalidDice(c)),
+            })
+            .collect::<Result<_, _>>()?;
+        let coins = coins
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| match c {
+                'H' | 'h' | '1' => Ok('H'),
+                'T' | 't' | '0' => Ok('T'),
+                _ => Err(Error::InvalidCoinFlip(c)),
+            })
+            .collect::<Result<_, _>>()?;
+        let data = hex
+            .map(|hex| Vec::<u8>::from_hex(hex).map_err(|_| Error::InvalidEntropyHex))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(UserEntropy { dice, coins, data })
     }
-    for chunk in source.chunks_mut(16) {
-        let block = Block::from_mut_slice(chunk);
-        cipher.encrypt_block(block);
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.dice.is_empty() && self.coins.is_empty() && self.data.is_empty()
+    }
+
+    /// Estimated amount of entropy, in bits, assuming fair dice and coins.
+    pub fn bits(&self) -> f64 {
+        self.dice.len() as f64 * 6f64.log2()
+            + self.coins.len() as f64
+            + self.data.len() as f64 * 8.0
+    }
+
+    /// SHA256 hash of the canonical serialization of the user entropy:
+    /// `dice:<rolls>\ncoins:<flips>\nhex:<lowercase hex data>`. Allows the
+    /// user to verify that the provided entropy was used without exposing it.
+    pub fn commitment(&self) -> sha256::Hash {
+        let serialized =
+            format!("dice:{}\ncoins:{}\nhex:{}", self.dice, self.coins, self.data.to_hex());
+        sha256::Hash::hash(serialized.as_bytes())
     }
-    source
 }
 
 struct Seed(Box<[u8]>);
@@ -174,19 +227,44 @@ impl Seed {
         Seed(Box::from(entropy))
     }
 
-    pub fn read<P>(file: P, password: &str) -> io::Result<Seed>
+    /// Generates seed mixing 32 bytes of OS randomness with the user entropy.
+    /// The seed is the first bytes of `SHA256(os_entropy || commitment)`,
+    /// where `commitment` is [`UserEntropy::commitment`]. Since the hash
+    /// function output is unpredictable unless both of its inputs are known,
+    /// the seed is secure if any of the entropy sources is.
+    ///
+    /// Returns the seed and the OS entropy, which may be used to re-compute
+    /// the seed independently.
+    pub fn with_mixed(seed_type: SeedType, user_entropy: &UserEntropy) -> (Seed, [u8; 32]) {
+        let mut os_entropy = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut os_entropy);
+        let mut engine = sha256::Hash::engine();
+        engine.input(&os_entropy);
+        engine.input(&user_entropy.commitment()[..]);
+        let mixed = sha256::Hash::from_engine(engine);
+        (Seed(Box::from(&mixed[..seed_type.byte_len()])), os_entropy)
+    }
+
+    /// Reads seed from the encrypted key file, falling back to the legacy
+    /// AES-encoded format for the files created by previous versions.
+    pub fn read<P>(file: P, password: &str) -> Result<Seed, Error>
     where
         P: AsRef<Path>,
     {
         let data = fs::read(file)?;
-        Ok(Seed(Box::from(decode(data, password))))
+        if !data.starts_with(&KEYFILE_MAGIC) {
+            return Ok(Seed(Box::from(decode(data, password))));
+        }
+        let key_file = KeyFile::load(data.as_slice())?;
+        Ok(Seed(Box::from(&key_file.unlock_seed(password)?[..])))
     }
 
-    pub fn write<P>(&self, file: P, password: &str) -> io::Result<()>
+    pub fn write<P>(&self, file: P, password: &str) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
-        fs::write(file, encode(&self.0, password))
+        KeyFile::create(SecretKind::Seed, &self.0, password)?.write_file(file)?;
+        Ok(())
     }
 
     #[inline]
@@ -205,6 +283,8 @@ impl Seed {
     }
 }
 
+/// Legacy format of signing account files, which is still read for the files
+/// created before the introduction of encrypted key files.
 trait SecretIo {
     fn read<C>(
         secp: &Secp256k1<C>,
@@ -214,12 +294,6 @@ trait SecretIo {
     where
         C: Signing,
         Self: Sized;
-
-    fn write(
-        &self,
-        writer: impl io::Write,
-        password: Option<&str>,
-    ) -> Result<(), consensus::encode::Error>;
 }
 
 impl SecretIo for MemorySigningAccount {
@@ -258,32 +332,73 @@ impl SecretIo for MemorySigningAccount {
             account_xpriv,
         ))
     }
+}
 
-    fn write(
-        &self,
-        mut writer: impl io::Write,
-        password: Option<&str>,
-    ) -> Result<(), consensus::encode::Error> {
-        writer.write_all(self.master_id())?;
-
-        let len = self.derivation().len() as u64;
-        len.consensus_encode(&mut writer)?;
-        for child in self.derivation() {
-            let index = u32::from(*child);
-            index.consensus_encode(&mut writer)?;
-        }
+/// Reads signing account from the encrypted key file, falling back to the
+/// legacy format for the files created by previous versions.
+fn read_account<C>(
+    secp: &Secp256k1<C>,
+    path: &Path,
+    password: &str,
+) -> Result<MemorySigningAccount, Error>
+where
+    C: Signing,
+{
+    let data = fs::read(path)?;
+    if data.starts_with(&KEYFILE_MAGIC) {
+        return Ok(KeyFile::load(data.as_slice())?.unlock_account(secp, password)?);
+    }
+    let password = Some(password).filter(|password| !password.is_empty());
+    Ok(MemorySigningAccount::read(secp, data.as_slice(), password)?)
+}
 
-        let mut data = self.account_xpriv().encode().to_vec();
-        data.resize(80, 0);
-        rand::thread_rng().fill_bytes(&mut data[78..]);
-        if let Some(password) = password {
-            data = encode(data, password);
-        }
+/// Reads password, which must not be empty since secrets are never written
+/// to the disk unencrypted.
+fn read_new_password(prompt: &str) -> Result<String, Error> {
+    print!("{}: ", prompt);
+    let password = rpassword::read_password()?;
+    if password.is_empty() {
+        return Err(Error::EmptyPassword);
+    }
+    print!("Repeat {}: ", prompt.to_lowercase());
+    if rpassword::read_password()? != password {
+        return Err(Error::PasswordMismatch);
+    }
+    Ok(password)
+}
 
-        writer.write_all(&data)?;
+/// Asks user to confirm an action, failing with [`Error::Aborted`] unless
+/// confirmed.
+fn confirm(prompt: &str) -> Result<(), Error> {
+    print!("{} [y/N]: ", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Err(Error::Aborted);
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Replaces file with the key file by writing it into a temporary file and
+/// renaming it over the original, such that the original file is never left
+/// partially written. If `backup` is set, a copy of the original file is
+/// kept with `.bak` extension appended.
+fn replace_key_file(path: &Path, key_file: &KeyFile, backup: bool) -> Result<(), Error> {
+    let with_suffix = |suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let tmp_path = with_suffix(".tmp");
+    key_file.write_file(&tmp_path)?;
+    if backup {
+        let backup_path = with_suffix(".bak");
+        fs::copy(path, &backup_path)?;
+        println!("Original file is kept as `{}`", backup_path.display());
     }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
 }
 
 /// Command-line arguments
@@ -310,8 +425,26 @@ pub struct Args {
 #[derive(Subcommand)]
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub enum Command {
-    /// Generate new seed and saves it as an encoded file
+    /// Generate new seed and saves it as an encoded file.
+    ///
+    /// If dice rolls, coin flips or hex entropy are provided, they are mixed
+    /// with the OS randomness, such that the seed remains secure if either of
+    /// the sources is not compromised.
     Seed {
+        /// Dice rolls to mix into the seed, as digits from 1 to 6. At least 50
+        /// rolls are required for a 128-bit seed not to rely on the OS
+        /// randomness.
+        #[clap(long)]
+        dice: Option<String>,
+
+        /// Coin flips to mix into the seed, as `H`/`T` or `1`/`0` characters
+        #[clap(long)]
+        coins: Option<String>,
+
+        /// Arbitrary hex-encoded entropy to mix into the seed
+        #[clap(long)]
+        entropy: Option<String>,
+
         /// File to save generated seed data and extended master key
         output_file: PathBuf,
     },
@@ -416,6 +549,14 @@ pub enum Command {
         file: PathBuf,
     },
 
+    /// Change password of the seed or signing account file. Files created by
+    /// previous versions are converted into the encrypted key file format.
+    Passwd {
+        /// Seed or signing account file, previously created with `seed` or
+        /// `derive` commands.
+        file: PathBuf,
+    },
+
     /// Sign PSBT with the provided account keys
     Sign {
         /// Add signature with known keys to the aggregated Schnorr signatures
@@ -423,6 +564,11 @@ pub enum Command {
         #[clap(short, long)]
         musig: bool,
 
+        /// Sign even if the transaction fee exceeds the maximum recorded in
+        /// PSBT or the default limit of 0.1 BTC
+        #[clap(long)]
+        force_fee: bool,
+
         /// File containing PSBT
         psbt_file: PathBuf,
 
@@ -434,7 +580,16 @@ pub enum Command {
 impl Args {
     pub fn exec(self) -> Result<(), Error> {
         match &self.command {
-            Command::Seed { output_file } => self.seed(output_file),
+            Command::Seed {
+                dice,
+                coins,
+                entropy,
+                output_file,
+            } => {
+                let user_entropy =
+                    UserEntropy::parse(dice.as_deref(), coins.as_deref(), entropy.as_deref())?;
+                self.seed(output_file, &user_entropy)
+            }
             Command::DeviceKeys {
                 account,
                 mainnet: _,
@@ -459,11 +614,13 @@ impl Args {
                 self.derive(seed_file, scheme, *account, network, output_file)
             }
             Command::Info { file } => self.info(file),
+            Command::Passwd { file } => self.passwd(file),
             Command::Sign {
                 musig,
+                force_fee,
                 psbt_file,
                 signing_account,
-            } => self.sign(psbt_file, signing_account, *musig),
+            } => self.sign(psbt_file, signing_account, *musig, *force_fee),
             Command::Key {
                 debug,
                 seed_file,
@@ -472,10 +629,41 @@ impl Args {
         }
     }
 
-    fn seed(&self, output_file: &Path) -> Result<(), Error> {
-        let seed = Seed::with(SeedType::Bit128);
-        print!("Password: ");
-        let password = rpassword::read_password()?;
+    fn seed(&self, output_file: &Path, user_entropy: &UserEntropy) -> Result<(), Error> {
+        let seed_type = SeedType::Bit128;
+        let seed = if user_entropy.is_empty() {
+            Seed::with(seed_type)
+        } else {
+            let (seed, os_entropy) = Seed::with_mixed(seed_type, user_entropy);
+            println!("{}", "Entropy mixing:".bright_white());
+            println!(
+                "{:-18} {:.1} bits from {} dice rolls, {} coin flips and {} bytes",
+                "  - user entropy:".bright_white(),
+                user_entropy.bits(),
+                user_entropy.dice.len(),
+                user_entropy.coins.len(),
+                user_entropy.data.len()
+            );
+            println!("{:-18} {}", "  - user hash:".bright_white(), user_entropy.commitment());
+            if self.print_private {
+                println!(
+                    "{:-18} {}",
+                    "  - OS entropy:".bright_white(),
+                    os_entropy.to_hex().black().dimmed()
+                );
+            }
+            if user_entropy.bits() < seed_type.bit_len() as f64 {
+                eprintln!(
+                    "{}: user entropy is less than {} bits; seed security relies on the OS \
+                     randomness",
+                    "Warning".bright_yellow(),
+                    seed_type.bit_len()
+                );
+            }
+            println!();
+            seed
+        };
+        let password = read_new_password("Password")?;
         seed.write(output_file, &password)?;
 
         let secp = Secp256k1::new();
@@ -613,13 +801,7 @@ impl Args {
 
         print!("Seed password: ");
         let seed_password = rpassword::read_password()?;
-        print!("Account password: ");
-        let account_password = rpassword::read_password()?;
-        let account_password = if account_password.is_empty() {
-            None
-        } else {
-            Some(account_password)
-        };
+        let account_password = read_new_password("Account password")?;
 
         let seed = Seed::read(seed_file, &seed_password)?;
         let master_xpriv = seed.master_xpriv(network.is_testnet())?;
@@ -630,8 +812,7 @@ impl Args {
         let account =
             MemorySigningAccount::with(&secp, master_xpub.identifier(), derivation, account_xpriv);
 
-        let file = fs::File::create(output_file)?;
-        account.write(file, account_password.as_deref())?;
+        KeyFile::create_account(&account, &account_password)?.write_file(output_file)?;
 
         self.info_account(account);
 
@@ -766,7 +947,7 @@ impl Args {
             format!("{}", account.derivation()).trim_start_matches("m/")
         );
         if self.print_private {
-            let account_xpriv = account.account_xpriv();
+            let account_xpriv = account.export_xpriv();
             println!(
                 "{:-18} {}",
                 "  - xpriv:".bright_white(),
@@ -815,21 +996,21 @@ impl Args {
 
     fn info(&self, path: &Path) -> Result<(), Error> {
         let secp = Secp256k1::new();
-        let file = fs::File::open(path)?;
 
         print!("Password: ");
         let password = rpassword::read_password()?;
 
-        {
-            let password = if password.is_empty() {
-                None
-            } else {
-                Some(password.clone())
-            };
-            if let Ok(account) = MemorySigningAccount::read(&secp, file, password.as_deref()) {
-                self.info_account(account);
-                return Ok(());
+        if let Ok(key_file) = KeyFile::read_file(path) {
+            match key_file.kind() {
+                SecretKind::Seed => self.info_seed(&secp, Seed::read(path, &password)?),
+                SecretKind::Account => self.info_account(read_account(&secp, path, &password)?),
             }
+            return Ok(());
+        }
+
+        if let Ok(account) = read_account(&secp, path, &password) {
+            self.info_account(account);
+            return Ok(());
         }
 
         if let Ok(seed) = Seed::read(path, &password) {
@@ -846,19 +1027,67 @@ impl Args {
         Ok(())
     }
 
-    fn sign(&self, psbt_path: &Path, account_path: &Path, musig: bool) -> Result<(), Error> {
-        print!("Account password: ");
+    fn passwd(&self, path: &Path) -> Result<(), Error> {
+        let secp = Secp256k1::new();
+
+        print!("Current password: ");
         let password = rpassword::read_password()?;
-        let password = if password.is_empty() {
-            None
-        } else {
-            Some(password)
+
+        let (key_file, legacy) = match KeyFile::read_file(path) {
+            Ok(mut key_file) => {
+                let new_password = read_new_password("New password")?;
+                key_file.change_password(&password, &new_password)?;
+                (key_file, false)
+            }
+            // Files in the legacy format are converted into key files. Since
+            // the legacy format has no integrity checks, wrong password
+            // produces garbage instead of an error, so the user has to
+            // confirm the decrypted secret before the file is replaced.
+            Err(KeyFileError::InvalidMagic) => {
+                let key_file = if let Ok(account) = read_account(&secp, path, &password) {
+                    println!(
+                        "{} {}",
+                        "Signing account:".bright_white(),
+                        account.to_account().to_string().bright_green()
+                    );
+                    confirm("Is this the expected account?")?;
+                    let new_password = read_new_password("New password")?;
+                    KeyFile::create_account(&account, &new_password)?
+                } else {
+                    let seed = Seed::read(path, &password)?;
+                    let fingerprint = seed.master_xpriv(false)?.fingerprint(&secp);
+                    println!(
+                        "{} {}",
+                        "Seed master key fingerprint:".bright_white(),
+                        fingerprint.to_string().bright_green()
+                    );
+                    confirm("Is this the expected fingerprint?")?;
+                    let new_password = read_new_password("New password")?;
+                    KeyFile::create(SecretKind::Seed, seed.as_entropy(), &new_password)?
+                };
+                (key_file, true)
+            }
+            Err(err) => return Err(err.into()),
         };
+        replace_key_file(path, &key_file, legacy)?;
+        println!("Password for {} changed", key_file.kind().to_string().bright_green());
+
+        Ok(())
+    }
+
+    fn sign(
+        &self,
+        psbt_path: &Path,
+        account_path: &Path,
+        musig: bool,
+        force_fee: bool,
+    ) -> Result<(), Error> {
+        print!("Account password: ");
+        let password = rpassword::read_password()?;
 
         let secp = Secp256k1::new();
 
-        let file = fs::File::open(account_path)?;
-        let account = MemorySigningAccount::read(&secp, file, password.as_deref())?;
+        let account = read_account(&secp, account_path, &password)?;
 
         println!("Signing with {}\n", account.to_account());
 
@@ -867,6 +1096,9 @@ impl Args {
 
         let mut key_provider = MemoryKeyProvider::with(&secp, musig);
         key_provider.add_account(account);
+        if force_fee {
+            key_provider.set_fee_policy(None);
+        }
 
         let sig_count = psbt.sign_all(&key_provider)?;
         println!("Done {} signatures\n", sig_count.to_string().bright_green());
@@ -895,9 +1127,36 @@ pub enum Error {
     #[from]
     Signing(SignError),
 
+    #[from]
+    KeyFile(KeyFileError),
+
     #[from]
     #[display(Debug)]
     Hwi(hwi::error::Error),
+
+    /// invalid dice roll `{0}`; only digits from 1 to 6 are allowed
+    #[display(doc_comments)]
+    InvalidDice(char),
+
+    /// invalid coin flip `{0}`; only `H`, `T`, `1` and `0` are allowed
+    #[display(doc_comments)]
+    InvalidCoinFlip(char),
+
+    /// user entropy must be a hex-encoded string
+    #[display(doc_comments)]
+    InvalidEntropyHex,
+
+    /// password must not be empty
+    #[display(doc_comments)]
+    EmptyPassword,
+
+    /// passwords do not match
+    #[display(doc_comments)]
+    PasswordMismatch,
+
+    /// operation aborted by the user
+    #[display(doc_comments)]
+    Aborted,
 }
 
 fn main() {